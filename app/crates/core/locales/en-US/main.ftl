@@ -22,6 +22,7 @@ conversation-placeholder = Type a message…
 conversation-send = Send
 message-delivered = delivered
 chatstate-typing = typing...
+chatstate-gone = left the conversation
 mode-normal = Normal
 mode-insert = Insert
 mode-command = Command
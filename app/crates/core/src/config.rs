@@ -103,12 +103,17 @@ impl Default for PluginsConfig {
 pub struct LoggingConfig {
     #[serde(default = "default_log_level")]
     pub level: String,
+    /// Record chat states sent while offline to the `chat_state_log` table
+    /// instead of silently dropping them; they are never delivered either way
+    #[serde(default)]
+    pub record_offline_chat_states: bool,
 }
 
 impl Default for LoggingConfig {
     fn default() -> Self {
         Self {
             level: "info".to_string(),
+            record_offline_chat_states: false,
         }
     }
 }
@@ -130,6 +135,9 @@ impl Default for EventBusConfig {
 #[derive(Debug, Clone, Default, Deserialize)]
 pub struct StorageConfig {
     pub path: Option<String>,
+    /// Most rows a single query may return before it fails; unbounded when
+    /// unset
+    pub max_rows: Option<usize>,
 }
 
 #[derive(Debug, Default, Clone)]
@@ -178,12 +186,14 @@ enabled = true
 
 [logging]
 level = "info"
+# record_offline_chat_states = false
 
 [event_bus]
 channel_capacity = 1024
 
 [storage]
 # path = "~/.local/share/waddle/waddle.db"
+# max_rows = 100000
 "#;
 
 /// Return the resolved platform-appropriate configuration file path.
@@ -371,8 +381,10 @@ password = "secret"
         assert!(config.plugins.enabled);
         assert!(config.plugins.directory.is_none());
         assert_eq!(config.logging.level, "info");
+        assert!(!config.logging.record_offline_chat_states);
         assert_eq!(config.event_bus.channel_capacity, 1024);
         assert!(config.storage.path.is_none());
+        assert!(config.storage.max_rows.is_none());
     }
 
     #[test]
@@ -440,6 +452,21 @@ directory = "/opt/waddle/plugins"
         );
     }
 
+    #[test]
+    fn parses_offline_chat_state_logging() {
+        let toml = r#"
+[account]
+jid = "user@example.com"
+password = "secret"
+
+[logging]
+record_offline_chat_states = true
+"#;
+        let config = parse_without_env(toml).unwrap();
+        assert!(config.logging.record_offline_chat_states);
+        assert_eq!(config.logging.level, "info");
+    }
+
     #[test]
     fn parses_custom_storage_path() {
         let toml = r#"
@@ -449,9 +476,11 @@ password = "secret"
 
 [storage]
 path = "/data/waddle.db"
+max_rows = 50000
 "#;
         let config = parse_without_env(toml).unwrap();
         assert_eq!(config.storage.path.as_deref(), Some("/data/waddle.db"));
+        assert_eq!(config.storage.max_rows, Some(50_000));
     }
 
     // ── Validation ────────────────────────────────────────────────
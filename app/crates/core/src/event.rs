@@ -1,3 +1,5 @@
+use std::collections::BTreeMap;
+
 use chrono::{DateTime, Utc};
 #[cfg(feature = "native")]
 use globset::{Glob, GlobMatcher};
@@ -155,13 +157,27 @@ pub enum EventPayload {
     ShutdownRequested {
         reason: String,
     },
+    /// Asks the outbound router to answer with `OutboundFlushed`, under the
+    /// same correlation id, once every command published before this one
+    /// has been handed to the transport. Published on `ui.outbound.flush`:
+    /// the bus only orders events within one domain.
+    OutboundFlushRequested,
+    OutboundFlushed,
     ConnectionEstablished {
         jid: String,
     },
+    /// `resumable` is set when stream management will try to resume the
+    /// session, so server-side state such as contact presence survives.
     ConnectionLost {
         reason: String,
         will_retry: bool,
+        #[serde(default)]
+        resumable: bool,
     },
+    /// Published after `ConnectionEstablished` when a reconnect that set out
+    /// to resume the previous stream started a new session instead, so
+    /// server-side state kept across the blip is gone after all.
+    StreamResumeFailed,
     ConnectionReconnecting {
         attempt: u32,
     },
@@ -177,16 +193,32 @@ pub enum EventPayload {
         message: String,
         recoverable: bool,
     },
+    /// A received or echoed message could not be stored after retrying; it
+    /// can be recovered from the archive.
+    MessagePersistFailed {
+        id: String,
+    },
+    /// A message went to a JID we have no presence subscription to, which
+    /// some servers silently drop. `subscription` is `None` when the JID is
+    /// not in the roster at all.
+    RecipientNotSubscribed {
+        to: String,
+        subscription: Option<Subscription>,
+    },
 
     // ── XMPP Roster events ────────────────────────────────────────
+    /// `version` is the server's roster version (RFC 6121 section 2.6), if any.
     RosterReceived {
         items: Vec<RosterItem>,
+        version: Option<String>,
     },
     RosterUpdated {
         item: RosterItem,
+        version: Option<String>,
     },
     RosterRemoved {
         jid: String,
+        version: Option<String>,
     },
     SubscriptionRequest {
         from: String,
@@ -217,9 +249,30 @@ pub enum EventPayload {
     MessageSent {
         message: ChatMessage,
     },
+    /// `resource` is the peer resource that sent the receipt, when the
+    /// receipt came from a full JID.
     MessageDelivered {
         id: String,
         to: String,
+        #[serde(default)]
+        resource: Option<String>,
+    },
+    /// A message we sent to `to` was never confirmed, e.g. the room did not
+    /// reflect it back in time. It can be sent again.
+    MessageNotDelivered {
+        id: String,
+        to: String,
+    },
+    /// XEP-0308: `from` replaced the body of its earlier message
+    /// `target_id`.
+    MessageCorrectionReceived {
+        from: String,
+        target_id: String,
+        body: String,
+    },
+    /// Messages we sent to `to` are now considered seen by them.
+    MessagesDisplayed {
+        to: String,
     },
     ChatStateReceived {
         from: String,
@@ -233,8 +286,55 @@ pub enum EventPayload {
         room: String,
         nick: String,
     },
+    /// The room answered our join presence with an error.
+    MucJoinFailed {
+        room: String,
+        nick: String,
+        failure: MucJoinFailure,
+    },
     MucLeft {
         room: String,
+        removal: Option<MucRemoval>,
+    },
+    MucRemoved {
+        room: String,
+        code: u16,
+        reason: Option<String>,
+        actor: Option<String>,
+    },
+    MucDestroyed {
+        room: String,
+        reason: Option<String>,
+        alternate: Option<String>,
+    },
+    /// A room moderator retracted a message (XEP-0425). `id` is the room's
+    /// stanza-id for it from the wire and our local message id once stored,
+    /// `by` the moderator's occupant JID if disclosed.
+    MessageModerated {
+        room: String,
+        id: String,
+        by: Option<String>,
+        reason: Option<String>,
+    },
+    /// A room's disco#info answer (XEP-0030).
+    MucRoomInfoReceived {
+        query_id: String,
+        room: String,
+        info: MucRoomInfo,
+    },
+    /// A room, or its service, answered query `query_id` with an IQ error,
+    /// e.g. `item-not-found` or `forbidden`.
+    MucQueryFailed {
+        query_id: String,
+        room: String,
+        condition: String,
+    },
+    /// A room's answer to an affiliation list query (XEP-0045): the bare JID
+    /// of each entry and the reason recorded for it, if any.
+    MucAffiliationListReceived {
+        query_id: String,
+        room: String,
+        items: Vec<(String, Option<String>)>,
     },
     MucSubjectChanged {
         room: String,
@@ -244,6 +344,24 @@ pub enum EventPayload {
         room: String,
         occupant: MucOccupant,
     },
+    /// The room confirmed an occupant's nick change (status 303). `own` is
+    /// set when the occupant is us.
+    MucNickChanged {
+        room: String,
+        old_nick: String,
+        new_nick: String,
+        own: bool,
+    },
+    /// Our own presence closed the room's initial flood of occupant
+    /// presences, so its occupant list is complete.
+    MucRosterComplete {
+        room: String,
+    },
+    MucChatStateReceived {
+        room: String,
+        nick: String,
+        state: ChatState,
+    },
 
     // ── XMPP MAM events ──────────────────────────────────────────
     MamResultReceived {
@@ -257,6 +375,25 @@ pub enum EventPayload {
         last_id: Option<String>,
     },
 
+    // ── XMPP PEP events ──────────────────────────────────────────
+    /// XEP-0163: `from` published an item on its PEP `node`. `payload` is
+    /// the item's child element serialized as XML.
+    PepItemReceived {
+        from: String,
+        node: String,
+        item_id: Option<String>,
+        payload: String,
+    },
+
+    // ── XMPP Disco events ────────────────────────────────────────
+    /// XEP-0030: `from` asked what we support, either for our entity or,
+    /// when it is resolving our entity capabilities, for `node`.
+    DiscoInfoQueried {
+        from: String,
+        id: String,
+        node: Option<String>,
+    },
+
     // ── XMPP Debug events ────────────────────────────────────────
     RawStanzaReceived {
         stanza: String,
@@ -276,6 +413,14 @@ pub enum EventPayload {
         jid: String,
         direction: ScrollDirection,
     },
+    /// Result of a history fetch triggered by scrolling; `reached_beginning`
+    /// means the archive has nothing older, and is never set when scrolling
+    /// down.
+    HistoryLoaded {
+        jid: String,
+        count: u64,
+        reached_beginning: bool,
+    },
     ComposeStarted {
         jid: String,
     },
@@ -295,9 +440,18 @@ pub enum EventPayload {
         body: String,
         message_type: MessageType,
     },
+    /// XEP-0308: sends `body` to `to` as the new text of our message
+    /// `target_id`.
+    MessageCorrectRequested {
+        to: String,
+        target_id: String,
+        body: String,
+    },
     PresenceSetRequested {
         show: PresenceShow,
         status: Option<String>,
+        /// Resource priority (RFC 6121 §4.7.2.3); 0 is the server default.
+        priority: i8,
     },
     RosterAddRequested {
         jid: String,
@@ -322,6 +476,12 @@ pub enum EventPayload {
     MucLeaveRequested {
         room: String,
     },
+    /// Asks `room` to let us go by `nick` from now on, confirmed by a
+    /// `MucNickChanged`.
+    MucNickChangeRequested {
+        room: String,
+        nick: String,
+    },
     RosterUpdateRequested {
         jid: String,
         name: Option<String>,
@@ -332,10 +492,34 @@ pub enum EventPayload {
         room: String,
         body: String,
     },
+    /// Moderate a room message (XEP-0425); `id` is the stanza-id the room
+    /// assigned it.
+    MucModerateRequested {
+        room: String,
+        id: String,
+        reason: Option<String>,
+    },
+    /// Asks `room` for its disco#info, answered by a `MucRoomInfoReceived`
+    /// or `MucQueryFailed` with the same `query_id`.
+    MucRoomInfoRequested {
+        query_id: String,
+        room: String,
+    },
+    /// Asks `room` for everyone holding `affiliation`, answered by a
+    /// `MucAffiliationListReceived` with the same `query_id`.
+    MucAffiliationListRequested {
+        query_id: String,
+        room: String,
+        affiliation: MucAffiliation,
+    },
     ChatStateSendRequested {
         to: String,
         state: ChatState,
     },
+    MucChatStateSendRequested {
+        room: String,
+        state: ChatState,
+    },
     MamQueryRequested {
         query_id: String,
         with_jid: Option<String>,
@@ -343,6 +527,18 @@ pub enum EventPayload {
         before: Option<String>,
         max: u32,
     },
+    /// Publishes `payload`, an XML element, as an item on our own PEP
+    /// `node`. Without `item_id` the server picks one.
+    PepPublishRequested {
+        node: String,
+        item_id: Option<String>,
+        payload: String,
+    },
+    /// The PEP nodes we want contacts' items for. They are advertised as
+    /// `<node>+notify` features in our entity capabilities.
+    PepInterestsChanged {
+        nodes: Vec<String>,
+    },
 
     // ── Plugin events ────────────────────────────────────────────
     PluginLoaded {
@@ -460,9 +656,60 @@ pub struct ChatMessage {
     /// Plugin-generated rich embeds (e.g. GitHub repo cards).
     #[serde(default, skip_serializing_if = "Vec::is_empty")]
     pub embeds: Vec<MessageEmbed>,
+
+    /// Body variants by `xml:lang` when the message carries more than one;
+    /// `body` holds the default. A body without `xml:lang` has the key `""`.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub bodies: BTreeMap<String, String>,
+
+    /// The sender's XEP-0359 origin-id, shared by every copy of the message
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub origin_id: Option<String>,
+
+    /// XEP-0382 spoiler hint: the body should stay hidden behind it. An
+    /// empty hint marks a spoiler without one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub spoiler: Option<String>,
+
+    /// XEP-0359 stanza-id assigned by the room or archive that stored the
+    /// message; moderation and archive paging refer to it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stanza_id: Option<String>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+impl ChatMessage {
+    /// The body variant for `lang`. Matches the full tag first, then the
+    /// primary language subtag (`de-AT` picks a `de` or `de-DE` body);
+    /// otherwise, or when `lang` is `None`, `body`. Use
+    /// [`I18n::body_for`](crate::i18n::I18n::body_for) for the user's
+    /// configured locale.
+    pub fn body_for_lang(&self, lang: Option<&str>) -> &str {
+        let Some(lang) = lang else {
+            return &self.body;
+        };
+        let primary = |tag: &str| {
+            tag.split(['-', '_'])
+                .next()
+                .unwrap_or(tag)
+                .to_ascii_lowercase()
+        };
+        let exact = self.bodies.iter().find(|(variant, _)| {
+            variant
+                .replace('_', "-")
+                .eq_ignore_ascii_case(&lang.replace('_', "-"))
+        });
+        let by_primary = || {
+            self.bodies
+                .iter()
+                .find(|(variant, _)| !variant.is_empty() && primary(variant) == primary(lang))
+        };
+        exact
+            .or_else(by_primary)
+            .map_or(&self.body, |(_, body)| body)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub enum MessageType {
     Chat,
@@ -472,8 +719,65 @@ pub enum MessageType {
     Error,
 }
 
+impl MessageType {
+    /// The RFC 6121 `type` attribute value, also used as the stored form.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            MessageType::Chat => "chat",
+            MessageType::Groupchat => "groupchat",
+            MessageType::Normal => "normal",
+            MessageType::Headline => "headline",
+            MessageType::Error => "error",
+        }
+    }
+
+    pub fn is_groupchat(&self) -> bool {
+        matches!(self, MessageType::Groupchat)
+    }
+}
+
+/// Unknown values parse as `Chat`.
+impl std::str::FromStr for MessageType {
+    type Err = std::convert::Infallible;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        Ok(match s {
+            "groupchat" => MessageType::Groupchat,
+            "normal" => MessageType::Normal,
+            "headline" => MessageType::Headline,
+            "error" => MessageType::Error,
+            _ => MessageType::Chat,
+        })
+    }
+}
+
+/// Which path stored a message, kept alongside it to help trace duplicates.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum MessageSource {
+    /// Received from or reflected by the server while online
+    Live,
+    /// Written by this client before the server saw it
+    Local,
+    /// Fetched from a message archive query
+    Mam,
+    /// Replayed by a MUC room as discussion history on join
+    MucHistory,
+}
+
+impl MessageSource {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            MessageSource::Live => "live",
+            MessageSource::Local => "local",
+            MessageSource::Mam => "mam",
+            MessageSource::MucHistory => "muc_history",
+        }
+    }
+}
+
 /// XMPP presence "show" values (RFC 6121 section 4.7.2.1).
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub enum PresenceShow {
     /// Available (no <show/> element -- the default)
@@ -490,6 +794,50 @@ pub enum PresenceShow {
     Unavailable,
 }
 
+impl PresenceShow {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            PresenceShow::Available => "available",
+            PresenceShow::Chat => "chat",
+            PresenceShow::Away => "away",
+            PresenceShow::Xa => "xa",
+            PresenceShow::Dnd => "dnd",
+            PresenceShow::Unavailable => "unavailable",
+        }
+    }
+
+    /// How reachable a contact showing this value is, higher meaning more
+    /// available: Chat > Available > Away > Xa > Dnd > Unavailable.
+    ///
+    /// Dnd ranks below Xa because the contact asked not to be interrupted,
+    /// while an extended-away contact will still see messages when back.
+    pub fn availability_rank(&self) -> u8 {
+        match self {
+            PresenceShow::Chat => 5,
+            PresenceShow::Available => 4,
+            PresenceShow::Away => 3,
+            PresenceShow::Xa => 2,
+            PresenceShow::Dnd => 1,
+            PresenceShow::Unavailable => 0,
+        }
+    }
+}
+
+impl std::str::FromStr for PresenceShow {
+    type Err = std::convert::Infallible;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        Ok(match s {
+            "available" => PresenceShow::Available,
+            "chat" => PresenceShow::Chat,
+            "away" => PresenceShow::Away,
+            "xa" => PresenceShow::Xa,
+            "dnd" => PresenceShow::Dnd,
+            _ => PresenceShow::Unavailable,
+        })
+    }
+}
+
 /// XEP-0085 Chat State Notifications.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
@@ -501,6 +849,18 @@ pub enum ChatState {
     Gone,
 }
 
+impl ChatState {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ChatState::Active => "active",
+            ChatState::Composing => "composing",
+            ChatState::Paused => "paused",
+            ChatState::Inactive => "inactive",
+            ChatState::Gone => "gone",
+        }
+    }
+}
+
 /// An occupant in a MUC room.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
@@ -518,7 +878,57 @@ pub struct MucOccupant {
     pub role: MucRole,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+/// What a room advertises about itself before we join it.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MucRoomInfo {
+    /// False when the service answered `item-not-found`; joining would
+    /// create the room, if the service allows that
+    pub exists: bool,
+
+    /// Only members may join (`muc_membersonly`)
+    pub members_only: bool,
+
+    /// Joining needs a password (`muc_passwordprotected`)
+    pub password_protected: bool,
+
+    /// Every disco#info feature `var` the room reported
+    pub features: Vec<String>,
+}
+
+/// Why the room removed us, taken from the XEP-0045 status code on our
+/// own unavailable presence (e.g. 307 kicked, 301 banned).
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MucRemoval {
+    /// The XEP-0045 status code
+    pub code: u16,
+
+    /// Reason given by the moderator, if any
+    pub reason: Option<String>,
+
+    /// Nick or JID of the moderator who removed us, if disclosed
+    pub actor: Option<String>,
+}
+
+/// Why a room refused to let us join, from the error condition on its
+/// presence reply (XEP-0045 section 7.2).
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum MucJoinFailure {
+    /// `<conflict/>` (409): another occupant has the nick
+    NickConflict,
+    /// `<registration-required/>` (407): the room is members-only
+    NotMember,
+    /// `<item-not-found/>` (404): the room is locked or does not exist
+    RoomLocked,
+    /// `<forbidden/>` (403): we are banned
+    Banned,
+    /// Any other condition, by its element name
+    Other(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub enum MucAffiliation {
     Owner,
@@ -528,6 +938,19 @@ pub enum MucAffiliation {
     None,
 }
 
+impl MucAffiliation {
+    /// The XEP-0045 `affiliation` attribute value.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            MucAffiliation::Owner => "owner",
+            MucAffiliation::Admin => "admin",
+            MucAffiliation::Member => "member",
+            MucAffiliation::Outcast => "outcast",
+            MucAffiliation::None => "none",
+        }
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub enum MucRole {
@@ -764,6 +1187,45 @@ mod tests {
         assert!(!Channel::is_valid(""));
     }
 
+    #[test]
+    fn message_type_round_trips_through_str() {
+        for message_type in [
+            MessageType::Chat,
+            MessageType::Groupchat,
+            MessageType::Normal,
+            MessageType::Headline,
+            MessageType::Error,
+        ] {
+            let parsed: MessageType = message_type.as_str().parse().unwrap();
+            assert_eq!(parsed, message_type);
+            assert_eq!(
+                parsed.is_groupchat(),
+                message_type == MessageType::Groupchat
+            );
+        }
+        assert_eq!("bogus".parse::<MessageType>().unwrap(), MessageType::Chat);
+    }
+
+    #[test]
+    fn presence_show_availability_rank_orders_by_reachability() {
+        let ordered = [
+            PresenceShow::Chat,
+            PresenceShow::Available,
+            PresenceShow::Away,
+            PresenceShow::Xa,
+            PresenceShow::Dnd,
+            PresenceShow::Unavailable,
+        ];
+        for pair in ordered.windows(2) {
+            assert!(
+                pair[0].availability_rank() > pair[1].availability_rank(),
+                "{:?} should rank above {:?}",
+                pair[0],
+                pair[1]
+            );
+        }
+    }
+
     #[test]
     fn test_channel_domain() {
         let c = Channel::new("xmpp.message.received").unwrap();
@@ -846,6 +1308,10 @@ mod tests {
                     message_type: MessageType::Chat,
                     thread: None,
                     embeds: vec![],
+                    bodies: BTreeMap::new(),
+                    origin_id: None,
+                    spoiler: None,
+                    stanza_id: None,
                 },
             },
             corr_id,
@@ -922,6 +1388,10 @@ mod event_bus_tests {
                     message_type: MessageType::Chat,
                     thread: None,
                     embeds: vec![],
+                    bodies: BTreeMap::new(),
+                    origin_id: None,
+                    spoiler: None,
+                    stanza_id: None,
                 },
             },
         ))
@@ -992,6 +1462,10 @@ mod event_bus_tests {
                     message_type: MessageType::Chat,
                     thread: None,
                     embeds: vec![],
+                    bodies: BTreeMap::new(),
+                    origin_id: None,
+                    spoiler: None,
+                    stanza_id: None,
                 },
             },
         ))
@@ -1029,6 +1503,7 @@ mod event_bus_tests {
                     subscription: Subscription::Both,
                     groups: vec![],
                 },
+                version: None,
             },
         ))
         .unwrap();
@@ -1066,6 +1541,10 @@ mod event_bus_tests {
                     message_type: MessageType::Chat,
                     thread: None,
                     embeds: vec![],
+                    bodies: BTreeMap::new(),
+                    origin_id: None,
+                    spoiler: None,
+                    stanza_id: None,
                 },
             },
         ))
@@ -1095,6 +1574,10 @@ mod event_bus_tests {
                     message_type: MessageType::Chat,
                     thread: None,
                     embeds: vec![],
+                    bodies: BTreeMap::new(),
+                    origin_id: None,
+                    spoiler: None,
+                    stanza_id: None,
                 },
             },
         ))
@@ -1108,6 +1591,7 @@ mod event_bus_tests {
                     subscription: Subscription::None,
                     groups: vec![],
                 },
+                version: None,
             },
         ))
         .unwrap();
@@ -1143,6 +1627,10 @@ mod event_bus_tests {
                     message_type: MessageType::Chat,
                     thread: None,
                     embeds: vec![],
+                    bodies: BTreeMap::new(),
+                    origin_id: None,
+                    spoiler: None,
+                    stanza_id: None,
                 },
             },
         ))
@@ -1158,6 +1646,7 @@ mod event_bus_tests {
                     subscription: Subscription::None,
                     groups: vec![],
                 },
+                version: None,
             },
         ))
         .unwrap();
@@ -1186,6 +1675,10 @@ mod event_bus_tests {
                     message_type: MessageType::Chat,
                     thread: None,
                     embeds: vec![],
+                    bodies: BTreeMap::new(),
+                    origin_id: None,
+                    spoiler: None,
+                    stanza_id: None,
                 },
             },
         ))
@@ -1220,6 +1713,10 @@ mod event_bus_tests {
                     message_type: MessageType::Chat,
                     thread: None,
                     embeds: vec![],
+                    bodies: BTreeMap::new(),
+                    origin_id: None,
+                    spoiler: None,
+                    stanza_id: None,
                 },
             },
         ))
@@ -1307,6 +1804,10 @@ mod event_bus_tests {
                         message_type: MessageType::Chat,
                         thread: None,
                         embeds: vec![],
+                        bodies: BTreeMap::new(),
+                        origin_id: None,
+                        spoiler: None,
+                        stanza_id: None,
                     },
                 },
             ))
@@ -1346,6 +1847,7 @@ mod event_bus_tests {
                     subscription: Subscription::None,
                     groups: vec![],
                 },
+                version: None,
             },
         ))
         .unwrap();
@@ -1397,6 +1899,10 @@ mod event_bus_tests {
                     message_type: MessageType::Chat,
                     thread: None,
                     embeds: vec![],
+                    bodies: BTreeMap::new(),
+                    origin_id: None,
+                    spoiler: None,
+                    stanza_id: None,
                 },
             },
             corr_id,
@@ -1409,6 +1915,7 @@ mod event_bus_tests {
             EventPayload::MessageDelivered {
                 id: "m1".into(),
                 to: "bob@example.com".into(),
+                resource: None,
             },
             corr_id,
         ))
@@ -1467,6 +1974,10 @@ mod event_bus_tests {
                     message_type: MessageType::Chat,
                     thread: None,
                     embeds: vec![],
+                    bodies: BTreeMap::new(),
+                    origin_id: None,
+                    spoiler: None,
+                    stanza_id: None,
                 },
             },
             target_corr,
@@ -1486,6 +1997,10 @@ mod event_bus_tests {
                     message_type: MessageType::Chat,
                     thread: None,
                     embeds: vec![],
+                    bodies: BTreeMap::new(),
+                    origin_id: None,
+                    spoiler: None,
+                    stanza_id: None,
                 },
             },
             other_corr,
@@ -1498,6 +2013,7 @@ mod event_bus_tests {
             EventPayload::MessageDelivered {
                 id: "m1".into(),
                 to: "c@d".into(),
+                resource: None,
             },
             target_corr,
         ))
@@ -1699,6 +2215,10 @@ mod event_bus_tests {
                 namespace: "urn:waddle:github:0".into(),
                 data: serde_json::json!({"owner": "cuenv", "name": "cuenv", "stars": 42}),
             }],
+            bodies: BTreeMap::new(),
+            origin_id: None,
+            spoiler: None,
+            stanza_id: None,
         };
         let json = serde_json::to_string(&msg).unwrap();
         let parsed: ChatMessage = serde_json::from_str(&json).unwrap();
@@ -1719,12 +2239,44 @@ mod event_bus_tests {
             message_type: MessageType::Chat,
             thread: None,
             embeds: vec![],
+            bodies: BTreeMap::new(),
+            origin_id: None,
+            spoiler: None,
+            stanza_id: None,
         };
         let json = serde_json::to_string(&msg).unwrap();
         // The embeds field should be skipped when empty
         assert!(!json.contains("embeds"));
     }
 
+    #[test]
+    fn body_for_lang_picks_matching_variant() {
+        let msg = ChatMessage {
+            id: "m".into(),
+            from: "a@b".into(),
+            to: "c@d".into(),
+            body: "Hello".into(),
+            timestamp: chrono::Utc::now(),
+            message_type: MessageType::Chat,
+            thread: None,
+            embeds: vec![],
+            bodies: BTreeMap::from([
+                ("en".to_string(), "Hello".to_string()),
+                ("de-DE".to_string(), "Hallo".to_string()),
+                ("pt-BR".to_string(), "Olá".to_string()),
+            ]),
+            origin_id: None,
+            spoiler: None,
+            stanza_id: None,
+        };
+
+        assert_eq!(msg.body_for_lang(Some("de-DE")), "Hallo");
+        assert_eq!(msg.body_for_lang(Some("de-at")), "Hallo");
+        assert_eq!(msg.body_for_lang(Some("pt_BR")), "Olá");
+        assert_eq!(msg.body_for_lang(Some("fr")), "Hello");
+        assert_eq!(msg.body_for_lang(None), "Hello");
+    }
+
     #[test]
     fn chat_message_deserializes_without_embeds_field() {
         let json = r#"{"id":"m","from":"a","to":"b","body":"hi","timestamp":"2025-01-01T00:00:00Z","messageType":"chat"}"#;
@@ -4,6 +4,8 @@ use fluent::{FluentArgs, FluentBundle, FluentResource};
 use fluent_langneg::{NegotiationStrategy, negotiate_languages};
 use unic_langid::LanguageIdentifier;
 
+use crate::event::ChatMessage;
+
 #[derive(Debug, thiserror::Error)]
 pub enum I18nError {
     #[error("failed to parse FTL for locale {locale}: {reason}")]
@@ -34,6 +36,9 @@ fn builtin_ftl(locale: &str) -> Option<LocaleData> {
 
 pub struct I18n {
     current_locale: String,
+    /// The configured locale, else the system's, before narrowing to the
+    /// bundles we ship
+    preferred_locale: Option<String>,
     bundles: HashMap<String, FluentBundle<FluentResource>>,
 }
 
@@ -88,6 +93,7 @@ impl I18n {
 
         Self {
             current_locale,
+            preferred_locale: requested_strs.first().map(|locale| locale.to_string()),
             bundles,
         }
     }
@@ -109,6 +115,17 @@ impl I18n {
         &self.current_locale
     }
 
+    /// The body variant of `message` for the user's locale. Bodies are not
+    /// limited to the locales we have translations for, so this goes by
+    /// the configured (or system) locale rather than [`Self::current_locale`].
+    pub fn body_for<'a>(&self, message: &'a ChatMessage) -> &'a str {
+        message.body_for_lang(
+            self.preferred_locale
+                .as_deref()
+                .or(Some(&self.current_locale)),
+        )
+    }
+
     pub fn available_locales(&self) -> Vec<String> {
         let mut locales: Vec<String> = self.bundles.keys().cloned().collect();
         locales.sort();
@@ -191,6 +208,35 @@ mod tests {
         assert_eq!(i18n.current_locale(), "en-US");
     }
 
+    #[test]
+    fn body_for_uses_the_configured_locale() {
+        use std::collections::BTreeMap;
+
+        use crate::event::MessageType;
+
+        let i18n = I18n::new(Some("de-AT"), &["en-US"]);
+        assert_eq!(i18n.current_locale(), "en-US");
+
+        let message = ChatMessage {
+            id: "m".into(),
+            from: "a@b".into(),
+            to: "c@d".into(),
+            body: "Hello".into(),
+            timestamp: chrono::Utc::now(),
+            message_type: MessageType::Chat,
+            thread: None,
+            embeds: vec![],
+            bodies: BTreeMap::from([
+                ("en".to_string(), "Hello".to_string()),
+                ("de".to_string(), "Hallo".to_string()),
+            ]),
+            origin_id: None,
+            spoiler: None,
+            stanza_id: None,
+        };
+        assert_eq!(i18n.body_for(&message), "Hallo");
+    }
+
     #[test]
     fn resolves_known_message() {
         let i18n = I18n::new(Some("en-US"), &["en-US"]);
@@ -2,6 +2,7 @@ pub mod config;
 pub mod error;
 pub mod event;
 pub mod i18n;
+pub mod retry;
 pub mod theme;
 
 pub use error::{EventBusError, Result, WaddleError};
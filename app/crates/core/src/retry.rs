@@ -0,0 +1,138 @@
+use std::time::Duration;
+
+#[cfg(feature = "native")]
+use std::{fmt::Display, future::Future};
+
+#[cfg(feature = "native")]
+use tracing::warn;
+
+/// How often a failing operation is retried and how long to wait in between.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// Attempts after the first failure before giving up
+    pub retries: u32,
+    /// Wait before the first retry; doubled for each retry after it
+    pub initial_delay: Duration,
+    /// Cap on the doubled wait
+    pub max_delay: Duration,
+}
+
+impl RetryPolicy {
+    /// Waits the same `delay` before every retry.
+    pub const fn fixed(retries: u32, delay: Duration) -> Self {
+        Self {
+            retries,
+            initial_delay: delay,
+            max_delay: delay,
+        }
+    }
+
+    /// Wait before retry number `retry`, counting from 1.
+    pub fn delay_for(&self, retry: u32) -> Duration {
+        let factor = 1_u32
+            .checked_shl(retry.saturating_sub(1))
+            .unwrap_or(u32::MAX);
+        self.initial_delay
+            .saturating_mul(factor)
+            .min(self.max_delay)
+    }
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            retries: 2,
+            initial_delay: Duration::from_millis(50),
+            max_delay: Duration::from_secs(1),
+        }
+    }
+}
+
+/// Runs `operation` until it succeeds or `policy.retries` retries have
+/// failed, sleeping with backoff in between. Returns the last error.
+#[cfg(feature = "native")]
+pub async fn retry<T, E, F, Fut>(policy: RetryPolicy, mut operation: F) -> Result<T, E>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, E>>,
+    E: Display,
+{
+    let mut retries = 0;
+    loop {
+        match operation().await {
+            Ok(value) => return Ok(value),
+            Err(error) if retries < policy.retries => {
+                retries += 1;
+                let delay = policy.delay_for(retries);
+                warn!(error = %error, retry = retries, ?delay, "operation failed, retrying");
+                tokio::time::sleep(delay).await;
+            }
+            Err(error) => return Err(error),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn delay_doubles_up_to_the_cap() {
+        let policy = RetryPolicy {
+            retries: 10,
+            initial_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(60),
+        };
+
+        assert_eq!(policy.delay_for(1), Duration::from_secs(1));
+        assert_eq!(policy.delay_for(2), Duration::from_secs(2));
+        assert_eq!(policy.delay_for(4), Duration::from_secs(8));
+        assert_eq!(policy.delay_for(7), Duration::from_secs(60));
+        assert_eq!(policy.delay_for(99), Duration::from_secs(60));
+    }
+
+    #[test]
+    fn fixed_policy_never_grows() {
+        let policy = RetryPolicy::fixed(3, Duration::from_millis(50));
+        assert_eq!(policy.delay_for(1), Duration::from_millis(50));
+        assert_eq!(policy.delay_for(3), Duration::from_millis(50));
+    }
+
+    #[cfg(feature = "native")]
+    #[tokio::test]
+    async fn succeeds_after_failures_within_budget() {
+        let mut attempts = 0;
+        let result: Result<u32, String> =
+            retry(RetryPolicy::fixed(3, Duration::from_millis(1)), || {
+                attempts += 1;
+                let attempt = attempts;
+                async move {
+                    if attempt < 3 {
+                        Err(format!("attempt {attempt} failed"))
+                    } else {
+                        Ok(attempt)
+                    }
+                }
+            })
+            .await;
+
+        assert_eq!(result, Ok(3));
+        assert_eq!(attempts, 3);
+    }
+
+    #[cfg(feature = "native")]
+    #[tokio::test]
+    async fn gives_up_after_retry_cap() {
+        let mut attempts = 0;
+        let result: Result<(), String> =
+            retry(RetryPolicy::fixed(2, Duration::from_millis(1)), || {
+                attempts += 1;
+                let attempt = attempts;
+                async move { Err(format!("attempt {attempt} failed")) }
+            })
+            .await;
+
+        assert_eq!(result, Err("attempt 3 failed".to_string()));
+        assert_eq!(attempts, 3);
+    }
+}
@@ -17,7 +17,7 @@ use waddle_core::event::{
     PresenceShow, RosterItem, ScrollDirection, UiTarget,
 };
 use waddle_mam::MamManager;
-use waddle_messaging::{MessageManager, MucManager};
+use waddle_messaging::{ConversationId, ManagerSet, MessageManager, MucManager};
 use waddle_notifications::NotificationManager;
 use waddle_plugins::{
     InstalledPlugin, PluginCapability, PluginError, PluginInfo as RuntimePluginInfo,
@@ -28,9 +28,9 @@ use waddle_presence::PresenceManager;
 use waddle_roster::RosterManager;
 use waddle_storage::{self, NativeDatabase, StorageError};
 use waddle_xmpp::{
-    ChatStateProcessor, ConnectionConfig, ConnectionManager, ConnectionState, MamProcessor,
-    MessageProcessor, MucProcessor, OutboundRouter, PresenceProcessor, RosterProcessor, Stanza,
-    StanzaPipeline, parse_stanza, stanza_channel,
+    ChatStateProcessor, ConnectionConfig, ConnectionManager, ConnectionState, DiscoProcessor,
+    MamProcessor, MessageProcessor, MucProcessor, OutboundRouter, PepManager, PepProcessor,
+    PresenceProcessor, RosterProcessor, Stanza, StanzaPipeline, parse_stanza, stanza_channel,
 };
 
 #[cfg(debug_assertions)]
@@ -40,7 +40,6 @@ const SYSTEM_COMPONENT: &str = "gui-backend";
 const CONNECTION_TIMEOUT_SECONDS: u32 = 30;
 const CONNECTION_MAX_RECONNECT_ATTEMPTS: u32 = 5;
 const WIRE_CHANNEL_CAPACITY: usize = 256;
-const SHUTDOWN_CLEANUP_TIMEOUT_SECONDS: u64 = 5;
 const MUC_OWNER_SUBMIT_QUERY_XML: &str = "<query xmlns='http://jabber.org/protocol/muc#owner'><x xmlns='jabber:x:data' type='submit'/></query>";
 const MUC_OWNER_DESTROY_QUERY_XML: &str =
     "<query xmlns='http://jabber.org/protocol/muc#owner'><destroy/></query>";
@@ -727,7 +726,7 @@ async fn load_local_history(
 ) -> Result<Vec<ChatMessage>, String> {
     let messages = state
         .message_manager
-        .get_messages(jid, limit, before)
+        .get_messages(&ConversationId::Direct(jid.to_string()), limit, before)
         .await
         .map_err(|error| error.to_string())?;
 
@@ -880,6 +879,7 @@ async fn initialize_backend(app_handle: AppHandle) -> Result<AppState, GuiBacken
     let storage_path = resolve_storage_path(&config);
     let database: Arc<NativeDatabase> =
         Arc::new(waddle_storage::open_native_database(storage_path.as_path()).await?);
+    database.set_max_rows(config.storage.max_rows);
 
     info!(path = %storage_path.display(), "storage initialized");
 
@@ -917,34 +917,57 @@ async fn initialize_backend(app_handle: AppHandle) -> Result<AppState, GuiBacken
 
     let roster_manager = Arc::new(RosterManager::new(database.clone(), event_bus.clone()));
     let message_manager = Arc::new(MessageManager::new(database.clone(), event_bus.clone()));
+    message_manager.set_record_offline_chat_states(config.logging.record_offline_chat_states);
     let muc_manager = Arc::new(MucManager::new(database.clone(), event_bus.clone()));
-    let presence_manager = Arc::new(PresenceManager::new(event_bus.clone()));
+    let pep_manager = Arc::new(PepManager::new(event_bus.clone()));
+    let presence_manager = Arc::new(PresenceManager::new(event_bus.clone(), pep_manager.clone()));
     let mam_manager = Arc::new(MamManager::new(database.clone(), event_bus.clone()));
 
-    spawn_component_task("roster", event_bus.clone(), {
-        let manager = roster_manager.clone();
-        async move { manager.run().await.map_err(|error| error.to_string()) }
-    });
-
-    spawn_component_task("messaging", event_bus.clone(), {
+    let messaging_task = spawn_component_task("messaging", event_bus.clone(), {
         let manager = message_manager.clone();
         async move { manager.run().await.map_err(|error| error.to_string()) }
     });
+    let mut managers = ManagerSet::new(message_manager.clone(), messaging_task);
+
+    managers.add_task(
+        "roster",
+        spawn_component_task("roster", event_bus.clone(), {
+            let manager = roster_manager.clone();
+            async move { manager.run().await.map_err(|error| error.to_string()) }
+        }),
+    );
 
-    spawn_component_task("muc", event_bus.clone(), {
-        let manager = muc_manager.clone();
-        async move { manager.run().await.map_err(|error| error.to_string()) }
-    });
+    managers.add_task(
+        "muc",
+        spawn_component_task("muc", event_bus.clone(), {
+            let manager = muc_manager.clone();
+            async move { manager.run().await.map_err(|error| error.to_string()) }
+        }),
+    );
 
-    spawn_component_task("presence", event_bus.clone(), {
-        let manager = presence_manager.clone();
-        async move { manager.run().await.map_err(|error| error.to_string()) }
-    });
+    managers.add_task(
+        "presence",
+        spawn_component_task("presence", event_bus.clone(), {
+            let manager = presence_manager.clone();
+            async move { manager.run().await.map_err(|error| error.to_string()) }
+        }),
+    );
 
-    spawn_component_task("mam", event_bus.clone(), {
-        let manager = mam_manager.clone();
-        async move { manager.run().await.map_err(|error| error.to_string()) }
-    });
+    managers.add_task(
+        "mam",
+        spawn_component_task("mam", event_bus.clone(), {
+            let manager = mam_manager.clone();
+            async move { manager.run().await.map_err(|error| error.to_string()) }
+        }),
+    );
+
+    managers.add_task(
+        "pep",
+        spawn_component_task("pep", event_bus.clone(), {
+            let manager = pep_manager.clone();
+            async move { manager.run().await.map_err(|error| error.to_string()) }
+        }),
+    );
 
     let pipeline = Arc::new(build_stanza_pipeline(event_bus.clone()));
     let (wire_sender, wire_receiver) = stanza_channel(WIRE_CHANNEL_CAPACITY);
@@ -966,7 +989,7 @@ async fn initialize_backend(app_handle: AppHandle) -> Result<AppState, GuiBacken
 
     spawn_wire_pump(connection.clone(), wire_receiver, event_bus.clone());
     spawn_inbound_pump(connection.clone(), pipeline, event_bus.clone());
-    spawn_connection_control(connection.clone(), event_bus.clone());
+    spawn_connection_control(connection.clone(), managers, event_bus.clone());
 
     spawn_notifications(event_bus.clone(), config.clone());
     spawn_event_forwarder(event_bus.clone(), app_handle);
@@ -1003,6 +1026,8 @@ fn build_stanza_pipeline(event_bus: Arc<dyn EventBus>) -> StanzaPipeline {
     pipeline.register(Box::new(MamProcessor::new(event_bus.clone())));
     pipeline.register(Box::new(MucProcessor::new(event_bus.clone())));
     pipeline.register(Box::new(ChatStateProcessor::new(event_bus.clone())));
+    pipeline.register(Box::new(PepProcessor::new(event_bus.clone())));
+    pipeline.register(Box::new(DiscoProcessor::new(event_bus.clone())));
 
     #[cfg(debug_assertions)]
     pipeline.register(Box::new(DebugProcessor::new(event_bus)));
@@ -1010,16 +1035,21 @@ fn build_stanza_pipeline(event_bus: Arc<dyn EventBus>) -> StanzaPipeline {
     pipeline
 }
 
-fn spawn_component_task<F>(component: &'static str, event_bus: Arc<dyn EventBus>, task: F)
+fn spawn_component_task<F>(
+    component: &'static str,
+    event_bus: Arc<dyn EventBus>,
+    task: F,
+) -> tokio::task::JoinHandle<()>
 where
     F: Future<Output = Result<(), String>> + Send + 'static,
 {
-    tauri::async_runtime::spawn(async move {
+    let tauri::async_runtime::JoinHandle::Tokio(handle) = tauri::async_runtime::spawn(async move {
         if let Err(reason) = task.await {
             error!(component, %reason, "component task terminated");
             emit_component_error(&event_bus, component, reason, true);
         }
     });
+    handle
 }
 
 fn spawn_notifications(event_bus: Arc<dyn EventBus>, config: Config) {
@@ -1203,6 +1233,7 @@ fn spawn_initial_connection(
 
 fn spawn_connection_control(
     connection: Arc<Mutex<ConnectionManager>>,
+    managers: ManagerSet<NativeDatabase>,
     event_bus: Arc<dyn EventBus>,
 ) {
     tauri::async_runtime::spawn(async move {
@@ -1248,19 +1279,11 @@ fn spawn_connection_control(
                         }
                     }
                     EventPayload::ShutdownRequested { .. } => {
-                        let mut unavailable_subscription =
-                            match event_bus.subscribe("xmpp.presence.own_changed") {
-                                Ok(subscription) => Some(subscription),
-                                Err(error) => {
-                                    emit_component_error(
-                                        &event_bus,
-                                        "presence",
-                                        error.to_string(),
-                                        true,
-                                    );
-                                    None
-                                }
-                            };
+                        // Queued sends go out before we leave rooms and close
+                        // the stream; the outbound router keeps running.
+                        if let Err(error) = managers.shutdown().await {
+                            emit_component_error(&event_bus, "messaging", error.to_string(), true);
+                        }
 
                         if let Err(error) = publish_event(
                             &event_bus,
@@ -1269,62 +1292,14 @@ fn spawn_connection_control(
                             EventPayload::PresenceSetRequested {
                                 show: PresenceShow::Unavailable,
                                 status: None,
+                                priority: 0,
                             },
                         ) {
                             emit_component_error(&event_bus, "presence", error.to_string(), true);
                         }
-
-                        let unavailable_seen = if let Some(mut unavailable_subscription) =
-                            unavailable_subscription.take()
-                        {
-                            tokio::time::timeout(
-                                Duration::from_secs(SHUTDOWN_CLEANUP_TIMEOUT_SECONDS),
-                                async {
-                                    loop {
-                                        match unavailable_subscription.recv().await {
-                                            Ok(event) => {
-                                                if matches!(
-                                                    event.payload,
-                                                    EventPayload::OwnPresenceChanged {
-                                                        show: PresenceShow::Unavailable,
-                                                        ..
-                                                    }
-                                                ) {
-                                                    return true;
-                                                }
-                                            }
-                                            Err(waddle_core::error::EventBusError::Lagged(
-                                                count,
-                                            )) => {
-                                                warn!(count, "shutdown presence wait lagged");
-                                            }
-                                            Err(
-                                                waddle_core::error::EventBusError::ChannelClosed,
-                                            ) => {
-                                                return false;
-                                            }
-                                            Err(error) => {
-                                                emit_component_error(
-                                                    &event_bus,
-                                                    "presence",
-                                                    error.to_string(),
-                                                    true,
-                                                );
-                                                return false;
-                                            }
-                                        }
-                                    }
-                                },
-                            )
-                            .await
-                            .unwrap_or_default()
-                        } else {
-                            false
-                        };
-
-                        if !unavailable_seen {
-                            warn!("timed out waiting for unavailable presence during shutdown");
-                        }
+                        // The presence manager is already stopped, so wait on
+                        // the router itself for the unavailable presence.
+                        waddle_messaging::wait_for_outbound(event_bus.as_ref()).await;
 
                         let disconnect_result = {
                             let mut manager = connection.lock().await;
@@ -1692,6 +1667,7 @@ fn emit_component_error(
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::collections::BTreeMap;
     use std::sync::Arc;
     use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
     use waddle_core::event::MessageType;
@@ -1708,6 +1684,10 @@ mod tests {
             message_type: MessageType::Chat,
             thread: None,
             embeds: vec![],
+            bodies: BTreeMap::new(),
+            origin_id: None,
+            spoiler: None,
+            stanza_id: None,
         }
     }
 
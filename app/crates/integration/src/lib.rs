@@ -1,8 +1,8 @@
-
 #[cfg(all(test, feature = "native"))]
 mod tests {
     #[cfg(test)]
     mod github_test;
+    use std::collections::BTreeMap;
     use std::sync::Arc;
     use std::time::Duration;
 
@@ -16,10 +16,11 @@ mod tests {
         Subscription,
     };
     use waddle_mam::MamManager;
-    use waddle_messaging::{MessageManager, MucManager};
+    use waddle_messaging::{ConversationId, MessageManager, MucManager};
     use waddle_presence::PresenceManager;
     use waddle_roster::RosterManager;
     use waddle_storage::{Database, Row, SqlValue};
+    use waddle_xmpp::PepManager;
 
     const TIMEOUT: Duration = Duration::from_millis(500);
 
@@ -53,6 +54,10 @@ mod tests {
             message_type: MessageType::Chat,
             thread: None,
             embeds: vec![],
+            bodies: BTreeMap::new(),
+            origin_id: None,
+            spoiler: None,
+            stanza_id: None,
         }
     }
 
@@ -67,7 +72,10 @@ mod tests {
         let bus: Arc<dyn EventBus> = Arc::new(BroadcastEventBus::default());
 
         let roster = Arc::new(RosterManager::new(db.clone(), bus.clone()));
-        let presence = Arc::new(PresenceManager::new(bus.clone()));
+        let presence = Arc::new(PresenceManager::new(
+            bus.clone(),
+            Arc::new(PepManager::new(bus.clone())),
+        ));
 
         let mut ui_sub = bus.subscribe("ui.**").unwrap();
 
@@ -99,7 +107,10 @@ mod tests {
         let db = setup_db(&dir).await;
         let bus: Arc<dyn EventBus> = Arc::new(BroadcastEventBus::default());
 
-        let presence = Arc::new(PresenceManager::new(bus.clone()));
+        let presence = Arc::new(PresenceManager::new(
+            bus.clone(),
+            Arc::new(PepManager::new(bus.clone())),
+        ));
         let messaging = Arc::new(MessageManager::new(db.clone(), bus.clone()));
 
         // Establish connection first
@@ -118,6 +129,7 @@ mod tests {
             EventPayload::ConnectionLost {
                 reason: "network error".to_string(),
                 will_retry: true,
+                resumable: false,
             },
         );
         presence.handle_event(&lost).await;
@@ -152,7 +164,10 @@ mod tests {
         let bus: Arc<dyn EventBus> = Arc::new(BroadcastEventBus::default());
 
         let roster = Arc::new(RosterManager::new(db.clone(), bus.clone()));
-        let presence = Arc::new(PresenceManager::new(bus.clone()));
+        let presence = Arc::new(PresenceManager::new(
+            bus.clone(),
+            Arc::new(PepManager::new(bus.clone())),
+        ));
 
         let mut ui_sub = bus.subscribe("ui.**").unwrap();
 
@@ -188,6 +203,7 @@ mod tests {
             "xmpp.roster.received",
             EventPayload::RosterReceived {
                 items: items.clone(),
+                version: None,
             },
         );
         roster.handle_event(&roster_received).await;
@@ -215,6 +231,7 @@ mod tests {
             EventPayload::PresenceSetRequested {
                 show: PresenceShow::Available,
                 status: None,
+                priority: 0,
             }
         ));
     }
@@ -237,6 +254,7 @@ mod tests {
                     subscription: Subscription::Both,
                     groups: vec![],
                 }],
+                version: None,
             },
         );
         roster.handle_event(&initial).await;
@@ -251,6 +269,7 @@ mod tests {
                     subscription: Subscription::None,
                     groups: vec!["Work".to_string()],
                 },
+                version: None,
             },
         );
         roster.handle_event(&push).await;
@@ -263,6 +282,7 @@ mod tests {
             "xmpp.roster.removed",
             EventPayload::RosterRemoved {
                 jid: "bob@example.com".to_string(),
+                version: None,
             },
         );
         roster.handle_event(&remove).await;
@@ -325,7 +345,7 @@ mod tests {
 
         // Verify both messages persisted
         let messages = messaging
-            .get_messages("bob@example.com", 50, None)
+            .get_messages(&ConversationId::Direct("bob@example.com".into()), 50, None)
             .await
             .unwrap();
         assert_eq!(messages.len(), 2);
@@ -378,6 +398,7 @@ mod tests {
             EventPayload::MessageDelivered {
                 id: sent.id.clone(),
                 to: "bob@example.com".to_string(),
+                resource: None,
             },
         );
         messaging.handle_event(&receipt).await;
@@ -504,6 +525,10 @@ mod tests {
             message_type: MessageType::Groupchat,
             thread: None,
             embeds: vec![],
+            bodies: BTreeMap::new(),
+            origin_id: None,
+            spoiler: None,
+            stanza_id: None,
         };
         let msg_event = make_xmpp_event(
             "xmpp.muc.message.received",
@@ -555,6 +580,7 @@ mod tests {
             "xmpp.muc.left",
             EventPayload::MucLeft {
                 room: "room@conference.example.com".to_string(),
+                removal: None,
             },
         );
         muc.handle_event(&left).await;
@@ -601,7 +627,10 @@ mod tests {
                 let bus: Arc<dyn EventBus> = Arc::new(BroadcastEventBus::default());
 
                 let mam = Arc::new(MamManager::new(db.clone(), bus.clone()));
-                let presence = Arc::new(PresenceManager::new(bus.clone()));
+                let presence = Arc::new(PresenceManager::new(
+                    bus.clone(),
+                    Arc::new(PepManager::new(bus.clone())),
+                ));
 
                 let mut ui_sub = bus.subscribe("ui.**").unwrap();
 
@@ -622,7 +651,10 @@ mod tests {
                 // Step 2: Roster received → presence sends initial Available
                 let roster_event = make_xmpp_event(
                     "xmpp.roster.received",
-                    EventPayload::RosterReceived { items: vec![] },
+                    EventPayload::RosterReceived {
+                        items: vec![],
+                        version: None,
+                    },
                 );
                 presence.handle_event(&roster_event).await;
 
@@ -630,8 +662,8 @@ mod tests {
                 let _pres_event = timeout(TIMEOUT, ui_sub.recv()).await;
 
                 // Step 3: OwnPresenceChanged triggers MAM sync
-                let own_presence = make_xmpp_event(
-                    "xmpp.presence.own_changed",
+                let own_presence = make_event(
+                    "system.presence.own_changed",
                     EventPayload::OwnPresenceChanged {
                         show: PresenceShow::Available,
                         status: None,
@@ -733,8 +765,8 @@ mod tests {
         mam.handle_event(&connected).await;
 
         // OwnPresenceChanged with Unavailable should NOT trigger sync
-        let unavailable = make_xmpp_event(
-            "xmpp.presence.own_changed",
+        let unavailable = make_event(
+            "system.presence.own_changed",
             EventPayload::OwnPresenceChanged {
                 show: PresenceShow::Unavailable,
                 status: None,
@@ -772,7 +804,7 @@ mod tests {
 
         // Verify messages persisted even while offline
         let stored = messaging
-            .get_messages("bob@example.com", 50, None)
+            .get_messages(&ConversationId::Direct("bob@example.com".into()), 50, None)
             .await
             .unwrap();
         assert_eq!(stored.len(), 1);
@@ -851,6 +883,7 @@ mod tests {
                 EventPayload::MessageDelivered {
                     id: msg1.id.clone(),
                     to: "bob@example.com".to_string(),
+                    resource: None,
                 },
             ))
             .await;
@@ -874,6 +907,10 @@ mod tests {
             message_type: MessageType::Chat,
             thread: None,
             embeds: vec![],
+            bodies: BTreeMap::new(),
+            origin_id: None,
+            spoiler: None,
+            stanza_id: None,
         };
 
         // First mark second as sent
@@ -987,7 +1024,10 @@ mod tests {
     async fn presence_tracks_contacts_after_roster_and_connection() {
         let bus: Arc<dyn EventBus> = Arc::new(BroadcastEventBus::default());
 
-        let presence = Arc::new(PresenceManager::new(bus.clone()));
+        let presence = Arc::new(PresenceManager::new(
+            bus.clone(),
+            Arc::new(PepManager::new(bus.clone())),
+        ));
 
         // Connection
         let connected = make_event(
@@ -1001,7 +1041,10 @@ mod tests {
         // Roster received
         let roster = make_xmpp_event(
             "xmpp.roster.received",
-            EventPayload::RosterReceived { items: vec![] },
+            EventPayload::RosterReceived {
+                items: vec![],
+                version: None,
+            },
         );
         presence.handle_event(&roster).await;
 
@@ -1043,6 +1086,7 @@ mod tests {
             EventPayload::ConnectionLost {
                 reason: "timeout".to_string(),
                 will_retry: true,
+                resumable: false,
             },
         );
         presence.handle_event(&lost).await;
@@ -1063,7 +1107,10 @@ mod tests {
                 let bus: Arc<dyn EventBus> = Arc::new(BroadcastEventBus::default());
 
                 let roster = Arc::new(RosterManager::new(db.clone(), bus.clone()));
-                let presence = Arc::new(PresenceManager::new(bus.clone()));
+                let presence = Arc::new(PresenceManager::new(
+                    bus.clone(),
+                    Arc::new(PepManager::new(bus.clone())),
+                ));
                 let messaging = Arc::new(MessageManager::new(db.clone(), bus.clone()));
                 let mam = Arc::new(MamManager::new(db.clone(), bus.clone()));
 
@@ -1106,6 +1153,7 @@ mod tests {
                             subscription: Subscription::Both,
                             groups: vec![],
                         }],
+                        version: None,
                     },
                 );
                 roster.handle_event(&roster_event).await;
@@ -1128,15 +1176,21 @@ mod tests {
                     }
                 ));
 
-                // 4. OwnPresenceChanged triggers MAM sync
-                let own_presence = make_xmpp_event(
-                    "xmpp.presence.own_changed",
+                // 4. Presence announces the change itself, which triggers MAM sync
+                let own_presence = timeout(TIMEOUT, sys_sub.recv())
+                    .await
+                    .expect("timed out waiting for OwnPresenceChanged")
+                    .unwrap();
+                assert!(matches!(
+                    own_presence.payload,
                     EventPayload::OwnPresenceChanged {
                         show: PresenceShow::Available,
-                        status: None,
-                    },
+                        ..
+                    }
+                ));
+                assert!(
+                    matches!(own_presence.source, EventSource::System(ref s) if s == "presence")
                 );
-                presence.handle_event(&own_presence).await;
 
                 let mam_clone = mam.clone();
                 let mam_handle = tokio::task::spawn_local(async move {
@@ -1202,7 +1256,10 @@ mod tests {
         let bus: Arc<dyn EventBus> = Arc::new(BroadcastEventBus::default());
 
         let messaging = Arc::new(MessageManager::new(db.clone(), bus.clone()));
-        let presence = Arc::new(PresenceManager::new(bus.clone()));
+        let presence = Arc::new(PresenceManager::new(
+            bus.clone(),
+            Arc::new(PepManager::new(bus.clone())),
+        ));
         let roster = Arc::new(RosterManager::new(db.clone(), bus.clone()));
 
         // Initial connection
@@ -1226,6 +1283,7 @@ mod tests {
                     subscription: Subscription::Both,
                     groups: vec![],
                 }],
+                version: None,
             },
         );
         roster.handle_event(&roster_event).await;
@@ -1258,6 +1316,7 @@ mod tests {
             EventPayload::ConnectionLost {
                 reason: "network error".to_string(),
                 will_retry: true,
+                resumable: false,
             },
         );
         messaging.handle_event(&lost).await;
@@ -1340,6 +1399,7 @@ mod tests {
                     subscription: Subscription::None,
                     groups: vec![],
                 }],
+                version: None,
             },
         );
         roster.handle_event(&initial).await;
@@ -1399,6 +1459,7 @@ mod tests {
                     subscription: Subscription::Both,
                     groups: vec!["Friends".to_string()],
                 },
+                version: None,
             },
         );
         roster.handle_event(&push).await;
@@ -1449,6 +1510,7 @@ mod tests {
                     subscription: Subscription::Both,
                     groups: vec![],
                 }],
+                version: None,
             },
         );
         roster.handle_event(&initial).await;
@@ -1479,7 +1541,10 @@ mod tests {
 
         let messaging = Arc::new(MessageManager::new(db.clone(), bus.clone()));
         let muc = Arc::new(MucManager::new(db.clone(), bus.clone()));
-        let presence = Arc::new(PresenceManager::new(bus.clone()));
+        let presence = Arc::new(PresenceManager::new(
+            bus.clone(),
+            Arc::new(PepManager::new(bus.clone())),
+        ));
 
         // Bring everything online
         let connected = make_event(
@@ -1526,6 +1591,10 @@ mod tests {
             message_type: MessageType::Groupchat,
             thread: None,
             embeds: vec![],
+            bodies: BTreeMap::new(),
+            origin_id: None,
+            spoiler: None,
+            stanza_id: None,
         };
         let muc_recv = make_xmpp_event(
             "xmpp.muc.message.received",
@@ -1549,7 +1618,7 @@ mod tests {
 
         // Verify both message stores are independent
         let direct_messages = messaging
-            .get_messages("bob@example.com", 50, None)
+            .get_messages(&ConversationId::Direct("bob@example.com".into()), 50, None)
             .await
             .unwrap();
         assert_eq!(direct_messages.len(), 2); // sent + received
@@ -1560,8 +1629,9 @@ mod tests {
             .get_room_messages("dev@conference.example.com", 50, None)
             .await
             .unwrap();
-        assert_eq!(room_messages.len(), 1);
-        assert_eq!(room_messages[0].body, "Hey from room");
+        assert_eq!(room_messages.len(), 2); // sent + received
+        assert!(room_messages.iter().any(|m| m.body == "Room hello"));
+        assert!(room_messages.iter().any(|m| m.body == "Hey from room"));
 
         // Verify MUC state unaffected by direct messaging
         let rooms = muc.get_joined_rooms().await.unwrap();
@@ -1612,8 +1682,8 @@ mod tests {
                 );
                 mam.handle_event(&connected).await;
 
-                let own_presence = make_xmpp_event(
-                    "xmpp.presence.own_changed",
+                let own_presence = make_event(
+                    "system.presence.own_changed",
                     EventPayload::OwnPresenceChanged {
                         show: PresenceShow::Available,
                         status: None,
@@ -1793,6 +1863,7 @@ mod tests {
                 EventPayload::MessageDelivered {
                     id: msg1.id.clone(),
                     to: "bob@example.com".to_string(),
+                    resource: None,
                 },
             ))
             .await;
@@ -1803,6 +1874,7 @@ mod tests {
             EventPayload::ConnectionLost {
                 reason: "timeout".to_string(),
                 will_retry: true,
+                resumable: false,
             },
         );
         messaging.handle_event(&lost).await;
@@ -1865,6 +1937,7 @@ mod tests {
                 EventPayload::MessageDelivered {
                     id: msg2.id.clone(),
                     to: "carol@example.com".to_string(),
+                    resource: None,
                 },
             ))
             .await;
@@ -1908,6 +1981,7 @@ mod tests {
                         groups: vec![],
                     },
                 ],
+                version: None,
             },
         );
         roster.handle_event(&first).await;
@@ -1923,6 +1997,7 @@ mod tests {
                     subscription: Subscription::Both,
                     groups: vec!["Work".to_string()],
                 }],
+                version: None,
             },
         );
         roster.handle_event(&second).await;
@@ -2090,6 +2165,7 @@ mod tests {
             "xmpp.muc.left",
             EventPayload::MucLeft {
                 room: "room1@conference.example.com".to_string(),
+                removal: None,
             },
         );
         muc.handle_event(&left).await;
@@ -2113,7 +2189,10 @@ mod tests {
         let bus: Arc<dyn EventBus> = Arc::new(BroadcastEventBus::default());
 
         let messaging = Arc::new(MessageManager::new(db.clone(), bus.clone()));
-        let presence = Arc::new(PresenceManager::new(bus.clone()));
+        let presence = Arc::new(PresenceManager::new(
+            bus.clone(),
+            Arc::new(PepManager::new(bus.clone())),
+        ));
 
         // Establish
         let connected = make_event(
@@ -2131,6 +2210,7 @@ mod tests {
             EventPayload::ConnectionLost {
                 reason: "timeout".to_string(),
                 will_retry: true,
+                resumable: false,
             },
         );
         messaging.handle_event(&lost).await;
@@ -2179,7 +2259,10 @@ mod tests {
 
         let messaging = Arc::new(MessageManager::new(db.clone(), bus.clone()));
         let roster = Arc::new(RosterManager::new(db.clone(), bus.clone()));
-        let presence = Arc::new(PresenceManager::new(bus.clone()));
+        let presence = Arc::new(PresenceManager::new(
+            bus.clone(),
+            Arc::new(PepManager::new(bus.clone())),
+        ));
         let mam = Arc::new(MamManager::new(db.clone(), bus.clone()));
 
         let error = make_event(
@@ -1,6 +1,7 @@
 #[cfg(all(test, feature = "native"))]
 mod tests {
     use serde_json::json;
+    use std::collections::BTreeMap;
     use std::sync::Arc;
     use std::time::Duration;
     use tempfile::TempDir;
@@ -8,7 +9,7 @@ mod tests {
         BroadcastEventBus, Channel, ChatMessage, Event, EventBus, EventPayload, EventSource,
         MessageEmbed, MessageType,
     };
-    use waddle_messaging::MessageManager;
+    use waddle_messaging::{ConversationId, MessageManager};
     use waddle_storage::Database;
 
     const TIMEOUT: Duration = Duration::from_millis(500);
@@ -31,6 +32,10 @@ mod tests {
             message_type: MessageType::Chat,
             thread: None,
             embeds: vec![],
+            bodies: BTreeMap::new(),
+            origin_id: None,
+            spoiler: None,
+            stanza_id: None,
         }
     }
 
@@ -105,7 +110,7 @@ mod tests {
 
         // Verify persistence
         let stored = messaging
-            .get_messages("bob@example.com", 50, None)
+            .get_messages(&ConversationId::Direct("bob@example.com".into()), 50, None)
             .await
             .unwrap();
 
@@ -3,24 +3,35 @@ use std::sync::Arc;
 use chrono::{DateTime, Utc};
 use uuid::Uuid;
 
-use waddle_core::event::ChatMessage;
-use waddle_storage::{Database, FromRow, Row, SqlValue, StorageError};
+use waddle_core::event::{ChatMessage, MessageSource};
+use waddle_storage::{
+    ConflictPolicy, Database, FromRow, MessageStore, Row, SqlValue, StorageError, format_timestamp,
+};
 
+#[cfg(feature = "native")]
+use std::collections::HashMap;
 #[cfg(feature = "native")]
 use std::sync::atomic::{AtomicBool, Ordering};
 #[cfg(feature = "native")]
-use tracing::{debug, error, info, warn};
+use std::sync::{Mutex, RwLock};
+#[cfg(feature = "native")]
+use tokio::sync::mpsc;
+#[cfg(feature = "native")]
+use tracing::{debug, error, field, info, instrument, warn};
 
 #[cfg(feature = "native")]
 use waddle_core::event::{
-    Channel, Event, EventBus, EventPayload, EventSource, EventSubscription, PresenceShow,
-    ScrollDirection,
+    Channel, Event, EventBus, EventPayload, EventSource, PresenceShow, ScrollDirection,
 };
 
 const MAM_PAGE_SIZE: u32 = 50;
 #[cfg(feature = "native")]
 const MAM_QUERY_TIMEOUT_SECS: u64 = 30;
 const GLOBAL_SYNC_KEY: &str = "__global__";
+/// A catch-up sync within this many seconds of the last one is skipped, so
+/// presence flapping or quick reconnects do not re-query the archive.
+#[cfg(feature = "native")]
+const MAM_SYNC_COOLDOWN_SECS: i64 = 60;
 
 #[derive(Debug, thiserror::Error)]
 pub enum MamError {
@@ -64,16 +75,6 @@ impl FromRow for SyncState {
     }
 }
 
-fn message_type_to_str(mt: &waddle_core::event::MessageType) -> &'static str {
-    match mt {
-        waddle_core::event::MessageType::Chat => "chat",
-        waddle_core::event::MessageType::Groupchat => "groupchat",
-        waddle_core::event::MessageType::Normal => "normal",
-        waddle_core::event::MessageType::Headline => "headline",
-        waddle_core::event::MessageType::Error => "error",
-    }
-}
-
 fn sync_key(jid: &str) -> String {
     if jid.is_empty() {
         GLOBAL_SYNC_KEY.to_string()
@@ -82,10 +83,101 @@ fn sync_key(jid: &str) -> String {
     }
 }
 
+/// Hands each MAM result and fin to the query that asked for it. One
+/// subscription feeds every query, so concurrent queries (a catch-up sync and
+/// a scroll-up fetch) neither see nor lag on each other's pages.
+#[cfg(feature = "native")]
+#[derive(Default)]
+struct QueryRouter {
+    pending: Mutex<HashMap<String, mpsc::UnboundedSender<EventPayload>>>,
+    started: AtomicBool,
+}
+
+#[cfg(feature = "native")]
+impl QueryRouter {
+    /// Subscribes to `xmpp.mam.**` on first use and forwards from a task, so
+    /// the subscription exists before any query is published.
+    fn start(self: &Arc<Self>, event_bus: &dyn EventBus) -> Result<(), MamError> {
+        if self.started.swap(true, Ordering::AcqRel) {
+            return Ok(());
+        }
+        let mut sub = event_bus.subscribe("xmpp.mam.**").map_err(|e| {
+            self.started.store(false, Ordering::Release);
+            MamError::EventBus(e.to_string())
+        })?;
+
+        let router = self.clone();
+        tokio::spawn(async move {
+            loop {
+                match sub.recv().await {
+                    Ok(event) => router.route(event.payload),
+                    Err(waddle_core::error::EventBusError::Lagged(count)) => {
+                        warn!(count, "MAM query router lagged");
+                    }
+                    Err(e) => {
+                        debug!(error = %e, "MAM query router stopping");
+                        router.started.store(false, Ordering::Release);
+                        return;
+                    }
+                }
+            }
+        });
+        Ok(())
+    }
+
+    fn route(&self, payload: EventPayload) {
+        let query_id = match &payload {
+            EventPayload::MamResultReceived { query_id, .. } => query_id.clone(),
+            EventPayload::MamFinReceived { iq_id, .. } => iq_id.clone(),
+            _ => return,
+        };
+        if let Some(pending) = self.pending.lock().unwrap().get(&query_id) {
+            let _ = pending.send(payload);
+        }
+    }
+
+    fn register(self: &Arc<Self>, query_id: &str) -> PendingQuery {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        self.pending
+            .lock()
+            .unwrap()
+            .insert(query_id.to_string(), sender);
+        PendingQuery {
+            router: self.clone(),
+            query_id: query_id.to_string(),
+            receiver,
+        }
+    }
+}
+
+/// The events routed to one query; unregisters the query when dropped.
+#[cfg(feature = "native")]
+struct PendingQuery {
+    router: Arc<QueryRouter>,
+    query_id: String,
+    receiver: mpsc::UnboundedReceiver<EventPayload>,
+}
+
+#[cfg(feature = "native")]
+impl Drop for PendingQuery {
+    fn drop(&mut self) {
+        self.router.pending.lock().unwrap().remove(&self.query_id);
+    }
+}
+
 pub struct MamManager<D: Database> {
     db: Arc<D>,
+    messages: MessageStore<D>,
+    #[cfg(feature = "native")]
+    queries: Arc<QueryRouter>,
     #[cfg(feature = "native")]
     startup_sync_pending: AtomicBool,
+    /// JID of the current session, so a duplicate connect does not re-sync
+    #[cfg(feature = "native")]
+    connected_jid: RwLock<Option<String>>,
+    /// Let [`Self::reconcile_empty_range`] delete local messages
+    #[cfg(feature = "native")]
+    prune_empty_ranges: AtomicBool,
     #[cfg(feature = "native")]
     event_bus: Arc<dyn EventBus>,
 }
@@ -94,12 +186,76 @@ impl<D: Database> MamManager<D> {
     #[cfg(feature = "native")]
     pub fn new(db: Arc<D>, event_bus: Arc<dyn EventBus>) -> Self {
         Self {
+            messages: MessageStore::new(db.clone()),
             db,
+            queries: Arc::default(),
             startup_sync_pending: AtomicBool::new(false),
+            connected_jid: RwLock::new(None),
+            prune_empty_ranges: AtomicBool::new(false),
             event_bus,
         }
     }
 
+    /// Opt-in: when enabled, [`Self::reconcile_empty_range`] deletes local
+    /// messages the archive no longer holds, e.g. after another device
+    /// cleared the history or the server pruned it.
+    #[cfg(feature = "native")]
+    pub fn set_prune_empty_ranges(&self, enabled: bool) {
+        self.prune_empty_ranges.store(enabled, Ordering::Relaxed);
+    }
+
+    /// Reconciles the conversation with `jid` against an archive known to
+    /// hold nothing for it between `start` and `end` (inclusive): local
+    /// copies of messages in that range are deleted, along with their pins
+    /// and receipts, and their count returned. A conversation left with no
+    /// messages loses its list entry. Messages still waiting in the offline
+    /// queue are kept, as the server has not seen them yet. Does nothing
+    /// unless enabled with [`Self::set_prune_empty_ranges`].
+    #[cfg(feature = "native")]
+    pub async fn reconcile_empty_range(
+        &self,
+        jid: &str,
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+    ) -> Result<u64, MamError> {
+        if !self.prune_empty_ranges.load(Ordering::Relaxed) {
+            return Ok(0);
+        }
+        let jid_s = jid.split('/').next().unwrap_or(jid).to_string();
+        let start_ms = start.timestamp_millis();
+        let end_ms = end.timestamp_millis();
+        let pruned = self
+            .db
+            .execute(
+                "DELETE FROM messages \
+                 WHERE (from_jid = ?1 OR substr(from_jid, 1, length(?1) + 1) = ?1 || '/' \
+                        OR to_jid = ?1 OR substr(to_jid, 1, length(?1) + 1) = ?1 || '/') \
+                   AND timestamp_ms BETWEEN ?2 AND ?3 \
+                   AND id NOT IN ( \
+                      SELECT json_extract(payload, '$.correlation_id') FROM offline_queue \
+                      WHERE status != 'confirmed' \
+                      AND json_extract(payload, '$.correlation_id') IS NOT NULL \
+                   )",
+                &[&jid_s, &start_ms, &end_ms],
+            )
+            .await?;
+        if pruned > 0 {
+            // Pins and receipts go with their messages by trigger.
+            self.db
+                .execute(
+                    "DELETE FROM conversation_meta WHERE jid = ?1 AND NOT EXISTS ( \
+                        SELECT 1 FROM messages WHERE message_type = 'chat' \
+                        AND (from_jid = ?1 OR substr(from_jid, 1, length(?1) + 1) = ?1 || '/' \
+                             OR to_jid = ?1 OR substr(to_jid, 1, length(?1) + 1) = ?1 || '/') \
+                     )",
+                    &[&jid_s],
+                )
+                .await?;
+            info!(jid = %jid_s, pruned, "pruned local messages missing from the archive");
+        }
+        Ok(pruned)
+    }
+
     pub async fn sync_since(&self, _timestamp: DateTime<Utc>) -> Result<MamSyncResult, MamError> {
         if !self.is_supported().await {
             return Ok(MamSyncResult {
@@ -140,6 +296,7 @@ impl<D: Database> MamManager<D> {
             complete = fin_complete || page_count == 0;
         }
 
+        self.touch_sync_state("").await?;
         self.emit_sync_completed(total_synced, correlation_id)?;
 
         Ok(MamSyncResult {
@@ -158,18 +315,31 @@ impl<D: Database> MamManager<D> {
             return Ok(Vec::new());
         }
 
+        let (messages, _complete) = self.fetch_history_page(jid, None, before, limit).await?;
+        Ok(messages)
+    }
+
+    /// Fetches and persists one page of history, also reporting whether the
+    /// archive says this was the last page.
+    async fn fetch_history_page(
+        &self,
+        jid: &str,
+        after: Option<&str>,
+        before: Option<&str>,
+        limit: u32,
+    ) -> Result<(Vec<ChatMessage>, bool), MamError> {
         let query_id = Uuid::new_v4().to_string();
         let page_size = limit.clamp(1, MAM_PAGE_SIZE);
 
-        let (messages, _complete, _last_id) = self
-            .query_page(&query_id, Some(jid), None, before, page_size)
+        let (messages, complete, _last_id) = self
+            .query_page(&query_id, Some(jid), after, before, page_size)
             .await?;
 
         for msg in &messages {
             self.persist_message(msg).await?;
         }
 
-        Ok(messages)
+        Ok((messages, complete))
     }
 
     pub async fn is_supported(&self) -> bool {
@@ -187,13 +357,17 @@ impl<D: Database> MamManager<D> {
             )
             .await?;
 
-        Ok(rows.into_iter().next().map(|s| s.last_stanza_id))
+        Ok(rows
+            .into_iter()
+            .next()
+            .map(|s| s.last_stanza_id)
+            .filter(|id| !id.is_empty()))
     }
 
     async fn update_sync_state(&self, jid: &str, stanza_id: &str) -> Result<(), MamError> {
         let jid_s = sync_key(jid);
         let stanza_id_s = stanza_id.to_string();
-        let now = Utc::now().to_rfc3339();
+        let now = format_timestamp(&Utc::now());
 
         self.db
             .execute(
@@ -206,20 +380,90 @@ impl<D: Database> MamManager<D> {
         Ok(())
     }
 
+    /// Records a completed sync that may not have advanced the stanza id. A
+    /// first sync that found nothing stores an empty stanza id, read back as
+    /// none.
+    async fn touch_sync_state(&self, jid: &str) -> Result<(), MamError> {
+        let jid_s = sync_key(jid);
+        let now = format_timestamp(&Utc::now());
+
+        self.db
+            .execute(
+                "INSERT INTO mam_sync_state (jid, last_stanza_id, last_sync_at) \
+                 VALUES (?1, '', ?2) \
+                 ON CONFLICT(jid) DO UPDATE SET last_sync_at = excluded.last_sync_at",
+                &[&jid_s, &now],
+            )
+            .await?;
+
+        Ok(())
+    }
+
     #[cfg(feature = "native")]
-    async fn oldest_local_message_id(&self, jid: &str) -> Result<Option<String>, MamError> {
-        let jid_s = jid.to_string();
+    async fn last_sync_at(&self, jid: &str) -> Result<Option<DateTime<Utc>>, MamError> {
+        let jid_s = sync_key(jid);
         let rows: Vec<Row> = self
             .db
             .query(
-                "SELECT id FROM messages \
-                 WHERE from_jid = ?1 OR to_jid = ?1 \
-                 ORDER BY timestamp ASC \
-                 LIMIT 1",
+                "SELECT last_sync_at FROM mam_sync_state WHERE jid = ?1",
                 &[&jid_s],
             )
             .await?;
 
+        Ok(rows.first().and_then(|row| match row.get(0) {
+            Some(SqlValue::Text(ts)) => ts.parse::<DateTime<Utc>>().ok(),
+            _ => None,
+        }))
+    }
+
+    #[cfg(feature = "native")]
+    async fn synced_within_cooldown(&self) -> bool {
+        match self.last_sync_at("").await {
+            Ok(Some(last)) => Utc::now() - last < chrono::Duration::seconds(MAM_SYNC_COOLDOWN_SECS),
+            Ok(None) => false,
+            Err(e) => {
+                warn!(error = %e, "failed to read MAM sync state, syncing anyway");
+                false
+            }
+        }
+    }
+
+    #[cfg(feature = "native")]
+    async fn oldest_local_message_id(&self, jid: &str) -> Result<Option<String>, MamError> {
+        self.edge_local_message_id(
+            "SELECT id FROM messages \
+             WHERE from_jid = ?1 OR to_jid = ?1 \
+             ORDER BY timestamp_ms ASC \
+             LIMIT 1",
+            jid,
+        )
+        .await
+    }
+
+    /// Archive id of the newest stored message with `jid` that came from the
+    /// archive, the RSM cursor for paging forward. Messages only seen live
+    /// have no archive id and cannot serve as one.
+    #[cfg(feature = "native")]
+    async fn newest_archive_id(&self, jid: &str) -> Result<Option<String>, MamError> {
+        self.edge_local_message_id(
+            "SELECT stanza_id FROM messages \
+             WHERE (from_jid = ?1 OR to_jid = ?1) AND stanza_id IS NOT NULL \
+             ORDER BY timestamp_ms DESC \
+             LIMIT 1",
+            jid,
+        )
+        .await
+    }
+
+    #[cfg(feature = "native")]
+    async fn edge_local_message_id(
+        &self,
+        sql: &str,
+        jid: &str,
+    ) -> Result<Option<String>, MamError> {
+        let jid_s = jid.to_string();
+        let rows: Vec<Row> = self.db.query(sql, &[&jid_s]).await?;
+
         let Some(row) = rows.first() else {
             return Ok(None);
         };
@@ -228,29 +472,17 @@ impl<D: Database> MamManager<D> {
             Some(SqlValue::Text(id)) => Ok(Some(id.clone())),
             Some(SqlValue::Null) | None => Ok(None),
             _ => Err(MamError::QueryFailed(
-                "invalid message id type in oldest message query".to_string(),
+                "invalid message id type in local message query".to_string(),
             )),
         }
     }
 
+    /// The archive's copy is authoritative, so its server timestamp replaces
+    /// the one a live copy was stored with.
     async fn persist_message(&self, message: &ChatMessage) -> Result<(), MamError> {
-        let id = message.id.clone();
-        let from = message.from.clone();
-        let to = message.to.clone();
-        let body = message.body.clone();
-        let ts = message.timestamp.to_rfc3339();
-        let mt = message_type_to_str(&message.message_type).to_string();
-        let thread = message.thread.clone();
-        let read = 0_i64;
-
-        self.db
-            .execute(
-                "INSERT OR IGNORE INTO messages (id, from_jid, to_jid, body, timestamp, message_type, thread, read) \
-                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
-                &[&id, &from, &to, &body, &ts, &mt, &thread, &read],
-            )
+        self.messages
+            .persist_message_with(message, MessageSource::Mam, ConflictPolicy::ReplaceNewer)
             .await?;
-
         Ok(())
     }
 
@@ -263,10 +495,8 @@ impl<D: Database> MamManager<D> {
         before: Option<&str>,
         max: u32,
     ) -> Result<(Vec<ChatMessage>, bool, Option<String>), MamError> {
-        let mut sub = self
-            .event_bus
-            .subscribe("xmpp.mam.**")
-            .map_err(|e| MamError::EventBus(e.to_string()))?;
+        self.queries.start(self.event_bus.as_ref())?;
+        let mut pending = self.queries.register(query_id);
 
         self.event_bus
             .publish(Event::new(
@@ -282,7 +512,7 @@ impl<D: Database> MamManager<D> {
             ))
             .map_err(|e| MamError::EventBus(e.to_string()))?;
 
-        self.collect_query_results(&mut sub, query_id).await
+        self.collect_query_results(&mut pending).await
     }
 
     #[cfg(not(feature = "native"))]
@@ -300,45 +530,41 @@ impl<D: Database> MamManager<D> {
     #[cfg(feature = "native")]
     async fn collect_query_results(
         &self,
-        sub: &mut EventSubscription,
-        query_id: &str,
+        pending: &mut PendingQuery,
     ) -> Result<(Vec<ChatMessage>, bool, Option<String>), MamError> {
         let mut messages = Vec::new();
         let mut last_id = None;
         let timeout_duration = tokio::time::Duration::from_secs(MAM_QUERY_TIMEOUT_SECS);
 
         loop {
-            match tokio::time::timeout(timeout_duration, sub.recv()).await {
-                Ok(Ok(event)) => match &event.payload {
-                    EventPayload::MamResultReceived {
-                        query_id: result_query_id,
-                        messages: page_msgs,
-                        complete,
-                    } if result_query_id == query_id => {
-                        for msg in page_msgs {
-                            last_id = Some(msg.id.clone());
-                            messages.push(msg.clone());
-                        }
-
-                        if *complete {
-                            return Ok((messages, true, last_id));
-                        }
+            match tokio::time::timeout(timeout_duration, pending.receiver.recv()).await {
+                Ok(Some(EventPayload::MamResultReceived {
+                    messages: page_msgs,
+                    complete,
+                    ..
+                })) => {
+                    for msg in page_msgs {
+                        last_id = Some(msg.id.clone());
+                        messages.push(msg);
                     }
-                    EventPayload::MamFinReceived {
-                        iq_id,
-                        complete,
-                        last_id: fin_last,
-                    } if iq_id == query_id => {
-                        let resolved_last = fin_last.clone().or(last_id);
-                        return Ok((messages, *complete, resolved_last));
+
+                    if complete {
+                        return Ok((messages, true, last_id));
                     }
-                    _ => {}
-                },
-                Ok(Err(waddle_core::error::EventBusError::Lagged(count))) => {
-                    warn!(count, "MAM result collector lagged");
                 }
-                Ok(Err(e)) => {
-                    return Err(MamError::QueryFailed(format!("event bus error: {e}")));
+                Ok(Some(EventPayload::MamFinReceived {
+                    complete,
+                    last_id: fin_last,
+                    ..
+                })) => {
+                    let resolved_last = fin_last.or(last_id);
+                    return Ok((messages, complete, resolved_last));
+                }
+                Ok(Some(_)) => {}
+                Ok(None) => {
+                    return Err(MamError::QueryFailed(
+                        "MAM query router stopped".to_string(),
+                    ));
                 }
                 Err(_) => {
                     return Err(MamError::Timeout(MAM_QUERY_TIMEOUT_SECS));
@@ -390,13 +616,20 @@ impl<D: Database> MamManager<D> {
     }
 
     #[cfg(feature = "native")]
+    #[instrument(skip_all, fields(channel = %event.channel, correlation_id = event.correlation_id.map(field::display)))]
     pub async fn handle_event(&self, event: &Event) {
         match &event.payload {
             EventPayload::ConnectionEstablished { jid } => {
+                let previous = self.connected_jid.write().unwrap().replace(jid.clone());
+                if previous.as_deref() == Some(jid.as_str()) {
+                    debug!(jid = %jid, "duplicate connection established, ignoring");
+                    return;
+                }
                 self.startup_sync_pending.store(true, Ordering::Relaxed);
                 info!(jid = %jid, "connection established, waiting for own presence before MAM catch-up sync");
             }
             EventPayload::ConnectionLost { .. } => {
+                self.connected_jid.write().unwrap().take();
                 self.startup_sync_pending.store(false, Ordering::Relaxed);
             }
             EventPayload::OwnPresenceChanged { show, .. } => {
@@ -408,6 +641,11 @@ impl<D: Database> MamManager<D> {
                     return;
                 }
 
+                if self.synced_within_cooldown().await {
+                    info!("MAM catch-up synced recently, skipping");
+                    return;
+                }
+
                 info!("initial own presence published, starting MAM catch-up sync");
                 match self.sync_since(Utc::now()).await {
                     Ok(result) => {
@@ -438,11 +676,54 @@ impl<D: Database> MamManager<D> {
                 };
 
                 match self
-                    .fetch_history(jid, before.as_deref(), MAM_PAGE_SIZE)
+                    .fetch_history_page(jid, None, before.as_deref(), MAM_PAGE_SIZE)
                     .await
                 {
-                    Ok(messages) => {
+                    Ok((messages, complete)) => {
                         debug!(count = messages.len(), jid = %jid, "fetched MAM history");
+                        let _ = self.event_bus.publish(Event::new(
+                            Channel::new("ui.history.loaded").unwrap(),
+                            EventSource::System("mam".into()),
+                            EventPayload::HistoryLoaded {
+                                jid: jid.clone(),
+                                count: messages.len() as u64,
+                                reached_beginning: complete || messages.is_empty(),
+                            },
+                        ));
+                    }
+                    Err(e) => {
+                        error!(error = %e, jid = %jid, "MAM history fetch failed");
+                    }
+                }
+            }
+            EventPayload::ScrollRequested {
+                jid,
+                direction: ScrollDirection::Down,
+            } => {
+                debug!(jid = %jid, "scroll down requested, fetching newer MAM history");
+                let after = match self.newest_archive_id(jid).await {
+                    Ok(newest) => newest,
+                    Err(e) => {
+                        error!(error = %e, jid = %jid, "failed to read newest archive id");
+                        None
+                    }
+                };
+
+                match self
+                    .fetch_history_page(jid, after.as_deref(), None, MAM_PAGE_SIZE)
+                    .await
+                {
+                    Ok((messages, _complete)) => {
+                        debug!(count = messages.len(), jid = %jid, "fetched newer MAM history");
+                        let _ = self.event_bus.publish(Event::new(
+                            Channel::new("ui.history.loaded").unwrap(),
+                            EventSource::System("mam".into()),
+                            EventPayload::HistoryLoaded {
+                                jid: jid.clone(),
+                                count: messages.len() as u64,
+                                reached_beginning: false,
+                            },
+                        ));
                     }
                     Err(e) => {
                         error!(error = %e, jid = %jid, "MAM history fetch failed");
@@ -484,6 +765,7 @@ impl<D: Database> MamManager<D> {
 #[cfg(all(test, feature = "native"))]
 mod tests {
     use super::*;
+    use std::collections::BTreeMap;
     use std::sync::Arc;
     use tempfile::TempDir;
     use waddle_core::event::{BroadcastEventBus, EventBus, MessageType, PresenceShow};
@@ -510,6 +792,10 @@ mod tests {
             message_type: MessageType::Chat,
             thread: None,
             embeds: vec![],
+            bodies: BTreeMap::new(),
+            origin_id: None,
+            spoiler: None,
+            stanza_id: None,
         }
     }
 
@@ -534,6 +820,132 @@ mod tests {
         assert_eq!(rows.len(), 1);
     }
 
+    #[tokio::test]
+    async fn persisted_message_records_mam_source() {
+        let (manager, _, _dir) = setup().await;
+
+        let msg = make_chat_message("mam-1", "alice@example.com", "bob@example.com", "Hello");
+        manager.persist_message(&msg).await.unwrap();
+
+        let rows: Vec<Row> = manager
+            .db
+            .query(
+                "SELECT source FROM messages WHERE id = ?1",
+                &[&"mam-1".to_string()],
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(rows[0].get(0), Some(&SqlValue::Text("mam".to_string())));
+    }
+
+    #[tokio::test]
+    async fn empty_archive_range_prunes_local_messages_when_enabled() {
+        let (manager, _, _dir) = setup().await;
+        let base = Utc::now() - chrono::Duration::hours(1);
+        for (id, from, minutes) in [
+            ("before", "alice@example.com/phone", 0),
+            ("inside-1", "alice@example.com/phone", 10),
+            ("inside-2", "me@example.com", 20),
+            ("other-peer", "carol@example.com", 15),
+            ("after", "alice@example.com", 40),
+        ] {
+            let to = if from.starts_with("me@") {
+                "alice@example.com"
+            } else {
+                "me@example.com"
+            };
+            let mut message = make_chat_message(id, from, to, id);
+            message.timestamp = base + chrono::Duration::minutes(minutes);
+            manager.persist_message(&message).await.unwrap();
+        }
+        let start = base + chrono::Duration::minutes(5);
+        let end = base + chrono::Duration::minutes(30);
+
+        assert_eq!(
+            manager
+                .reconcile_empty_range("alice@example.com", start, end)
+                .await
+                .unwrap(),
+            0,
+            "pruning is opt-in"
+        );
+
+        manager.set_prune_empty_ranges(true);
+        assert_eq!(
+            manager
+                .reconcile_empty_range("alice@example.com", start, end)
+                .await
+                .unwrap(),
+            2
+        );
+        let rows: Vec<Row> = manager
+            .db
+            .query("SELECT id FROM messages ORDER BY id", &[])
+            .await
+            .unwrap();
+        let ids: Vec<String> = rows
+            .iter()
+            .filter_map(|row| match row.get(0) {
+                Some(SqlValue::Text(id)) => Some(id.clone()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(ids, ["after", "before", "other-peer"]);
+    }
+
+    #[tokio::test]
+    async fn pruning_matches_the_jid_literally_and_takes_what_hangs_off_it() {
+        let (manager, _, _dir) = setup().await;
+        manager.set_prune_empty_ranges(true);
+        let at = Utc::now() - chrono::Duration::minutes(10);
+        for (id, from) in [
+            ("wild", "a_ice@example.com"),
+            ("lookalike", "alice@example.com/phone"),
+        ] {
+            let mut message = make_chat_message(id, from, "me@example.com", id);
+            message.timestamp = at;
+            manager.persist_message(&message).await.unwrap();
+        }
+        for sql in [
+            "INSERT INTO local_pins (jid, message_id, pinned_at) \
+             VALUES ('a_ice@example.com', 'wild', '2025-01-01T00:00:00Z')",
+            "INSERT INTO message_deliveries (message_id, delivered_to, delivered_at) \
+             VALUES ('wild', 'a_ice@example.com/phone', '2025-01-01T00:00:00Z')",
+        ] {
+            manager.db.execute(sql, &[]).await.unwrap();
+        }
+
+        let pruned = manager
+            .reconcile_empty_range(
+                "a_ice@example.com",
+                at - chrono::Duration::minutes(1),
+                at + chrono::Duration::minutes(1),
+            )
+            .await
+            .unwrap();
+        assert_eq!(pruned, 1);
+
+        let count = |sql: &'static str| {
+            let manager = manager.clone();
+            async move {
+                let rows: Vec<Row> = manager.db.query(sql, &[]).await.unwrap();
+                rows[0].get(0).cloned()
+            }
+        };
+        assert_eq!(
+            count("SELECT COUNT(*) FROM messages WHERE id = 'lookalike'").await,
+            Some(SqlValue::Integer(1))
+        );
+        for sql in [
+            "SELECT COUNT(*) FROM conversation_meta WHERE jid = 'a_ice@example.com'",
+            "SELECT COUNT(*) FROM local_pins",
+            "SELECT COUNT(*) FROM message_deliveries",
+        ] {
+            assert_eq!(count(sql).await, Some(SqlValue::Integer(0)), "{sql}");
+        }
+    }
+
     #[tokio::test]
     async fn sync_state_round_trip() {
         let (manager, _, _dir) = setup().await;
@@ -549,6 +961,23 @@ mod tests {
         assert_eq!(last, Some("archive-id-42".to_string()));
     }
 
+    #[tokio::test]
+    async fn empty_first_sync_still_starts_the_cooldown() {
+        let (manager, _, _dir) = setup().await;
+
+        manager.touch_sync_state("").await.unwrap();
+
+        assert!(manager.synced_within_cooldown().await);
+        assert!(manager.get_last_stanza_id("").await.unwrap().is_none());
+
+        manager.update_sync_state("", "archive-id-1").await.unwrap();
+        manager.touch_sync_state("").await.unwrap();
+        assert_eq!(
+            manager.get_last_stanza_id("").await.unwrap(),
+            Some("archive-id-1".to_string())
+        );
+    }
+
     #[tokio::test]
     async fn sync_state_update_replaces() {
         let (manager, _, _dir) = setup().await;
@@ -730,8 +1159,8 @@ mod tests {
                 let manager_clone = manager.clone();
                 let handle = tokio::task::spawn_local(async move {
                     let own_presence = Event::new(
-                        Channel::new("xmpp.presence.own_changed").unwrap(),
-                        EventSource::Xmpp,
+                        Channel::new("system.presence.own_changed").unwrap(),
+                        EventSource::System("presence".into()),
                         EventPayload::OwnPresenceChanged {
                             show: PresenceShow::Available,
                             status: None,
@@ -772,6 +1201,118 @@ mod tests {
             .await;
     }
 
+    #[tokio::test]
+    async fn presence_transitions_within_cooldown_sync_once() {
+        let local = tokio::task::LocalSet::new();
+        local
+            .run_until(async {
+                let (manager, event_bus, _dir) = setup().await;
+                let mut ui_sub = event_bus.subscribe("ui.mam.query").unwrap();
+
+                let connected = Event::new(
+                    Channel::new("system.connection.established").unwrap(),
+                    EventSource::Xmpp,
+                    EventPayload::ConnectionEstablished {
+                        jid: "alice@example.com".to_string(),
+                    },
+                );
+                let lost = Event::new(
+                    Channel::new("system.connection.lost").unwrap(),
+                    EventSource::Xmpp,
+                    EventPayload::ConnectionLost {
+                        reason: "flaky".to_string(),
+                        will_retry: true,
+                        resumable: false,
+                    },
+                );
+                let available = Event::new(
+                    Channel::new("system.presence.own_changed").unwrap(),
+                    EventSource::System("presence".into()),
+                    EventPayload::OwnPresenceChanged {
+                        show: PresenceShow::Available,
+                        status: None,
+                    },
+                );
+
+                // First session: the catch-up sync runs.
+                manager.handle_event(&connected).await;
+                let manager_clone = manager.clone();
+                let available_clone = available.clone();
+                let handle = tokio::task::spawn_local(async move {
+                    manager_clone.handle_event(&available_clone).await;
+                });
+                let query_event =
+                    tokio::time::timeout(std::time::Duration::from_millis(500), ui_sub.recv())
+                        .await
+                        .expect("timed out waiting for MAM query")
+                        .expect("should receive query event");
+                let EventPayload::MamQueryRequested { query_id, .. } = query_event.payload else {
+                    panic!("expected MamQueryRequested");
+                };
+                event_bus
+                    .publish(Event::new(
+                        Channel::new("xmpp.mam.fin.received").unwrap(),
+                        EventSource::Xmpp,
+                        EventPayload::MamFinReceived {
+                            iq_id: query_id,
+                            complete: true,
+                            last_id: Some("archive-1".to_string()),
+                        },
+                    ))
+                    .unwrap();
+                tokio::time::timeout(std::time::Duration::from_secs(5), handle)
+                    .await
+                    .expect("handle_event timed out")
+                    .expect("handle_event should not panic");
+
+                // Quick reconnect and Available again: within the cooldown.
+                manager.handle_event(&lost).await;
+                manager.handle_event(&connected).await;
+                manager.handle_event(&available).await;
+
+                let second_query =
+                    tokio::time::timeout(std::time::Duration::from_millis(100), ui_sub.recv())
+                        .await;
+                assert!(second_query.is_err(), "second sync should be skipped");
+            })
+            .await;
+    }
+
+    #[tokio::test]
+    async fn duplicate_connection_established_does_not_rearm_sync() {
+        let (manager, _event_bus, _dir) = setup().await;
+        let connected = Event::new(
+            Channel::new("system.connection.established").unwrap(),
+            EventSource::Xmpp,
+            EventPayload::ConnectionEstablished {
+                jid: "alice@example.com".to_string(),
+            },
+        );
+
+        manager.handle_event(&connected).await;
+        assert!(manager.startup_sync_pending.load(Ordering::Relaxed));
+
+        // The catch-up sync has run for this session.
+        manager.startup_sync_pending.store(false, Ordering::Relaxed);
+        manager.handle_event(&connected).await;
+        assert!(!manager.startup_sync_pending.load(Ordering::Relaxed));
+
+        // After a real disconnect the next session syncs again.
+        manager
+            .handle_event(&Event::new(
+                Channel::new("system.connection.lost").unwrap(),
+                EventSource::Xmpp,
+                EventPayload::ConnectionLost {
+                    reason: "test".to_string(),
+                    will_retry: true,
+                    resumable: false,
+                },
+            ))
+            .await;
+        manager.handle_event(&connected).await;
+        assert!(manager.startup_sync_pending.load(Ordering::Relaxed));
+    }
+
     #[tokio::test]
     async fn sync_with_existing_state_uses_after() {
         let local = tokio::task::LocalSet::new();
@@ -899,6 +1440,152 @@ mod tests {
             .await;
     }
 
+    #[tokio::test]
+    async fn scroll_up_without_older_history_reports_beginning() {
+        let local = tokio::task::LocalSet::new();
+        local
+            .run_until(async {
+                let (manager, event_bus, _dir) = setup().await;
+                let mut query_sub = event_bus.subscribe("ui.mam.query").unwrap();
+                let mut loaded_sub = event_bus.subscribe("ui.history.loaded").unwrap();
+
+                let manager_clone = manager.clone();
+                let handle = tokio::task::spawn_local(async move {
+                    manager_clone
+                        .handle_event(&Event::new(
+                            Channel::new("ui.scroll.requested").unwrap(),
+                            EventSource::System("ui".into()),
+                            EventPayload::ScrollRequested {
+                                jid: "bob@example.com".to_string(),
+                                direction: ScrollDirection::Up,
+                            },
+                        ))
+                        .await;
+                });
+
+                let query_event =
+                    tokio::time::timeout(std::time::Duration::from_millis(500), query_sub.recv())
+                        .await
+                        .expect("timed out waiting for MAM query")
+                        .expect("should receive query event");
+                let EventPayload::MamQueryRequested { query_id, .. } = query_event.payload else {
+                    panic!("expected MamQueryRequested");
+                };
+                event_bus
+                    .publish(Event::new(
+                        Channel::new("xmpp.mam.fin.received").unwrap(),
+                        EventSource::Xmpp,
+                        EventPayload::MamFinReceived {
+                            iq_id: query_id,
+                            complete: true,
+                            last_id: None,
+                        },
+                    ))
+                    .unwrap();
+                tokio::time::timeout(std::time::Duration::from_secs(5), handle)
+                    .await
+                    .expect("handle_event timed out")
+                    .expect("handle_event should not panic");
+
+                let loaded =
+                    tokio::time::timeout(std::time::Duration::from_millis(100), loaded_sub.recv())
+                        .await
+                        .expect("timed out waiting for history loaded")
+                        .expect("should receive history loaded");
+                assert!(matches!(
+                    loaded.payload,
+                    EventPayload::HistoryLoaded {
+                        ref jid,
+                        count: 0,
+                        reached_beginning: true,
+                    } if jid == "bob@example.com"
+                ));
+            })
+            .await;
+    }
+
+    #[tokio::test]
+    async fn scroll_down_pages_after_the_newest_archive_id() {
+        let local = tokio::task::LocalSet::new();
+        local
+            .run_until(async {
+                let (manager, event_bus, _dir) = setup().await;
+                let mut older =
+                    make_chat_message("older-1", "bob@example.com", "alice@example.com", "hi");
+                older.timestamp = Utc::now() - chrono::Duration::minutes(5);
+                older.stanza_id = Some("archive-7".to_string());
+                // Seen live only, so it has no archive id to page from.
+                let newer =
+                    make_chat_message("newer-1", "alice@example.com", "bob@example.com", "hey");
+                manager.persist_message(&older).await.unwrap();
+                manager.persist_message(&newer).await.unwrap();
+
+                let mut query_sub = event_bus.subscribe("ui.mam.query").unwrap();
+                let mut loaded_sub = event_bus.subscribe("ui.history.loaded").unwrap();
+                let manager_clone = manager.clone();
+                let handle = tokio::task::spawn_local(async move {
+                    manager_clone
+                        .handle_event(&Event::new(
+                            Channel::new("ui.scroll.requested").unwrap(),
+                            EventSource::System("ui".into()),
+                            EventPayload::ScrollRequested {
+                                jid: "bob@example.com".to_string(),
+                                direction: ScrollDirection::Down,
+                            },
+                        ))
+                        .await;
+                });
+
+                let query_event =
+                    tokio::time::timeout(std::time::Duration::from_millis(500), query_sub.recv())
+                        .await
+                        .expect("timed out waiting for MAM query")
+                        .expect("should receive query event");
+                let query_id = match query_event.payload {
+                    EventPayload::MamQueryRequested {
+                        query_id,
+                        with_jid,
+                        after,
+                        before,
+                        ..
+                    } => {
+                        assert_eq!(with_jid.as_deref(), Some("bob@example.com"));
+                        assert_eq!(after.as_deref(), Some("archive-7"));
+                        assert!(before.is_none());
+                        query_id
+                    }
+                    other => panic!("expected MamQueryRequested event, got {other:?}"),
+                };
+
+                event_bus
+                    .publish(Event::new(
+                        Channel::new("xmpp.mam.fin.received").unwrap(),
+                        EventSource::Xmpp,
+                        EventPayload::MamFinReceived {
+                            iq_id: query_id,
+                            complete: true,
+                            last_id: None,
+                        },
+                    ))
+                    .unwrap();
+                tokio::time::timeout(std::time::Duration::from_secs(5), handle)
+                    .await
+                    .expect("handle_event timed out")
+                    .expect("handle_event should not panic");
+
+                let loaded =
+                    tokio::time::timeout(std::time::Duration::from_millis(100), loaded_sub.recv())
+                        .await
+                        .expect("timed out waiting for history loaded")
+                        .expect("should receive history loaded");
+                assert!(matches!(
+                    loaded.payload,
+                    EventPayload::HistoryLoaded { ref jid, count: 0, .. } if jid == "bob@example.com"
+                ));
+            })
+            .await;
+    }
+
     #[tokio::test]
     async fn sync_since_ignores_other_query_results() {
         let local = tokio::task::LocalSet::new();
@@ -995,4 +1682,91 @@ mod tests {
             })
             .await;
     }
+
+    #[tokio::test]
+    async fn concurrent_fetches_each_collect_their_own_results() {
+        let local = tokio::task::LocalSet::new();
+        local
+            .run_until(async {
+                let (manager, event_bus, _dir) = setup().await;
+                let mut ui_sub = event_bus.subscribe("ui.mam.query").unwrap();
+
+                let fetches = {
+                    let manager = manager.clone();
+                    tokio::task::spawn_local(async move {
+                        tokio::join!(
+                            manager.fetch_history("bob@example.com", None, 10),
+                            manager.fetch_history("carol@example.com", None, 10),
+                        )
+                    })
+                };
+
+                let mut query_ids = HashMap::new();
+                for _ in 0..2 {
+                    let event =
+                        tokio::time::timeout(std::time::Duration::from_secs(1), ui_sub.recv())
+                            .await
+                            .expect("timed out waiting for MAM query")
+                            .unwrap();
+                    let EventPayload::MamQueryRequested {
+                        query_id, with_jid, ..
+                    } = event.payload
+                    else {
+                        panic!("expected MamQueryRequested");
+                    };
+                    query_ids.insert(with_jid.unwrap(), query_id);
+                }
+                let bob = query_ids["bob@example.com"].clone();
+                let carol = query_ids["carol@example.com"].clone();
+
+                let result = |query_id: &str, id: &str, from: &str| {
+                    Event::new(
+                        Channel::new("xmpp.mam.result.received").unwrap(),
+                        EventSource::Xmpp,
+                        EventPayload::MamResultReceived {
+                            query_id: query_id.to_string(),
+                            messages: vec![make_chat_message(id, from, "alice@example.com", id)],
+                            complete: false,
+                        },
+                    )
+                };
+                let fin = |query_id: &str| {
+                    Event::new(
+                        Channel::new("xmpp.mam.fin.received").unwrap(),
+                        EventSource::Xmpp,
+                        EventPayload::MamFinReceived {
+                            iq_id: query_id.to_string(),
+                            complete: true,
+                            last_id: None,
+                        },
+                    )
+                };
+                for event in [
+                    result(&carol, "carol-1", "carol@example.com"),
+                    result(&bob, "bob-1", "bob@example.com"),
+                    result(&carol, "carol-2", "carol@example.com"),
+                    fin(&bob),
+                    result(&carol, "carol-3", "carol@example.com"),
+                    fin(&carol),
+                ] {
+                    event_bus.publish(event).unwrap();
+                }
+
+                let (bob_history, carol_history) =
+                    tokio::time::timeout(std::time::Duration::from_secs(5), fetches)
+                        .await
+                        .expect("fetches timed out")
+                        .unwrap();
+                let ids = |history: Vec<ChatMessage>| -> Vec<String> {
+                    history.into_iter().map(|m| m.id).collect()
+                };
+                assert_eq!(ids(bob_history.unwrap()), vec!["bob-1"]);
+                assert_eq!(
+                    ids(carol_history.unwrap()),
+                    vec!["carol-1", "carol-2", "carol-3"]
+                );
+                assert!(manager.queries.pending.lock().unwrap().is_empty());
+            })
+            .await;
+    }
 }
@@ -1,21 +1,27 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::sync::Arc;
 
 use chrono::{DateTime, Utc};
-#[cfg(feature = "native")]
 use serde::{Deserialize, Serialize};
 use std::sync::RwLock;
-use tracing::{debug, error, warn};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use tracing::{debug, error, field, info, instrument, warn};
 use uuid::Uuid;
 
 use waddle_core::event::{
-    ChatMessage, ChatState, Event, EventPayload, MessageType, MucOccupant, MucRole,
+    ChatMessage, ChatState, Event, EventPayload, MessageSource, MessageType, MucAffiliation,
+    MucJoinFailure, MucOccupant, MucRole, MucRoomInfo, Subscription,
+};
+use waddle_storage::{
+    Database, FromRow, MessageStore, Row, SqlValue, StorageError, format_timestamp,
+    timestamp_millis,
 };
-use waddle_storage::{Database, FromRow, Row, SqlValue, StorageError};
 use waddle_xmpp::Stanza;
 
 #[cfg(feature = "native")]
 use waddle_core::event::{Channel, EventBus, EventSource};
+#[cfg(feature = "native")]
+use waddle_core::retry::{RetryPolicy, retry};
 
 #[derive(Debug, thiserror::Error)]
 pub enum MessagingError {
@@ -30,6 +36,67 @@ pub enum MessagingError {
 
     #[error("invalid JID: {0}")]
     InvalidJid(String),
+
+    #[error("invalid timestamp: {0}")]
+    InvalidTimestamp(String),
+}
+
+/// Errors from room operations. Refusals by the room get their own variants
+/// so callers can react to them, e.g. rejoin under another nick after a
+/// conflict.
+#[derive(Debug, thiserror::Error)]
+pub enum MucError {
+    #[error("nick {nick} is already in use in {room}")]
+    NickConflict { room: String, nick: String },
+
+    #[error("not a member of members-only room {0}")]
+    NotMember(String),
+
+    #[error("room {0} is locked")]
+    RoomLocked(String),
+
+    #[error("banned from room {0}")]
+    Banned(String),
+
+    #[error("room {room} refused the join: {condition}")]
+    JoinRefused { room: String, condition: String },
+
+    #[error("room {0} did not answer in time")]
+    Timeout(String),
+
+    #[error("room {room} refused the query: {condition}")]
+    QueryFailed { room: String, condition: String },
+
+    #[error("room {room} assigned no stanza-id to message {id}")]
+    NoStanzaId { room: String, id: String },
+
+    #[error(transparent)]
+    Messaging(#[from] MessagingError),
+}
+
+impl MucError {
+    pub fn join_failed(room: &str, nick: &str, failure: &MucJoinFailure) -> Self {
+        let room = room.to_string();
+        match failure {
+            MucJoinFailure::NickConflict => MucError::NickConflict {
+                room,
+                nick: nick.to_string(),
+            },
+            MucJoinFailure::NotMember => MucError::NotMember(room),
+            MucJoinFailure::RoomLocked => MucError::RoomLocked(room),
+            MucJoinFailure::Banned => MucError::Banned(room),
+            MucJoinFailure::Other(condition) => MucError::JoinRefused {
+                room,
+                condition: condition.clone(),
+            },
+        }
+    }
+}
+
+impl From<StorageError> for MucError {
+    fn from(e: StorageError) -> Self {
+        MucError::Messaging(e.into())
+    }
 }
 
 struct StoredMessage {
@@ -41,6 +108,10 @@ struct StoredMessage {
     message_type: String,
     thread: Option<String>,
     embeds: Option<String>,
+    bodies: Option<String>,
+    origin_id: Option<String>,
+    spoiler: Option<String>,
+    stanza_id: Option<String>,
 }
 
 impl FromRow for StoredMessage {
@@ -95,6 +166,26 @@ impl FromRow for StoredMessage {
             Some(SqlValue::Null) | None => None,
             _ => None,
         };
+        let bodies = match row.get(8) {
+            Some(SqlValue::Text(s)) => Some(s.clone()),
+            Some(SqlValue::Null) | None => None,
+            _ => None,
+        };
+        let origin_id = match row.get(9) {
+            Some(SqlValue::Text(s)) => Some(s.clone()),
+            Some(SqlValue::Null) | None => None,
+            _ => None,
+        };
+        let spoiler = match row.get(10) {
+            Some(SqlValue::Text(s)) => Some(s.clone()),
+            Some(SqlValue::Null) | None => None,
+            _ => None,
+        };
+        let stanza_id = match row.get(11) {
+            Some(SqlValue::Text(s)) => Some(s.clone()),
+            Some(SqlValue::Null) | None => None,
+            _ => None,
+        };
         Ok(StoredMessage {
             id,
             from_jid,
@@ -104,20 +195,17 @@ impl FromRow for StoredMessage {
             message_type,
             thread,
             embeds,
+            bodies,
+            origin_id,
+            spoiler,
+            stanza_id,
         })
     }
 }
 
 impl StoredMessage {
     fn into_chat_message(self) -> ChatMessage {
-        let message_type = match self.message_type.as_str() {
-            "chat" => MessageType::Chat,
-            "groupchat" => MessageType::Groupchat,
-            "normal" => MessageType::Normal,
-            "headline" => MessageType::Headline,
-            "error" => MessageType::Error,
-            _ => MessageType::Chat,
-        };
+        let message_type = self.message_type.parse::<MessageType>().unwrap();
         let timestamp = self
             .timestamp
             .parse::<DateTime<Utc>>()
@@ -129,6 +217,12 @@ impl StoredMessage {
             Vec::new()
         };
 
+        let bodies = if let Some(json) = self.bodies {
+            serde_json::from_str(&json).unwrap_or_default()
+        } else {
+            BTreeMap::new()
+        };
+
         ChatMessage {
             id: self.id,
             from: self.from_jid,
@@ -138,20 +232,40 @@ impl StoredMessage {
             message_type,
             thread: self.thread,
             embeds,
+            bodies,
+            origin_id: self.origin_id,
+            spoiler: self.spoiler,
+            stanza_id: self.stanza_id,
         }
     }
 }
 
-fn message_type_to_str(mt: &MessageType) -> &'static str {
-    match mt {
-        MessageType::Chat => "chat",
-        MessageType::Groupchat => "groupchat",
-        MessageType::Normal => "normal",
-        MessageType::Headline => "headline",
-        MessageType::Error => "error",
+/// A `message_type` as a bindable query parameter; queries never quote type
+/// values in SQL.
+fn message_type_param(mt: &MessageType) -> String {
+    mt.as_str().to_string()
+}
+
+/// Drops the draft kept for the conversation with `jid`.
+async fn delete_draft<D: Database>(db: &D, jid: &str) -> Result<(), StorageError> {
+    let jid_s = bare_jid(jid);
+    db.execute("DELETE FROM drafts WHERE jid = ?1", &[&jid_s])
+        .await?;
+    Ok(())
+}
+
+/// Drops the draft for a conversation whose message has just been sent. The
+/// send already went out, so a failure here is only logged.
+async fn discard_sent_draft<D: Database>(db: &D, jid: &str) {
+    if let Err(e) = delete_draft(db, jid).await {
+        warn!(jid = %jid, error = %e, "failed to clear draft after send");
     }
 }
 
+fn bare_jid(jid: &str) -> String {
+    jid.split('/').next().unwrap_or(jid).to_string()
+}
+
 #[cfg(feature = "native")]
 const OFFLINE_STATUS_PENDING: &str = "pending";
 #[cfg(feature = "native")]
@@ -162,25 +276,274 @@ const OFFLINE_STATUS_CONFIRMED: &str = "confirmed";
 const OFFLINE_STATUS_FAILED: &str = "failed";
 #[cfg(feature = "native")]
 const OFFLINE_SOURCE: &str = "offline";
+/// Default for how far an archived copy's timestamp may be from when its
+/// queued send went out for a content-only match.
+#[cfg(feature = "native")]
+const DEFAULT_CONTENT_MATCH_WINDOW_SECS: i64 = 300;
+
+/// How long a contact's resource counts as typing without a fresh
+/// `composing`.
+#[cfg(feature = "native")]
+const CHAT_TYPING_EXPIRY_SECS: i64 = 30;
+
+/// How many rows `chat_state_log` keeps; older ones are dropped as new
+/// ones are written.
+#[cfg(feature = "native")]
+const CHAT_STATE_LOG_MAX_ROWS: i64 = 1000;
+
+/// Commands the offline queue can hold, stored by a stable id so that
+/// renaming a channel does not strand items queued under the old name.
+#[cfg(feature = "native")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "&'static str")]
+enum QueuedChannel {
+    MessageSend,
+    ChatStateSend,
+    PresenceSet,
+    SubscriptionSend,
+    SubscriptionRespond,
+    RosterAdd,
+    RosterUpdate,
+    RosterRemove,
+    RosterFetch,
+    MucJoin,
+    MucLeave,
+    MucSend,
+    MucModerate,
+}
+
+#[cfg(feature = "native")]
+impl QueuedChannel {
+    const ALL: [QueuedChannel; 13] = [
+        QueuedChannel::MessageSend,
+        QueuedChannel::ChatStateSend,
+        QueuedChannel::PresenceSet,
+        QueuedChannel::SubscriptionSend,
+        QueuedChannel::SubscriptionRespond,
+        QueuedChannel::RosterAdd,
+        QueuedChannel::RosterUpdate,
+        QueuedChannel::RosterRemove,
+        QueuedChannel::RosterFetch,
+        QueuedChannel::MucJoin,
+        QueuedChannel::MucLeave,
+        QueuedChannel::MucSend,
+        QueuedChannel::MucModerate,
+    ];
+
+    /// Id stored in the queue. Never change these; rename `channel` instead.
+    fn id(self) -> &'static str {
+        match self {
+            QueuedChannel::MessageSend => "message_send",
+            QueuedChannel::ChatStateSend => "chat_state_send",
+            QueuedChannel::PresenceSet => "presence_set",
+            QueuedChannel::SubscriptionSend => "subscription_send",
+            QueuedChannel::SubscriptionRespond => "subscription_respond",
+            QueuedChannel::RosterAdd => "roster_add",
+            QueuedChannel::RosterUpdate => "roster_update",
+            QueuedChannel::RosterRemove => "roster_remove",
+            QueuedChannel::RosterFetch => "roster_fetch",
+            QueuedChannel::MucJoin => "muc_join",
+            QueuedChannel::MucLeave => "muc_leave",
+            QueuedChannel::MucSend => "muc_send",
+            QueuedChannel::MucModerate => "muc_moderate",
+        }
+    }
+
+    /// Channel the command is published on when the queue drains.
+    fn channel(self) -> &'static str {
+        match self {
+            QueuedChannel::MessageSend => "ui.message.send",
+            QueuedChannel::ChatStateSend => "ui.chatstate.send",
+            QueuedChannel::PresenceSet => "ui.presence.set",
+            QueuedChannel::SubscriptionSend => "ui.subscription.send",
+            QueuedChannel::SubscriptionRespond => "ui.subscription.respond",
+            QueuedChannel::RosterAdd => "ui.roster.add",
+            QueuedChannel::RosterUpdate => "ui.roster.update",
+            QueuedChannel::RosterRemove => "ui.roster.remove",
+            QueuedChannel::RosterFetch => "ui.roster.fetch",
+            QueuedChannel::MucJoin => "ui.muc.join",
+            QueuedChannel::MucLeave => "ui.muc.leave",
+            QueuedChannel::MucSend => "ui.muc.send",
+            QueuedChannel::MucModerate => "ui.muc.moderate",
+        }
+    }
+
+    fn from_channel(channel: &str) -> Option<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|queued| queued.channel() == channel)
+    }
+}
+
+#[cfg(feature = "native")]
+impl TryFrom<String> for QueuedChannel {
+    type Error = String;
+
+    fn try_from(id: String) -> Result<Self, Self::Error> {
+        Self::ALL
+            .into_iter()
+            .find(|queued| queued.id() == id)
+            .ok_or_else(|| format!("unknown queued channel id `{id}`"))
+    }
+}
+
+#[cfg(feature = "native")]
+impl From<QueuedChannel> for &'static str {
+    fn from(queued: QueuedChannel) -> Self {
+        queued.id()
+    }
+}
 
 #[cfg(feature = "native")]
 #[derive(Debug, Clone, Serialize, Deserialize)]
 struct QueuedOutboundEvent {
+    /// Missing from items queued before channel ids were stored
+    #[serde(default)]
+    channel_id: Option<QueuedChannel>,
+    /// Channel name at the time of queueing; only used to resolve items
+    /// without a `channel_id`
     channel: String,
     payload: EventPayload,
     correlation_id: Option<Uuid>,
 }
 
+#[cfg(feature = "native")]
+impl QueuedOutboundEvent {
+    fn queued_channel(&self) -> Option<QueuedChannel> {
+        self.channel_id
+            .or_else(|| QueuedChannel::from_channel(&self.channel))
+    }
+}
+
 #[cfg(feature = "native")]
 struct StoredOfflineQueueItem {
     id: i64,
-    stanza_type: String,
     payload: String,
     status: String,
+    /// When the item went out, or was queued if it has not yet. Only
+    /// selected by queries that reconcile on timing
+    sent_at: Option<String>,
 }
 
 #[cfg(feature = "native")]
 impl FromRow for StoredOfflineQueueItem {
+    fn from_row(row: &Row) -> Result<Self, StorageError> {
+        let id = match row.get(0) {
+            Some(SqlValue::Integer(v)) => *v,
+            _ => return Err(StorageError::QueryFailed("missing id column".to_string())),
+        };
+        let payload = match row.get(1) {
+            Some(SqlValue::Text(v)) => v.clone(),
+            _ => {
+                return Err(StorageError::QueryFailed(
+                    "missing payload column".to_string(),
+                ));
+            }
+        };
+        let status = match row.get(2) {
+            Some(SqlValue::Text(v)) => v.clone(),
+            _ => {
+                return Err(StorageError::QueryFailed(
+                    "missing status column".to_string(),
+                ));
+            }
+        };
+        let sent_at = match row.get(3) {
+            Some(SqlValue::Text(v)) => Some(v.clone()),
+            _ => None,
+        };
+        Ok(Self {
+            id,
+            payload,
+            status,
+            sent_at,
+        })
+    }
+}
+
+/// An outbound command tracked by the offline queue that has not been confirmed yet.
+#[cfg(feature = "native")]
+#[derive(Debug, Clone)]
+pub struct OutboundQueueItem {
+    pub id: i64,
+    pub stanza_type: String,
+    pub status: String,
+    pub created_at: String,
+    /// Why the item was marked `failed`, if it was.
+    pub last_error: Option<String>,
+}
+
+/// Aggregate counts over all stored messages.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MessageStats {
+    pub total: u64,
+    /// Message count per stored type (`chat`, `groupchat`, ...)
+    pub by_type: HashMap<String, u64>,
+    /// 1:1 peers and rooms with at least one message
+    pub distinct_conversations: u64,
+    pub oldest: Option<DateTime<Utc>>,
+    pub newest: Option<DateTime<Utc>>,
+}
+
+/// Stores a message under `policy`, publishing `ui.message.persist_failed`
+/// once the retries are used up so the UI can warn instead of the message
+/// being lost silently.
+#[cfg(feature = "native")]
+async fn persist_with_policy<F, Fut, E>(
+    policy: RetryPolicy,
+    event_bus: &dyn EventBus,
+    id: &str,
+    persist: F,
+) where
+    F: FnMut() -> Fut,
+    Fut: std::future::Future<Output = Result<(), E>>,
+    E: std::fmt::Display,
+{
+    if let Err(e) = retry(policy, persist).await {
+        error!(error = %e, id = %id, "failed to persist message, giving up");
+        let _ = event_bus.publish(Event::new(
+            Channel::new("ui.message.persist_failed").unwrap(),
+            EventSource::System("messaging".into()),
+            EventPayload::MessagePersistFailed { id: id.to_string() },
+        ));
+    }
+}
+
+/// Latest message and unread count for one 1:1 conversation.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ConversationSummary {
+    pub jid: String,
+    pub last_message: ChatMessage,
+    pub unread: u64,
+    /// Hidden from the default conversation list until unarchived or a new
+    /// message arrives.
+    pub archived: bool,
+}
+
+/// Initial conversation state for a client, newest conversation first.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MessageSnapshot {
+    pub conversations: Vec<ConversationSummary>,
+}
+
+fn row_count(row: &Row, index: usize) -> u64 {
+    match row.get(index) {
+        Some(SqlValue::Integer(v)) => u64::try_from(*v).unwrap_or(0),
+        _ => 0,
+    }
+}
+
+fn row_timestamp(row: &Row, index: usize) -> Option<DateTime<Utc>> {
+    match row.get(index) {
+        Some(SqlValue::Text(v)) => v.parse::<DateTime<Utc>>().ok(),
+        _ => None,
+    }
+}
+
+#[cfg(feature = "native")]
+impl FromRow for OutboundQueueItem {
     fn from_row(row: &Row) -> Result<Self, StorageError> {
         let id = match row.get(0) {
             Some(SqlValue::Integer(v)) => *v,
@@ -194,27 +557,32 @@ impl FromRow for StoredOfflineQueueItem {
                 ));
             }
         };
-        let payload = match row.get(2) {
+        let status = match row.get(2) {
             Some(SqlValue::Text(v)) => v.clone(),
             _ => {
                 return Err(StorageError::QueryFailed(
-                    "missing payload column".to_string(),
+                    "missing status column".to_string(),
                 ));
             }
         };
-        let status = match row.get(3) {
+        let created_at = match row.get(3) {
             Some(SqlValue::Text(v)) => v.clone(),
             _ => {
                 return Err(StorageError::QueryFailed(
-                    "missing status column".to_string(),
+                    "missing created_at column".to_string(),
                 ));
             }
         };
+        let last_error = match row.get(4) {
+            Some(SqlValue::Text(v)) => Some(v.clone()),
+            _ => None,
+        };
         Ok(Self {
             id,
             stanza_type,
-            payload,
             status,
+            created_at,
+            last_error,
         })
     }
 }
@@ -223,6 +591,7 @@ impl FromRow for StoredOfflineQueueItem {
 fn command_stanza_type(payload: &EventPayload) -> Option<&'static str> {
     match payload {
         EventPayload::MessageSendRequested { .. }
+        | EventPayload::MessageCorrectRequested { .. }
         | EventPayload::MucSendRequested { .. }
         | EventPayload::ChatStateSendRequested { .. } => Some("message"),
         EventPayload::PresenceSetRequested { .. }
@@ -230,7 +599,8 @@ fn command_stanza_type(payload: &EventPayload) -> Option<&'static str> {
         | EventPayload::SubscriptionSendRequested { .. }
         | EventPayload::MucJoinRequested { .. }
         | EventPayload::MucLeaveRequested { .. } => Some("presence"),
-        EventPayload::RosterAddRequested { .. }
+        EventPayload::MucModerateRequested { .. }
+        | EventPayload::RosterAddRequested { .. }
         | EventPayload::RosterUpdateRequested { .. }
         | EventPayload::RosterRemoveRequested { .. }
         | EventPayload::RosterFetchRequested => Some("iq"),
@@ -238,22 +608,271 @@ fn command_stanza_type(payload: &EventPayload) -> Option<&'static str> {
     }
 }
 
+/// Target a queued roster command is keyed on, together with its channel.
+/// A newer pending command with the same key replaces the older one, so
+/// retries never stack duplicate roster IQs. A fetch has no target.
+#[cfg(feature = "native")]
+fn roster_queue_target(payload: &EventPayload) -> Option<String> {
+    match payload {
+        EventPayload::RosterAddRequested { jid, .. }
+        | EventPayload::RosterUpdateRequested { jid, .. }
+        | EventPayload::RosterRemoveRequested { jid } => Some(bare_jid(jid)),
+        EventPayload::RosterFetchRequested => Some(String::new()),
+        _ => None,
+    }
+}
+
+/// 1:1 history with the contact bound to `?1`, `?2` being the chat type.
+const DIRECT_HISTORY_FILTER: &str = "(from_jid = ?1 OR to_jid = ?1) AND message_type = ?2";
+
+/// History of the room bound to `?1`, `?2` being the groupchat type. Served
+/// by `idx_messages_room_timeline`.
+const ROOM_HISTORY_FILTER: &str = "to_jid = ?1 AND message_type = ?2";
+
+/// A conversation a read targets. Contact and room JIDs are both plain
+/// strings, so the variant decides which messages belong to it.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum ConversationId {
+    /// 1:1 chat with a contact's bare JID.
+    Direct(String),
+    /// Groupchat in a room, by room JID.
+    Room(String),
+}
+
+impl ConversationId {
+    pub fn jid(&self) -> &str {
+        match self {
+            ConversationId::Direct(jid) | ConversationId::Room(jid) => jid,
+        }
+    }
+
+    pub fn message_type(&self) -> MessageType {
+        match self {
+            ConversationId::Direct(_) => MessageType::Chat,
+            ConversationId::Room(_) => MessageType::Groupchat,
+        }
+    }
+
+    /// WHERE clause selecting this conversation's messages, with the JID
+    /// bound to `?1` and [`Self::message_type`] to `?2`.
+    fn history_filter(&self) -> &'static str {
+        match self {
+            ConversationId::Direct(_) => DIRECT_HISTORY_FILTER,
+            ConversationId::Room(_) => ROOM_HISTORY_FILTER,
+        }
+    }
+}
+
+/// Newest-first page of the messages matching `filter`; with a cursor the
+/// page holds only messages older than `?3`.
+fn history_page_sql(filter: &str, with_cursor: bool) -> String {
+    let (cursor, limit) = if with_cursor {
+        (" AND timestamp_ms < ?3", "?4")
+    } else {
+        ("", "?3")
+    };
+    format!(
+        "SELECT id, from_jid, to_jid, body, timestamp, message_type, thread, embeds, bodies, origin_id, spoiler, stanza_id \
+         FROM messages \
+         WHERE {filter}{cursor} \
+         ORDER BY timestamp_ms DESC \
+         LIMIT {limit}"
+    )
+}
+
+/// Loads up to `limit` messages of `conversation`, newest first, older than
+/// the `before` timestamp when one is given.
+async fn paginate<D: Database>(
+    db: &D,
+    conversation: &ConversationId,
+    limit: u32,
+    before: Option<&str>,
+) -> Result<Vec<ChatMessage>, MessagingError> {
+    let filter = conversation.history_filter();
+    let key = conversation.jid().to_string();
+    let message_type = message_type_param(&conversation.message_type());
+    let limit = i64::from(limit);
+
+    let rows: Vec<StoredMessage> = match before {
+        Some(before_ts) => {
+            let before_ms = timestamp_millis(before_ts)
+                .ok_or_else(|| MessagingError::InvalidTimestamp(before_ts.to_string()))?;
+            db.query(
+                &history_page_sql(filter, true),
+                &[&key, &message_type, &before_ms, &limit],
+            )
+            .await?
+        }
+        None => {
+            db.query(
+                &history_page_sql(filter, false),
+                &[&key, &message_type, &limit],
+            )
+            .await?
+        }
+    };
+
+    Ok(rows.into_iter().map(|r| r.into_chat_message()).collect())
+}
+
 pub struct MessageManager<D: Database> {
     db: Arc<D>,
+    messages: MessageStore<D>,
     #[cfg(feature = "native")]
     event_bus: Arc<dyn EventBus>,
+    /// JID of the current session, `None` while offline
+    #[cfg(feature = "native")]
+    connected_jid: RwLock<Option<String>>,
+    /// Resource we ask for; the one the server binds wins
+    #[cfg(feature = "native")]
+    resource: RwLock<Option<String>>,
+    #[cfg(feature = "native")]
+    persist_policy: RwLock<RetryPolicy>,
+    /// How far apart a queued send and an archived copy may be and still be
+    /// matched by content alone
+    #[cfg(feature = "native")]
+    content_match_window: RwLock<chrono::Duration>,
+    /// Check the roster before sending and warn about unsubscribed recipients
+    #[cfg(feature = "native")]
+    check_recipient_subscription: RwLock<bool>,
+    /// Count a peer's active chat state as having seen our messages
+    #[cfg(feature = "native")]
+    chat_state_marks_displayed: RwLock<bool>,
+    /// Bare JID -> the last chat state the peer sent us, until it sends
+    /// `gone` or we go offline
+    #[cfg(feature = "native")]
+    peer_chat_states: RwLock<HashMap<String, ChatState>>,
+    /// Bare JID -> full JID of each composing resource -> when it started
+    #[cfg(feature = "native")]
+    composing_resources: RwLock<HashMap<String, HashMap<String, DateTime<Utc>>>>,
+    /// Log chat states dropped while offline to `chat_state_log`
     #[cfg(feature = "native")]
-    is_online: RwLock<bool>,
+    record_offline_chat_states: RwLock<bool>,
 }
 
 impl<D: Database> MessageManager<D> {
     #[cfg(feature = "native")]
     pub fn new(db: Arc<D>, event_bus: Arc<dyn EventBus>) -> Self {
         Self {
+            messages: MessageStore::new(db.clone()),
             db,
             event_bus,
-            is_online: RwLock::new(false),
+            connected_jid: RwLock::new(None),
+            resource: RwLock::new(None),
+            persist_policy: RwLock::new(RetryPolicy::default()),
+            content_match_window: RwLock::new(chrono::Duration::seconds(
+                DEFAULT_CONTENT_MATCH_WINDOW_SECS,
+            )),
+            check_recipient_subscription: RwLock::new(false),
+            chat_state_marks_displayed: RwLock::new(false),
+            peer_chat_states: RwLock::new(HashMap::new()),
+            composing_resources: RwLock::new(HashMap::new()),
+            record_offline_chat_states: RwLock::new(false),
+        }
+    }
+
+    #[cfg(feature = "native")]
+    pub fn set_persist_failure_policy(&self, policy: RetryPolicy) {
+        *self.persist_policy.write().unwrap() = policy;
+    }
+
+    /// Sets how close in time an archived message must be to a queued send
+    /// for the content fallback to confirm it.
+    #[cfg(feature = "native")]
+    pub fn set_content_match_window(&self, window: std::time::Duration) {
+        *self.content_match_window.write().unwrap() =
+            chrono::Duration::from_std(window).unwrap_or(chrono::Duration::MAX);
+    }
+
+    /// When enabled, [`Self::send_message`] looks the recipient up in the
+    /// roster and publishes `ui.message.not_subscribed` unless we are
+    /// subscribed to their presence.
+    #[cfg(feature = "native")]
+    pub fn set_check_recipient_subscription(&self, enabled: bool) {
+        *self.check_recipient_subscription.write().unwrap() = enabled;
+    }
+
+    /// Opt-in heuristic for peers that send no read markers: an `active` chat
+    /// state from a peer marks every message we sent them as displayed.
+    #[cfg(feature = "native")]
+    pub fn set_chat_state_marks_displayed(&self, enabled: bool) {
+        *self.chat_state_marks_displayed.write().unwrap() = enabled;
+    }
+
+    /// Debugging aid for typing indicators: chat states dropped while
+    /// offline are written to the `chat_state_log` table, which keeps the
+    /// newest 1000 rows. They are still never delivered.
+    #[cfg(feature = "native")]
+    pub fn set_record_offline_chat_states(&self, enabled: bool) {
+        *self.record_offline_chat_states.write().unwrap() = enabled;
+    }
+
+    #[cfg(feature = "native")]
+    async fn drop_offline_chat_state(
+        &self,
+        to: &str,
+        state: &ChatState,
+    ) -> Result<(), MessagingError> {
+        debug!(to = %to, ?state, "offline, dropping chat state");
+        if !*self.record_offline_chat_states.read().unwrap() {
+            return Ok(());
+        }
+        let to_s = to.to_string();
+        let state_s = state.as_str().to_string();
+        let recorded_at = format_timestamp(&Utc::now());
+        self.db
+            .execute(
+                "INSERT INTO chat_state_log (to_jid, state, recorded_at) VALUES (?1, ?2, ?3)",
+                &[&to_s, &state_s, &recorded_at],
+            )
+            .await?;
+        self.db
+            .execute(
+                "DELETE FROM chat_state_log \
+                 WHERE id <= (SELECT MAX(id) FROM chat_state_log) - ?1",
+                &[&CHAT_STATE_LOG_MAX_ROWS],
+            )
+            .await?;
+        Ok(())
+    }
+
+    /// Our subscription to `to`'s presence per the roster, `None` when `to`
+    /// is not a contact.
+    #[cfg(feature = "native")]
+    async fn recipient_subscription(
+        &self,
+        to: &str,
+    ) -> Result<Option<Subscription>, MessagingError> {
+        let bare = bare_jid(to);
+        let rows: Vec<Row> = self
+            .db
+            .query("SELECT subscription FROM roster WHERE jid = ?1", &[&bare])
+            .await?;
+        Ok(rows.first().and_then(|row| match row.get(0) {
+            Some(SqlValue::Text(subscription)) => subscription.parse::<Subscription>().ok(),
+            _ => None,
+        }))
+    }
+
+    #[cfg(feature = "native")]
+    async fn warn_if_not_subscribed(&self, to: &str) -> Result<(), MessagingError> {
+        if !*self.check_recipient_subscription.read().unwrap() {
+            return Ok(());
+        }
+        let subscription = self.recipient_subscription(to).await?;
+        if matches!(subscription, Some(Subscription::To | Subscription::Both)) {
+            return Ok(());
         }
+        debug!(to = %to, subscription = ?subscription, "recipient is not subscribed to");
+        let _ = self.event_bus.publish(Event::new(
+            Channel::new("ui.message.not_subscribed").unwrap(),
+            EventSource::System("messaging".into()),
+            EventPayload::RecipientNotSubscribed {
+                to: to.to_string(),
+                subscription,
+            },
+        ));
+        Ok(())
     }
 
     pub async fn send_message(&self, to: &str, body: &str) -> Result<ChatMessage, MessagingError> {
@@ -268,12 +887,22 @@ impl<D: Database> MessageManager<D> {
             message_type: MessageType::Chat,
             thread: None,
             embeds: vec![],
+            bodies: BTreeMap::new(),
+            origin_id: Some(id.to_string()),
+            spoiler: None,
+            stanza_id: None,
         };
 
-        self.persist_message(&message).await?;
+        self.persist_message(&message, MessageSource::Local).await?;
 
         #[cfg(feature = "native")]
         {
+            // The message is already stored, so a failed lookup must not
+            // keep it from being sent.
+            if let Err(e) = self.warn_if_not_subscribed(to).await {
+                warn!(to = %to, error = %e, "failed to check recipient subscription");
+            }
+
             let payload = EventPayload::MessageSendRequested {
                 to: to.to_string(),
                 body: body.to_string(),
@@ -292,69 +921,337 @@ impl<D: Database> MessageManager<D> {
                     .await?;
             }
         }
+        discard_sent_draft(self.db.as_ref(), to).await;
 
         Ok(message)
     }
 
-    pub async fn send_chat_state(&self, to: &str, state: ChatState) -> Result<(), MessagingError> {
+    /// Replaces the body of our last chat message `target_id` to its
+    /// recipient with `new_body` (XEP-0308) and sends the correction,
+    /// queueing it while offline. The stored message keeps its id and
+    /// timestamp. Older messages cannot be corrected.
+    pub async fn correct_message(
+        &self,
+        target_id: &str,
+        new_body: &str,
+    ) -> Result<(), MessagingError> {
+        let id_s = target_id.to_string();
+        let chat = message_type_param(&MessageType::Chat);
+        let rows: Vec<Row> = self
+            .db
+            .query(
+                "SELECT from_jid, to_jid FROM messages WHERE id = ?1 AND message_type = ?2",
+                &[&id_s, &chat],
+            )
+            .await?;
+        let (from, to) = match rows.first().map(|row| (row.get(0), row.get(1))) {
+            Some((Some(SqlValue::Text(from)), Some(SqlValue::Text(to)))) => {
+                (from.clone(), to.clone())
+            }
+            _ => {
+                return Err(MessagingError::SendFailed(format!(
+                    "no chat message {target_id} to correct"
+                )));
+            }
+        };
+        #[cfg(feature = "native")]
+        let sent_by_us = from.is_empty() || self.is_own_jid(&bare_jid(&from));
+        #[cfg(not(feature = "native"))]
+        let sent_by_us = from.is_empty();
+        if !sent_by_us {
+            return Err(MessagingError::SendFailed(format!(
+                "message {target_id} was sent by {from}, not us"
+            )));
+        }
+        if self.last_message_to(&to).await?.as_deref() != Some(target_id) {
+            return Err(MessagingError::SendFailed(format!(
+                "message {target_id} is not the last message to {to}"
+            )));
+        }
+
+        let body_s = new_body.to_string();
+        self.db
+            .execute(
+                "UPDATE messages SET body = ?1, bodies = NULL, corrected = 1 WHERE id = ?2",
+                &[&body_s, &id_s],
+            )
+            .await?;
+
         #[cfg(feature = "native")]
         {
-            let payload = EventPayload::ChatStateSendRequested {
-                to: to.to_string(),
-                state,
+            let payload = EventPayload::MessageCorrectRequested {
+                to,
+                target_id: target_id.to_string(),
+                body: body_s,
             };
-
+            let correlation_id = Uuid::new_v4();
             if self.is_online() {
-                let _ = self.event_bus.publish(Event::new(
-                    Channel::new("ui.chatstate.send").unwrap(),
+                let _ = self.event_bus.publish(Event::with_correlation(
+                    Channel::new("ui.message.send").unwrap(),
                     EventSource::System("messaging".into()),
                     payload,
+                    correlation_id,
                 ));
             } else {
-                self.enqueue_command_event("ui.chatstate.send", payload, None)
+                self.enqueue_command_event("ui.message.send", payload, Some(correlation_id))
                     .await?;
             }
         }
+
+        Ok(())
+    }
+
+    /// Id of the newest chat message we sent to `to`, the only one XEP-0308
+    /// lets us correct. Inbound messages are addressed to us, so everything
+    /// addressed to `to` is ours.
+    async fn last_message_to(&self, to: &str) -> Result<Option<String>, MessagingError> {
+        let to_s = bare_jid(to);
+        let chat = message_type_param(&MessageType::Chat);
+        let rows: Vec<Row> = self
+            .db
+            .query(
+                "SELECT id FROM messages \
+                 WHERE (to_jid = ?1 OR substr(to_jid, 1, length(?1) + 1) = ?1 || '/') \
+                   AND message_type = ?2 AND retracted = 0 \
+                 ORDER BY timestamp_ms DESC, id DESC LIMIT 1",
+                &[&to_s, &chat],
+            )
+            .await?;
+        Ok(rows.first().and_then(|row| match row.get(0) {
+            Some(SqlValue::Text(id)) => Some(id.clone()),
+            _ => None,
+        }))
+    }
+
+    /// Applies `from`'s correction of its message `target_id`, which names
+    /// the original by its stanza id or origin-id. Corrections of messages
+    /// someone else sent are ignored.
+    async fn apply_correction(
+        &self,
+        from: &str,
+        target_id: &str,
+        body: &str,
+    ) -> Result<bool, MessagingError> {
+        let from_s = bare_jid(from);
+        let id_s = target_id.to_string();
+        let body_s = body.to_string();
+        let chat = message_type_param(&MessageType::Chat);
+        let updated = self
+            .db
+            .execute(
+                "UPDATE messages SET body = ?1, bodies = NULL, corrected = 1 \
+                 WHERE (id = ?2 OR origin_id = ?2) AND origin_sender = ?3 AND message_type = ?4",
+                &[&body_s, &id_s, &from_s, &chat],
+            )
+            .await?;
+        Ok(updated > 0)
+    }
+
+    /// The last chat state `jid` sent us this session. `None` if it sent
+    /// none or closed the conversation with `gone`, which the UI can show
+    /// as ended.
+    #[cfg(feature = "native")]
+    pub fn peer_chat_state(&self, jid: &str) -> Option<ChatState> {
+        self.peer_chat_states
+            .read()
+            .unwrap()
+            .get(&bare_jid(jid))
+            .cloned()
+    }
+
+    /// Whether any resource of `jid` is composing, counting a `composing`
+    /// for [`CHAT_TYPING_EXPIRY_SECS`] unless that resource sends another
+    /// state first.
+    #[cfg(feature = "native")]
+    pub fn is_typing(&self, jid: &str) -> bool {
+        let cutoff = Utc::now() - chrono::Duration::seconds(CHAT_TYPING_EXPIRY_SECS);
+        self.composing_resources
+            .read()
+            .unwrap()
+            .get(&bare_jid(jid))
+            .is_some_and(|resources| resources.values().any(|since| *since > cutoff))
+    }
+
+    #[cfg(feature = "native")]
+    fn track_chat_state(&self, from: &str, state: &ChatState) {
+        let bare = bare_jid(from);
+        {
+            let mut states = self.peer_chat_states.write().unwrap();
+            if matches!(state, ChatState::Gone) {
+                states.remove(&bare);
+            } else {
+                states.insert(bare.clone(), state.clone());
+            }
+        }
+
+        let mut composing = self.composing_resources.write().unwrap();
+        if matches!(state, ChatState::Composing) {
+            composing
+                .entry(bare)
+                .or_default()
+                .insert(from.to_string(), Utc::now());
+        } else if let Some(resources) = composing.get_mut(&bare) {
+            resources.remove(from);
+        }
+        // Peers that stopped sending states would otherwise stay forever.
+        let cutoff = Utc::now() - chrono::Duration::seconds(CHAT_TYPING_EXPIRY_SECS);
+        composing.retain(|_, resources| {
+            resources.retain(|_, since| *since > cutoff);
+            !resources.is_empty()
+        });
+    }
+
+    /// Tells `to` we closed the conversation with a `gone` chat state.
+    #[cfg(feature = "native")]
+    pub async fn close_conversation(&self, to: &str) -> Result<(), MessagingError> {
+        self.send_chat_state(to, ChatState::Gone).await
+    }
+
+    /// Sends a XEP-0085 chat state to `to`. Chat states are only meaningful
+    /// as they happen, so one sent while offline is dropped, not queued.
+    pub async fn send_chat_state(&self, to: &str, state: ChatState) -> Result<(), MessagingError> {
+        #[cfg(feature = "native")]
+        {
+            if !self.is_online() {
+                return self.drop_offline_chat_state(to, &state).await;
+            }
+            let _ = self.event_bus.publish(Event::new(
+                Channel::new("ui.chatstate.send").unwrap(),
+                EventSource::System("messaging".into()),
+                EventPayload::ChatStateSendRequested {
+                    to: to.to_string(),
+                    state,
+                },
+            ));
+        }
         Ok(())
     }
 
     pub async fn get_messages(
         &self,
-        jid: &str,
+        conversation: &ConversationId,
         limit: u32,
         before: Option<&str>,
     ) -> Result<Vec<ChatMessage>, MessagingError> {
-        let jid_s = jid.to_string();
-        let limit_i = i64::from(limit);
+        paginate(self.db.as_ref(), conversation, limit, before).await
+    }
 
-        let rows: Vec<StoredMessage> = if let Some(before_ts) = before {
-            let before_s = before_ts.to_string();
-            self.db
-                .query(
-                    "SELECT id, from_jid, to_jid, body, timestamp, message_type, thread, embeds \
+    /// Loads up to `radius` messages on each side of `message_id` within
+    /// `conversation`, oldest first. Returns an empty window when the target
+    /// is not part of that conversation.
+    pub async fn context_around(
+        &self,
+        conversation: &ConversationId,
+        message_id: &str,
+        radius: u32,
+    ) -> Result<Vec<ChatMessage>, MessagingError> {
+        let filter = conversation.history_filter();
+        let jid_s = conversation.jid().to_string();
+        let type_s = message_type_param(&conversation.message_type());
+        let id_s = message_id.to_string();
+        let radius_i = i64::from(radius);
+
+        let target: Vec<Row> = self
+            .db
+            .query(
+                &format!("SELECT timestamp_ms FROM messages WHERE {filter} AND id = ?3"),
+                &[&jid_s, &type_s, &id_s],
+            )
+            .await?;
+        let Some(SqlValue::Integer(target_ms)) = target.first().and_then(|row| row.get(0)).cloned()
+        else {
+            return Ok(Vec::new());
+        };
+
+        let mut window: Vec<StoredMessage> = self
+            .db
+            .query(
+                &format!(
+                    "SELECT id, from_jid, to_jid, body, timestamp, message_type, thread, embeds, bodies, origin_id, spoiler, stanza_id \
                      FROM messages \
-                     WHERE (from_jid = ?1 OR to_jid = ?1) AND message_type = 'chat' AND timestamp < ?2 \
-                     ORDER BY timestamp DESC \
-                     LIMIT ?3",
-                    &[&jid_s, &before_s, &limit_i],
-                )
-                .await?
-        } else {
-            self.db
-                .query(
-                    "SELECT id, from_jid, to_jid, body, timestamp, message_type, thread, embeds \
+                     WHERE {filter} \
+                       AND (timestamp_ms < ?3 OR (timestamp_ms = ?3 AND id < ?4)) \
+                     ORDER BY timestamp_ms DESC, id DESC \
+                     LIMIT ?5"
+                ),
+                &[&jid_s, &type_s, &target_ms, &id_s, &radius_i],
+            )
+            .await?;
+        window.reverse();
+
+        let rest: Vec<StoredMessage> = self
+            .db
+            .query(
+                &format!(
+                    "SELECT id, from_jid, to_jid, body, timestamp, message_type, thread, embeds, bodies, origin_id, spoiler, stanza_id \
                      FROM messages \
-                     WHERE (from_jid = ?1 OR to_jid = ?1) AND message_type = 'chat' \
-                     ORDER BY timestamp DESC \
-                     LIMIT ?2",
-                    &[&jid_s, &limit_i],
-                )
-                .await?
-        };
+                     WHERE {filter} \
+                       AND (timestamp_ms > ?3 OR (timestamp_ms = ?3 AND id >= ?4)) \
+                     ORDER BY timestamp_ms ASC, id ASC \
+                     LIMIT ?5"
+                ),
+                &[&jid_s, &type_s, &target_ms, &id_s, &(radius_i + 1)],
+            )
+            .await?;
+        window.extend(rest);
+
+        Ok(window.into_iter().map(|r| r.into_chat_message()).collect())
+    }
+
+    /// Notices sent by servers (MOTD, maintenance), newest first: `normal`
+    /// and `headline` messages whose sender is a bare domain, not a user.
+    pub async fn service_notices(&self, limit: u32) -> Result<Vec<ChatMessage>, MessagingError> {
+        let normal = message_type_param(&MessageType::Normal);
+        let headline = message_type_param(&MessageType::Headline);
+        let limit_i = i64::from(limit);
+        let rows: Vec<StoredMessage> = self
+            .db
+            .query(
+                "SELECT id, from_jid, to_jid, body, timestamp, message_type, thread, embeds, bodies, origin_id, spoiler, stanza_id \
+                 FROM messages \
+                 WHERE message_type IN (?1, ?2) \
+                   AND from_jid != '' AND INSTR(from_jid, '@') = 0 \
+                 ORDER BY timestamp_ms DESC \
+                 LIMIT ?3",
+                &[&normal, &headline, &limit_i],
+            )
+            .await?;
 
         Ok(rows.into_iter().map(|r| r.into_chat_message()).collect())
     }
 
+    /// Marks the 1:1 messages we sent to `jid` as seen by them and returns
+    /// how many changed.
+    pub async fn mark_sent_displayed(&self, jid: &str) -> Result<u64, MessagingError> {
+        let bare = bare_jid(jid);
+        let chat = message_type_param(&MessageType::Chat);
+        let displayed = 1_i64;
+        let updated = self
+            .db
+            .execute(
+                "UPDATE messages SET displayed = ?1 \
+                 WHERE (to_jid = ?2 OR substr(to_jid, 1, length(?2) + 1) = ?2 || '/') \
+                   AND message_type = ?3 \
+                   AND displayed = 0",
+                &[&displayed, &bare, &chat],
+            )
+            .await?;
+        Ok(updated)
+    }
+
+    pub async fn is_displayed(&self, id: &str) -> Result<bool, MessagingError> {
+        let id_s = id.to_string();
+        let rows: Vec<Row> = self
+            .db
+            .query("SELECT displayed FROM messages WHERE id = ?1", &[&id_s])
+            .await?;
+        Ok(matches!(
+            rows.first().and_then(|row| row.get(0)),
+            Some(SqlValue::Integer(1))
+        ))
+    }
+
+    /// Marks every message from `jid` read and clears a manual unread flag.
     pub async fn mark_read(&self, jid: &str) -> Result<(), MessagingError> {
         let jid_s = jid.to_string();
         let read_val = 1_i64;
@@ -364,71 +1261,452 @@ impl<D: Database> MessageManager<D> {
                 &[&read_val, &jid_s],
             )
             .await?;
-        Ok(())
+        self.set_unread(jid, false).await
     }
 
-    async fn persist_message(&self, message: &ChatMessage) -> Result<(), MessagingError> {
-        let id = message.id.clone();
-        let from = message.from.clone();
-        let to = message.to.clone();
-        let body = message.body.clone();
-        let ts = message.timestamp.to_rfc3339();
-        let mt = message_type_to_str(&message.message_type).to_string();
-        let thread = message.thread.clone();
-        let read = 0_i64;
+    /// Marks every 1:1 message read and clears all manual unread flags.
+    /// Returns how many messages changed.
+    pub async fn mark_all_read(&self) -> Result<u64, MessagingError> {
+        let read_val = 1_i64;
+        let chat = message_type_param(&MessageType::Chat);
+        let cleared = self
+            .db
+            .execute(
+                "UPDATE messages SET read = ?1 WHERE message_type = ?2 AND read = 0",
+                &[&read_val, &chat],
+            )
+            .await?;
+        self.db
+            .execute(
+                "UPDATE conversation_meta SET marked_unread = 0 WHERE marked_unread = 1",
+                &[],
+            )
+            .await?;
+        Ok(cleared)
+    }
 
-        let embeds = if message.embeds.is_empty() {
-            None
+    /// Flags the conversation with `jid` as unread regardless of its
+    /// messages' read state, or clears that flag.
+    pub async fn set_unread(&self, jid: &str, unread: bool) -> Result<(), MessagingError> {
+        let jid_s = jid.to_string();
+        if unread {
+            self.db
+                .execute(
+                    "INSERT INTO conversation_meta (jid, marked_unread) VALUES (?1, 1) \
+                     ON CONFLICT(jid) DO UPDATE SET marked_unread = 1",
+                    &[&jid_s],
+                )
+                .await?;
         } else {
-            Some(serde_json::to_string(&message.embeds).unwrap_or_default())
+            self.db
+                .execute(
+                    "UPDATE conversation_meta SET marked_unread = 0 WHERE jid = ?1",
+                    &[&jid_s],
+                )
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Unread count per 1:1 peer, for every conversation with anything
+    /// unread; `own_jid` is our bare JID, whose messages never count. A
+    /// conversation flagged with [`Self::set_unread`] reports at least 1.
+    pub async fn unread_counts_all(
+        &self,
+        own_jid: &str,
+    ) -> Result<HashMap<String, u64>, MessagingError> {
+        let chat = message_type_param(&MessageType::Chat);
+        let own_s = own_jid.to_string();
+        let rows: Vec<Row> = self
+            .db
+            .query(
+                "SELECT jid, MAX(unread) FROM ( \
+                    SELECT from_jid AS jid, COUNT(*) AS unread FROM messages \
+                    WHERE message_type = ?1 AND read = 0 \
+                      AND from_jid != '' AND from_jid != ?2 \
+                      AND substr(from_jid, 1, length(?2) + 1) != ?2 || '/' \
+                    GROUP BY from_jid \
+                    UNION ALL \
+                    SELECT jid, 1 FROM conversation_meta WHERE marked_unread = 1 \
+                 ) GROUP BY jid",
+                &[&chat, &own_s],
+            )
+            .await?;
+
+        Ok(rows
+            .iter()
+            .filter_map(|row| match row.get(0) {
+                Some(SqlValue::Text(jid)) => Some((jid.clone(), row_count(row, 1))),
+                _ => None,
+            })
+            .collect())
+    }
+
+    /// Deletes the local 1:1 history with `jid` and returns how many messages
+    /// were removed. Messages still awaiting confirmation in the offline queue
+    /// are kept so their queue items stay reconcilable. Pins and delivery
+    /// receipts go with their messages, and the conversation's archived and
+    /// unread flags are dropped once nothing of it is left.
+    #[cfg(feature = "native")]
+    pub async fn delete_conversation(&self, jid: &str) -> Result<u64, MessagingError> {
+        let jid_s = jid.to_string();
+        let confirmed = OFFLINE_STATUS_CONFIRMED.to_string();
+        let chat = message_type_param(&MessageType::Chat);
+        // A single statement, so the guard and the delete see the same state.
+        let deleted = self
+            .db
+            .execute(
+                "DELETE FROM messages \
+                 WHERE (from_jid = ?1 OR to_jid = ?1) AND message_type = ?3 \
+                 AND id NOT IN ( \
+                    SELECT json_extract(payload, '$.correlation_id') FROM offline_queue \
+                    WHERE status != ?2 \
+                    AND json_extract(payload, '$.correlation_id') IS NOT NULL \
+                 )",
+                &[&jid_s, &confirmed, &chat],
+            )
+            .await?;
+        self.db
+            .execute(
+                "DELETE FROM conversation_meta WHERE jid = ?1 AND last_activity IS NULL",
+                &[&jid_s],
+            )
+            .await?;
+        Ok(deleted)
+    }
+
+    /// Counts stored messages. A 1:1 conversation is the unordered pair of
+    /// participants, a groupchat conversation is its room.
+    pub async fn stats(&self) -> Result<MessageStats, MessagingError> {
+        let groupchat = message_type_param(&MessageType::Groupchat);
+        let totals: Vec<Row> = self
+            .db
+            .query(
+                "SELECT COUNT(*), \
+                 COUNT(DISTINCT CASE \
+                    WHEN message_type = ?1 THEN to_jid \
+                    WHEN from_jid < to_jid THEN from_jid || ' ' || to_jid \
+                    ELSE to_jid || ' ' || from_jid \
+                 END), \
+                 MIN(timestamp), MAX(timestamp) \
+                 FROM messages",
+                &[&groupchat],
+            )
+            .await?;
+        let by_type_rows: Vec<Row> = self
+            .db
+            .query(
+                "SELECT message_type, COUNT(*) FROM messages GROUP BY message_type",
+                &[],
+            )
+            .await?;
+
+        let by_type = by_type_rows
+            .iter()
+            .filter_map(|row| match row.get(0) {
+                Some(SqlValue::Text(message_type)) => {
+                    Some((message_type.clone(), row_count(row, 1)))
+                }
+                _ => None,
+            })
+            .collect();
+
+        let Some(totals) = totals.first() else {
+            return Ok(MessageStats {
+                total: 0,
+                by_type,
+                distinct_conversations: 0,
+                oldest: None,
+                newest: None,
+            });
         };
+        Ok(MessageStats {
+            total: row_count(totals, 0),
+            by_type,
+            distinct_conversations: row_count(totals, 1),
+            oldest: row_timestamp(totals, 2),
+            newest: row_timestamp(totals, 3),
+        })
+    }
+
+    /// Summarises every 1:1 conversation, archived ones included and flagged,
+    /// so a client can render both its inbox and its archive.
+    pub async fn snapshot(&self, own_jid: &str) -> Result<MessageSnapshot, MessagingError> {
+        let conversations = self.list_conversations(own_jid, true).await?;
+        Ok(MessageSnapshot { conversations })
+    }
+
+    /// Summarises 1:1 conversations in one query, newest first by the
+    /// stored `last_activity`. `own_jid` is our bare JID; messages sent from
+    /// it (or with no sender yet) belong to the conversation with their
+    /// recipient. Archived conversations are left out unless
+    /// `include_archived` is set.
+    pub async fn list_conversations(
+        &self,
+        own_jid: &str,
+        include_archived: bool,
+    ) -> Result<Vec<ConversationSummary>, MessagingError> {
+        let own_s = own_jid.to_string();
+        let include_archived_i = i64::from(include_archived);
+        let chat = message_type_param(&MessageType::Chat);
+        let rows: Vec<Row> = self
+            .db
+            .query(
+                "SELECT m.id, m.from_jid, m.to_jid, m.body, m.timestamp, m.message_type, m.thread, \
+                    m.embeds, m.bodies, m.origin_id, m.spoiler, m.stanza_id, meta.jid, \
+                    MAX((SELECT COUNT(*) FROM messages u \
+                     WHERE u.from_jid = meta.jid AND u.message_type = ?3 AND u.read = 0), \
+                        meta.marked_unread), \
+                    meta.archived \
+                 FROM conversation_meta meta \
+                 JOIN messages m ON m.id = ( \
+                    SELECT id FROM messages \
+                    WHERE message_type = ?3 AND (from_jid = meta.jid OR (to_jid = meta.jid \
+                        AND (from_jid = '' OR from_jid = ?1 \
+                             OR substr(from_jid, 1, length(?1) + 1) = ?1 || '/'))) \
+                    ORDER BY timestamp_ms DESC, id DESC LIMIT 1 \
+                 ) \
+                 WHERE meta.last_activity IS NOT NULL \
+                   AND meta.jid != ?1 AND substr(meta.jid, 1, length(?1) + 1) != ?1 || '/' \
+                   AND (?2 = 1 OR meta.archived = 0) \
+                 ORDER BY meta.last_activity DESC, meta.jid",
+                &[&own_s, &include_archived_i, &chat],
+            )
+            .await?;
+
+        let mut conversations = Vec::with_capacity(rows.len());
+        for row in &rows {
+            let Some(SqlValue::Text(jid)) = row.get(12) else {
+                continue;
+            };
+            conversations.push(ConversationSummary {
+                jid: jid.clone(),
+                last_message: StoredMessage::from_row(row)?.into_chat_message(),
+                unread: row_count(row, 13),
+                archived: row_count(row, 14) != 0,
+            });
+        }
+        Ok(conversations)
+    }
+
+    /// Hides the conversation with `jid` from the default list.
+    pub async fn archive(&self, jid: &str) -> Result<(), MessagingError> {
+        let jid_s = jid.to_string();
+        self.db
+            .execute(
+                "INSERT INTO conversation_meta (jid, archived) VALUES (?1, 1) \
+                 ON CONFLICT(jid) DO UPDATE SET archived = 1",
+                &[&jid_s],
+            )
+            .await?;
+        Ok(())
+    }
+
+    pub async fn unarchive(&self, jid: &str) -> Result<(), MessagingError> {
+        let jid_s = jid.to_string();
+        self.db
+            .execute(
+                "UPDATE conversation_meta SET archived = 0 WHERE jid = ?1",
+                &[&jid_s],
+            )
+            .await?;
+        Ok(())
+    }
+
+    /// Keeps `body` as the unsent draft for the conversation with `jid`. An
+    /// empty body clears it.
+    pub async fn set_draft(&self, jid: &str, body: &str) -> Result<(), MessagingError> {
+        if body.is_empty() {
+            return self.clear_draft(jid).await;
+        }
+        let jid_s = bare_jid(jid);
+        let body_s = body.to_string();
+        let updated_at = format_timestamp(&Utc::now());
+        self.db
+            .execute(
+                "INSERT INTO drafts (jid, body, updated_at) VALUES (?1, ?2, ?3) \
+                 ON CONFLICT(jid) DO UPDATE SET body = excluded.body, updated_at = excluded.updated_at",
+                &[&jid_s, &body_s, &updated_at],
+            )
+            .await?;
+        Ok(())
+    }
+
+    /// The unsent draft for the conversation with `jid`, if any.
+    pub async fn draft(&self, jid: &str) -> Result<Option<String>, MessagingError> {
+        let jid_s = bare_jid(jid);
+        let rows: Vec<Row> = self
+            .db
+            .query("SELECT body FROM drafts WHERE jid = ?1", &[&jid_s])
+            .await?;
+        Ok(rows.first().and_then(|row| match row.get(0) {
+            Some(SqlValue::Text(body)) => Some(body.clone()),
+            _ => None,
+        }))
+    }
 
+    pub async fn clear_draft(&self, jid: &str) -> Result<(), MessagingError> {
+        delete_draft(self.db.as_ref(), jid).await?;
+        Ok(())
+    }
+
+    /// Pins `message_id` to the top of the conversation with `jid` on this
+    /// device only. Unlike room pins, local pins are never synced.
+    pub async fn pin_local(&self, jid: &str, message_id: &str) -> Result<(), MessagingError> {
+        let jid_s = bare_jid(jid);
+        let id_s = message_id.to_string();
+        let pinned_at = format_timestamp(&Utc::now());
+        self.db
+            .execute(
+                "INSERT INTO local_pins (jid, message_id, pinned_at) VALUES (?1, ?2, ?3) \
+                 ON CONFLICT(jid, message_id) DO NOTHING",
+                &[&jid_s, &id_s, &pinned_at],
+            )
+            .await?;
+        Ok(())
+    }
+
+    pub async fn unpin_local(&self, jid: &str, message_id: &str) -> Result<(), MessagingError> {
+        let jid_s = bare_jid(jid);
+        let id_s = message_id.to_string();
         self.db
             .execute(
-                "INSERT INTO messages (id, from_jid, to_jid, body, timestamp, message_type, thread, read, embeds) \
-                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9) \
-                 ON CONFLICT(id) DO UPDATE SET \
-                 from_jid = CASE \
-                    WHEN TRIM(COALESCE(excluded.from_jid, '')) = '' THEN messages.from_jid \
-                    WHEN TRIM(COALESCE(messages.from_jid, '')) = '' THEN excluded.from_jid \
-                    WHEN LENGTH(excluded.from_jid) > LENGTH(messages.from_jid) THEN excluded.from_jid \
-                    ELSE messages.from_jid \
-                 END, \
-                 to_jid = CASE \
-                    WHEN TRIM(COALESCE(excluded.to_jid, '')) = '' THEN messages.to_jid \
-                    WHEN TRIM(COALESCE(messages.to_jid, '')) = '' THEN excluded.to_jid \
-                    WHEN LENGTH(excluded.to_jid) > LENGTH(messages.to_jid) THEN excluded.to_jid \
-                    ELSE messages.to_jid \
-                 END, \
-                 thread = CASE \
-                    WHEN TRIM(COALESCE(excluded.thread, '')) = '' THEN messages.thread \
-                    WHEN TRIM(COALESCE(messages.thread, '')) = '' THEN excluded.thread \
-                    WHEN LENGTH(COALESCE(excluded.thread, '')) > LENGTH(COALESCE(messages.thread, '')) THEN excluded.thread \
-                    ELSE messages.thread \
-                 END, \
-                 embeds = CASE \
-                    WHEN excluded.embeds IS NULL OR TRIM(excluded.embeds) = '' OR excluded.embeds = '[]' THEN messages.embeds \
-                    WHEN messages.embeds IS NULL OR TRIM(messages.embeds) = '' OR messages.embeds = '[]' THEN excluded.embeds \
-                    WHEN LENGTH(excluded.embeds) > LENGTH(COALESCE(messages.embeds, '')) THEN excluded.embeds \
-                    ELSE messages.embeds \
-                 END",
-                &[&id, &from, &to, &body, &ts, &mt, &thread, &read, &embeds],
+                "DELETE FROM local_pins WHERE jid = ?1 AND message_id = ?2",
+                &[&jid_s, &id_s],
+            )
+            .await?;
+        Ok(())
+    }
+
+    /// Messages pinned locally in the conversation with `jid`, most recently
+    /// pinned first. Pins of messages no longer stored are skipped.
+    pub async fn local_pins(&self, jid: &str) -> Result<Vec<ChatMessage>, MessagingError> {
+        let jid_s = bare_jid(jid);
+        let chat = message_type_param(&MessageType::Chat);
+        let rows: Vec<StoredMessage> = self
+            .db
+            .query(
+                "SELECT m.id, m.from_jid, m.to_jid, m.body, m.timestamp, m.message_type, m.thread, \
+                        m.embeds, m.bodies, m.origin_id, m.spoiler, m.stanza_id \
+                 FROM local_pins p JOIN messages m ON m.id = p.message_id \
+                 WHERE p.jid = ?1 AND (m.from_jid = ?1 OR m.to_jid = ?1) AND m.message_type = ?2 \
+                 ORDER BY p.pinned_at DESC, p.rowid DESC",
+                &[&jid_s, &chat],
+            )
+            .await?;
+        Ok(rows.into_iter().map(|r| r.into_chat_message()).collect())
+    }
+
+    /// The resources of the peer that acknowledged `message_id` with a
+    /// delivery receipt, in the order the receipts arrived. Each entry is
+    /// the full JID the receipt came from (`bob@example.com/phone`), not
+    /// just the resource part. A send to a bare JID can reach some of the
+    /// peer's devices and not others.
+    pub async fn delivered_resources(
+        &self,
+        message_id: &str,
+    ) -> Result<Vec<String>, MessagingError> {
+        let id_s = message_id.to_string();
+        let rows: Vec<Row> = self
+            .db
+            .query(
+                "SELECT delivered_to FROM message_deliveries WHERE message_id = ?1 \
+                 ORDER BY delivered_at ASC, rowid ASC",
+                &[&id_s],
+            )
+            .await?;
+        Ok(rows
+            .iter()
+            .filter_map(|row| match row.get(0) {
+                Some(SqlValue::Text(jid)) => Some(jid.clone()),
+                _ => None,
+            })
+            .collect())
+    }
+
+    /// Records that `from` (a full JID) acknowledged `message_id`. Returns
+    /// false, recording nothing, unless the message is one we sent to
+    /// `from`'s bare JID or one of its resources, so a receipt cannot mark
+    /// someone else's message.
+    async fn record_delivery(&self, message_id: &str, from: &str) -> Result<bool, MessagingError> {
+        let id_s = message_id.to_string();
+        let from_s = from.to_string();
+        let bare_s = bare_jid(from);
+        let delivered_at = format_timestamp(&Utc::now());
+        let recorded = self
+            .db
+            .execute(
+                "INSERT INTO message_deliveries (message_id, delivered_to, delivered_at) \
+                 SELECT id, ?2, ?3 FROM messages WHERE id = ?1 \
+                   AND (to_jid = ?4 OR substr(to_jid, 1, length(?4) + 1) = ?4 || '/') \
+                 ON CONFLICT(message_id, delivered_to) DO NOTHING",
+                &[&id_s, &from_s, &delivered_at, &bare_s],
             )
             .await?;
+        Ok(recorded > 0)
+    }
+
+    async fn persist_message(
+        &self,
+        message: &ChatMessage,
+        source: MessageSource,
+    ) -> Result<(), MessagingError> {
+        self.messages.persist(message, source).await?;
         Ok(())
     }
 
+    /// Sets the resource this client uses, for sessions whose JID comes
+    /// without one.
+    #[cfg(feature = "native")]
+    pub fn set_resource(&self, resource: Option<String>) {
+        *self.resource.write().unwrap() = resource.filter(|resource| !resource.is_empty());
+    }
+
+    /// Full JID of the current session: the session JID with its bound
+    /// resource, or with the configured one when it has none. `None` while
+    /// offline.
+    #[cfg(feature = "native")]
+    pub fn own_jid(&self) -> Option<String> {
+        let connected = self.connected_jid.read().unwrap().clone()?;
+        if connected.contains('/') {
+            return Some(connected);
+        }
+        Some(match self.resource.read().unwrap().as_deref() {
+            Some(resource) => format!("{connected}/{resource}"),
+            None => connected,
+        })
+    }
+
+    /// Whether `jid` addresses this client: our bare JID, or our full JID
+    /// with this session's resource. Our other resources are not us.
+    #[cfg(feature = "native")]
+    pub fn is_own_jid(&self, jid: &str) -> bool {
+        let Some(own) = self.own_jid() else {
+            return false;
+        };
+        if bare_jid(jid) != bare_jid(&own) {
+            return false;
+        }
+        match (jid.split_once('/'), own.split_once('/')) {
+            (Some((_, resource)), Some((_, own_resource))) => resource == own_resource,
+            (Some(_), None) => false,
+            (None, _) => true,
+        }
+    }
+
     #[cfg(feature = "native")]
     fn is_online(&self) -> bool {
-        *self.is_online.read().unwrap()
+        self.connected_jid.read().unwrap().is_some()
     }
 
+    /// Records the session JID (or `None` when offline) and returns the
+    /// previous one.
     #[cfg(feature = "native")]
-    fn set_online(&self, online: bool) -> bool {
-        let mut state = self.is_online.write().unwrap();
-        let previous = *state;
-        *state = online;
-        previous
+    fn set_connected(&self, jid: Option<&str>) -> Option<String> {
+        std::mem::replace(
+            &mut *self.connected_jid.write().unwrap(),
+            jid.map(str::to_string),
+        )
     }
 
     #[cfg(feature = "native")]
@@ -441,6 +1719,9 @@ impl<D: Database> MessageManager<D> {
         let Some(stanza_type) = command_stanza_type(&payload) else {
             return Ok(());
         };
+        let channel_id = QueuedChannel::from_channel(channel).ok_or_else(|| {
+            MessagingError::SendFailed(format!("cannot queue command on channel {channel}"))
+        })?;
 
         let resolved_correlation = if matches!(&payload, EventPayload::MessageSendRequested { .. })
             && correlation_id.is_none()
@@ -456,10 +1737,11 @@ impl<D: Database> MessageManager<D> {
             message_type,
         } = &payload
         {
+            let id = resolved_correlation
+                .unwrap_or_else(Uuid::new_v4)
+                .to_string();
             let message = ChatMessage {
-                id: resolved_correlation
-                    .unwrap_or_else(Uuid::new_v4)
-                    .to_string(),
+                id: id.clone(),
                 from: String::new(),
                 to: to.clone(),
                 body: body.clone(),
@@ -467,18 +1749,28 @@ impl<D: Database> MessageManager<D> {
                 message_type: message_type.clone(),
                 thread: None,
                 embeds: vec![],
+                bodies: BTreeMap::new(),
+                origin_id: Some(id),
+                spoiler: None,
+                stanza_id: None,
             };
-            self.persist_message(&message).await?;
+            self.persist_message(&message, MessageSource::Local).await?;
+        }
+
+        if let Some(target) = roster_queue_target(&payload) {
+            self.drop_pending_roster_command(channel_id, &target)
+                .await?;
         }
 
         let queued = QueuedOutboundEvent {
+            channel_id: Some(channel_id),
             channel: channel.to_string(),
             payload,
             correlation_id: resolved_correlation,
         };
         let payload_json = serde_json::to_string(&queued)
             .map_err(|e| MessagingError::SendFailed(format!("queue serialization failed: {e}")))?;
-        let created_at = Utc::now().to_rfc3339();
+        let created_at = format_timestamp(&Utc::now());
         let status = OFFLINE_STATUS_PENDING.to_string();
         let stanza_type_s = stanza_type.to_string();
 
@@ -493,19 +1785,51 @@ impl<D: Database> MessageManager<D> {
         Ok(())
     }
 
+    /// Removes pending roster commands on `channel_id` for `target`, so the
+    /// command about to be queued takes their place at the back of the queue.
     #[cfg(feature = "native")]
-    async fn load_offline_queue_by_status(
+    async fn drop_pending_roster_command(
         &self,
-        status: &str,
-    ) -> Result<Vec<StoredOfflineQueueItem>, MessagingError> {
-        let status_s = status.to_string();
-        self.db
-            .query(
-                "SELECT id, stanza_type, payload, status \
-                 FROM offline_queue \
-                 WHERE status = ?1 \
-                 ORDER BY id ASC",
-                &[&status_s],
+        channel_id: QueuedChannel,
+        target: &str,
+    ) -> Result<(), MessagingError> {
+        for item in self
+            .load_offline_queue_by_status(OFFLINE_STATUS_PENDING)
+            .await?
+        {
+            let Ok(queued) = serde_json::from_str::<QueuedOutboundEvent>(&item.payload) else {
+                continue;
+            };
+            if queued.queued_channel() != Some(channel_id)
+                || roster_queue_target(&queued.payload).as_deref() != Some(target)
+            {
+                continue;
+            }
+            let id = item.id;
+            self.db
+                .execute("DELETE FROM offline_queue WHERE id = ?1", &[&id])
+                .await?;
+            debug!(jid = %target, "collapsed duplicate queued roster command");
+        }
+        Ok(())
+    }
+
+    /// Queue reads go by `created_at`, falling back to `id` for items queued
+    /// within the same millisecond, so FIFO does not depend on ids staying
+    /// monotonic across resets or imports.
+    #[cfg(feature = "native")]
+    async fn load_offline_queue_by_status(
+        &self,
+        status: &str,
+    ) -> Result<Vec<StoredOfflineQueueItem>, MessagingError> {
+        let status_s = status.to_string();
+        self.db
+            .query(
+                "SELECT id, payload, status \
+                 FROM offline_queue \
+                 WHERE status = ?1 \
+                 ORDER BY created_at ASC, id ASC",
+                &[&status_s],
             )
             .await
             .map_err(Into::into)
@@ -517,28 +1841,136 @@ impl<D: Database> MessageManager<D> {
     ) -> Result<Vec<StoredOfflineQueueItem>, MessagingError> {
         self.db
             .query(
-                "SELECT id, stanza_type, payload, status \
+                "SELECT id, payload, status, COALESCE(sent_at, created_at) \
                  FROM offline_queue \
                  WHERE stanza_type = 'message' AND status != 'confirmed' \
-                 ORDER BY id ASC",
+                 ORDER BY created_at ASC, id ASC",
                 &[],
             )
             .await
             .map_err(Into::into)
     }
 
+    /// Moving an item to `sent` also records when it went out.
     #[cfg(feature = "native")]
     async fn update_queue_status(&self, id: i64, status: &str) -> Result<(), MessagingError> {
         let status_s = status.to_string();
+        let sent_at = (status == OFFLINE_STATUS_SENT).then(|| format_timestamp(&Utc::now()));
+        self.db
+            .execute(
+                "UPDATE offline_queue SET status = ?1, sent_at = COALESCE(?3, sent_at) \
+                 WHERE id = ?2",
+                &[&status_s, &id, &sent_at],
+            )
+            .await?;
+        Ok(())
+    }
+
+    #[cfg(feature = "native")]
+    async fn mark_queue_failed(&self, id: i64, reason: &str) -> Result<(), MessagingError> {
+        let status_s = OFFLINE_STATUS_FAILED.to_string();
+        let reason_s = reason.to_string();
         self.db
             .execute(
-                "UPDATE offline_queue SET status = ?1 WHERE id = ?2",
-                &[&status_s, &id],
+                "UPDATE offline_queue SET status = ?1, last_error = ?2 WHERE id = ?3",
+                &[&status_s, &reason_s, &id],
             )
             .await?;
         Ok(())
     }
 
+    /// Marks the queued send correlated with `message_id` failed, so it can
+    /// be resent.
+    #[cfg(feature = "native")]
+    async fn mark_queued_message_failed(
+        &self,
+        message_id: &str,
+        reason: &str,
+    ) -> Result<bool, MessagingError> {
+        let candidates = self.load_message_queue_candidates().await?;
+
+        for item in candidates {
+            let Ok(queued) = serde_json::from_str::<QueuedOutboundEvent>(&item.payload) else {
+                continue;
+            };
+
+            if queued.correlation_id.map(|id| id.to_string()).as_deref() == Some(message_id) {
+                self.mark_queue_failed(item.id, reason).await?;
+                return Ok(true);
+            }
+        }
+
+        Ok(false)
+    }
+
+    /// Outbound commands that have not been confirmed yet, oldest first.
+    /// Failed items carry the reason they failed in `last_error`.
+    #[cfg(feature = "native")]
+    pub async fn pending_outbound(&self) -> Result<Vec<OutboundQueueItem>, MessagingError> {
+        let confirmed = OFFLINE_STATUS_CONFIRMED.to_string();
+        self.db
+            .query(
+                "SELECT id, stanza_type, status, created_at, last_error \
+                 FROM offline_queue \
+                 WHERE status != ?1 \
+                 ORDER BY created_at ASC, id ASC",
+                &[&confirmed],
+            )
+            .await
+            .map_err(Into::into)
+    }
+
+    /// Puts the failed and unacknowledged sends to `jid` (a contact or a
+    /// room) back into the queue, and sends them right away when online.
+    /// Returns how many were requeued.
+    #[cfg(feature = "native")]
+    pub async fn resend_conversation(&self, jid: &str) -> Result<usize, MessagingError> {
+        let jid = bare_jid(jid);
+        let mut requeued = 0;
+        for item in self.load_message_queue_candidates().await? {
+            if item.status != OFFLINE_STATUS_FAILED && item.status != OFFLINE_STATUS_SENT {
+                continue;
+            }
+            let Ok(queued) = serde_json::from_str::<QueuedOutboundEvent>(&item.payload) else {
+                continue;
+            };
+            let target = match &queued.payload {
+                EventPayload::MessageSendRequested { to, .. } => bare_jid(to),
+                EventPayload::MucSendRequested { room, .. } => bare_jid(room),
+                _ => continue,
+            };
+            if target != jid {
+                continue;
+            }
+
+            let pending = OFFLINE_STATUS_PENDING.to_string();
+            let id = item.id;
+            self.db
+                .execute(
+                    "UPDATE offline_queue SET status = ?1, last_error = NULL WHERE id = ?2",
+                    &[&pending, &id],
+                )
+                .await?;
+            requeued += 1;
+        }
+
+        info!(jid = %jid, requeued, "requeued conversation sends");
+        if requeued > 0 && self.is_online() {
+            self.drain_offline_queue().await?;
+        }
+        Ok(requeued)
+    }
+
+    /// Publishes every pending offline-queue item while we are online. Offline,
+    /// the items stay queued for the next connection.
+    #[cfg(feature = "native")]
+    pub async fn flush(&self) -> Result<(), MessagingError> {
+        if !self.is_online() {
+            return Ok(());
+        }
+        self.drain_offline_queue().await
+    }
+
     #[cfg(feature = "native")]
     async fn drain_offline_queue(&self) -> Result<(), MessagingError> {
         let pending_items = self
@@ -555,28 +1987,25 @@ impl<D: Database> MessageManager<D> {
                         "failed to deserialize offline queue item"
                     );
                     let _ = self
-                        .update_queue_status(item.id, OFFLINE_STATUS_FAILED)
+                        .mark_queue_failed(item.id, &format!("invalid queued payload: {error}"))
                         .await;
                     continue;
                 }
             };
 
-            let channel = match Channel::new(&queued.channel) {
-                Ok(channel) => channel,
-                Err(error) => {
-                    error!(
-                        queue_id = item.id,
-                        channel = %queued.channel,
-                        error = %error,
-                        "invalid queued channel"
-                    );
-                    let _ = self
-                        .update_queue_status(item.id, OFFLINE_STATUS_FAILED)
-                        .await;
-                    continue;
-                }
+            // Only sends come back as `MessageSent`; everything else, a
+            // correction included, is done once it is published.
+            let awaits_sent = matches!(
+                queued.payload,
+                EventPayload::MessageSendRequested { .. } | EventPayload::MucSendRequested { .. }
+            );
+            let Some(channel_id) = queued.queued_channel() else {
+                let reason = format!("unknown queued channel `{}`", queued.channel);
+                error!(queue_id = item.id, reason = %reason, "cannot drain offline queue item");
+                let _ = self.mark_queue_failed(item.id, &reason).await;
+                continue;
             };
-
+            let channel = Channel::new(channel_id.channel()).unwrap();
             let source = EventSource::System(OFFLINE_SOURCE.to_string());
             let event = if let Some(correlation_id) = queued.correlation_id {
                 Event::with_correlation(channel, source, queued.payload, correlation_id)
@@ -591,12 +2020,12 @@ impl<D: Database> MessageManager<D> {
                     "failed to publish queued offline command"
                 );
                 let _ = self
-                    .update_queue_status(item.id, OFFLINE_STATUS_FAILED)
+                    .mark_queue_failed(item.id, &format!("publish failed: {error}"))
                     .await;
                 continue;
             }
 
-            if item.stanza_type != "message" {
+            if !awaits_sent {
                 if let Err(error) = self.update_queue_status(item.id, OFFLINE_STATUS_SENT).await {
                     error!(
                         queue_id = item.id,
@@ -648,13 +2077,18 @@ impl<D: Database> MessageManager<D> {
     }
 
     #[cfg(feature = "native")]
+    /// Fallback for archived copies without a matching id: confirms the
+    /// oldest queued send with the same recipient and body that went out
+    /// within the content match window of `sent_at`.
     async fn update_message_queue_status_by_content(
         &self,
         to: &str,
         body: &str,
+        sent_at: DateTime<Utc>,
         from_statuses: &[&str],
         to_status: &str,
     ) -> Result<bool, MessagingError> {
+        let window = *self.content_match_window.read().unwrap();
         let candidates = self.load_message_queue_candidates().await?;
 
         for item in candidates {
@@ -662,6 +2096,14 @@ impl<D: Database> MessageManager<D> {
                 continue;
             }
 
+            let went_out_at = item
+                .sent_at
+                .as_deref()
+                .and_then(|at| at.parse::<DateTime<Utc>>().ok());
+            if went_out_at.is_none_or(|went_out_at| (sent_at - went_out_at).abs() > window) {
+                continue;
+            }
+
             let Ok(queued) = serde_json::from_str::<QueuedOutboundEvent>(&item.payload) else {
                 continue;
             };
@@ -695,11 +2137,16 @@ impl<D: Database> MessageManager<D> {
     }
 
     #[cfg(feature = "native")]
+    #[instrument(skip_all, fields(channel = %event.channel, correlation_id = event.correlation_id.map(field::display)))]
     pub async fn handle_event(&self, event: &Event) {
         match &event.payload {
-            EventPayload::ConnectionEstablished { .. } => {
-                let was_online = self.set_online(true);
-                if !was_online {
+            EventPayload::ConnectionEstablished { jid } => {
+                let previous = self.set_connected(Some(jid));
+                if previous.as_deref() == Some(jid.as_str()) {
+                    debug!(jid = %jid, "duplicate connection established, ignoring");
+                    return;
+                }
+                if previous.is_none() {
                     self.emit_system_transition("system.coming_online", EventPayload::ComingOnline);
                 }
                 if let Err(error) = self.drain_offline_queue().await {
@@ -707,12 +2154,15 @@ impl<D: Database> MessageManager<D> {
                 }
             }
             EventPayload::ConnectionLost { .. } => {
-                let was_online = self.set_online(false);
+                let was_online = self.set_connected(None).is_some();
+                self.peer_chat_states.write().unwrap().clear();
+                self.composing_resources.write().unwrap().clear();
                 if was_online {
                     self.emit_system_transition("system.going_offline", EventPayload::GoingOffline);
                 }
             }
             EventPayload::MessageSendRequested { .. }
+            | EventPayload::MessageCorrectRequested { .. }
             | EventPayload::PresenceSetRequested { .. }
             | EventPayload::RosterAddRequested { .. }
             | EventPayload::RosterUpdateRequested { .. }
@@ -723,7 +2173,7 @@ impl<D: Database> MessageManager<D> {
             | EventPayload::MucJoinRequested { .. }
             | EventPayload::MucLeaveRequested { .. }
             | EventPayload::MucSendRequested { .. }
-            | EventPayload::ChatStateSendRequested { .. } => {
+            | EventPayload::MucModerateRequested { .. } => {
                 if self.is_online() {
                     return;
                 }
@@ -744,14 +2194,24 @@ impl<D: Database> MessageManager<D> {
                     error!(error = %error, "failed to enqueue offline command event");
                 }
             }
+            EventPayload::ChatStateSendRequested { to, state } if !self.is_online() => {
+                if let Err(error) = self.drop_offline_chat_state(to, state).await {
+                    error!(error = %error, to = %to, "failed to record offline chat state");
+                }
+            }
             EventPayload::MessageReceived { message } => {
                 debug!(
                     id = %message.id,
                     from = %message.from,
                     "message received, persisting"
                 );
-                if let Err(e) = self.persist_message(message).await {
-                    error!(error = %e, "failed to persist received message");
+                let policy = *self.persist_policy.read().unwrap();
+                persist_with_policy(policy, self.event_bus.as_ref(), &message.id, || {
+                    self.persist_message(message, MessageSource::Live)
+                })
+                .await;
+                if let Err(error) = self.unarchive(&message.from).await {
+                    error!(error = %error, from = %message.from, "failed to unarchive conversation");
                 }
             }
             EventPayload::MessageSent { message } => {
@@ -760,9 +2220,11 @@ impl<D: Database> MessageManager<D> {
                     to = %message.to,
                     "message sent, persisting"
                 );
-                if let Err(e) = self.persist_message(message).await {
-                    error!(error = %e, "failed to persist sent message");
-                }
+                let policy = *self.persist_policy.read().unwrap();
+                persist_with_policy(policy, self.event_bus.as_ref(), &message.id, || {
+                    self.persist_message(message, MessageSource::Live)
+                })
+                .await;
                 if let Err(error) = self
                     .update_message_queue_status_by_id(
                         &message.id,
@@ -774,8 +2236,19 @@ impl<D: Database> MessageManager<D> {
                     error!(error = %error, "failed to update queued message to sent");
                 }
             }
-            EventPayload::MessageDelivered { id, to } => {
-                debug!(id = %id, to = %to, "delivery receipt received");
+            EventPayload::MessageDelivered { id, to, resource } => {
+                debug!(id = %id, to = %to, resource = ?resource, "delivery receipt received");
+                if let Some(resource) = resource {
+                    match self.record_delivery(id, &format!("{to}/{resource}")).await {
+                        Ok(true) => {}
+                        Ok(false) => {
+                            debug!(id = %id, to = %to, "receipt is not for a message we sent them")
+                        }
+                        Err(error) => {
+                            error!(error = %error, "failed to record delivering resource")
+                        }
+                    }
+                }
                 if let Err(error) = self
                     .update_message_queue_status_by_id(
                         id,
@@ -787,6 +2260,23 @@ impl<D: Database> MessageManager<D> {
                     error!(error = %error, "failed to update queued message to confirmed");
                 }
             }
+            EventPayload::MessageCorrectionReceived {
+                from,
+                target_id,
+                body,
+            } => match self.apply_correction(from, target_id, body).await {
+                Ok(true) => debug!(from = %from, target_id = %target_id, "message corrected"),
+                Ok(false) => {
+                    debug!(from = %from, target_id = %target_id, "ignoring correction of a message they did not send")
+                }
+                Err(error) => error!(error = %error, "failed to apply message correction"),
+            },
+            EventPayload::MessageNotDelivered { id, to } => {
+                debug!(id = %id, to = %to, "message not delivered");
+                if let Err(error) = self.mark_queued_message_failed(id, "not delivered").await {
+                    error!(error = %error, "failed to mark queued message failed");
+                }
+            }
             EventPayload::MamResultReceived { messages, .. } => {
                 for message in messages {
                     let confirmed_by_id = match self
@@ -816,6 +2306,7 @@ impl<D: Database> MessageManager<D> {
                         .update_message_queue_status_by_content(
                             &message.to,
                             &message.body,
+                            message.timestamp,
                             &[OFFLINE_STATUS_SENT],
                             OFFLINE_STATUS_CONFIRMED,
                         )
@@ -831,6 +2322,24 @@ impl<D: Database> MessageManager<D> {
             }
             EventPayload::ChatStateReceived { from, state } => {
                 debug!(from = %from, ?state, "chat state received");
+                self.track_chat_state(from, state);
+                if matches!(state, ChatState::Active)
+                    && *self.chat_state_marks_displayed.read().unwrap()
+                {
+                    match self.mark_sent_displayed(from).await {
+                        Ok(0) => {}
+                        Ok(_) => {
+                            let _ = self.event_bus.publish(Event::new(
+                                Channel::new("ui.message.displayed").unwrap(),
+                                EventSource::System("messaging".into()),
+                                EventPayload::MessagesDisplayed { to: bare_jid(from) },
+                            ));
+                        }
+                        Err(error) => {
+                            error!(error = %error, from = %from, "failed to mark sent messages displayed");
+                        }
+                    }
+                }
             }
             _ => {}
         }
@@ -866,12 +2375,136 @@ impl<D: Database> MessageManager<D> {
     pub fn handle_stanza(&self, _stanza: &Stanza) {}
 }
 
+/// How long [`wait_for_outbound`] waits for the outbound router to hand
+/// earlier commands to the transport.
+#[cfg(feature = "native")]
+const OUTBOUND_FLUSH_TIMEOUT_SECS: u64 = 5;
+
+/// The managers' run loops, stopped in a safe order.
+///
+/// Shutdown runs in this order:
+/// 1. [`MessageManager::flush`] publishes the pending offline queue, so
+///    queued sends reach the outbound router while the event bus is open.
+/// 2. An `OutboundFlushRequested` waits until the router has turned those
+///    commands into stanzas and the wire pump has taken them.
+/// 3. The other run loops are stopped, in the order they were added.
+/// 4. The message manager's own run loop is stopped last.
+///
+/// The outbound router and wire pump are not part of the set: keep them
+/// running until the connection is closed, and drop the event bus only
+/// after [`ManagerSet::shutdown`] returns.
+#[cfg(feature = "native")]
+pub struct ManagerSet<D: Database> {
+    messages: Arc<MessageManager<D>>,
+    run_loop: tokio::task::JoinHandle<()>,
+    tasks: Vec<(&'static str, tokio::task::JoinHandle<()>)>,
+}
+
+#[cfg(feature = "native")]
+impl<D: Database> ManagerSet<D> {
+    /// `run_loop` is the task driving `messages.run()`.
+    pub fn new(messages: Arc<MessageManager<D>>, run_loop: tokio::task::JoinHandle<()>) -> Self {
+        Self {
+            messages,
+            run_loop,
+            tasks: Vec::new(),
+        }
+    }
+
+    /// Tracks another manager's run loop, stopped after the flush.
+    pub fn add_task(&mut self, name: &'static str, task: tokio::task::JoinHandle<()>) {
+        self.tasks.push((name, task));
+    }
+
+    /// Flushes the message manager, then stops every run loop; returns the
+    /// flush error, if any, once everything is stopped.
+    pub async fn shutdown(self) -> Result<(), MessagingError> {
+        let flushed = self.messages.flush().await;
+        if let Err(e) = &flushed {
+            error!(error = %e, "failed to flush offline queue on shutdown");
+        }
+        if self.messages.is_online() {
+            wait_for_outbound(self.messages.event_bus.as_ref()).await;
+        }
+        for (name, task) in self.tasks {
+            debug!(manager = name, "stopping manager");
+            task.abort();
+            let _ = task.await;
+        }
+        self.run_loop.abort();
+        let _ = self.run_loop.await;
+        flushed
+    }
+}
+
+/// Waits until the outbound router has put every command published on `ui.*`
+/// before this call on the wire, or gives up after
+/// [`OUTBOUND_FLUSH_TIMEOUT_SECS`].
+#[cfg(feature = "native")]
+pub async fn wait_for_outbound(event_bus: &dyn EventBus) {
+    let mut sub = match event_bus.subscribe("xmpp.outbound.flushed") {
+        Ok(sub) => sub,
+        Err(e) => {
+            warn!(error = %e, "cannot wait for the outbound router");
+            return;
+        }
+    };
+    let correlation_id = Uuid::new_v4();
+    if let Err(e) = event_bus.publish(Event::with_correlation(
+        Channel::new("ui.outbound.flush").unwrap(),
+        EventSource::System("messaging".into()),
+        EventPayload::OutboundFlushRequested,
+        correlation_id,
+    )) {
+        warn!(error = %e, "failed to ask the outbound router to flush");
+        return;
+    }
+
+    let deadline =
+        tokio::time::Instant::now() + tokio::time::Duration::from_secs(OUTBOUND_FLUSH_TIMEOUT_SECS);
+    loop {
+        match tokio::time::timeout_at(deadline, sub.recv()).await {
+            Ok(Ok(event)) if event.correlation_id == Some(correlation_id) => return,
+            Ok(Ok(_)) => {}
+            Ok(Err(waddle_core::error::EventBusError::Lagged(count))) => {
+                warn!(count, "outbound flush wait lagged");
+            }
+            Ok(Err(e)) => {
+                warn!(error = %e, "outbound flush wait failed");
+                return;
+            }
+            Err(_) => {
+                warn!("timed out waiting for the outbound router to flush");
+                return;
+            }
+        }
+    }
+}
+
+/// Whether a room shows occupants' real JIDs to us, inferred from the
+/// occupant presences seen so far.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RoomAnonymity {
+    /// No occupants known yet.
+    #[default]
+    Unknown,
+    /// Real JIDs are visible, so occupants can be added as contacts.
+    NonAnonymous,
+    /// Occupants are known only by nick.
+    SemiAnonymous,
+}
+
 #[derive(Debug, Clone)]
 pub struct MucRoom {
     pub room_jid: String,
     pub nick: String,
     pub joined: bool,
     pub subject: Option<String>,
+    /// The room was destroyed by its owner and must not be rejoined.
+    pub destroyed: bool,
+    pub anonymity: RoomAnonymity,
+    /// When the newest stored message in the room was sent
+    pub last_activity: Option<DateTime<Utc>>,
 }
 
 struct StoredRoom {
@@ -879,6 +2512,8 @@ struct StoredRoom {
     nick: String,
     joined: i64,
     subject: Option<String>,
+    destroyed: i64,
+    last_activity: Option<i64>,
 }
 
 impl FromRow for StoredRoom {
@@ -908,47 +2543,212 @@ impl FromRow for StoredRoom {
             Some(SqlValue::Null) | None => None,
             _ => None,
         };
+        let destroyed = match row.get(4) {
+            Some(SqlValue::Integer(i)) => *i,
+            _ => 0,
+        };
+        let last_activity = match row.get(5) {
+            Some(SqlValue::Integer(i)) => Some(*i),
+            _ => None,
+        };
         Ok(StoredRoom {
             room_jid,
             nick,
             joined,
             subject,
+            destroyed,
+            last_activity,
         })
     }
 }
 
 impl StoredRoom {
-    fn into_muc_room(self) -> MucRoom {
+    fn into_muc_room(self, anonymity: RoomAnonymity) -> MucRoom {
         MucRoom {
             room_jid: self.room_jid,
             nick: self.nick,
             joined: self.joined != 0,
             subject: self.subject,
+            destroyed: self.destroyed != 0,
+            anonymity,
+            last_activity: self.last_activity.and_then(DateTime::from_timestamp_millis),
         }
     }
 }
 
-/// Per-room occupant map: nick -> MucOccupant
-type OccupantMap = HashMap<String, MucOccupant>;
+/// Per-room occupant map: nick -> occupant
+type OccupantMap = HashMap<String, TrackedOccupant>;
+
+struct TrackedOccupant {
+    occupant: MucOccupant,
+    /// Tick of the manager's activity clock when the occupant last changed
+    /// presence, spoke or sent a chat state
+    last_active: u64,
+}
+
+/// Position of an affiliation in occupant listings, highest first.
+fn affiliation_rank(affiliation: &MucAffiliation) -> u8 {
+    match affiliation {
+        MucAffiliation::Owner => 0,
+        MucAffiliation::Admin => 1,
+        MucAffiliation::Member => 2,
+        MucAffiliation::None => 3,
+        MucAffiliation::Outcast => 4,
+    }
+}
+
+impl TrackedOccupant {
+    /// Moderators, owners and admins are never evicted by the occupant cap.
+    fn is_privileged(&self) -> bool {
+        matches!(self.occupant.role, MucRole::Moderator)
+            || matches!(
+                self.occupant.affiliation,
+                MucAffiliation::Owner | MucAffiliation::Admin
+            )
+    }
+}
+
+/// Per-room composing occupants: nick -> when they started composing
+type TyperMap = HashMap<String, DateTime<Utc>>;
+
+/// How long an occupant counts as typing without a fresh `composing`.
+const MUC_TYPING_EXPIRY_SECS: i64 = 30;
+
+/// Drops expired typers, and rooms left with none.
+fn prune_typers(typers: &mut HashMap<String, TyperMap>) {
+    let cutoff = Utc::now() - chrono::Duration::seconds(MUC_TYPING_EXPIRY_SECS);
+    typers.retain(|_, room_typers| {
+        room_typers.retain(|_, since| *since > cutoff);
+        !room_typers.is_empty()
+    });
+}
+
+/// How long to wait for a room to answer a query.
+#[cfg(feature = "native")]
+const MUC_QUERY_TIMEOUT_SECS: u64 = 10;
+const MUC_ECHO_TIMEOUT_SECS: u64 = 30;
 
 pub struct MucManager<D: Database> {
     db: Arc<D>,
+    messages: MessageStore<D>,
     occupants: RwLock<HashMap<String, OccupantMap>>,
+    /// Source of `TrackedOccupant::last_active` ticks
+    activity_clock: AtomicU64,
+    /// Most occupants kept per room; `None` keeps everyone
+    max_occupants: RwLock<Option<usize>>,
+    typers: RwLock<HashMap<String, TyperMap>>,
+    /// Optimistic sends awaiting their room reflection, by message id
+    pending_echoes: Arc<RwLock<HashMap<String, PendingEcho>>>,
+    /// Whether sends go out now rather than wait in the offline queue
+    online: AtomicBool,
+    /// How long a send may go unreflected before it counts as not delivered
+    #[cfg(feature = "native")]
+    echo_timeout: RwLock<std::time::Duration>,
+    /// Why the latest join of a room was refused, until taken or retried
+    join_errors: RwLock<HashMap<String, MucError>>,
+    /// Joins awaiting the room's answer: room -> nick asked for by the user
+    /// and how many nicks have been tried
+    pending_joins: RwLock<HashMap<String, PendingJoin>>,
+    /// Joins the connection dropped before the room answered: room -> nick
+    /// asked for by the user. Requested again on reconnect.
+    interrupted_joins: RwLock<HashMap<String, String>>,
+    /// Cached disco#info answers: room -> what it advertised
+    room_info: RwLock<HashMap<String, MucRoomInfo>>,
+    /// Joined rooms whose subject has not arrived yet, so messages from
+    /// them are history replay
+    replaying_rooms: RwLock<HashSet<String>>,
+    /// Joined rooms whose own occupant entry has not been tracked yet:
+    /// room -> our nick there
+    loading_rosters: RwLock<HashMap<String, String>>,
+    /// Rooms that confirmed our nick change but have not sent the
+    /// self-presence for the new nick yet: room -> new nick
+    renamed_rooms: RwLock<HashMap<String, String>>,
     #[cfg(feature = "native")]
     event_bus: Arc<dyn EventBus>,
+    #[cfg(feature = "native")]
+    persist_policy: RwLock<RetryPolicy>,
+    /// Suffixed nicks to try after a nick conflict; 0 turns retrying off
+    #[cfg(feature = "native")]
+    nick_conflict_retries: RwLock<u32>,
+}
+
+struct PendingJoin {
+    requested_nick: String,
+    attempt: u32,
+}
+
+struct PendingEcho {
+    room: String,
+    /// The echo timer is running. Sends queued while offline wait for the
+    /// reconnect before their timer starts.
+    armed: bool,
 }
 
 impl<D: Database> MucManager<D> {
     #[cfg(feature = "native")]
     pub fn new(db: Arc<D>, event_bus: Arc<dyn EventBus>) -> Self {
         Self {
+            messages: MessageStore::new(db.clone()),
             db,
             occupants: RwLock::new(HashMap::new()),
+            activity_clock: AtomicU64::new(0),
+            max_occupants: RwLock::new(None),
+            typers: RwLock::new(HashMap::new()),
+            pending_echoes: Arc::new(RwLock::new(HashMap::new())),
+            online: AtomicBool::new(false),
+            echo_timeout: RwLock::new(std::time::Duration::from_secs(MUC_ECHO_TIMEOUT_SECS)),
+            join_errors: RwLock::new(HashMap::new()),
+            pending_joins: RwLock::new(HashMap::new()),
+            interrupted_joins: RwLock::new(HashMap::new()),
+            room_info: RwLock::new(HashMap::new()),
+            replaying_rooms: RwLock::new(HashSet::new()),
+            loading_rosters: RwLock::new(HashMap::new()),
+            renamed_rooms: RwLock::new(HashMap::new()),
             event_bus,
+            persist_policy: RwLock::new(RetryPolicy::default()),
+            nick_conflict_retries: RwLock::new(0),
         }
     }
 
-    pub async fn join_room(&self, room: &str, nick: &str) -> Result<(), MessagingError> {
+    #[cfg(feature = "native")]
+    pub fn set_persist_failure_policy(&self, policy: RetryPolicy) {
+        *self.persist_policy.write().unwrap() = policy;
+    }
+
+    /// Sets how long a sent message may wait for the room to reflect it
+    /// before it is reported as `MessageNotDelivered`.
+    #[cfg(feature = "native")]
+    pub fn set_echo_timeout(&self, timeout: std::time::Duration) {
+        *self.echo_timeout.write().unwrap() = timeout;
+    }
+
+    /// Caps how many occupants are kept per room. Past the cap the least
+    /// recently active occupants are dropped, except moderators, owners and
+    /// admins.
+    pub fn set_max_occupants(&self, max: Option<usize>) {
+        *self.max_occupants.write().unwrap() = max;
+    }
+
+    /// Lets a join refused with a nick conflict be retried as `nick_2`,
+    /// `nick_3`, ... up to `retries` times before the conflict is surfaced.
+    #[cfg(feature = "native")]
+    pub fn set_nick_conflict_retries(&self, retries: u32) {
+        *self.nick_conflict_retries.write().unwrap() = retries;
+    }
+
+    pub async fn join_room(&self, room: &str, nick: &str) -> Result<(), MucError> {
+        self.join_errors.write().unwrap().remove(room);
+        self.pending_joins.write().unwrap().insert(
+            room.to_string(),
+            PendingJoin {
+                requested_nick: nick.to_string(),
+                attempt: 1,
+            },
+        );
+        self.request_join(room, nick).await
+    }
+
+    async fn request_join(&self, room: &str, nick: &str) -> Result<(), MucError> {
         let room_s = room.to_string();
         let nick_s = nick.to_string();
         let joined = 0_i64;
@@ -956,12 +2756,18 @@ impl<D: Database> MucManager<D> {
 
         self.db
             .execute(
-                "INSERT OR REPLACE INTO muc_rooms (room_jid, nick, joined, subject) \
-                 VALUES (?1, ?2, ?3, ?4)",
+                "INSERT OR REPLACE INTO muc_rooms (room_jid, nick, joined, subject, last_activity) \
+                 VALUES (?1, ?2, ?3, ?4, \
+                 (SELECT last_activity FROM muc_rooms WHERE room_jid = ?1))",
                 &[&room_s, &nick_s, &joined, &subject],
             )
             .await?;
 
+        self.publish_join_request(room, nick);
+        Ok(())
+    }
+
+    fn publish_join_request(&self, room: &str, nick: &str) {
         #[cfg(feature = "native")]
         {
             let _ = self.event_bus.publish(Event::new(
@@ -973,11 +2779,55 @@ impl<D: Database> MucManager<D> {
                 },
             ));
         }
+    }
 
-        Ok(())
+    /// Asks every room still stored as joined to let us back in under its
+    /// stored nick, as after a reconnect. Rooms stay joined until a rejoin is
+    /// refused. Returns how many joins were requested.
+    pub async fn rejoin_all(&self) -> Result<usize, MucError> {
+        let mut requested = 0;
+        for room in self.get_joined_rooms().await? {
+            if room.destroyed
+                || self
+                    .pending_joins
+                    .read()
+                    .unwrap()
+                    .contains_key(&room.room_jid)
+            {
+                continue;
+            }
+            // The old session's occupants and typers are gone with it.
+            self.occupants.write().unwrap().remove(&room.room_jid);
+            self.typers.write().unwrap().remove(&room.room_jid);
+            self.join_errors.write().unwrap().remove(&room.room_jid);
+            self.pending_joins.write().unwrap().insert(
+                room.room_jid.clone(),
+                PendingJoin {
+                    requested_nick: room.nick.clone(),
+                    attempt: 1,
+                },
+            );
+            self.interrupted_joins
+                .write()
+                .unwrap()
+                .remove(&room.room_jid);
+            self.publish_join_request(&room.room_jid, &room.nick);
+            requested += 1;
+        }
+
+        let interrupted: Vec<(String, String)> =
+            self.interrupted_joins.write().unwrap().drain().collect();
+        for (room, nick) in interrupted {
+            if self.pending_joins.read().unwrap().contains_key(&room) {
+                continue;
+            }
+            self.join_room(&room, &nick).await?;
+            requested += 1;
+        }
+        Ok(requested)
     }
 
-    pub async fn leave_room(&self, room: &str) -> Result<(), MessagingError> {
+    pub async fn leave_room(&self, room: &str) -> Result<(), MucError> {
         #[cfg(feature = "native")]
         {
             let _ = self.event_bus.publish(Event::new(
@@ -992,46 +2842,322 @@ impl<D: Database> MucManager<D> {
         Ok(())
     }
 
-    pub async fn send_message(&self, room: &str, body: &str) -> Result<(), MessagingError> {
+    /// Asks `room` to rename us to `new_nick`. The stored nick only changes
+    /// once the room confirms with status 303.
+    pub async fn change_nick(&self, room: &str, new_nick: &str) -> Result<(), MucError> {
         #[cfg(feature = "native")]
         {
             let _ = self.event_bus.publish(Event::new(
-                Channel::new("ui.muc.send").unwrap(),
+                Channel::new("ui.muc.nick").unwrap(),
                 EventSource::System("muc".into()),
-                EventPayload::MucSendRequested {
+                EventPayload::MucNickChangeRequested {
                     room: room.to_string(),
-                    body: body.to_string(),
+                    nick: new_nick.to_string(),
                 },
             ));
         }
-
         Ok(())
     }
 
-    pub async fn get_rooms(&self) -> Result<Vec<MucRoom>, MessagingError> {
-        let rows: Vec<StoredRoom> = self
-            .db
-            .query(
-                "SELECT room_jid, nick, joined, subject FROM muc_rooms ORDER BY room_jid",
-                &[],
+    /// Leaves the room and drops everything stored about it: the room record
+    /// and its message history.
+    pub async fn forget_room(&self, room: &str) -> Result<(), MucError> {
+        self.leave_room(room).await?;
+
+        let room_s = room.to_string();
+        let groupchat = message_type_param(&MessageType::Groupchat);
+        self.db
+            .execute(
+                "DELETE FROM messages WHERE to_jid = ?1 AND message_type = ?2",
+                &[&room_s, &groupchat],
             )
             .await?;
+        self.db
+            .execute("DELETE FROM muc_rooms WHERE room_jid = ?1", &[&room_s])
+            .await?;
 
-        Ok(rows.into_iter().map(|r| r.into_muc_room()).collect())
+        self.occupants.write().unwrap().remove(room);
+        self.typers.write().unwrap().remove(room);
+        self.pending_joins.write().unwrap().remove(room);
+        self.interrupted_joins.write().unwrap().remove(room);
+        self.pending_echoes
+            .write()
+            .unwrap()
+            .retain(|_, pending| pending.room != room);
+        Ok(())
     }
 
-    pub async fn get_joined_rooms(&self) -> Result<Vec<MucRoom>, MessagingError> {
-        let joined = 1_i64;
-        let rows: Vec<StoredRoom> = self
-            .db
-            .query(
-                "SELECT room_jid, nick, joined, subject FROM muc_rooms \
-                 WHERE joined = ?1 ORDER BY room_jid",
-                &[&joined],
-            )
-            .await?;
+    /// Persists the message optimistically and requests the send. The row is
+    /// reconciled when the room reflects the message back to us.
+    pub async fn send_message(&self, room: &str, body: &str) -> Result<ChatMessage, MucError> {
+        let id = Uuid::new_v4();
+        let from = match self.room_nick(room).await? {
+            Some(nick) => format!("{room}/{nick}"),
+            None => String::new(),
+        };
+        let message = ChatMessage {
+            id: id.to_string(),
+            from,
+            to: room.to_string(),
+            body: body.to_string(),
+            timestamp: Utc::now(),
+            message_type: MessageType::Groupchat,
+            thread: None,
+            embeds: vec![],
+            bodies: BTreeMap::new(),
+            origin_id: Some(id.to_string()),
+            spoiler: None,
+            stanza_id: None,
+        };
 
-        Ok(rows.into_iter().map(|r| r.into_muc_room()).collect())
+        self.persist_message(&message, MessageSource::Local).await?;
+        let online = self.online.load(Ordering::Relaxed);
+        self.pending_echoes.write().unwrap().insert(
+            message.id.clone(),
+            PendingEcho {
+                room: room.to_string(),
+                armed: online,
+            },
+        );
+
+        #[cfg(feature = "native")]
+        {
+            let _ = self.event_bus.publish(Event::with_correlation(
+                Channel::new("ui.muc.send").unwrap(),
+                EventSource::System("muc".into()),
+                EventPayload::MucSendRequested {
+                    room: room.to_string(),
+                    body: body.to_string(),
+                },
+                id,
+            ));
+            if online {
+                self.expire_pending_echo(message.id.clone(), room.to_string());
+            }
+        }
+        discard_sent_draft(self.db.as_ref(), room).await;
+
+        Ok(message)
+    }
+
+    /// Sends a XEP-0085 chat state (e.g. composing) to the whole room.
+    pub async fn send_chat_state(&self, room: &str, state: ChatState) -> Result<(), MucError> {
+        #[cfg(feature = "native")]
+        {
+            let _ = self.event_bus.publish(Event::new(
+                Channel::new("ui.muc.chatstate.send").unwrap(),
+                EventSource::System("muc".into()),
+                EventPayload::MucChatStateSendRequested {
+                    room: room.to_string(),
+                    state,
+                },
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Asks the room to retract another occupant's message (XEP-0425). The
+    /// request names the message by the stanza-id the room assigned it, so
+    /// only messages the room stamped can be moderated. The stored copy is
+    /// tombstoned once the room confirms the moderation.
+    pub async fn moderate(
+        &self,
+        room: &str,
+        message_id: &str,
+        reason: Option<&str>,
+    ) -> Result<(), MucError> {
+        let Some(stanza_id) = self.room_stanza_id(room, message_id).await? else {
+            return Err(MucError::NoStanzaId {
+                room: room.to_string(),
+                id: message_id.to_string(),
+            });
+        };
+
+        #[cfg(feature = "native")]
+        {
+            let _ = self.event_bus.publish(Event::new(
+                Channel::new("ui.muc.moderate").unwrap(),
+                EventSource::System("muc".into()),
+                EventPayload::MucModerateRequested {
+                    room: room.to_string(),
+                    id: stanza_id,
+                    reason: reason.map(str::to_string),
+                },
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Everyone holding `affiliation` in `room`, present or not, with the
+    /// reason recorded for each. Rooms usually only answer owners and admins.
+    #[cfg(feature = "native")]
+    pub async fn fetch_affiliation_list(
+        &self,
+        room: &str,
+        affiliation: MucAffiliation,
+    ) -> Result<Vec<(String, Option<String>)>, MucError> {
+        let query_id = Uuid::new_v4().to_string();
+        let request = EventPayload::MucAffiliationListRequested {
+            query_id: query_id.clone(),
+            room: room.to_string(),
+            affiliation,
+        };
+        self.query_room(
+            room,
+            &query_id,
+            "ui.muc.affiliations.query",
+            request,
+            |payload| match payload {
+                EventPayload::MucAffiliationListReceived {
+                    query_id: answered,
+                    items,
+                    ..
+                } if *answered == query_id => Some(items.clone()),
+                _ => None,
+            },
+        )
+        .await
+    }
+
+    /// What `room` advertises via disco#info, so the UI can ask for a
+    /// password or warn before creating a room. Answers are cached until we
+    /// join the room or it is destroyed.
+    #[cfg(feature = "native")]
+    pub async fn room_info(&self, room: &str) -> Result<MucRoomInfo, MucError> {
+        if let Some(info) = self.room_info.read().unwrap().get(room) {
+            return Ok(info.clone());
+        }
+
+        let query_id = Uuid::new_v4().to_string();
+        let request = EventPayload::MucRoomInfoRequested {
+            query_id: query_id.clone(),
+            room: room.to_string(),
+        };
+        let answer = self
+            .query_room(
+                room,
+                &query_id,
+                "ui.muc.info.query",
+                request,
+                |payload| match payload {
+                    EventPayload::MucRoomInfoReceived {
+                        query_id: answered,
+                        info,
+                        ..
+                    } if *answered == query_id => Some(info.clone()),
+                    _ => None,
+                },
+            )
+            .await;
+        let info = match answer {
+            Ok(info) => info,
+            Err(MucError::QueryFailed { condition, .. }) if condition == "item-not-found" => {
+                MucRoomInfo::default()
+            }
+            Err(e) => return Err(e),
+        };
+
+        self.room_info
+            .write()
+            .unwrap()
+            .insert(room.to_string(), info.clone());
+        Ok(info)
+    }
+
+    /// Publishes `request` and waits for the room's answer to `query_id`,
+    /// which `answer` picks out of the `xmpp.muc.*` events. An IQ error from
+    /// the room becomes `MucError::QueryFailed`.
+    #[cfg(feature = "native")]
+    async fn query_room<T>(
+        &self,
+        room: &str,
+        query_id: &str,
+        channel: &str,
+        request: EventPayload,
+        answer: impl Fn(&EventPayload) -> Option<T>,
+    ) -> Result<T, MucError> {
+        let mut sub = self
+            .event_bus
+            .subscribe("xmpp.muc.**")
+            .map_err(|e| MessagingError::EventBus(e.to_string()))?;
+
+        self.event_bus
+            .publish(Event::new(
+                Channel::new(channel).unwrap(),
+                EventSource::System("muc".into()),
+                request,
+            ))
+            .map_err(|e| MessagingError::EventBus(e.to_string()))?;
+
+        let deadline =
+            tokio::time::Instant::now() + tokio::time::Duration::from_secs(MUC_QUERY_TIMEOUT_SECS);
+        loop {
+            match tokio::time::timeout_at(deadline, sub.recv()).await {
+                Ok(Ok(event)) => {
+                    if let Some(value) = answer(&event.payload) {
+                        return Ok(value);
+                    }
+                    if let EventPayload::MucQueryFailed {
+                        query_id: failed,
+                        condition,
+                        ..
+                    } = event.payload
+                        && failed == query_id
+                    {
+                        return Err(MucError::QueryFailed {
+                            room: room.to_string(),
+                            condition,
+                        });
+                    }
+                }
+                Ok(Err(waddle_core::error::EventBusError::Lagged(count))) => {
+                    warn!(count, room = %room, "MUC query collector lagged");
+                }
+                Ok(Err(e)) => return Err(MessagingError::EventBus(e.to_string()).into()),
+                Err(_) => return Err(MucError::Timeout(room.to_string())),
+            }
+        }
+    }
+
+    pub async fn get_rooms(&self) -> Result<Vec<MucRoom>, MucError> {
+        let rows: Vec<StoredRoom> = self
+            .db
+            .query(
+                "SELECT room_jid, nick, joined, subject, destroyed, last_activity FROM muc_rooms \
+                 ORDER BY last_activity DESC, room_jid",
+                &[],
+            )
+            .await?;
+
+        Ok(rows
+            .into_iter()
+            .map(|r| {
+                let anonymity = self.room_anonymity(&r.room_jid);
+                r.into_muc_room(anonymity)
+            })
+            .collect())
+    }
+
+    pub async fn get_joined_rooms(&self) -> Result<Vec<MucRoom>, MucError> {
+        let joined = 1_i64;
+        let rows: Vec<StoredRoom> = self
+            .db
+            .query(
+                "SELECT room_jid, nick, joined, subject, destroyed, last_activity FROM muc_rooms \
+                 WHERE joined = ?1 ORDER BY last_activity DESC, room_jid",
+                &[&joined],
+            )
+            .await?;
+
+        Ok(rows
+            .into_iter()
+            .map(|r| {
+                let anonymity = self.room_anonymity(&r.room_jid);
+                r.into_muc_room(anonymity)
+            })
+            .collect())
     }
 
     pub async fn get_room_messages(
@@ -1039,94 +3165,99 @@ impl<D: Database> MucManager<D> {
         room: &str,
         limit: u32,
         before: Option<&str>,
-    ) -> Result<Vec<ChatMessage>, MessagingError> {
-        let room_s = room.to_string();
-        let limit_i = i64::from(limit);
+    ) -> Result<Vec<ChatMessage>, MucError> {
+        let conversation = ConversationId::Room(room.to_string());
+        Ok(paginate(self.db.as_ref(), &conversation, limit, before).await?)
+    }
 
-        let rows: Vec<StoredMessage> = if let Some(before_ts) = before {
-            let before_s = before_ts.to_string();
-            self.db
-                .query(
-                    "SELECT id, from_jid, to_jid, body, timestamp, message_type, thread \
-                     FROM messages \
-                     WHERE to_jid = ?1 AND message_type = 'groupchat' AND timestamp < ?2 \
-                     ORDER BY timestamp DESC \
-                     LIMIT ?3",
-                    &[&room_s, &before_s, &limit_i],
-                )
-                .await?
-        } else {
-            self.db
-                .query(
-                    "SELECT id, from_jid, to_jid, body, timestamp, message_type, thread \
-                     FROM messages \
-                     WHERE to_jid = ?1 AND message_type = 'groupchat' \
-                     ORDER BY timestamp DESC \
-                     LIMIT ?2",
-                    &[&room_s, &limit_i],
-                )
-                .await?
-        };
+    /// Next suffixed nick to try for a pending join of `room`, or `None` once
+    /// the retries are used up.
+    #[cfg(feature = "native")]
+    fn next_conflict_nick(&self, room: &str) -> Option<String> {
+        let retries = *self.nick_conflict_retries.read().unwrap();
+        let mut pending_joins = self.pending_joins.write().unwrap();
+        let pending = pending_joins.get_mut(room)?;
+        if pending.attempt > retries {
+            pending_joins.remove(room);
+            return None;
+        }
+        pending.attempt += 1;
+        Some(format!("{}_{}", pending.requested_nick, pending.attempt))
+    }
 
-        Ok(rows.into_iter().map(|r| r.into_chat_message()).collect())
+    /// Takes the reason the room refused our latest join, if it did.
+    pub fn take_join_error(&self, room: &str) -> Option<MucError> {
+        self.join_errors.write().unwrap().remove(room)
     }
 
+    /// Occupants of `room`, owners first, then admins, members, unaffiliated
+    /// occupants and outcasts; each group sorted by nick, ignoring case.
     pub fn get_occupants(&self, room: &str) -> Vec<MucOccupant> {
         let occupants = self.occupants.read().unwrap();
-        match occupants.get(room) {
-            Some(map) => map.values().cloned().collect(),
+        let mut list: Vec<MucOccupant> = match occupants.get(room) {
+            Some(map) => map.values().map(|t| t.occupant.clone()).collect(),
             None => Vec::new(),
-        }
+        };
+        list.sort_by(|a, b| {
+            affiliation_rank(&a.affiliation)
+                .cmp(&affiliation_rank(&b.affiliation))
+                .then_with(|| a.nick.to_lowercase().cmp(&b.nick.to_lowercase()))
+                .then_with(|| a.nick.cmp(&b.nick))
+        });
+        list
     }
 
-    async fn persist_message(&self, message: &ChatMessage) -> Result<(), MessagingError> {
-        let id = message.id.clone();
-        let from = message.from.clone();
-        let to = message.to.clone();
-        let body = message.body.clone();
-        let ts = message.timestamp.to_rfc3339();
-        let mt = message_type_to_str(&message.message_type).to_string();
-        let thread = message.thread.clone();
-        let read = 0_i64;
+    /// Occupants of `room` whose nick starts with `prefix`, ignoring case,
+    /// sorted by nick for mention autocomplete.
+    pub fn occupants_matching(&self, room: &str, prefix: &str) -> Vec<MucOccupant> {
+        let prefix = prefix.to_lowercase();
+        let occupants = self.occupants.read().unwrap();
+        let mut matches: Vec<MucOccupant> = occupants
+            .get(room)
+            .map(|map| {
+                map.values()
+                    .map(|t| &t.occupant)
+                    .filter(|occupant| occupant.nick.to_lowercase().starts_with(&prefix))
+                    .cloned()
+                    .collect()
+            })
+            .unwrap_or_default();
+        matches.sort_by_key(|occupant| occupant.nick.to_lowercase());
+        matches
+    }
 
-        let embeds = if message.embeds.is_empty() {
-            None
-        } else {
-            Some(serde_json::to_string(&message.embeds).unwrap_or_default())
-        };
+    /// Rooms count as non-anonymous once any occupant presence carried a
+    /// real JID; with occupants but no JIDs they are semi-anonymous.
+    pub fn room_anonymity(&self, room: &str) -> RoomAnonymity {
+        let occupants = self.occupants.read().unwrap();
+        match occupants.get(room) {
+            Some(map) if map.values().any(|t| t.occupant.jid.is_some()) => {
+                RoomAnonymity::NonAnonymous
+            }
+            Some(map) if !map.is_empty() => RoomAnonymity::SemiAnonymous,
+            _ => RoomAnonymity::Unknown,
+        }
+    }
 
-        self.db
-            .execute(
-                "INSERT INTO messages (id, from_jid, to_jid, body, timestamp, message_type, thread, read, embeds) \
-                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9) \
-                 ON CONFLICT(id) DO UPDATE SET \
-                 from_jid = CASE \
-                    WHEN TRIM(COALESCE(excluded.from_jid, '')) = '' THEN messages.from_jid \
-                    WHEN TRIM(COALESCE(messages.from_jid, '')) = '' THEN excluded.from_jid \
-                    WHEN LENGTH(excluded.from_jid) > LENGTH(messages.from_jid) THEN excluded.from_jid \
-                    ELSE messages.from_jid \
-                 END, \
-                 to_jid = CASE \
-                    WHEN TRIM(COALESCE(excluded.to_jid, '')) = '' THEN messages.to_jid \
-                    WHEN TRIM(COALESCE(messages.to_jid, '')) = '' THEN excluded.to_jid \
-                    WHEN LENGTH(excluded.to_jid) > LENGTH(messages.to_jid) THEN excluded.to_jid \
-                    ELSE messages.to_jid \
-                 END, \
-                 thread = CASE \
-                    WHEN TRIM(COALESCE(excluded.thread, '')) = '' THEN messages.thread \
-                    WHEN TRIM(COALESCE(messages.thread, '')) = '' THEN excluded.thread \
-                    WHEN LENGTH(COALESCE(excluded.thread, '')) > LENGTH(COALESCE(messages.thread, '')) THEN excluded.thread \
-                    ELSE messages.thread \
-                 END, \
-                 embeds = CASE \
-                    WHEN excluded.embeds IS NULL OR TRIM(excluded.embeds) = '' OR excluded.embeds = '[]' THEN messages.embeds \
-                    WHEN messages.embeds IS NULL OR TRIM(messages.embeds) = '' OR messages.embeds = '[]' THEN excluded.embeds \
-                    WHEN LENGTH(excluded.embeds) > LENGTH(COALESCE(messages.embeds, '')) THEN excluded.embeds \
-                    ELSE messages.embeds \
-                 END",
-                &[&id, &from, &to, &body, &ts, &mt, &thread, &read, &embeds],
-            )
-            .await?;
+    /// Nicks of occupants currently composing in `room`, sorted. Entries
+    /// older than the typing expiry are stale and get dropped.
+    pub fn typers(&self, room: &str) -> Vec<String> {
+        let mut typers = self.typers.write().unwrap();
+        prune_typers(&mut typers);
+        let mut nicks: Vec<String> = typers
+            .get(room)
+            .map(|map| map.keys().cloned().collect())
+            .unwrap_or_default();
+        nicks.sort();
+        nicks
+    }
+
+    async fn persist_message(
+        &self,
+        message: &ChatMessage,
+        source: MessageSource,
+    ) -> Result<(), MucError> {
+        self.messages.persist(message, source).await?;
         Ok(())
     }
 
@@ -1134,14 +3265,173 @@ impl<D: Database> MucManager<D> {
         &self,
         room: &str,
         message: &ChatMessage,
-    ) -> Result<(), MessagingError> {
+    ) -> Result<(), MucError> {
         let mut normalized = message.clone();
         normalized.to = room.to_string();
         normalized.message_type = MessageType::Groupchat;
-        self.persist_message(&normalized).await
+        // Rooms send their discussion history between our join and the subject.
+        let source = if self.replaying_rooms.read().unwrap().contains(room) {
+            MessageSource::MucHistory
+        } else {
+            MessageSource::Live
+        };
+        self.persist_message(&normalized, source).await
+    }
+
+    /// Keeps the row, so history paging is unaffected, but drops its content.
+    /// The stanza-id `room` assigned to the stored message `id`.
+    async fn room_stanza_id(&self, room: &str, id: &str) -> Result<Option<String>, MucError> {
+        let room_s = room.to_string();
+        let id_s = id.to_string();
+        let groupchat = message_type_param(&MessageType::Groupchat);
+        let rows: Vec<Row> = self
+            .db
+            .query(
+                "SELECT stanza_id FROM messages \
+                 WHERE id = ?1 AND to_jid = ?2 AND message_type = ?3",
+                &[&id_s, &room_s, &groupchat],
+            )
+            .await?;
+        Ok(rows.first().and_then(|row| match row.get(0) {
+            Some(SqlValue::Text(stanza_id)) => Some(stanza_id.clone()),
+            _ => None,
+        }))
+    }
+
+    /// Tombstones the message `room` knows as `stanza_id`, returning its
+    /// local id when we had it stored.
+    async fn tombstone_room_message(
+        &self,
+        room: &str,
+        stanza_id: &str,
+    ) -> Result<Option<String>, MucError> {
+        let room_s = room.to_string();
+        let stanza_id_s = stanza_id.to_string();
+        let retracted = 1_i64;
+        let groupchat = message_type_param(&MessageType::Groupchat);
+
+        let rows: Vec<Row> = self
+            .db
+            .query(
+                "SELECT id FROM messages \
+                 WHERE stanza_id = ?1 AND to_jid = ?2 AND message_type = ?3",
+                &[&stanza_id_s, &room_s, &groupchat],
+            )
+            .await?;
+        let Some(SqlValue::Text(id)) = rows.first().and_then(|row| row.get(0)).cloned() else {
+            return Ok(None);
+        };
+
+        self.db
+            .execute(
+                "UPDATE messages SET body = '', embeds = NULL, bodies = NULL, retracted = ?1 \
+                 WHERE id = ?2",
+                &[&retracted, &id],
+            )
+            .await?;
+        Ok(Some(id))
+    }
+
+    async fn room_nick(&self, room: &str) -> Result<Option<String>, MucError> {
+        let room_s = room.to_string();
+        let rows: Vec<Row> = self
+            .db
+            .query("SELECT nick FROM muc_rooms WHERE room_jid = ?1", &[&room_s])
+            .await?;
+
+        Ok(rows.first().and_then(|row| match row.get(0) {
+            Some(SqlValue::Text(nick)) => Some(nick.clone()),
+            _ => None,
+        }))
+    }
+
+    /// Claims the pending optimistic send that `message` reflects, if it was
+    /// sent by us under our current room nick.
+    async fn take_pending_echo(&self, room: &str, message: &ChatMessage) -> Result<bool, MucError> {
+        let is_pending = self
+            .pending_echoes
+            .read()
+            .unwrap()
+            .get(&message.id)
+            .is_some_and(|pending| pending.room == room);
+        if !is_pending {
+            return Ok(false);
+        }
+
+        let sender_nick = message.from.split_once('/').map(|(_, nick)| nick);
+        let own_nick = self.room_nick(room).await?;
+        if sender_nick.is_none() || sender_nick != own_nick.as_deref() {
+            return Ok(false);
+        }
+
+        Ok(self
+            .pending_echoes
+            .write()
+            .unwrap()
+            .remove(&message.id)
+            .is_some())
+    }
+
+    /// Drops what only held for the lost session: occupants, typers, and
+    /// joins, history replays and nick changes still in progress. Unanswered
+    /// joins are kept aside so that `rejoin_all` asks for them again.
+    fn forget_session(&self) {
+        let interrupted: Vec<(String, PendingJoin)> =
+            self.pending_joins.write().unwrap().drain().collect();
+        self.interrupted_joins.write().unwrap().extend(
+            interrupted
+                .into_iter()
+                .map(|(room, pending)| (room, pending.requested_nick)),
+        );
+        self.occupants.write().unwrap().clear();
+        self.typers.write().unwrap().clear();
+        self.replaying_rooms.write().unwrap().clear();
+        self.loading_rosters.write().unwrap().clear();
+        self.renamed_rooms.write().unwrap().clear();
+    }
+
+    /// Starts the echo timers of sends that were queued while offline; the
+    /// reconnect drains them from the offline queue.
+    #[cfg(feature = "native")]
+    fn arm_queued_echoes(&self) {
+        let queued: Vec<(String, String)> = self
+            .pending_echoes
+            .write()
+            .unwrap()
+            .iter_mut()
+            .filter(|(_, pending)| !pending.armed)
+            .map(|(id, pending)| {
+                pending.armed = true;
+                (id.clone(), pending.room.clone())
+            })
+            .collect();
+        for (id, room) in queued {
+            self.expire_pending_echo(id, room);
+        }
+    }
+
+    /// Reports the send `id` as not delivered if the room has not reflected
+    /// it once the echo timeout has passed.
+    #[cfg(feature = "native")]
+    fn expire_pending_echo(&self, id: String, room: String) {
+        let timeout = *self.echo_timeout.read().unwrap();
+        let pending_echoes = self.pending_echoes.clone();
+        let event_bus = self.event_bus.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(timeout).await;
+            if pending_echoes.write().unwrap().remove(&id).is_none() {
+                return;
+            }
+            warn!(room = %room, id = %id, "MUC message not reflected in time");
+            let _ = event_bus.publish(Event::new(
+                Channel::new("ui.muc.not_delivered").unwrap(),
+                EventSource::System("muc".into()),
+                EventPayload::MessageNotDelivered { id, to: room },
+            ));
+        });
     }
 
-    async fn mark_room_joined(&self, room: &str, nick: &str) -> Result<(), MessagingError> {
+    async fn mark_room_joined(&self, room: &str, nick: &str) -> Result<(), MucError> {
         let room_s = room.to_string();
         let nick_s = nick.to_string();
         let joined = 1_i64;
@@ -1149,16 +3439,60 @@ impl<D: Database> MucManager<D> {
 
         self.db
             .execute(
-                "INSERT OR REPLACE INTO muc_rooms (room_jid, nick, joined, subject) \
+                "INSERT OR REPLACE INTO muc_rooms (room_jid, nick, joined, subject, last_activity) \
                  VALUES (?1, ?2, ?3, \
-                 COALESCE((SELECT subject FROM muc_rooms WHERE room_jid = ?1), ?4))",
+                 COALESCE((SELECT subject FROM muc_rooms WHERE room_jid = ?1), ?4), \
+                 (SELECT last_activity FROM muc_rooms WHERE room_jid = ?1))",
                 &[&room_s, &nick_s, &joined, &subject],
             )
             .await?;
         Ok(())
     }
 
-    async fn mark_room_left(&self, room: &str) -> Result<(), MessagingError> {
+    /// A room only sends us messages once it has let us in, so a message for
+    /// a join still waiting on its self-presence marks the room joined. The
+    /// room counts as replaying history, as the server sends history right
+    /// after the self-presence. If the join is refused after all, the
+    /// failure handler marks the room left again.
+    #[cfg(feature = "native")]
+    async fn confirm_join_by_message(&self, room: &str) {
+        if !self.pending_joins.read().unwrap().contains_key(room)
+            || !self
+                .replaying_rooms
+                .write()
+                .unwrap()
+                .insert(room.to_string())
+        {
+            return;
+        }
+        debug!(room = %room, "MUC message before join confirmation, marking room joined");
+        let room_s = room.to_string();
+        let joined = 1_i64;
+        if let Err(e) = self
+            .db
+            .execute(
+                "UPDATE muc_rooms SET joined = ?1 WHERE room_jid = ?2",
+                &[&joined, &room_s],
+            )
+            .await
+        {
+            error!(error = %e, room = %room, "failed to persist early room join");
+        }
+    }
+
+    async fn update_nick(&self, room: &str, nick: &str) -> Result<(), MucError> {
+        let room_s = room.to_string();
+        let nick_s = nick.to_string();
+        self.db
+            .execute(
+                "UPDATE muc_rooms SET nick = ?1 WHERE room_jid = ?2",
+                &[&nick_s, &room_s],
+            )
+            .await?;
+        Ok(())
+    }
+
+    async fn mark_room_left(&self, room: &str) -> Result<(), MucError> {
         let room_s = room.to_string();
         let joined = 0_i64;
 
@@ -1170,10 +3504,28 @@ impl<D: Database> MucManager<D> {
             .await?;
 
         self.occupants.write().unwrap().remove(room);
+        self.typers.write().unwrap().remove(room);
+        Ok(())
+    }
+
+    async fn mark_room_destroyed(&self, room: &str) -> Result<(), MucError> {
+        let room_s = room.to_string();
+        let joined = 0_i64;
+        let destroyed = 1_i64;
+
+        self.db
+            .execute(
+                "UPDATE muc_rooms SET joined = ?1, destroyed = ?2 WHERE room_jid = ?3",
+                &[&joined, &destroyed, &room_s],
+            )
+            .await?;
+
+        self.occupants.write().unwrap().remove(room);
+        self.typers.write().unwrap().remove(room);
         Ok(())
     }
 
-    async fn update_subject(&self, room: &str, subject: &str) -> Result<(), MessagingError> {
+    async fn update_subject(&self, room: &str, subject: &str) -> Result<(), MucError> {
         let room_s = room.to_string();
         let subject_s = subject.to_string();
 
@@ -1186,32 +3538,227 @@ impl<D: Database> MucManager<D> {
         Ok(())
     }
 
+    fn track_chat_state(&self, room: &str, nick: &str, state: &ChatState) {
+        let mut typers = self.typers.write().unwrap();
+        if matches!(state, ChatState::Composing) {
+            typers
+                .entry(room.to_string())
+                .or_default()
+                .insert(nick.to_string(), Utc::now());
+        } else if let Some(room_typers) = typers.get_mut(room) {
+            room_typers.remove(nick);
+        }
+        prune_typers(&mut typers);
+    }
+
+    fn clear_typer(&self, room: &str, nick: &str) {
+        let mut typers = self.typers.write().unwrap();
+        if let Some(room_typers) = typers.get_mut(room) {
+            room_typers.remove(nick);
+        }
+        prune_typers(&mut typers);
+    }
+
     fn track_occupant(&self, room: &str, occupant: &MucOccupant) {
         let mut occupants = self.occupants.write().unwrap();
         let room_occupants = occupants.entry(room.to_string()).or_default();
 
         if matches!(occupant.role, MucRole::None) {
             room_occupants.remove(&occupant.nick);
-        } else {
-            room_occupants.insert(occupant.nick.clone(), occupant.clone());
+            return;
+        }
+
+        room_occupants.insert(
+            occupant.nick.clone(),
+            TrackedOccupant {
+                occupant: occupant.clone(),
+                last_active: self.activity_clock.fetch_add(1, Ordering::Relaxed),
+            },
+        );
+
+        let Some(max) = *self.max_occupants.read().unwrap() else {
+            return;
+        };
+        if room_occupants.len() <= max {
+            return;
+        }
+        let mut evictable: Vec<(u64, String)> = room_occupants
+            .iter()
+            .filter(|(_, tracked)| !tracked.is_privileged())
+            .map(|(nick, tracked)| (tracked.last_active, nick.clone()))
+            .collect();
+        evictable.sort();
+        let excess = room_occupants.len() - max;
+        for (_, nick) in evictable.into_iter().take(excess) {
+            debug!(room = %room, nick = %nick, "evicting idle occupant past the cap");
+            room_occupants.remove(&nick);
+        }
+    }
+
+    /// Moves an occupant's entry to their new nick, keeping its activity.
+    fn rename_occupant(&self, room: &str, old_nick: &str, new_nick: &str) {
+        let mut occupants = self.occupants.write().unwrap();
+        let Some(room_occupants) = occupants.get_mut(room) else {
+            return;
+        };
+        if let Some(mut tracked) = room_occupants.remove(old_nick) {
+            tracked.occupant.nick = new_nick.to_string();
+            room_occupants.insert(new_nick.to_string(), tracked);
+        }
+    }
+
+    /// Marks an occupant as just active, keeping them clear of eviction.
+    fn touch_occupant(&self, room: &str, nick: &str) {
+        if let Some(tracked) = self
+            .occupants
+            .write()
+            .unwrap()
+            .get_mut(room)
+            .and_then(|room_occupants| room_occupants.get_mut(nick))
+        {
+            tracked.last_active = self.activity_clock.fetch_add(1, Ordering::Relaxed);
         }
     }
 
     #[cfg(feature = "native")]
+    #[instrument(skip_all, fields(channel = %event.channel, correlation_id = event.correlation_id.map(field::display)))]
     pub async fn handle_event(&self, event: &Event) {
         match &event.payload {
-            EventPayload::MucJoined { room, nick } => {
-                debug!(room = %room, nick = %nick, "joined MUC room");
-                if let Err(e) = self.mark_room_joined(room, nick).await {
+            EventPayload::ConnectionEstablished { .. } => {
+                self.online.store(true, Ordering::Relaxed);
+                match self.rejoin_all().await {
+                    Ok(0) => {}
+                    Ok(requested) => info!(rooms = requested, "rejoining MUC rooms"),
+                    Err(e) => error!(error = %e, "failed to rejoin MUC rooms"),
+                }
+                self.arm_queued_echoes();
+            }
+            EventPayload::ConnectionLost { .. } => {
+                self.online.store(false, Ordering::Relaxed);
+                self.forget_session();
+            }
+            EventPayload::MucJoined { room, nick } => {
+                let renamed = self.renamed_rooms.write().unwrap().remove(room);
+                if renamed.as_ref() == Some(nick) {
+                    // The self-presence that follows a nick change, not a
+                    // fresh join.
+                    debug!(room = %room, nick = %nick, "MUC nick change completed");
+                    return;
+                }
+                debug!(room = %room, nick = %nick, "joined MUC room");
+                if let Err(e) = self.mark_room_joined(room, nick).await {
                     error!(error = %e, room = %room, "failed to persist room join");
                 }
+                self.replaying_rooms.write().unwrap().insert(room.clone());
+                self.loading_rosters
+                    .write()
+                    .unwrap()
+                    .insert(room.clone(), nick.clone());
+                // Joining may have created the room.
+                self.room_info.write().unwrap().remove(room);
+                let pending = self.pending_joins.write().unwrap().remove(room);
+                if let Some(pending) = pending
+                    && pending.requested_nick != *nick
+                {
+                    info!(room = %room, nick = %nick, "joined MUC room under a substitute nick");
+                    let _ = self.event_bus.publish(Event::new(
+                        Channel::new("ui.muc.nick_assigned").unwrap(),
+                        EventSource::System("muc".into()),
+                        EventPayload::MucJoined {
+                            room: room.clone(),
+                            nick: nick.clone(),
+                        },
+                    ));
+                }
+            }
+            EventPayload::MucJoinFailed {
+                room,
+                nick,
+                failure,
+            } => {
+                // An error presence also ends a nick change in progress.
+                self.renamed_rooms.write().unwrap().remove(room);
+                if *failure == MucJoinFailure::NickConflict
+                    && let Some(next_nick) = self.next_conflict_nick(room)
+                {
+                    info!(room = %room, nick = %nick, next_nick = %next_nick, "MUC nick taken, retrying join");
+                    if let Err(e) = self.request_join(room, &next_nick).await {
+                        error!(error = %e, room = %room, "failed to retry MUC join");
+                    }
+                    return;
+                }
+                let pending = self.pending_joins.write().unwrap().remove(room);
+                let replaying = self.replaying_rooms.write().unwrap().remove(room);
+                // Covers rejoins, which keep the room joined until refused,
+                // and joins already confirmed by an early message.
+                if (pending.is_some() || replaying)
+                    && let Err(e) = self.mark_room_left(room).await
+                {
+                    error!(error = %e, room = %room, "failed to mark refused room left");
+                }
+                let join_error = MucError::join_failed(room, nick, failure);
+                warn!(error = %join_error, room = %room, "MUC join refused");
+                self.join_errors
+                    .write()
+                    .unwrap()
+                    .insert(room.clone(), join_error);
+                let _ = self.event_bus.publish(Event::new(
+                    Channel::new("ui.muc.join.failed").unwrap(),
+                    EventSource::System("muc".into()),
+                    EventPayload::MucJoinFailed {
+                        room: room.clone(),
+                        nick: nick.clone(),
+                        failure: failure.clone(),
+                    },
+                ));
             }
-            EventPayload::MucLeft { room } => {
+            EventPayload::MucLeft { room, removal } => {
                 debug!(room = %room, "left MUC room");
+                self.replaying_rooms.write().unwrap().remove(room);
+                self.loading_rosters.write().unwrap().remove(room);
+                self.renamed_rooms.write().unwrap().remove(room);
+                if let Some(removal) = removal {
+                    info!(
+                        room = %room,
+                        code = removal.code,
+                        actor = ?removal.actor,
+                        "removed from MUC room"
+                    );
+                    let _ = self.event_bus.publish(Event::new(
+                        Channel::new("ui.muc.removed").unwrap(),
+                        EventSource::System("muc".into()),
+                        EventPayload::MucRemoved {
+                            room: room.clone(),
+                            code: removal.code,
+                            reason: removal.reason.clone(),
+                            actor: removal.actor.clone(),
+                        },
+                    ));
+                }
                 if let Err(e) = self.mark_room_left(room).await {
                     error!(error = %e, room = %room, "failed to persist room leave");
                 }
             }
+            EventPayload::MucDestroyed {
+                room,
+                reason,
+                alternate,
+            } => {
+                info!(room = %room, alternate = ?alternate, "MUC room destroyed");
+                self.room_info.write().unwrap().remove(room);
+                if let Err(e) = self.mark_room_destroyed(room).await {
+                    error!(error = %e, room = %room, "failed to persist room destruction");
+                }
+                let _ = self.event_bus.publish(Event::new(
+                    Channel::new("ui.muc.destroyed").unwrap(),
+                    EventSource::System("muc".into()),
+                    EventPayload::MucDestroyed {
+                        room: room.clone(),
+                        reason: reason.clone(),
+                        alternate: alternate.clone(),
+                    },
+                ));
+            }
             EventPayload::MucMessageReceived { room, message } => {
                 debug!(
                     room = %room,
@@ -1219,12 +3766,108 @@ impl<D: Database> MucManager<D> {
                     from = %message.from,
                     "MUC message received, persisting"
                 );
-                if let Err(e) = self.persist_room_message(room, message).await {
-                    error!(error = %e, room = %room, "failed to persist MUC message");
+                self.confirm_join_by_message(room).await;
+                match self.take_pending_echo(room, message).await {
+                    Ok(true) => {
+                        debug!(room = %room, id = %message.id, "own MUC message reflected");
+                        let _ = self.event_bus.publish(Event::new(
+                            Channel::new("ui.muc.delivered").unwrap(),
+                            EventSource::System("muc".into()),
+                            EventPayload::MessageDelivered {
+                                id: message.id.clone(),
+                                to: room.clone(),
+                                resource: None,
+                            },
+                        ));
+                        return;
+                    }
+                    Ok(false) => {}
+                    Err(e) => {
+                        error!(error = %e, room = %room, "failed to check MUC reflection");
+                    }
+                }
+                if let Some((_, nick)) = message.from.split_once('/') {
+                    self.clear_typer(room, nick);
+                    self.touch_occupant(room, nick);
+                }
+                let policy = *self.persist_policy.read().unwrap();
+                persist_with_policy(policy, self.event_bus.as_ref(), &message.id, || {
+                    self.persist_room_message(room, message)
+                })
+                .await;
+            }
+            EventPayload::MessageModerated {
+                room,
+                id,
+                by,
+                reason,
+            } => {
+                info!(room = %room, stanza_id = %id, by = ?by, "MUC message moderated");
+                let local_id = match self.tombstone_room_message(room, id).await {
+                    Ok(Some(local_id)) => local_id,
+                    Ok(None) => {
+                        debug!(room = %room, stanza_id = %id, "moderated message not stored");
+                        return;
+                    }
+                    Err(e) => {
+                        error!(error = %e, room = %room, "failed to tombstone moderated message");
+                        return;
+                    }
+                };
+                let _ = self.event_bus.publish(Event::new(
+                    Channel::new("ui.muc.moderated").unwrap(),
+                    EventSource::System("muc".into()),
+                    EventPayload::MessageModerated {
+                        room: room.clone(),
+                        id: local_id,
+                        by: by.clone(),
+                        reason: reason.clone(),
+                    },
+                ));
+            }
+            EventPayload::MucChatStateReceived { room, nick, state } => {
+                match self.room_nick(room).await {
+                    Ok(own_nick) if own_nick.as_deref() == Some(nick.as_str()) => return,
+                    Ok(_) => {}
+                    Err(e) => {
+                        error!(error = %e, room = %room, "failed to look up own room nick");
+                    }
+                }
+                debug!(room = %room, nick = %nick, state = ?state, "MUC chat state received");
+                self.track_chat_state(room, nick, state);
+                self.touch_occupant(room, nick);
+                let _ = self.event_bus.publish(Event::new(
+                    Channel::new("ui.muc.chatstate.received").unwrap(),
+                    EventSource::System("muc".into()),
+                    EventPayload::MucChatStateReceived {
+                        room: room.clone(),
+                        nick: nick.clone(),
+                        state: state.clone(),
+                    },
+                ));
+            }
+            EventPayload::MucNickChanged {
+                room,
+                old_nick,
+                new_nick,
+                own,
+            } => {
+                debug!(room = %room, old_nick = %old_nick, new_nick = %new_nick, "MUC nick changed");
+                self.rename_occupant(room, old_nick, new_nick);
+                self.clear_typer(room, old_nick);
+                if *own {
+                    if let Err(e) = self.update_nick(room, new_nick).await {
+                        error!(error = %e, room = %room, "failed to persist nick change");
+                    }
+                    self.renamed_rooms
+                        .write()
+                        .unwrap()
+                        .insert(room.clone(), new_nick.clone());
                 }
             }
             EventPayload::MucSubjectChanged { room, subject } => {
                 debug!(room = %room, subject = %subject, "MUC subject changed");
+                self.replaying_rooms.write().unwrap().remove(room);
                 if let Err(e) = self.update_subject(room, subject).await {
                     error!(error = %e, room = %room, "failed to persist subject change");
                 }
@@ -1236,16 +3879,35 @@ impl<D: Database> MucManager<D> {
                     "MUC occupant changed"
                 );
                 self.track_occupant(room, occupant);
+                if matches!(occupant.role, MucRole::None) {
+                    self.clear_typer(room, &occupant.nick);
+                }
+                let completes_roster = {
+                    let mut loading = self.loading_rosters.write().unwrap();
+                    if loading.get(room) == Some(&occupant.nick) {
+                        loading.remove(room);
+                        true
+                    } else {
+                        false
+                    }
+                };
+                if completes_roster {
+                    let _ = self.event_bus.publish(Event::new(
+                        Channel::new("ui.muc.roster_complete").unwrap(),
+                        EventSource::System("muc".into()),
+                        EventPayload::MucRosterComplete { room: room.clone() },
+                    ));
+                }
             }
             _ => {}
         }
     }
 
     #[cfg(feature = "native")]
-    pub async fn run(self: Arc<Self>) -> Result<(), MessagingError> {
+    pub async fn run(self: Arc<Self>) -> Result<(), MucError> {
         let mut sub = self
             .event_bus
-            .subscribe("xmpp.muc.**")
+            .subscribe("{system,xmpp}.**")
             .map_err(|e| MessagingError::EventBus(e.to_string()))?;
 
         loop {
@@ -1262,7 +3924,7 @@ impl<D: Database> MucManager<D> {
                 }
                 Err(e) => {
                     error!(error = %e, "MUC manager subscription error");
-                    return Err(MessagingError::EventBus(e.to_string()));
+                    return Err(MessagingError::EventBus(e.to_string()).into());
                 }
             }
         }
@@ -1277,6 +3939,7 @@ mod tests {
     use std::sync::Arc;
     use tempfile::TempDir;
     use waddle_core::event::{BroadcastEventBus, EventBus, MessageEmbed};
+    use waddle_storage::{MockDatabase, MockFailure};
 
     async fn setup() -> (
         Arc<MessageManager<impl Database>>,
@@ -1302,6 +3965,10 @@ mod tests {
         )
     }
 
+    fn direct(jid: &str) -> ConversationId {
+        ConversationId::Direct(jid.to_string())
+    }
+
     fn make_chat_message(id: &str, from: &str, to: &str, body: &str) -> ChatMessage {
         ChatMessage {
             id: id.to_string(),
@@ -1312,6 +3979,10 @@ mod tests {
             message_type: MessageType::Chat,
             thread: None,
             embeds: vec![],
+            bodies: BTreeMap::new(),
+            origin_id: None,
+            spoiler: None,
+            stanza_id: None,
         }
     }
 
@@ -1329,7 +4000,7 @@ mod tests {
     async fn get_messages_empty() {
         let (manager, _, _dir) = setup().await;
         let messages = manager
-            .get_messages("alice@example.com", 50, None)
+            .get_messages(&direct("alice@example.com"), 50, None)
             .await
             .unwrap();
         assert!(messages.is_empty());
@@ -1370,501 +4041,2129 @@ mod tests {
     }
 
     #[tokio::test]
-    async fn send_message_then_retrieve() {
-        let (manager, _, _dir) = setup().await;
+    async fn stranger_recipient_gets_not_subscribed_advisory() {
+        let (manager, event_bus, _dir) = setup().await;
+        manager.set_check_recipient_subscription(true);
+        set_connection_online(manager.as_ref()).await;
+        let mut sub = event_bus.subscribe("ui.message.not_subscribed").unwrap();
 
+        let contact = "alice@example.com".to_string();
+        let subscription = Subscription::Both.as_str().to_string();
         manager
-            .send_message("bob@example.com", "Hello!")
+            .db
+            .execute(
+                "INSERT INTO roster (jid, subscription) VALUES (?1, ?2)",
+                &[&contact, &subscription],
+            )
             .await
             .unwrap();
 
-        let messages = manager
-            .get_messages("bob@example.com", 50, None)
+        manager
+            .send_message("alice@example.com/phone", "hi")
             .await
             .unwrap();
+        assert!(
+            tokio::time::timeout(std::time::Duration::from_millis(100), sub.recv())
+                .await
+                .is_err(),
+            "a subscribed contact should not get an advisory"
+        );
 
-        assert_eq!(messages.len(), 1);
-        assert_eq!(messages[0].body, "Hello!");
-        assert_eq!(messages[0].to, "bob@example.com");
+        manager
+            .send_message("stranger@example.com", "hello?")
+            .await
+            .unwrap();
+        let received = tokio::time::timeout(std::time::Duration::from_millis(100), sub.recv())
+            .await
+            .expect("timed out")
+            .expect("recv error");
+        assert!(matches!(
+            received.payload,
+            EventPayload::RecipientNotSubscribed {
+                ref to,
+                subscription: None,
+            } if to == "stranger@example.com"
+        ));
     }
 
     #[tokio::test]
-    async fn handle_message_received_persists() {
-        let (manager, _, _dir) = setup().await;
-        let msg = make_chat_message("msg-1", "alice@example.com", "me@example.com", "Hi there!");
-
-        let event = make_event(
-            "xmpp.message.received",
-            EventPayload::MessageReceived {
-                message: msg.clone(),
-            },
-        );
-        manager.handle_event(&event).await;
+    async fn failed_subscription_check_still_sends() {
+        let (manager, event_bus, _dir) = setup().await;
+        manager.set_check_recipient_subscription(true);
+        set_connection_online(manager.as_ref()).await;
+        let mut sub = event_bus.subscribe("ui.message.send").unwrap();
+        manager.db.execute("DROP TABLE roster", &[]).await.unwrap();
 
-        let messages = manager
-            .get_messages("alice@example.com", 50, None)
+        manager
+            .send_message("alice@example.com", "hi")
             .await
-            .unwrap();
-
-        assert_eq!(messages.len(), 1);
-        assert_eq!(messages[0].id, "msg-1");
-        assert_eq!(messages[0].body, "Hi there!");
-        assert_eq!(messages[0].from, "alice@example.com");
+            .expect("a failed roster lookup should not fail the send");
+        let received = tokio::time::timeout(std::time::Duration::from_millis(100), sub.recv())
+            .await
+            .expect("timed out")
+            .expect("recv error");
+        assert!(matches!(
+            received.payload,
+            EventPayload::MessageSendRequested { ref to, .. } if to == "alice@example.com"
+        ));
     }
 
     #[tokio::test]
-    async fn handle_message_sent_persists() {
-        let (manager, _, _dir) = setup().await;
-        let msg = make_chat_message("msg-2", "me@example.com", "bob@example.com", "Hey Bob");
-
-        let event = make_event(
-            "xmpp.message.sent",
-            EventPayload::MessageSent {
-                message: msg.clone(),
-            },
-        );
-        manager.handle_event(&event).await;
+    async fn failed_draft_cleanup_does_not_fail_the_send() {
+        let (manager, event_bus, _dir) = setup().await;
+        set_connection_online(manager.as_ref()).await;
+        let mut sub = event_bus.subscribe("ui.message.send").unwrap();
+        manager.db.execute("DROP TABLE drafts", &[]).await.unwrap();
 
-        let messages = manager
-            .get_messages("bob@example.com", 50, None)
+        manager
+            .send_message("alice@example.com", "hi")
             .await
-            .unwrap();
-
-        assert_eq!(messages.len(), 1);
-        assert_eq!(messages[0].id, "msg-2");
-        assert_eq!(messages[0].body, "Hey Bob");
+            .expect("the draft is cleaned up best-effort");
+        let received = tokio::time::timeout(std::time::Duration::from_millis(100), sub.recv())
+            .await
+            .expect("timed out")
+            .expect("recv error");
+        assert!(matches!(
+            received.payload,
+            EventPayload::MessageSendRequested { ref to, .. } if to == "alice@example.com"
+        ));
     }
 
     #[tokio::test]
-    async fn duplicate_message_not_inserted_twice() {
+    async fn send_message_then_retrieve() {
         let (manager, _, _dir) = setup().await;
-        let msg = make_chat_message("msg-dup", "alice@example.com", "me@example.com", "Hello");
 
-        manager.persist_message(&msg).await.unwrap();
-        manager.persist_message(&msg).await.unwrap();
+        manager
+            .send_message("bob@example.com", "Hello!")
+            .await
+            .unwrap();
 
         let messages = manager
-            .get_messages("alice@example.com", 50, None)
+            .get_messages(&direct("bob@example.com"), 50, None)
             .await
             .unwrap();
 
         assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].body, "Hello!");
+        assert_eq!(messages[0].to, "bob@example.com");
     }
 
     #[tokio::test]
-    async fn duplicate_message_with_richer_embeds_updates_row() {
+    async fn drafts_are_kept_per_conversation_until_sent() {
         let (manager, _, _dir) = setup().await;
-        let msg_id = "msg-embed-upgrade";
 
-        let initial = make_chat_message(msg_id, "alice@example.com", "me@example.com", "See link");
-        manager.persist_message(&initial).await.unwrap();
+        assert_eq!(manager.draft("bob@example.com").await.unwrap(), None);
+        manager
+            .set_draft("bob@example.com", "Half a thought")
+            .await
+            .unwrap();
+        manager
+            .set_draft("bob@example.com/phone", "A whole thought")
+            .await
+            .unwrap();
+        manager.set_draft("carol@example.com", "Hi").await.unwrap();
+        assert_eq!(
+            manager.draft("bob@example.com").await.unwrap().as_deref(),
+            Some("A whole thought")
+        );
 
-        let mut enriched = initial.clone();
-        enriched.embeds.push(MessageEmbed {
-            namespace: "urn:waddle:github:0".to_string(),
-            data: serde_json::json!({
-                "type": "repo",
-                "owner": "rust-lang",
-                "name": "rust",
-                "url": "https://github.com/rust-lang/rust"
-            }),
-        });
-        manager.persist_message(&enriched).await.unwrap();
+        manager.clear_draft("carol@example.com").await.unwrap();
+        assert_eq!(manager.draft("carol@example.com").await.unwrap(), None);
 
-        let messages = manager
-            .get_messages("alice@example.com", 50, None)
+        manager
+            .send_message("bob@example.com", "A whole thought")
             .await
             .unwrap();
-
-        assert_eq!(messages.len(), 1);
-        assert_eq!(messages[0].id, msg_id);
-        assert_eq!(messages[0].embeds.len(), 1);
-        assert_eq!(messages[0].embeds[0].namespace, "urn:waddle:github:0");
-        assert_eq!(messages[0].embeds[0].data["owner"], "rust-lang");
+        assert_eq!(manager.draft("bob@example.com").await.unwrap(), None);
     }
 
     #[tokio::test]
-    async fn duplicate_message_with_blank_sender_fields_gets_upgraded() {
-        let (manager, _, _dir) = setup().await;
-        let msg_id = "msg-from-upgrade";
+    async fn correct_message_rewrites_body_and_sends_correction() {
+        let (manager, event_bus, _dir) = setup().await;
+        set_connection_online(manager.as_ref()).await;
+        let sent = manager
+            .send_message("bob@example.com", "Helo Bob")
+            .await
+            .unwrap();
+        let mut sub = event_bus.subscribe("ui.message.send").unwrap();
 
-        let initial = make_chat_message(msg_id, "", "bob@example.com", "Hello Bob");
-        manager.persist_message(&initial).await.unwrap();
+        manager
+            .correct_message(&sent.id, "Hello Bob")
+            .await
+            .unwrap();
 
-        let mut upgraded = initial.clone();
-        upgraded.from = "alice@example.com/web".to_string();
-        upgraded.to = "bob@example.com/phone".to_string();
-        upgraded.thread = Some("thread-123".to_string());
-        manager.persist_message(&upgraded).await.unwrap();
+        let event = tokio::time::timeout(std::time::Duration::from_millis(100), sub.recv())
+            .await
+            .expect("timed out waiting for correction")
+            .unwrap();
+        let EventPayload::MessageCorrectRequested {
+            to,
+            target_id,
+            body,
+        } = event.payload
+        else {
+            panic!("expected MessageCorrectRequested, got {:?}", event.payload);
+        };
+        assert_eq!(to, "bob@example.com");
+        assert_eq!(target_id, sent.id);
+        assert_eq!(body, "Hello Bob");
 
-        let messages = manager
-            .get_messages("bob@example.com/phone", 50, None)
+        let stored = manager
+            .get_messages(&direct("bob@example.com"), 50, None)
             .await
             .unwrap();
+        assert_eq!(stored.len(), 1);
+        assert_eq!(stored[0].id, sent.id);
+        assert_eq!(stored[0].body, "Hello Bob");
+        assert_eq!(
+            stored[0].timestamp.timestamp_millis(),
+            sent.timestamp.timestamp_millis()
+        );
 
-        assert_eq!(messages.len(), 1);
-        assert_eq!(messages[0].id, msg_id);
-        assert_eq!(messages[0].from, "alice@example.com/web");
-        assert_eq!(messages[0].to, "bob@example.com/phone");
-        assert_eq!(messages[0].thread.as_deref(), Some("thread-123"));
+        let missing = manager.correct_message("no-such-id", "x").await;
+        assert!(matches!(missing, Err(MessagingError::SendFailed(_))));
     }
 
     #[tokio::test]
-    async fn get_messages_with_limit() {
-        let (manager, _, _dir) = setup().await;
-
-        for i in 0..5 {
-            let msg = ChatMessage {
-                id: format!("msg-{i}"),
-                from: "alice@example.com".to_string(),
-                to: "me@example.com".to_string(),
-                body: format!("Message {i}"),
-                timestamp: Utc::now() + chrono::Duration::seconds(i),
-                message_type: MessageType::Chat,
-                thread: None,
-                embeds: vec![],
-            };
-            manager.persist_message(&msg).await.unwrap();
-        }
-
-        let messages = manager
-            .get_messages("alice@example.com", 3, None)
+    async fn offline_correction_is_sent_once_and_only_for_the_last_message() {
+        let (manager, event_bus, _dir) = setup().await;
+        let first = manager
+            .send_message("bob@example.com", "See you at 5")
+            .await
+            .unwrap();
+        tokio::time::sleep(std::time::Duration::from_millis(5)).await;
+        let last = manager
+            .send_message("bob@example.com", "Bring snaks")
             .await
             .unwrap();
 
-        assert_eq!(messages.len(), 3);
-    }
+        let older = manager.correct_message(&first.id, "See you at 6").await;
+        assert!(matches!(older, Err(MessagingError::SendFailed(_))));
+        manager
+            .correct_message(&last.id, "Bring snacks")
+            .await
+            .unwrap();
 
-    #[tokio::test]
-    async fn get_messages_with_before_pagination() {
-        let (manager, _, _dir) = setup().await;
+        let mut sub = event_bus.subscribe("ui.message.send").unwrap();
+        set_connection_online(manager.as_ref()).await;
+        let mut corrections = 0;
+        while let Ok(Ok(event)) =
+            tokio::time::timeout(std::time::Duration::from_millis(100), sub.recv()).await
+        {
+            if let EventPayload::MessageCorrectRequested { target_id, .. } = &event.payload {
+                assert_eq!(target_id, &last.id);
+                assert!(event.correlation_id.is_some());
+                corrections += 1;
+            }
+        }
+        assert_eq!(corrections, 1);
 
-        let base = Utc::now();
-        for i in 0..5 {
-            let msg = ChatMessage {
-                id: format!("msg-{i}"),
-                from: "alice@example.com".to_string(),
-                to: "me@example.com".to_string(),
-                body: format!("Message {i}"),
-                timestamp: base + chrono::Duration::seconds(i),
-                message_type: MessageType::Chat,
-                thread: None,
-                embeds: vec![],
-            };
-            manager.persist_message(&msg).await.unwrap();
+        set_connection_online(manager.as_ref()).await;
+        while let Ok(Ok(event)) =
+            tokio::time::timeout(std::time::Duration::from_millis(100), sub.recv()).await
+        {
+            assert!(
+                !matches!(event.payload, EventPayload::MessageCorrectRequested { .. }),
+                "correction was sent again on reconnect"
+            );
         }
 
-        let cutoff = (base + chrono::Duration::seconds(3)).to_rfc3339();
-        let messages = manager
-            .get_messages("alice@example.com", 50, Some(&cutoff))
+        let stanza_types: Vec<String> = manager
+            .pending_outbound()
             .await
-            .unwrap();
+            .unwrap()
+            .into_iter()
+            .map(|item| item.stanza_type)
+            .collect();
+        assert_eq!(stanza_types, vec!["message", "message"]);
+    }
 
-        assert_eq!(messages.len(), 3);
+    #[tokio::test]
+    async fn inbound_correction_only_applies_to_the_sender() {
+        let (manager, _, _dir) = setup().await;
+        let original = make_chat_message(
+            "msg-c1",
+            "bob@example.com",
+            "alice@example.com",
+            "See you at 5",
+        );
+        manager
+            .handle_event(&make_event(
+                "xmpp.message.received",
+                EventPayload::MessageReceived {
+                    message: original.clone(),
+                },
+            ))
+            .await;
+
+        let correction = |from: &str, body: &str| {
+            make_event(
+                "xmpp.message.corrected",
+                EventPayload::MessageCorrectionReceived {
+                    from: from.to_string(),
+                    target_id: "msg-c1".to_string(),
+                    body: body.to_string(),
+                },
+            )
+        };
+        manager
+            .handle_event(&correction("mallory@example.com/x", "See you never"))
+            .await;
+        let stored = manager
+            .get_messages(&direct("bob@example.com"), 50, None)
+            .await
+            .unwrap();
+        assert_eq!(stored[0].body, "See you at 5");
+
+        manager
+            .handle_event(&correction("bob@example.com/phone", "See you at 6"))
+            .await;
+        let stored = manager
+            .get_messages(&direct("bob@example.com"), 50, None)
+            .await
+            .unwrap();
+        assert_eq!(stored.len(), 1);
+        assert_eq!(stored[0].body, "See you at 6");
+        assert_eq!(
+            stored[0].timestamp.timestamp_millis(),
+            original.timestamp.timestamp_millis()
+        );
     }
 
     #[tokio::test]
-    async fn mark_read_updates_messages() {
+    async fn receipts_record_each_delivering_resource() {
         let (manager, _, _dir) = setup().await;
+        let sent = manager
+            .send_message("bob@example.com", "hello")
+            .await
+            .unwrap();
 
-        let msg = make_chat_message("msg-r", "alice@example.com", "me@example.com", "Read me");
-        manager.persist_message(&msg).await.unwrap();
+        let receipt = |to: &str, resource: &str| {
+            make_event(
+                "xmpp.message.delivered",
+                EventPayload::MessageDelivered {
+                    id: sent.id.clone(),
+                    to: to.into(),
+                    resource: Some(resource.into()),
+                },
+            )
+        };
+        for resource in ["phone", "laptop", "phone"] {
+            manager
+                .handle_event(&receipt("bob@example.com", resource))
+                .await;
+        }
+        manager
+            .handle_event(&receipt("mallory@example.com", "phone"))
+            .await;
 
-        manager.mark_read("alice@example.com").await.unwrap();
+        assert_eq!(
+            manager.delivered_resources(&sent.id).await.unwrap(),
+            vec![
+                "bob@example.com/phone".to_string(),
+                "bob@example.com/laptop".to_string()
+            ]
+        );
+        assert!(
+            manager
+                .delivered_resources("other")
+                .await
+                .unwrap()
+                .is_empty()
+        );
 
-        // Verify read flag was updated by querying raw
-        let rows: Vec<Row> = manager
-            .db
-            .query(
-                "SELECT read FROM messages WHERE from_jid = ?1",
-                &[&"alice@example.com".to_string()],
-            )
+        // A send to one of the peer's resources is acknowledged by it.
+        let direct = manager
+            .send_message("bob@example.com/phone", "just the phone")
             .await
             .unwrap();
-
-        assert_eq!(rows.len(), 1);
-        assert_eq!(rows[0].get(0), Some(&SqlValue::Integer(1)));
+        manager
+            .handle_event(&make_event(
+                "xmpp.message.delivered",
+                EventPayload::MessageDelivered {
+                    id: direct.id.clone(),
+                    to: "bob@example.com".into(),
+                    resource: Some("phone".into()),
+                },
+            ))
+            .await;
+        assert_eq!(
+            manager.delivered_resources(&direct.id).await.unwrap(),
+            vec!["bob@example.com/phone".to_string()]
+        );
     }
 
     #[tokio::test]
-    async fn send_chat_state_emits_event() {
-        let (manager, event_bus, _dir) = setup().await;
-        let mut sub = event_bus.subscribe("ui.**").unwrap();
-        set_connection_online(manager.as_ref()).await;
+    async fn local_pins_stay_with_their_conversation() {
+        let (manager, _, _dir) = setup().await;
+        let first = manager
+            .send_message("bob@example.com", "first")
+            .await
+            .unwrap();
+        let second = manager
+            .send_message("bob@example.com", "second")
+            .await
+            .unwrap();
+        let other = manager
+            .send_message("carol@example.com", "hi")
+            .await
+            .unwrap();
 
         manager
-            .send_chat_state("bob@example.com", ChatState::Composing)
+            .pin_local("bob@example.com", &first.id)
+            .await
+            .unwrap();
+        manager
+            .pin_local("bob@example.com", &second.id)
+            .await
+            .unwrap();
+        manager
+            .pin_local("bob@example.com", &second.id)
+            .await
+            .unwrap();
+        // A message from another conversation is not listed here.
+        manager
+            .pin_local("bob@example.com", &other.id)
             .await
             .unwrap();
 
-        let received = tokio::time::timeout(std::time::Duration::from_millis(100), sub.recv())
+        let pinned: Vec<String> = manager
+            .local_pins("bob@example.com")
             .await
-            .expect("timed out")
-            .expect("should receive event");
+            .unwrap()
+            .into_iter()
+            .map(|m| m.body)
+            .collect();
+        assert_eq!(pinned, vec!["second", "first"]);
+        assert!(
+            manager
+                .local_pins("carol@example.com")
+                .await
+                .unwrap()
+                .is_empty()
+        );
 
-        assert!(matches!(
-            received.payload,
-            EventPayload::ChatStateSendRequested {
-                ref to,
-                state: ChatState::Composing,
-            } if to == "bob@example.com"
-        ));
+        manager
+            .unpin_local("bob@example.com", &second.id)
+            .await
+            .unwrap();
+        let pinned = manager.local_pins("bob@example.com").await.unwrap();
+        assert_eq!(pinned.len(), 1);
+        assert_eq!(pinned[0].id, first.id);
     }
 
     #[tokio::test]
-    async fn handle_delivery_receipt_does_not_error() {
+    async fn handle_message_received_persists() {
         let (manager, _, _dir) = setup().await;
+        let msg = make_chat_message("msg-1", "alice@example.com", "me@example.com", "Hi there!");
 
         let event = make_event(
-            "xmpp.message.delivered",
-            EventPayload::MessageDelivered {
-                id: "msg-1".to_string(),
-                to: "bob@example.com".to_string(),
+            "xmpp.message.received",
+            EventPayload::MessageReceived {
+                message: msg.clone(),
             },
         );
         manager.handle_event(&event).await;
+
+        let messages = manager
+            .get_messages(&direct("alice@example.com"), 50, None)
+            .await
+            .unwrap();
+
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].id, "msg-1");
+        assert_eq!(messages[0].body, "Hi there!");
+        assert_eq!(messages[0].from, "alice@example.com");
     }
 
     #[tokio::test]
-    async fn handle_chat_state_received_does_not_error() {
+    async fn persisted_messages_record_their_source() {
         let (manager, _, _dir) = setup().await;
 
-        let event = make_event(
-            "xmpp.chatstate.received",
-            EventPayload::ChatStateReceived {
-                from: "alice@example.com".to_string(),
-                state: ChatState::Composing,
-            },
-        );
-        manager.handle_event(&event).await;
+        let msg = make_chat_message("live-1", "bob@example.com", "me@example.com", "Hi");
+        manager
+            .handle_event(&make_event(
+                "xmpp.message.received",
+                EventPayload::MessageReceived { message: msg },
+            ))
+            .await;
+        let sent = manager.send_message("bob@example.com", "hi").await.unwrap();
+
+        for (id, source) in [("live-1", "live"), (sent.id.as_str(), "local")] {
+            let rows: Vec<Row> = manager
+                .db
+                .query(
+                    "SELECT source FROM messages WHERE id = ?1",
+                    &[&id.to_string()],
+                )
+                .await
+                .unwrap();
+            assert_eq!(rows[0].get(0), Some(&SqlValue::Text(source.to_string())));
+        }
     }
 
     #[tokio::test]
-    async fn messages_with_thread_round_trip() {
+    async fn delayed_message_persists_with_historical_time() {
         let (manager, _, _dir) = setup().await;
 
-        let msg = ChatMessage {
-            id: "msg-thread".to_string(),
-            from: "alice@example.com".to_string(),
-            to: "me@example.com".to_string(),
-            body: "threaded message".to_string(),
-            timestamp: Utc::now(),
-            message_type: MessageType::Chat,
-            thread: Some("thread-123".to_string()),
-            embeds: vec![],
-        };
-        manager.persist_message(&msg).await.unwrap();
+        let live = make_chat_message("live-1", "alice@example.com", "me@example.com", "Now");
+        manager
+            .handle_event(&make_event(
+                "xmpp.message.received",
+                EventPayload::MessageReceived { message: live },
+            ))
+            .await;
+
+        // Offline storage flushed after the live message, stamped by <delay/>.
+        let stamp = "2023-06-15T12:00:00Z".parse::<DateTime<Utc>>().unwrap();
+        let mut delayed = make_chat_message(
+            "offline-1",
+            "alice@example.com",
+            "me@example.com",
+            "Earlier",
+        );
+        delayed.timestamp = stamp;
+        manager
+            .handle_event(&make_event(
+                "xmpp.message.received",
+                EventPayload::MessageReceived { message: delayed },
+            ))
+            .await;
 
         let messages = manager
-            .get_messages("alice@example.com", 50, None)
+            .get_messages(&direct("alice@example.com"), 50, None)
             .await
             .unwrap();
-
-        assert_eq!(messages.len(), 1);
-        assert_eq!(messages[0].thread, Some("thread-123".to_string()));
+        let ids: Vec<&str> = messages.iter().map(|m| m.id.as_str()).collect();
+        assert_eq!(ids, vec!["live-1", "offline-1"]);
+        assert_eq!(messages[1].timestamp, stamp);
     }
 
     #[tokio::test]
-    async fn groupchat_messages_excluded_from_get_messages() {
+    async fn server_headline_is_a_service_notice() {
         let (manager, _, _dir) = setup().await;
 
-        let chat_msg = ChatMessage {
-            id: "msg-chat".to_string(),
-            from: "alice@example.com".to_string(),
-            to: "me@example.com".to_string(),
-            body: "chat message".to_string(),
-            timestamp: Utc::now(),
-            message_type: MessageType::Chat,
-            thread: None,
-            embeds: vec![],
-        };
-        manager.persist_message(&chat_msg).await.unwrap();
+        let mut motd = make_chat_message(
+            "motd-1",
+            "example.com",
+            "me@example.com",
+            "Maintenance at 22:00 UTC",
+        );
+        motd.message_type = MessageType::Headline;
+        let mut feed = make_chat_message("feed-1", "alice@example.com", "me@example.com", "news");
+        feed.message_type = MessageType::Headline;
+        for message in [motd, feed] {
+            manager
+                .handle_event(&make_event(
+                    "xmpp.message.received",
+                    EventPayload::MessageReceived { message },
+                ))
+                .await;
+        }
 
-        let gc_msg = ChatMessage {
-            id: "msg-gc".to_string(),
-            from: "room@muc.example.com".to_string(),
-            to: "me@example.com".to_string(),
-            body: "group message".to_string(),
-            timestamp: Utc::now(),
-            message_type: MessageType::Groupchat,
-            thread: None,
-            embeds: vec![],
-        };
-        manager.persist_message(&gc_msg).await.unwrap();
+        let notices = manager.service_notices(10).await.unwrap();
+        assert_eq!(notices.len(), 1);
+        assert_eq!(notices[0].id, "motd-1");
+        assert!(matches!(notices[0].message_type, MessageType::Headline));
+
+        assert!(
+            manager
+                .get_messages(&direct("example.com"), 50, None)
+                .await
+                .unwrap()
+                .is_empty()
+        );
+    }
+
+    #[tokio::test]
+    async fn handle_message_sent_persists() {
+        let (manager, _, _dir) = setup().await;
+        let msg = make_chat_message("msg-2", "me@example.com", "bob@example.com", "Hey Bob");
+
+        let event = make_event(
+            "xmpp.message.sent",
+            EventPayload::MessageSent {
+                message: msg.clone(),
+            },
+        );
+        manager.handle_event(&event).await;
 
         let messages = manager
-            .get_messages("alice@example.com", 50, None)
+            .get_messages(&direct("bob@example.com"), 50, None)
             .await
             .unwrap();
 
         assert_eq!(messages.len(), 1);
-        assert_eq!(messages[0].id, "msg-chat");
+        assert_eq!(messages[0].id, "msg-2");
+        assert_eq!(messages[0].body, "Hey Bob");
     }
 
     #[tokio::test]
-    async fn run_loop_processes_events() {
-        let local = tokio::task::LocalSet::new();
-        local
-            .run_until(async {
-                let (manager, event_bus, _dir) = setup().await;
-
-                let manager_clone = manager.clone();
-                let handle = tokio::task::spawn_local(async move { manager_clone.run().await });
-
-                tokio::task::yield_now().await;
-                tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+    async fn duplicate_message_not_inserted_twice() {
+        let (manager, _, _dir) = setup().await;
+        let msg = make_chat_message("msg-dup", "alice@example.com", "me@example.com", "Hello");
 
-                let msg = make_chat_message(
-                    "msg-run",
-                    "alice@example.com",
-                    "me@example.com",
-                    "via run loop",
-                );
+        manager
+            .persist_message(&msg, MessageSource::Live)
+            .await
+            .unwrap();
+        manager
+            .persist_message(&msg, MessageSource::Live)
+            .await
+            .unwrap();
 
-                event_bus
-                    .publish(Event::new(
-                        Channel::new("xmpp.message.received").unwrap(),
-                        EventSource::Xmpp,
-                        EventPayload::MessageReceived { message: msg },
-                    ))
-                    .unwrap();
+        let messages = manager
+            .get_messages(&direct("alice@example.com"), 50, None)
+            .await
+            .unwrap();
 
-                tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        assert_eq!(messages.len(), 1);
+    }
 
-                let messages = manager
-                    .get_messages("alice@example.com", 50, None)
-                    .await
-                    .unwrap();
+    #[tokio::test]
+    async fn duplicate_message_with_richer_embeds_updates_row() {
+        let (manager, _, _dir) = setup().await;
+        let msg_id = "msg-embed-upgrade";
 
-                assert_eq!(messages.len(), 1);
-                assert_eq!(messages[0].body, "via run loop");
+        let initial = make_chat_message(msg_id, "alice@example.com", "me@example.com", "See link");
+        manager
+            .persist_message(&initial, MessageSource::Live)
+            .await
+            .unwrap();
 
-                handle.abort();
-            })
+        let mut enriched = initial.clone();
+        enriched.embeds.push(MessageEmbed {
+            namespace: "urn:waddle:github:0".to_string(),
+            data: serde_json::json!({
+                "type": "repo",
+                "owner": "rust-lang",
+                "name": "rust",
+                "url": "https://github.com/rust-lang/rust"
+            }),
+        });
+        manager
+            .persist_message(&enriched, MessageSource::Live)
+            .await
+            .unwrap();
+
+        let messages = manager
+            .get_messages(&direct("alice@example.com"), 50, None)
+            .await
+            .unwrap();
+
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].id, msg_id);
+        assert_eq!(messages[0].embeds.len(), 1);
+        assert_eq!(messages[0].embeds[0].namespace, "urn:waddle:github:0");
+        assert_eq!(messages[0].embeds[0].data["owner"], "rust-lang");
+    }
+
+    #[tokio::test]
+    async fn multi_lang_message_round_trips_its_variants() {
+        let (manager, _, _dir) = setup().await;
+        let mut message =
+            make_chat_message("msg-lang", "alice@example.com", "me@example.com", "Hello");
+        message.bodies = BTreeMap::from([
+            (String::new(), "Hello".to_string()),
+            ("de".to_string(), "Hallo".to_string()),
+            ("es".to_string(), "Hola".to_string()),
+        ]);
+        manager
+            .handle_event(&make_event(
+                "xmpp.message.received",
+                EventPayload::MessageReceived { message },
+            ))
             .await;
+
+        let messages = manager
+            .get_messages(&direct("alice@example.com"), 50, None)
+            .await
+            .unwrap();
+
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].bodies.len(), 3);
+        assert_eq!(messages[0].body_for_lang(Some("de-CH")), "Hallo");
+        assert_eq!(messages[0].body_for_lang(Some("es")), "Hola");
+        assert_eq!(messages[0].body_for_lang(Some("ja")), "Hello");
     }
 
     #[tokio::test]
-    async fn bidirectional_conversation_retrieval() {
+    async fn duplicate_message_with_blank_sender_fields_gets_upgraded() {
         let (manager, _, _dir) = setup().await;
+        let msg_id = "msg-from-upgrade";
 
-        let msg1 = make_chat_message(
-            "msg-from-alice",
-            "alice@example.com",
-            "me@example.com",
-            "Hello from Alice",
-        );
-        manager.persist_message(&msg1).await.unwrap();
+        let initial = make_chat_message(msg_id, "", "bob@example.com", "Hello Bob");
+        manager
+            .persist_message(&initial, MessageSource::Live)
+            .await
+            .unwrap();
 
-        let msg2 = make_chat_message(
-            "msg-to-alice",
-            "me@example.com",
-            "alice@example.com",
-            "Hello back",
-        );
-        manager.persist_message(&msg2).await.unwrap();
+        let mut upgraded = initial.clone();
+        upgraded.from = "alice@example.com/web".to_string();
+        upgraded.to = "bob@example.com/phone".to_string();
+        upgraded.thread = Some("thread-123".to_string());
+        manager
+            .persist_message(&upgraded, MessageSource::Live)
+            .await
+            .unwrap();
 
         let messages = manager
-            .get_messages("alice@example.com", 50, None)
+            .get_messages(&direct("bob@example.com/phone"), 50, None)
             .await
             .unwrap();
 
-        assert_eq!(messages.len(), 2);
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].id, msg_id);
+        assert_eq!(messages[0].from, "alice@example.com/web");
+        assert_eq!(messages[0].to, "bob@example.com/phone");
+        assert_eq!(messages[0].thread.as_deref(), Some("thread-123"));
     }
 
     #[tokio::test]
-    async fn send_message_offline_enqueues_without_emitting_ui_event() {
-        let (manager, event_bus, _dir) = setup().await;
-        let mut sub = event_bus.subscribe("ui.message.send").unwrap();
+    async fn spoiler_hint_is_stored_with_the_body() {
+        let (manager, _, _dir) = setup().await;
 
-        let message = manager
-            .send_message("bob@example.com", "queued while offline")
+        let mut msg = make_chat_message(
+            "msg-s",
+            "alice@example.com",
+            "me@example.com",
+            "Vader is Luke's father",
+        );
+        msg.spoiler = Some("Star Wars".to_string());
+        manager
+            .persist_message(&msg, MessageSource::Live)
+            .await
+            .unwrap();
+        let plain = make_chat_message("msg-p", "alice@example.com", "me@example.com", "hi");
+        manager
+            .persist_message(&plain, MessageSource::Live)
             .await
             .unwrap();
 
-        let ui_event =
-            tokio::time::timeout(std::time::Duration::from_millis(100), sub.recv()).await;
-        assert!(
-            ui_event.is_err(),
-            "offline send should not publish ui.message.send"
-        );
+        let messages = manager
+            .get_messages(&direct("alice@example.com"), 50, None)
+            .await
+            .unwrap();
+        let stored = messages.iter().find(|m| m.id == "msg-s").unwrap();
+        assert_eq!(stored.body, "Vader is Luke's father");
+        assert_eq!(stored.spoiler.as_deref(), Some("Star Wars"));
+        let plain = messages.iter().find(|m| m.id == "msg-p").unwrap();
+        assert!(plain.spoiler.is_none());
+    }
 
-        let rows: Vec<Row> = manager
-            .db
-            .query(
-                "SELECT stanza_type, status FROM offline_queue ORDER BY id ASC",
-                &[],
-            )
+    #[tokio::test]
+    async fn get_messages_with_limit() {
+        let (manager, _, _dir) = setup().await;
+
+        for i in 0..5 {
+            let msg = ChatMessage {
+                id: format!("msg-{i}"),
+                from: "alice@example.com".to_string(),
+                to: "me@example.com".to_string(),
+                body: format!("Message {i}"),
+                timestamp: Utc::now() + chrono::Duration::seconds(i),
+                message_type: MessageType::Chat,
+                thread: None,
+                embeds: vec![],
+                bodies: BTreeMap::new(),
+                origin_id: None,
+                spoiler: None,
+                stanza_id: None,
+            };
+            manager
+                .persist_message(&msg, MessageSource::Live)
+                .await
+                .unwrap();
+        }
+
+        let messages = manager
+            .get_messages(&direct("alice@example.com"), 3, None)
             .await
             .unwrap();
 
-        assert_eq!(rows.len(), 1);
-        assert_eq!(rows[0].get(0), Some(&SqlValue::Text("message".to_string())));
-        assert_eq!(rows[0].get(1), Some(&SqlValue::Text("pending".to_string())));
+        assert_eq!(messages.len(), 3);
+    }
 
-        let stored = manager
-            .get_messages("bob@example.com", 50, None)
+    #[tokio::test]
+    async fn get_messages_with_before_pagination() {
+        let (manager, _, _dir) = setup().await;
+
+        let base = Utc::now();
+        for i in 0..5 {
+            let msg = ChatMessage {
+                id: format!("msg-{i}"),
+                from: "alice@example.com".to_string(),
+                to: "me@example.com".to_string(),
+                body: format!("Message {i}"),
+                timestamp: base + chrono::Duration::seconds(i),
+                message_type: MessageType::Chat,
+                thread: None,
+                embeds: vec![],
+                bodies: BTreeMap::new(),
+                origin_id: None,
+                spoiler: None,
+                stanza_id: None,
+            };
+            manager
+                .persist_message(&msg, MessageSource::Live)
+                .await
+                .unwrap();
+        }
+
+        let cutoff = (base + chrono::Duration::seconds(3)).to_rfc3339();
+        let messages = manager
+            .get_messages(&direct("alice@example.com"), 50, Some(&cutoff))
             .await
             .unwrap();
-        assert_eq!(stored.len(), 1);
-        assert_eq!(stored[0].id, message.id);
+
+        assert_eq!(messages.len(), 3);
     }
 
     #[tokio::test]
-    async fn reconnect_drains_offline_queue_fifo_and_marks_sent() {
-        let (manager, event_bus, _dir) = setup().await;
+    async fn paginate_matches_inline_history_queries() {
+        let (manager, _, _dir) = setup().await;
 
-        let first = manager
-            .send_message("bob@example.com", "first queued")
+        let base = "2024-05-01T10:00:00Z".parse::<DateTime<Utc>>().unwrap();
+        for i in 0..6 {
+            let mut direct = make_chat_message(
+                &format!("direct-{i}"),
+                if i % 2 == 0 {
+                    "alice@example.com"
+                } else {
+                    "me@example.com"
+                },
+                if i % 2 == 0 {
+                    "me@example.com"
+                } else {
+                    "alice@example.com"
+                },
+                &format!("Direct {i}"),
+            );
+            direct.timestamp = base + chrono::Duration::minutes(i);
+            manager
+                .persist_message(&direct, MessageSource::Live)
+                .await
+                .unwrap();
+
+            let mut room = make_chat_message(
+                &format!("room-{i}"),
+                "room@conference.example.com/alice",
+                "room@conference.example.com",
+                &format!("Room {i}"),
+            );
+            room.message_type = MessageType::Groupchat;
+            room.timestamp = base + chrono::Duration::minutes(i);
+            manager
+                .persist_message(&room, MessageSource::Live)
+                .await
+                .unwrap();
+        }
+
+        let cursor = (base + chrono::Duration::minutes(4)).to_rfc3339();
+        let cursor_ms = timestamp_millis(&cursor).unwrap();
+        let limit = 3_i64;
+
+        for (conversation, inline_latest, inline_before) in [
+            (
+                ConversationId::Direct("alice@example.com".to_string()),
+                "SELECT id, from_jid, to_jid, body, timestamp, message_type, thread, embeds \
+                 FROM messages \
+                 WHERE (from_jid = ?1 OR to_jid = ?1) AND message_type = 'chat' \
+                 ORDER BY timestamp_ms DESC \
+                 LIMIT ?2",
+                "SELECT id, from_jid, to_jid, body, timestamp, message_type, thread, embeds \
+                 FROM messages \
+                 WHERE (from_jid = ?1 OR to_jid = ?1) AND message_type = 'chat' AND timestamp_ms < ?2 \
+                 ORDER BY timestamp_ms DESC \
+                 LIMIT ?3",
+            ),
+            (
+                ConversationId::Room("room@conference.example.com".to_string()),
+                "SELECT id, from_jid, to_jid, body, timestamp, message_type, thread \
+                 FROM messages \
+                 WHERE to_jid = ?1 AND message_type = 'groupchat' \
+                 ORDER BY timestamp_ms DESC \
+                 LIMIT ?2",
+                "SELECT id, from_jid, to_jid, body, timestamp, message_type, thread \
+                 FROM messages \
+                 WHERE to_jid = ?1 AND message_type = 'groupchat' AND timestamp_ms < ?2 \
+                 ORDER BY timestamp_ms DESC \
+                 LIMIT ?3",
+            ),
+        ] {
+            let key = conversation.jid().to_string();
+            let ids = |messages: Vec<ChatMessage>| -> Vec<String> {
+                messages.into_iter().map(|m| m.id).collect()
+            };
+            let stored_ids = |rows: Vec<StoredMessage>| -> Vec<String> {
+                rows.into_iter().map(|r| r.id).collect()
+            };
+
+            let expected: Vec<StoredMessage> = manager
+                .db
+                .query(inline_latest, &[&key, &limit])
+                .await
+                .unwrap();
+            let paged = paginate(manager.db.as_ref(), &conversation, 3, None)
+                .await
+                .unwrap();
+            assert_eq!(ids(paged), stored_ids(expected));
+
+            let expected: Vec<StoredMessage> = manager
+                .db
+                .query(inline_before, &[&key, &cursor_ms, &limit])
+                .await
+                .unwrap();
+            let paged = paginate(manager.db.as_ref(), &conversation, 3, Some(&cursor))
+                .await
+                .unwrap();
+            let paged = ids(paged);
+            assert_eq!(paged.len(), 3);
+            assert_eq!(paged, stored_ids(expected));
+        }
+    }
+
+    #[test]
+    fn history_queries_bind_centralized_message_types() {
+        for (conversation, message_type) in [
+            (direct("alice@example.com"), "chat"),
+            (
+                ConversationId::Room("room@conference.example.com".to_string()),
+                "groupchat",
+            ),
+        ] {
+            assert_eq!(
+                message_type_param(&conversation.message_type()),
+                message_type
+            );
+            for with_cursor in [false, true] {
+                let sql = history_page_sql(conversation.history_filter(), with_cursor);
+                assert!(sql.contains("message_type = ?2"), "{sql}");
+                assert!(!sql.contains(&format!("'{message_type}'")), "{sql}");
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn conversation_id_routes_direct_and_room_reads() {
+        let (manager, _, _dir) = setup().await;
+
+        // The same JID used both as a 1:1 peer and as a room.
+        let jid = "team@conference.example.com";
+        let direct_msg = make_chat_message("dm-1", jid, "me@example.com", "Private");
+        manager
+            .persist_message(&direct_msg, MessageSource::Live)
             .await
             .unwrap();
-        let second = manager
-            .send_message("carol@example.com", "second queued")
+        let mut room_msg =
+            make_chat_message("gc-1", "team@conference.example.com/bob", jid, "Hi all");
+        room_msg.message_type = MessageType::Groupchat;
+        manager
+            .persist_message(&room_msg, MessageSource::Live)
             .await
             .unwrap();
 
-        let mut sub = event_bus.subscribe("ui.message.send").unwrap();
-        set_connection_online(manager.as_ref()).await;
+        let direct_ids: Vec<String> = manager
+            .get_messages(&direct(jid), 50, None)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|m| m.id)
+            .collect();
+        assert_eq!(direct_ids, vec!["dm-1"]);
+
+        let room = ConversationId::Room(jid.to_string());
+        let room_ids: Vec<String> = manager
+            .get_messages(&room, 50, None)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|m| m.id)
+            .collect();
+        assert_eq!(room_ids, vec!["gc-1"]);
+
+        let context = manager.context_around(&room, "gc-1", 2).await.unwrap();
+        assert_eq!(context.len(), 1);
+        assert!(
+            manager
+                .context_around(&room, "dm-1", 2)
+                .await
+                .unwrap()
+                .is_empty()
+        );
+    }
 
-        let first_event = tokio::time::timeout(std::time::Duration::from_millis(100), sub.recv())
+    #[tokio::test]
+    async fn ordering_and_pagination_use_timestamp_ms() {
+        let (manager, _, _dir) = setup().await;
+
+        let base = "2024-05-01T10:00:00Z".parse::<DateTime<Utc>>().unwrap();
+        for i in 0..3 {
+            let mut msg = make_chat_message(
+                &format!("ms-{i}"),
+                "alice@example.com",
+                "me@example.com",
+                &format!("Message {i}"),
+            );
+            msg.timestamp = base + chrono::Duration::minutes(i);
+            manager
+                .persist_message(&msg, MessageSource::Live)
+                .await
+                .unwrap();
+        }
+        // A legacy text timestamp whose lexical order disagrees with time:
+        // 11:01+02:00 is 09:01Z, the earliest message.
+        manager
+            .db
+            .execute(
+                "UPDATE messages SET timestamp = ?1, timestamp_ms = ?2 WHERE id = 'ms-2'",
+                &[
+                    &"2024-05-01T11:01:00+02:00".to_string(),
+                    &timestamp_millis("2024-05-01T11:01:00+02:00").unwrap(),
+                ],
+            )
             .await
-            .expect("timed out waiting for first drained item")
-            .expect("expected first drained item");
-        let second_event = tokio::time::timeout(std::time::Duration::from_millis(100), sub.recv())
+            .unwrap();
+
+        let messages = manager
+            .get_messages(&direct("alice@example.com"), 50, None)
             .await
-            .expect("timed out waiting for second drained item")
-            .expect("expected second drained item");
+            .unwrap();
+        let ids: Vec<&str> = messages.iter().map(|m| m.id.as_str()).collect();
+        assert_eq!(ids, vec!["ms-1", "ms-0", "ms-2"]);
+
+        let page = manager
+            .get_messages(
+                &direct("alice@example.com"),
+                50,
+                Some("2024-05-01T10:00:00Z"),
+            )
+            .await
+            .unwrap();
+        let ids: Vec<&str> = page.iter().map(|m| m.id.as_str()).collect();
+        assert_eq!(ids, vec!["ms-2"]);
 
         assert!(matches!(
-            first_event.payload,
-            EventPayload::MessageSendRequested { ref body, .. } if body == "first queued"
-        ));
-        assert!(matches!(
-            second_event.payload,
-            EventPayload::MessageSendRequested { ref body, .. } if body == "second queued"
+            manager
+                .get_messages(&direct("alice@example.com"), 50, Some("yesterday"))
+                .await,
+            Err(MessagingError::InvalidTimestamp(_))
         ));
+    }
 
-        manager
-            .handle_event(&make_event(
-                "xmpp.message.sent",
-                EventPayload::MessageSent {
-                    message: make_chat_message(
-                        &first.id,
-                        "alice@example.com",
-                        "bob@example.com",
-                        "first queued",
-                    ),
-                },
-            ))
-            .await;
+    #[tokio::test]
+    async fn mark_read_updates_messages() {
+        let (manager, _, _dir) = setup().await;
 
+        let msg = make_chat_message("msg-r", "alice@example.com", "me@example.com", "Read me");
         manager
-            .handle_event(&make_event(
-                "xmpp.message.sent",
-                EventPayload::MessageSent {
-                    message: make_chat_message(
-                        &second.id,
-                        "alice@example.com",
+            .persist_message(&msg, MessageSource::Live)
+            .await
+            .unwrap();
+
+        manager.mark_read("alice@example.com").await.unwrap();
+
+        // Verify read flag was updated by querying raw
+        let rows: Vec<Row> = manager
+            .db
+            .query(
+                "SELECT read FROM messages WHERE from_jid = ?1",
+                &[&"alice@example.com".to_string()],
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(rows.len(), 1);
+        assert_eq!(rows[0].get(0), Some(&SqlValue::Integer(1)));
+    }
+
+    #[tokio::test]
+    async fn context_around_centers_on_target_within_conversation() {
+        let (manager, _, _dir) = setup().await;
+
+        let base = Utc::now();
+        for i in 0..7 {
+            let mut message = make_chat_message(
+                &format!("c-{i}"),
+                "alice@example.com",
+                "me@example.com",
+                "hi",
+            );
+            message.timestamp = base + chrono::Duration::seconds(i);
+            manager
+                .persist_message(&message, MessageSource::Live)
+                .await
+                .unwrap();
+        }
+        let mut other = make_chat_message("other-1", "bob@example.com", "me@example.com", "yo");
+        other.timestamp = base + chrono::Duration::seconds(3);
+        manager
+            .persist_message(&other, MessageSource::Live)
+            .await
+            .unwrap();
+
+        let window = manager
+            .context_around(&direct("alice@example.com"), "c-3", 2)
+            .await
+            .unwrap();
+        let ids: Vec<&str> = window.iter().map(|m| m.id.as_str()).collect();
+        assert_eq!(ids, vec!["c-1", "c-2", "c-3", "c-4", "c-5"]);
+
+        let edge = manager
+            .context_around(&direct("alice@example.com"), "c-0", 2)
+            .await
+            .unwrap();
+        let ids: Vec<&str> = edge.iter().map(|m| m.id.as_str()).collect();
+        assert_eq!(ids, vec!["c-0", "c-1", "c-2"]);
+
+        let foreign = manager
+            .context_around(&direct("alice@example.com"), "other-1", 2)
+            .await
+            .unwrap();
+        assert!(foreign.is_empty());
+    }
+
+    #[tokio::test]
+    async fn snapshot_summarises_seeded_conversations() {
+        let (manager, _, _dir) = setup().await;
+
+        let base = Utc::now();
+        let seeded = [
+            ("a-1", "alice@example.com", "me@example.com"),
+            ("a-2", "me@example.com/laptop", "alice@example.com"),
+            ("b-1", "bob@example.com", "me@example.com"),
+            ("b-2", "bob@example.com", "me@example.com"),
+        ];
+        for (offset, (id, from, to)) in seeded.into_iter().enumerate() {
+            let mut message = make_chat_message(id, from, to, "hi");
+            message.timestamp = base + chrono::Duration::seconds(offset as i64);
+            manager
+                .persist_message(&message, MessageSource::Live)
+                .await
+                .unwrap();
+        }
+        manager.mark_read("alice@example.com").await.unwrap();
+
+        let snapshot = manager.snapshot("me@example.com").await.unwrap();
+        let summaries: Vec<(&str, &str, u64)> = snapshot
+            .conversations
+            .iter()
+            .map(|c| (c.jid.as_str(), c.last_message.id.as_str(), c.unread))
+            .collect();
+        assert_eq!(
+            summaries,
+            vec![
+                ("bob@example.com", "b-2", 2),
+                ("alice@example.com", "a-2", 0),
+            ]
+        );
+
+        let json = serde_json::to_value(&snapshot).unwrap();
+        assert_eq!(json["conversations"][0]["lastMessage"]["id"], "b-2");
+    }
+
+    #[tokio::test]
+    async fn conversation_order_follows_deletes() {
+        let (manager, _, _dir) = setup().await;
+        let base = Utc::now() - chrono::Duration::minutes(10);
+        for (offset, id, from) in [
+            (0, "c-1", "carol@example.com"),
+            (1, "b-1", "bob@example.com"),
+            (2, "c-2", "carol@example.com"),
+        ] {
+            let mut message = make_chat_message(id, from, "me@example.com", "hi");
+            message.timestamp = base + chrono::Duration::seconds(offset);
+            manager
+                .persist_message(&message, MessageSource::Live)
+                .await
+                .unwrap();
+        }
+        let order = |conversations: Vec<ConversationSummary>| -> Vec<(String, String)> {
+            conversations
+                .into_iter()
+                .map(|c| (c.jid, c.last_message.id))
+                .collect()
+        };
+        assert_eq!(
+            order(
+                manager
+                    .list_conversations("me@example.com", false)
+                    .await
+                    .unwrap()
+            ),
+            vec![
+                ("carol@example.com".to_string(), "c-2".to_string()),
+                ("bob@example.com".to_string(), "b-1".to_string()),
+            ]
+        );
+
+        let id = "c-2".to_string();
+        manager
+            .db
+            .execute("DELETE FROM messages WHERE id = ?1", &[&id])
+            .await
+            .unwrap();
+        assert_eq!(
+            order(
+                manager
+                    .list_conversations("me@example.com", false)
+                    .await
+                    .unwrap()
+            ),
+            vec![
+                ("bob@example.com".to_string(), "b-1".to_string()),
+                ("carol@example.com".to_string(), "c-1".to_string()),
+            ]
+        );
+
+        manager
+            .delete_conversation("bob@example.com")
+            .await
+            .unwrap();
+        assert_eq!(
+            order(
+                manager
+                    .list_conversations("me@example.com", false)
+                    .await
+                    .unwrap()
+            ),
+            vec![("carol@example.com".to_string(), "c-1".to_string())]
+        );
+    }
+
+    #[tokio::test]
+    async fn archived_conversation_hidden_until_new_message() {
+        let (manager, _, _dir) = setup().await;
+
+        for (id, from) in [("a-1", "alice@example.com"), ("b-1", "bob@example.com")] {
+            let message = make_chat_message(id, from, "me@example.com", "hi");
+            manager
+                .persist_message(&message, MessageSource::Live)
+                .await
+                .unwrap();
+        }
+        manager.archive("alice@example.com").await.unwrap();
+
+        let listed: Vec<String> = manager
+            .list_conversations("me@example.com", false)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|c| c.jid)
+            .collect();
+        assert_eq!(listed, vec!["bob@example.com"]);
+
+        let all = manager
+            .list_conversations("me@example.com", true)
+            .await
+            .unwrap();
+        let alice = all
+            .iter()
+            .find(|c| c.jid == "alice@example.com")
+            .expect("archived conversation should be included on request");
+        assert!(alice.archived);
+
+        let event = make_event(
+            "xmpp.message.received",
+            EventPayload::MessageReceived {
+                message: make_chat_message("a-2", "alice@example.com", "me@example.com", "back"),
+            },
+        );
+        manager.handle_event(&event).await;
+
+        let listed = manager
+            .list_conversations("me@example.com", false)
+            .await
+            .unwrap();
+        assert_eq!(listed[0].jid, "alice@example.com");
+        assert!(!listed[0].archived);
+        assert_eq!(listed.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn manually_unread_conversation_counts_until_mark_read() {
+        let (manager, _, _dir) = setup().await;
+
+        for (id, from, to) in [
+            ("a-1", "alice@example.com", "me@example.com"),
+            ("a-2", "me@example.com", "alice@example.com"),
+            ("b-1", "bob@example.com", "me@example.com"),
+            ("b-2", "bob@example.com", "me@example.com"),
+        ] {
+            let message = make_chat_message(id, from, to, "hi");
+            manager
+                .persist_message(&message, MessageSource::Live)
+                .await
+                .unwrap();
+        }
+        manager.mark_read("alice@example.com").await.unwrap();
+        assert_eq!(
+            manager.unread_counts_all("me@example.com").await.unwrap(),
+            HashMap::from([("bob@example.com".to_string(), 2)])
+        );
+
+        manager.set_unread("alice@example.com", true).await.unwrap();
+        manager.set_unread("bob@example.com", true).await.unwrap();
+        let counts = manager.unread_counts_all("me@example.com").await.unwrap();
+        assert_eq!(counts.get("alice@example.com"), Some(&1));
+        assert_eq!(counts.get("bob@example.com"), Some(&2));
+        let alice = manager
+            .list_conversations("me@example.com", false)
+            .await
+            .unwrap()
+            .into_iter()
+            .find(|c| c.jid == "alice@example.com")
+            .unwrap();
+        assert_eq!(alice.unread, 1);
+
+        manager.mark_read("alice@example.com").await.unwrap();
+        let counts = manager.unread_counts_all("me@example.com").await.unwrap();
+        assert!(!counts.contains_key("alice@example.com"));
+        assert_eq!(counts.get("bob@example.com"), Some(&2));
+    }
+
+    #[tokio::test]
+    async fn unread_counts_exclude_only_our_own_jid_literally() {
+        let (manager, _, _dir) = setup().await;
+        for (id, from) in [
+            ("own", "m_@example.com/laptop"),
+            ("peer", "me@example.com/phone"),
+        ] {
+            let message = make_chat_message(id, from, "m_@example.com", "hi");
+            manager
+                .persist_message(&message, MessageSource::Live)
+                .await
+                .unwrap();
+        }
+
+        assert_eq!(
+            manager.unread_counts_all("m_@example.com").await.unwrap(),
+            HashMap::from([("me@example.com/phone".to_string(), 1)])
+        );
+    }
+
+    #[tokio::test]
+    async fn mark_all_read_clears_every_conversation() {
+        let (manager, _, _dir) = setup().await;
+
+        for (id, from) in [
+            ("a-1", "alice@example.com"),
+            ("a-2", "alice@example.com"),
+            ("b-1", "bob@example.com"),
+            ("c-1", "carol@example.com"),
+        ] {
+            let message = make_chat_message(id, from, "me@example.com", "hi");
+            manager
+                .persist_message(&message, MessageSource::Live)
+                .await
+                .unwrap();
+        }
+        manager.mark_read("carol@example.com").await.unwrap();
+        manager.set_unread("carol@example.com", true).await.unwrap();
+        assert_eq!(
+            manager
+                .unread_counts_all("me@example.com")
+                .await
+                .unwrap()
+                .len(),
+            3
+        );
+
+        assert_eq!(manager.mark_all_read().await.unwrap(), 3);
+        assert!(
+            manager
+                .unread_counts_all("me@example.com")
+                .await
+                .unwrap()
+                .is_empty()
+        );
+        assert_eq!(manager.mark_all_read().await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn stats_aggregate_seeded_messages() {
+        let (manager, _, _dir) = setup().await;
+
+        let empty = manager.stats().await.unwrap();
+        assert_eq!(empty.total, 0);
+        assert_eq!(empty.distinct_conversations, 0);
+        assert!(empty.by_type.is_empty());
+        assert!(empty.oldest.is_none());
+
+        let base = Utc::now();
+        let mut seeded = vec![
+            make_chat_message("s-1", "alice@example.com", "me@example.com", "hi"),
+            make_chat_message("s-2", "me@example.com", "alice@example.com", "hey"),
+            make_chat_message("s-3", "bob@example.com", "me@example.com", "yo"),
+        ];
+        let mut room_message = make_chat_message(
+            "s-4",
+            "room@conference.example.com/carol",
+            "room@conference.example.com",
+            "hello room",
+        );
+        room_message.message_type = MessageType::Groupchat;
+        seeded.push(room_message);
+        for (offset, message) in seeded.iter_mut().enumerate() {
+            message.timestamp = base + chrono::Duration::seconds(offset as i64);
+            manager
+                .persist_message(message, MessageSource::Live)
+                .await
+                .unwrap();
+        }
+
+        let stats = manager.stats().await.unwrap();
+        assert_eq!(stats.total, 4);
+        assert_eq!(stats.by_type.get("chat"), Some(&3));
+        assert_eq!(stats.by_type.get("groupchat"), Some(&1));
+        // alice (both directions), bob, and the room
+        assert_eq!(stats.distinct_conversations, 3);
+        assert_eq!(stats.oldest.map(|t| t.timestamp()), Some(base.timestamp()));
+        assert_eq!(
+            stats.newest.map(|t| t.timestamp()),
+            Some((base + chrono::Duration::seconds(3)).timestamp())
+        );
+    }
+
+    #[tokio::test]
+    async fn delete_conversation_removes_only_that_conversation() {
+        let (manager, _, _dir) = setup().await;
+
+        for message in [
+            make_chat_message("d-1", "alice@example.com", "me@example.com", "hi"),
+            make_chat_message("d-2", "me@example.com", "alice@example.com", "hey"),
+            make_chat_message("d-3", "bob@example.com", "me@example.com", "yo"),
+        ] {
+            manager
+                .persist_message(&message, MessageSource::Live)
+                .await
+                .unwrap();
+        }
+        // Sent while offline: still queued, so it must survive the delete.
+        let queued = manager
+            .send_message("alice@example.com", "queued")
+            .await
+            .unwrap();
+
+        let deleted = manager
+            .delete_conversation("alice@example.com")
+            .await
+            .unwrap();
+        assert_eq!(deleted, 2);
+
+        let alice = manager
+            .get_messages(&direct("alice@example.com"), 50, None)
+            .await
+            .unwrap();
+        assert_eq!(alice.len(), 1);
+        assert_eq!(alice[0].id, queued.id);
+
+        let bob = manager
+            .get_messages(&direct("bob@example.com"), 50, None)
+            .await
+            .unwrap();
+        assert_eq!(bob.len(), 1);
+        assert_eq!(bob[0].id, "d-3");
+    }
+
+    #[tokio::test]
+    async fn delete_conversation_drops_its_pins_receipts_and_flags() {
+        let (manager, _, _dir) = setup().await;
+
+        let message = make_chat_message("p-1", "me@example.com", "alice@example.com", "hi");
+        manager
+            .persist_message(&message, MessageSource::Live)
+            .await
+            .unwrap();
+        manager.pin_local("alice@example.com", "p-1").await.unwrap();
+        assert!(
+            manager
+                .record_delivery("p-1", "alice@example.com/phone")
+                .await
+                .unwrap()
+        );
+        manager.archive("alice@example.com").await.unwrap();
+        manager.set_unread("alice@example.com", true).await.unwrap();
+
+        manager
+            .delete_conversation("alice@example.com")
+            .await
+            .unwrap();
+
+        let count = |sql: &'static str| {
+            let manager = manager.clone();
+            async move {
+                let rows: Vec<Row> = manager.db.query(sql, &[]).await.unwrap();
+                row_count(&rows[0], 0)
+            }
+        };
+        assert_eq!(count("SELECT COUNT(*) FROM local_pins").await, 0);
+        assert_eq!(count("SELECT COUNT(*) FROM message_deliveries").await, 0);
+        assert_eq!(
+            count("SELECT COUNT(*) FROM conversation_meta WHERE jid = 'alice@example.com'").await,
+            0
+        );
+
+        // Reusing the id in a new conversation starts clean.
+        manager
+            .persist_message(&message, MessageSource::Live)
+            .await
+            .unwrap();
+        assert!(
+            manager
+                .local_pins("alice@example.com")
+                .await
+                .unwrap()
+                .is_empty()
+        );
+        assert!(manager.delivered_resources("p-1").await.unwrap().is_empty());
+        let listed = manager
+            .list_conversations("me@example.com", false)
+            .await
+            .unwrap();
+        assert_eq!(listed.len(), 1);
+        assert!(!listed[0].archived);
+        assert_eq!(listed[0].unread, 0);
+    }
+
+    #[tokio::test]
+    async fn send_chat_state_emits_event() {
+        let (manager, event_bus, _dir) = setup().await;
+        let mut sub = event_bus.subscribe("ui.**").unwrap();
+        set_connection_online(manager.as_ref()).await;
+
+        manager
+            .send_chat_state("bob@example.com", ChatState::Composing)
+            .await
+            .unwrap();
+
+        let received = tokio::time::timeout(std::time::Duration::from_millis(100), sub.recv())
+            .await
+            .expect("timed out")
+            .expect("should receive event");
+
+        assert!(matches!(
+            received.payload,
+            EventPayload::ChatStateSendRequested {
+                ref to,
+                state: ChatState::Composing,
+            } if to == "bob@example.com"
+        ));
+    }
+
+    #[tokio::test]
+    async fn handle_delivery_receipt_does_not_error() {
+        let (manager, _, _dir) = setup().await;
+
+        let event = make_event(
+            "xmpp.message.delivered",
+            EventPayload::MessageDelivered {
+                id: "msg-1".to_string(),
+                to: "bob@example.com".to_string(),
+                resource: None,
+            },
+        );
+        manager.handle_event(&event).await;
+    }
+
+    #[tokio::test]
+    async fn handle_chat_state_received_does_not_error() {
+        let (manager, _, _dir) = setup().await;
+
+        let event = make_event(
+            "xmpp.chatstate.received",
+            EventPayload::ChatStateReceived {
+                from: "alice@example.com".to_string(),
+                state: ChatState::Composing,
+            },
+        );
+        manager.handle_event(&event).await;
+    }
+
+    #[tokio::test]
+    async fn inbound_gone_clears_composing() {
+        let (manager, _, _dir) = setup().await;
+        for state in [ChatState::Composing, ChatState::Gone] {
+            manager
+                .handle_event(&make_event(
+                    "xmpp.chatstate.received",
+                    EventPayload::ChatStateReceived {
+                        from: "alice@example.com/phone".to_string(),
+                        state,
+                    },
+                ))
+                .await;
+        }
+
+        assert!(manager.peer_chat_state("alice@example.com").is_none());
+        assert!(!manager.is_typing("alice@example.com"));
+        assert!(manager.peer_chat_states.read().unwrap().is_empty());
+        assert!(manager.composing_resources.read().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn chat_states_are_forgotten_when_they_expire_or_we_disconnect() {
+        let (manager, _, _dir) = setup().await;
+        let chat_state = |from: &str, state: ChatState| {
+            make_event(
+                "xmpp.chatstate.received",
+                EventPayload::ChatStateReceived {
+                    from: from.to_string(),
+                    state,
+                },
+            )
+        };
+
+        manager
+            .handle_event(&chat_state("alice@example.com/phone", ChatState::Composing))
+            .await;
+        let stale = Utc::now() - chrono::Duration::seconds(CHAT_TYPING_EXPIRY_SECS + 1);
+        for since in manager
+            .composing_resources
+            .write()
+            .unwrap()
+            .values_mut()
+            .flat_map(|resources| resources.values_mut())
+        {
+            *since = stale;
+        }
+        manager
+            .handle_event(&chat_state("bob@example.com/desk", ChatState::Active))
+            .await;
+        assert!(manager.composing_resources.read().unwrap().is_empty());
+
+        manager
+            .handle_event(&make_event(
+                "system.connection.lost",
+                EventPayload::ConnectionLost {
+                    reason: "test".to_string(),
+                    will_retry: true,
+                    resumable: false,
+                },
+            ))
+            .await;
+        assert!(manager.peer_chat_state("alice@example.com").is_none());
+        assert!(manager.peer_chat_state("bob@example.com").is_none());
+    }
+
+    #[tokio::test]
+    async fn typing_aggregates_resources_until_all_clear() {
+        let (manager, _, _dir) = setup().await;
+        let chat_state = |from: &str, state: ChatState| {
+            make_event(
+                "xmpp.chatstate.received",
+                EventPayload::ChatStateReceived {
+                    from: from.to_string(),
+                    state,
+                },
+            )
+        };
+
+        manager
+            .handle_event(&chat_state("alice@example.com/phone", ChatState::Composing))
+            .await;
+        manager
+            .handle_event(&chat_state(
+                "alice@example.com/laptop",
+                ChatState::Composing,
+            ))
+            .await;
+        manager
+            .handle_event(&chat_state("alice@example.com/laptop", ChatState::Paused))
+            .await;
+        assert!(manager.is_typing("alice@example.com"));
+        assert!(!manager.is_typing("bob@example.com"));
+
+        manager
+            .handle_event(&chat_state("alice@example.com/phone", ChatState::Paused))
+            .await;
+        assert!(!manager.is_typing("alice@example.com"));
+
+        manager
+            .handle_event(&chat_state("alice@example.com/phone", ChatState::Composing))
+            .await;
+        let stale = Utc::now() - chrono::Duration::seconds(CHAT_TYPING_EXPIRY_SECS + 1);
+        for since in manager
+            .composing_resources
+            .write()
+            .unwrap()
+            .values_mut()
+            .flat_map(|resources| resources.values_mut())
+        {
+            *since = stale;
+        }
+        assert!(!manager.is_typing("alice@example.com"));
+    }
+
+    #[tokio::test]
+    async fn close_conversation_sends_gone() {
+        let (manager, event_bus, _dir) = setup().await;
+        let mut sub = event_bus.subscribe("ui.chatstate.send").unwrap();
+        set_connection_online(manager.as_ref()).await;
+
+        manager.close_conversation("bob@example.com").await.unwrap();
+
+        let received = tokio::time::timeout(std::time::Duration::from_millis(100), sub.recv())
+            .await
+            .expect("timed out")
+            .expect("should receive event");
+        assert!(matches!(
+            received.payload,
+            EventPayload::ChatStateSendRequested {
+                ref to,
+                state: ChatState::Gone,
+            } if to == "bob@example.com"
+        ));
+    }
+
+    #[tokio::test]
+    async fn active_chat_state_marks_sent_messages_displayed_when_enabled() {
+        let (manager, event_bus, _dir) = setup().await;
+        set_connection_online(manager.as_ref()).await;
+        let mut sub = event_bus.subscribe("ui.message.displayed").unwrap();
+        let active = make_event(
+            "xmpp.chatstate.received",
+            EventPayload::ChatStateReceived {
+                from: "bob@example.com/phone".to_string(),
+                state: ChatState::Active,
+            },
+        );
+
+        let sent = manager.send_message("bob@example.com", "hi").await.unwrap();
+        manager.handle_event(&active).await;
+        assert!(!manager.is_displayed(&sent.id).await.unwrap());
+
+        manager.set_chat_state_marks_displayed(true);
+        manager.handle_event(&active).await;
+        assert!(manager.is_displayed(&sent.id).await.unwrap());
+        let received = tokio::time::timeout(std::time::Duration::from_millis(100), sub.recv())
+            .await
+            .expect("timed out")
+            .expect("recv error");
+        assert!(matches!(
+            received.payload,
+            EventPayload::MessagesDisplayed { ref to } if to == "bob@example.com"
+        ));
+
+        // `_` in a JID is not a wildcard.
+        let other = manager
+            .send_message("alice@example.com/phone", "hi")
+            .await
+            .unwrap();
+        assert_eq!(
+            manager
+                .mark_sent_displayed("a_ice@example.com")
+                .await
+                .unwrap(),
+            0
+        );
+        assert!(!manager.is_displayed(&other.id).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn messages_with_thread_round_trip() {
+        let (manager, _, _dir) = setup().await;
+
+        let msg = ChatMessage {
+            id: "msg-thread".to_string(),
+            from: "alice@example.com".to_string(),
+            to: "me@example.com".to_string(),
+            body: "threaded message".to_string(),
+            timestamp: Utc::now(),
+            message_type: MessageType::Chat,
+            thread: Some("thread-123".to_string()),
+            embeds: vec![],
+            bodies: BTreeMap::new(),
+            origin_id: None,
+            spoiler: None,
+            stanza_id: None,
+        };
+        manager
+            .persist_message(&msg, MessageSource::Live)
+            .await
+            .unwrap();
+
+        let messages = manager
+            .get_messages(&direct("alice@example.com"), 50, None)
+            .await
+            .unwrap();
+
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].thread, Some("thread-123".to_string()));
+    }
+
+    #[tokio::test]
+    async fn groupchat_messages_excluded_from_get_messages() {
+        let (manager, _, _dir) = setup().await;
+
+        let chat_msg = ChatMessage {
+            id: "msg-chat".to_string(),
+            from: "alice@example.com".to_string(),
+            to: "me@example.com".to_string(),
+            body: "chat message".to_string(),
+            timestamp: Utc::now(),
+            message_type: MessageType::Chat,
+            thread: None,
+            embeds: vec![],
+            bodies: BTreeMap::new(),
+            origin_id: None,
+            spoiler: None,
+            stanza_id: None,
+        };
+        manager
+            .persist_message(&chat_msg, MessageSource::Live)
+            .await
+            .unwrap();
+
+        let gc_msg = ChatMessage {
+            id: "msg-gc".to_string(),
+            from: "room@muc.example.com".to_string(),
+            to: "me@example.com".to_string(),
+            body: "group message".to_string(),
+            timestamp: Utc::now(),
+            message_type: MessageType::Groupchat,
+            thread: None,
+            embeds: vec![],
+            bodies: BTreeMap::new(),
+            origin_id: None,
+            spoiler: None,
+            stanza_id: None,
+        };
+        manager
+            .persist_message(&gc_msg, MessageSource::Live)
+            .await
+            .unwrap();
+
+        let messages = manager
+            .get_messages(&direct("alice@example.com"), 50, None)
+            .await
+            .unwrap();
+
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].id, "msg-chat");
+    }
+
+    #[tokio::test]
+    async fn run_loop_processes_events() {
+        let local = tokio::task::LocalSet::new();
+        local
+            .run_until(async {
+                let (manager, event_bus, _dir) = setup().await;
+
+                let manager_clone = manager.clone();
+                let handle = tokio::task::spawn_local(async move { manager_clone.run().await });
+
+                tokio::task::yield_now().await;
+                tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+
+                let msg = make_chat_message(
+                    "msg-run",
+                    "alice@example.com",
+                    "me@example.com",
+                    "via run loop",
+                );
+
+                event_bus
+                    .publish(Event::new(
+                        Channel::new("xmpp.message.received").unwrap(),
+                        EventSource::Xmpp,
+                        EventPayload::MessageReceived { message: msg },
+                    ))
+                    .unwrap();
+
+                tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+
+                let messages = manager
+                    .get_messages(&direct("alice@example.com"), 50, None)
+                    .await
+                    .unwrap();
+
+                assert_eq!(messages.len(), 1);
+                assert_eq!(messages[0].body, "via run loop");
+
+                handle.abort();
+            })
+            .await;
+    }
+
+    #[tokio::test]
+    async fn bidirectional_conversation_retrieval() {
+        let (manager, _, _dir) = setup().await;
+
+        let msg1 = make_chat_message(
+            "msg-from-alice",
+            "alice@example.com",
+            "me@example.com",
+            "Hello from Alice",
+        );
+        manager
+            .persist_message(&msg1, MessageSource::Live)
+            .await
+            .unwrap();
+
+        let msg2 = make_chat_message(
+            "msg-to-alice",
+            "me@example.com",
+            "alice@example.com",
+            "Hello back",
+        );
+        manager
+            .persist_message(&msg2, MessageSource::Live)
+            .await
+            .unwrap();
+
+        let messages = manager
+            .get_messages(&direct("alice@example.com"), 50, None)
+            .await
+            .unwrap();
+
+        assert_eq!(messages.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn send_message_offline_enqueues_without_emitting_ui_event() {
+        let (manager, event_bus, _dir) = setup().await;
+        let mut sub = event_bus.subscribe("ui.message.send").unwrap();
+
+        let message = manager
+            .send_message("bob@example.com", "queued while offline")
+            .await
+            .unwrap();
+
+        let ui_event =
+            tokio::time::timeout(std::time::Duration::from_millis(100), sub.recv()).await;
+        assert!(
+            ui_event.is_err(),
+            "offline send should not publish ui.message.send"
+        );
+
+        let rows: Vec<Row> = manager
+            .db
+            .query(
+                "SELECT stanza_type, status FROM offline_queue ORDER BY id ASC",
+                &[],
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(rows.len(), 1);
+        assert_eq!(rows[0].get(0), Some(&SqlValue::Text("message".to_string())));
+        assert_eq!(rows[0].get(1), Some(&SqlValue::Text("pending".to_string())));
+
+        let stored = manager
+            .get_messages(&direct("bob@example.com"), 50, None)
+            .await
+            .unwrap();
+        assert_eq!(stored.len(), 1);
+        assert_eq!(stored[0].id, message.id);
+    }
+
+    #[tokio::test]
+    async fn offline_chat_state_is_dropped_not_queued() {
+        let (manager, event_bus, _dir) = setup().await;
+        let mut sub = event_bus.subscribe("ui.chatstate.send").unwrap();
+
+        manager
+            .send_chat_state("bob@example.com", ChatState::Composing)
+            .await
+            .unwrap();
+        // A chat state published straight onto the bus is not queued either.
+        manager
+            .handle_event(&make_event(
+                "ui.chatstate.send",
+                EventPayload::ChatStateSendRequested {
+                    to: "bob@example.com".to_string(),
+                    state: ChatState::Paused,
+                },
+            ))
+            .await;
+
+        let rows: Vec<Row> = manager
+            .db
+            .query("SELECT id FROM offline_queue", &[])
+            .await
+            .unwrap();
+        assert!(rows.is_empty());
+        let ui_event =
+            tokio::time::timeout(std::time::Duration::from_millis(100), sub.recv()).await;
+        assert!(
+            ui_event.is_err(),
+            "offline chat state must not be published"
+        );
+    }
+
+    #[tokio::test]
+    async fn recorded_offline_chat_state_is_logged_but_never_sent() {
+        let (manager, event_bus, _dir) = setup().await;
+        manager.set_record_offline_chat_states(true);
+        let mut sub = event_bus.subscribe("ui.chatstate.send").unwrap();
+
+        manager
+            .send_chat_state("bob@example.com", ChatState::Composing)
+            .await
+            .unwrap();
+
+        let rows: Vec<Row> = manager
+            .db
+            .query("SELECT to_jid, state FROM chat_state_log", &[])
+            .await
+            .unwrap();
+        assert_eq!(rows.len(), 1);
+        assert_eq!(
+            rows[0].get(0),
+            Some(&SqlValue::Text("bob@example.com".to_string()))
+        );
+        assert_eq!(
+            rows[0].get(1),
+            Some(&SqlValue::Text("composing".to_string()))
+        );
+
+        set_connection_online(manager.as_ref()).await;
+        let ui_event =
+            tokio::time::timeout(std::time::Duration::from_millis(100), sub.recv()).await;
+        assert!(
+            ui_event.is_err(),
+            "a logged chat state must not be sent on reconnect"
+        );
+    }
+
+    #[tokio::test]
+    async fn chat_state_log_keeps_only_the_newest_rows() {
+        let (manager, _, _dir) = setup().await;
+        manager.set_record_offline_chat_states(true);
+        manager
+            .db
+            .execute(
+                "WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n WHERE i < ?1) \
+                 INSERT INTO chat_state_log (to_jid, state, recorded_at) \
+                 SELECT 'old@example.com', 'composing', '2025-01-01T00:00:00Z' FROM n",
+                &[&CHAT_STATE_LOG_MAX_ROWS],
+            )
+            .await
+            .unwrap();
+
+        manager
+            .send_chat_state("bob@example.com", ChatState::Paused)
+            .await
+            .unwrap();
+
+        let rows: Vec<Row> = manager
+            .db
+            .query(
+                "SELECT COUNT(*), MAX(to_jid = 'bob@example.com') FROM chat_state_log",
+                &[],
+            )
+            .await
+            .unwrap();
+        assert_eq!(
+            rows[0].get(0),
+            Some(&SqlValue::Integer(CHAT_STATE_LOG_MAX_ROWS))
+        );
+        assert_eq!(rows[0].get(1), Some(&SqlValue::Integer(1)));
+    }
+
+    #[tokio::test]
+    async fn duplicate_connection_established_does_not_drain_again() {
+        let (manager, event_bus, _dir) = setup().await;
+        set_connection_online(manager.as_ref()).await;
+
+        // A pending item that a second drain would pick up.
+        manager
+            .enqueue_command_event(
+                "ui.message.send",
+                EventPayload::MessageSendRequested {
+                    to: "bob@example.com".to_string(),
+                    body: "still queued".to_string(),
+                    message_type: MessageType::Chat,
+                },
+                None,
+            )
+            .await
+            .unwrap();
+
+        let mut sub = event_bus.subscribe("{ui,system}.**").unwrap();
+        set_connection_online(manager.as_ref()).await;
+        let duplicate =
+            tokio::time::timeout(std::time::Duration::from_millis(100), sub.recv()).await;
+        assert!(
+            duplicate.is_err(),
+            "duplicate connection should neither drain nor announce coming online"
+        );
+
+        // A session for a different JID is a real reconnect and drains.
+        manager
+            .handle_event(&make_event(
+                "system.connection.established",
+                EventPayload::ConnectionEstablished {
+                    jid: "alice@example.com/other".to_string(),
+                },
+            ))
+            .await;
+        let drained = tokio::time::timeout(std::time::Duration::from_millis(100), sub.recv())
+            .await
+            .expect("timed out waiting for drained item")
+            .expect("expected drained item");
+        assert!(matches!(
+            drained.payload,
+            EventPayload::MessageSendRequested { ref body, .. } if body == "still queued"
+        ));
+    }
+
+    #[tokio::test]
+    async fn reconnect_drains_offline_queue_fifo_and_marks_sent() {
+        let (manager, event_bus, _dir) = setup().await;
+
+        let first = manager
+            .send_message("bob@example.com", "first queued")
+            .await
+            .unwrap();
+        let second = manager
+            .send_message("carol@example.com", "second queued")
+            .await
+            .unwrap();
+
+        let mut sub = event_bus.subscribe("ui.message.send").unwrap();
+        set_connection_online(manager.as_ref()).await;
+
+        let first_event = tokio::time::timeout(std::time::Duration::from_millis(100), sub.recv())
+            .await
+            .expect("timed out waiting for first drained item")
+            .expect("expected first drained item");
+        let second_event = tokio::time::timeout(std::time::Duration::from_millis(100), sub.recv())
+            .await
+            .expect("timed out waiting for second drained item")
+            .expect("expected second drained item");
+
+        assert!(matches!(
+            first_event.payload,
+            EventPayload::MessageSendRequested { ref body, .. } if body == "first queued"
+        ));
+        assert!(matches!(
+            second_event.payload,
+            EventPayload::MessageSendRequested { ref body, .. } if body == "second queued"
+        ));
+
+        manager
+            .handle_event(&make_event(
+                "xmpp.message.sent",
+                EventPayload::MessageSent {
+                    message: make_chat_message(
+                        &first.id,
+                        "alice@example.com",
+                        "bob@example.com",
+                        "first queued",
+                    ),
+                },
+            ))
+            .await;
+
+        manager
+            .handle_event(&make_event(
+                "xmpp.message.sent",
+                EventPayload::MessageSent {
+                    message: make_chat_message(
+                        &second.id,
+                        "alice@example.com",
                         "carol@example.com",
                         "second queued",
                     ),
@@ -1872,176 +6171,1821 @@ mod tests {
             ))
             .await;
 
-        let rows: Vec<Row> = manager
-            .db
-            .query("SELECT status FROM offline_queue ORDER BY id ASC", &[])
+        let rows: Vec<Row> = manager
+            .db
+            .query("SELECT status FROM offline_queue ORDER BY id ASC", &[])
+            .await
+            .unwrap();
+
+        assert_eq!(rows.len(), 2);
+        assert_eq!(rows[0].get(0), Some(&SqlValue::Text("sent".to_string())));
+        assert_eq!(rows[1].get(0), Some(&SqlValue::Text("sent".to_string())));
+    }
+
+    #[tokio::test]
+    async fn persist_failure_retries_then_emits_event() {
+        let db = Arc::new(MockDatabase::new());
+        db.fail_matching("INTO messages", MockFailure::DiskFull, 3);
+        let event_bus: Arc<dyn EventBus> = Arc::new(BroadcastEventBus::default());
+        let manager = MessageManager::new(db.clone(), event_bus.clone());
+        manager.set_persist_failure_policy(RetryPolicy::fixed(2, std::time::Duration::ZERO));
+        let mut sub = event_bus.subscribe("ui.message.persist_failed").unwrap();
+
+        manager
+            .handle_event(&make_event(
+                "xmpp.message.received",
+                EventPayload::MessageReceived {
+                    message: make_chat_message(
+                        "lost-1",
+                        "bob@example.com",
+                        "alice@example.com",
+                        "hello",
+                    ),
+                },
+            ))
+            .await;
+
+        let stored = |db: &MockDatabase| {
+            db.executed()
+                .iter()
+                .filter(|(sql, _)| sql.contains("INTO messages"))
+                .count()
+        };
+        assert_eq!(stored(&db), 0);
+        let failed = tokio::time::timeout(std::time::Duration::from_millis(100), sub.recv())
+            .await
+            .expect("timed out waiting for persist failure")
+            .expect("expected persist failure event");
+        assert!(matches!(
+            failed.payload,
+            EventPayload::MessagePersistFailed { ref id } if id == "lost-1"
+        ));
+
+        db.fail_matching("INTO messages", MockFailure::Busy, 2);
+        manager
+            .handle_event(&make_event(
+                "xmpp.message.received",
+                EventPayload::MessageReceived {
+                    message: make_chat_message(
+                        "kept-1",
+                        "bob@example.com",
+                        "alice@example.com",
+                        "hello again",
+                    ),
+                },
+            ))
+            .await;
+        assert_eq!(stored(&db), 1);
+        let no_event =
+            tokio::time::timeout(std::time::Duration::from_millis(100), sub.recv()).await;
+        assert!(
+            no_event.is_err(),
+            "a retry that succeeds must not report failure"
+        );
+    }
+
+    #[tokio::test]
+    #[tracing_test::traced_test]
+    async fn send_echo_span_carries_correlation_id() {
+        let (manager, event_bus, _dir) = setup().await;
+        set_connection_online(manager.as_ref()).await;
+        let mut sub = event_bus.subscribe("ui.message.send").unwrap();
+
+        let sent = manager
+            .send_message("bob@example.com", "traced")
+            .await
+            .unwrap();
+        let request = tokio::time::timeout(std::time::Duration::from_millis(100), sub.recv())
+            .await
+            .expect("timed out waiting for send request")
+            .expect("expected send request");
+        let correlation_id = request
+            .correlation_id
+            .expect("send carries a correlation id");
+        assert_eq!(correlation_id.to_string(), sent.id);
+
+        manager
+            .handle_event(&Event::with_correlation(
+                Channel::new("xmpp.message.sent").unwrap(),
+                EventSource::Xmpp,
+                EventPayload::MessageSent {
+                    message: make_chat_message(
+                        &sent.id,
+                        "alice@example.com",
+                        "bob@example.com",
+                        "traced",
+                    ),
+                },
+                correlation_id,
+            ))
+            .await;
+
+        assert!(logs_contain(&format!(
+            "handle_event{{channel=xmpp.message.sent correlation_id={correlation_id}}}"
+        )));
+        assert!(logs_contain("message sent, persisting"));
+    }
+
+    #[tokio::test]
+    async fn shutdown_flushes_offline_queue_before_stopping_run_loops() {
+        let local = tokio::task::LocalSet::new();
+        local
+            .run_until(async {
+                let (manager, event_bus, _dir) = setup().await;
+                let queued = manager
+                    .send_message("bob@example.com", "sent on close")
+                    .await
+                    .unwrap();
+
+                let (wire_sender, mut wire_receiver) = waddle_xmpp::stanza_channel(8);
+                let router = Arc::new(waddle_xmpp::OutboundRouter::new(
+                    event_bus.clone(),
+                    Arc::new(waddle_xmpp::StanzaPipeline::new()),
+                    wire_sender,
+                ));
+                let _router = tokio::task::spawn_local({
+                    let router = router.clone();
+                    async move {
+                        let _ = router.run().await;
+                    }
+                });
+                let wire = Arc::new(std::sync::Mutex::new(Vec::new()));
+                let _pump = tokio::task::spawn_local({
+                    let wire = wire.clone();
+                    async move {
+                        while let Some(bytes) = wire_receiver.recv().await {
+                            wire.lock().unwrap().push(String::from_utf8(bytes).unwrap());
+                        }
+                    }
+                });
+                tokio::task::yield_now().await;
+                event_bus
+                    .publish(make_event(
+                        "system.connection.established",
+                        EventPayload::ConnectionEstablished {
+                            jid: "alice@example.com/desktop".to_string(),
+                        },
+                    ))
+                    .unwrap();
+                tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+                // Online, but the reconnect drain has not run yet.
+                manager.set_connected(Some("alice@example.com/desktop"));
+
+                let run_loop = tokio::task::spawn_local({
+                    let manager = manager.clone();
+                    async move {
+                        let _ = manager.run().await;
+                    }
+                });
+                let mut managers = ManagerSet::new(manager.clone(), run_loop);
+                let (stopped_tx, stopped_rx) = tokio::sync::oneshot::channel::<()>();
+                managers.add_task(
+                    "idle",
+                    tokio::task::spawn_local(async move {
+                        let _stopped = stopped_tx;
+                        std::future::pending::<()>().await;
+                    }),
+                );
+                managers.shutdown().await.unwrap();
+
+                let wire = wire.lock().unwrap().clone();
+                assert_eq!(wire.len(), 1, "queued message should be on the wire");
+                assert!(wire[0].contains("sent on close"));
+                assert!(wire[0].contains(&queued.id));
+                assert!(stopped_rx.await.is_err(), "run loop should be stopped");
+            })
+            .await;
+    }
+
+    #[tokio::test]
+    async fn delivery_receipt_marks_queued_message_confirmed() {
+        let (manager, _event_bus, _dir) = setup().await;
+
+        let queued = manager
+            .send_message("bob@example.com", "needs confirmation")
+            .await
+            .unwrap();
+        set_connection_online(manager.as_ref()).await;
+
+        manager
+            .handle_event(&make_event(
+                "xmpp.message.sent",
+                EventPayload::MessageSent {
+                    message: make_chat_message(
+                        &queued.id,
+                        "alice@example.com",
+                        "bob@example.com",
+                        "needs confirmation",
+                    ),
+                },
+            ))
+            .await;
+
+        manager
+            .handle_event(&make_event(
+                "xmpp.message.delivered",
+                EventPayload::MessageDelivered {
+                    id: queued.id.clone(),
+                    to: "bob@example.com".to_string(),
+                    resource: None,
+                },
+            ))
+            .await;
+
+        let row: Row = manager
+            .db
+            .query_one(
+                "SELECT status FROM offline_queue ORDER BY id ASC LIMIT 1",
+                &[],
+            )
+            .await
+            .unwrap();
+        assert_eq!(row.get(0), Some(&SqlValue::Text("confirmed".to_string())));
+    }
+
+    #[tokio::test]
+    async fn mam_result_reconciles_sent_queue_item_by_content() {
+        let (manager, _event_bus, _dir) = setup().await;
+
+        let queued = manager
+            .send_message("bob@example.com", "reconcile me")
+            .await
+            .unwrap();
+        set_connection_online(manager.as_ref()).await;
+
+        manager
+            .handle_event(&make_event(
+                "xmpp.message.sent",
+                EventPayload::MessageSent {
+                    message: make_chat_message(
+                        &queued.id,
+                        "alice@example.com",
+                        "bob@example.com",
+                        "reconcile me",
+                    ),
+                },
+            ))
+            .await;
+
+        let mam_message = ChatMessage {
+            id: "archive-id-42".to_string(),
+            from: "alice@example.com".to_string(),
+            to: "bob@example.com".to_string(),
+            body: "reconcile me".to_string(),
+            timestamp: Utc::now(),
+            message_type: MessageType::Chat,
+            thread: None,
+            embeds: vec![],
+            bodies: BTreeMap::new(),
+            origin_id: None,
+            spoiler: None,
+            stanza_id: None,
+        };
+
+        manager
+            .handle_event(&make_event(
+                "xmpp.mam.result.received",
+                EventPayload::MamResultReceived {
+                    query_id: "q1".to_string(),
+                    messages: vec![mam_message],
+                    complete: true,
+                },
+            ))
+            .await;
+
+        let row: Row = manager
+            .db
+            .query_one(
+                "SELECT status FROM offline_queue ORDER BY id ASC LIMIT 1",
+                &[],
+            )
+            .await
+            .unwrap();
+        assert_eq!(row.get(0), Some(&SqlValue::Text("confirmed".to_string())));
+    }
+
+    #[tokio::test]
+    async fn identical_bodies_reconcile_their_own_queue_items() {
+        let (manager, _event_bus, _dir) = setup().await;
+        manager.set_content_match_window(std::time::Duration::from_secs(30));
+
+        let first_at = "2024-05-01T10:00:00Z".parse::<DateTime<Utc>>().unwrap();
+        let second_at = first_at + chrono::Duration::seconds(60);
+        for queued_at in [first_at, second_at] {
+            let queued = QueuedOutboundEvent {
+                channel_id: Some(QueuedChannel::MessageSend),
+                channel: "ui.message.send".to_string(),
+                payload: EventPayload::MessageSendRequested {
+                    to: "bob@example.com".to_string(),
+                    body: "ok".to_string(),
+                    message_type: MessageType::Chat,
+                },
+                correlation_id: None,
+            };
+            let stanza_type = "message".to_string();
+            let payload = serde_json::to_string(&queued).unwrap();
+            let created_at = format_timestamp(&queued_at);
+            let status = OFFLINE_STATUS_SENT.to_string();
+            manager
+                .db
+                .execute(
+                    "INSERT INTO offline_queue (stanza_type, payload, created_at, status) \
+                     VALUES (?1, ?2, ?3, ?4)",
+                    &[&stanza_type, &payload, &created_at, &status],
+                )
+                .await
+                .unwrap();
+        }
+
+        let statuses = || async {
+            let rows: Vec<Row> = manager
+                .db
+                .query("SELECT status FROM offline_queue ORDER BY id ASC", &[])
+                .await
+                .unwrap();
+            rows.iter()
+                .map(|row| match row.get(0) {
+                    Some(SqlValue::Text(status)) => status.clone(),
+                    _ => String::new(),
+                })
+                .collect::<Vec<_>>()
+        };
+        let archived = |id: &str, at: DateTime<Utc>| {
+            let mut message = make_chat_message(id, "me@example.com", "bob@example.com", "ok");
+            message.timestamp = at;
+            make_event(
+                "xmpp.mam.result.received",
+                EventPayload::MamResultReceived {
+                    query_id: "q1".to_string(),
+                    messages: vec![message],
+                    complete: true,
+                },
+            )
+        };
+
+        // The second send's copy arrives first; it must not confirm the first.
+        manager
+            .handle_event(&archived(
+                "archive-2",
+                second_at + chrono::Duration::seconds(1),
+            ))
+            .await;
+        assert_eq!(statuses().await, vec!["sent", "confirmed"]);
+
+        manager
+            .handle_event(&archived(
+                "archive-1",
+                first_at + chrono::Duration::seconds(1),
+            ))
+            .await;
+        assert_eq!(statuses().await, vec!["confirmed", "confirmed"]);
+    }
+
+    #[tokio::test]
+    async fn content_match_window_runs_from_when_the_send_went_out() {
+        let (manager, _event_bus, _dir) = setup().await;
+        manager.set_content_match_window(std::time::Duration::from_secs(30));
+
+        // Queued an hour ago, but only drained now.
+        let queued = QueuedOutboundEvent {
+            channel_id: Some(QueuedChannel::MessageSend),
+            channel: "ui.message.send".to_string(),
+            payload: EventPayload::MessageSendRequested {
+                to: "bob@example.com".to_string(),
+                body: "ok".to_string(),
+                message_type: MessageType::Chat,
+            },
+            correlation_id: None,
+        };
+        let stanza_type = "message".to_string();
+        let payload = serde_json::to_string(&queued).unwrap();
+        let created_at = format_timestamp(&(Utc::now() - chrono::Duration::hours(1)));
+        manager
+            .db
+            .execute(
+                "INSERT INTO offline_queue (stanza_type, payload, created_at) VALUES (?1, ?2, ?3)",
+                &[&stanza_type, &payload, &created_at],
+            )
+            .await
+            .unwrap();
+        let row: Row = manager
+            .db
+            .query_one("SELECT id FROM offline_queue", &[])
+            .await
+            .unwrap();
+        let Some(SqlValue::Integer(id)) = row.get(0) else {
+            panic!("queue item should have an id");
+        };
+        manager
+            .update_queue_status(*id, OFFLINE_STATUS_SENT)
+            .await
+            .unwrap();
+
+        let mut message = make_chat_message("archive-1", "me@example.com", "bob@example.com", "ok");
+        message.timestamp = Utc::now();
+        manager
+            .handle_event(&make_event(
+                "xmpp.mam.result.received",
+                EventPayload::MamResultReceived {
+                    query_id: "q1".to_string(),
+                    messages: vec![message],
+                    complete: true,
+                },
+            ))
+            .await;
+
+        let row: Row = manager
+            .db
+            .query_one("SELECT status FROM offline_queue", &[])
+            .await
+            .unwrap();
+        assert_eq!(row.get(0), Some(&SqlValue::Text("confirmed".to_string())));
+    }
+
+    #[tokio::test]
+    async fn corrupt_queue_payload_records_failure_reason() {
+        let (manager, _event_bus, _dir) = setup().await;
+
+        let stanza_type = "message".to_string();
+        let payload = "{not valid json".to_string();
+        let created_at = format_timestamp(&Utc::now());
+        manager
+            .db
+            .execute(
+                "INSERT INTO offline_queue (stanza_type, payload, created_at) VALUES (?1, ?2, ?3)",
+                &[&stanza_type, &payload, &created_at],
+            )
+            .await
+            .unwrap();
+
+        set_connection_online(manager.as_ref()).await;
+
+        let pending = manager.pending_outbound().await.unwrap();
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending[0].status, "failed");
+        let reason = pending[0]
+            .last_error
+            .as_deref()
+            .expect("failed item should record a reason");
+        assert!(reason.starts_with("invalid queued payload"), "{reason}");
+    }
+
+    async fn insert_queued_json(
+        manager: &MessageManager<impl Database>,
+        queued: serde_json::Value,
+    ) {
+        let stanza_type = "message".to_string();
+        let payload = queued.to_string();
+        let created_at = format_timestamp(&Utc::now());
+        manager
+            .db
+            .execute(
+                "INSERT INTO offline_queue (stanza_type, payload, created_at) VALUES (?1, ?2, ?3)",
+                &[&stanza_type, &payload, &created_at],
+            )
+            .await
+            .unwrap();
+    }
+
+    fn queued_send() -> EventPayload {
+        EventPayload::MessageSendRequested {
+            to: "bob@example.com".to_string(),
+            body: "queued".to_string(),
+            message_type: MessageType::Chat,
+        }
+    }
+
+    #[tokio::test]
+    async fn queue_drains_by_created_at_when_ids_are_out_of_order() {
+        let (manager, event_bus, _dir) = setup().await;
+        let base = "2024-05-01T10:00:00Z".parse::<DateTime<Utc>>().unwrap();
+        // Ids as after a reset: the oldest items got the highest ids, and
+        // two items share a millisecond.
+        for (id, body, offset_ms) in [
+            (7_i64, "third", 2_i64),
+            (8, "fourth", 2),
+            (9, "first", 0),
+            (3, "second", 1),
+        ] {
+            let queued = QueuedOutboundEvent {
+                channel_id: Some(QueuedChannel::MessageSend),
+                channel: "ui.message.send".to_string(),
+                payload: EventPayload::MessageSendRequested {
+                    to: "bob@example.com".to_string(),
+                    body: body.to_string(),
+                    message_type: MessageType::Chat,
+                },
+                correlation_id: None,
+            };
+            let stanza_type = "message".to_string();
+            let payload = serde_json::to_string(&queued).unwrap();
+            let created_at = format_timestamp(&(base + chrono::Duration::milliseconds(offset_ms)));
+            manager
+                .db
+                .execute(
+                    "INSERT INTO offline_queue (id, stanza_type, payload, created_at) \
+                     VALUES (?1, ?2, ?3, ?4)",
+                    &[&id, &stanza_type, &payload, &created_at],
+                )
+                .await
+                .unwrap();
+        }
+
+        let mut sub = event_bus.subscribe("ui.message.send").unwrap();
+        set_connection_online(manager.as_ref()).await;
+
+        let mut bodies = Vec::new();
+        for _ in 0..4 {
+            let event = tokio::time::timeout(std::time::Duration::from_millis(100), sub.recv())
+                .await
+                .expect("timed out waiting for drained item")
+                .unwrap();
+            let EventPayload::MessageSendRequested { body, .. } = event.payload else {
+                panic!("unexpected payload: {:?}", event.payload);
+            };
+            bodies.push(body);
+        }
+        assert_eq!(bodies, vec!["first", "second", "third", "fourth"]);
+        let ids: Vec<i64> = manager
+            .pending_outbound()
+            .await
+            .unwrap()
+            .iter()
+            .map(|item| item.id)
+            .collect();
+        assert_eq!(ids, vec![9, 3, 7, 8]);
+    }
+
+    #[tokio::test]
+    async fn own_jid_uses_configured_resource() {
+        let (manager, _event_bus, _dir) = setup().await;
+        manager.set_resource(Some("laptop".to_string()));
+        assert_eq!(manager.own_jid(), None);
+
+        set_connection_online(manager.as_ref()).await;
+
+        assert_eq!(
+            manager.own_jid().as_deref(),
+            Some("alice@example.com/laptop")
+        );
+        assert!(manager.is_own_jid("alice@example.com/laptop"));
+        assert!(manager.is_own_jid("alice@example.com"));
+        assert!(!manager.is_own_jid("alice@example.com/phone"));
+        assert!(!manager.is_own_jid("bob@example.com/laptop"));
+    }
+
+    #[tokio::test]
+    async fn bound_resource_overrides_configured_one() {
+        let (manager, _event_bus, _dir) = setup().await;
+        manager.set_resource(Some("laptop".to_string()));
+        manager
+            .handle_event(&make_event(
+                "system.connection.established",
+                EventPayload::ConnectionEstablished {
+                    jid: "alice@example.com/waddle-4f2a".to_string(),
+                },
+            ))
+            .await;
+
+        assert_eq!(
+            manager.own_jid().as_deref(),
+            Some("alice@example.com/waddle-4f2a")
+        );
+        assert!(manager.is_own_jid("alice@example.com/waddle-4f2a"));
+        assert!(!manager.is_own_jid("alice@example.com/laptop"));
+    }
+
+    #[tokio::test]
+    async fn resend_conversation_requeues_only_that_jid() {
+        let (manager, _event_bus, _dir) = setup().await;
+        let created_at = format_timestamp(&Utc::now());
+        for (to, status) in [
+            ("bob@example.com/phone", "failed"),
+            ("bob@example.com", "sent"),
+            ("bob@example.com", "confirmed"),
+            ("carol@example.com", "failed"),
+            ("carol@example.com", "sent"),
+        ] {
+            let queued = QueuedOutboundEvent {
+                channel_id: Some(QueuedChannel::MessageSend),
+                channel: "ui.message.send".to_string(),
+                payload: EventPayload::MessageSendRequested {
+                    to: to.to_string(),
+                    body: "hello".to_string(),
+                    message_type: MessageType::Chat,
+                },
+                correlation_id: Some(Uuid::new_v4()),
+            };
+            let stanza_type = "message".to_string();
+            let payload = serde_json::to_string(&queued).unwrap();
+            let status = status.to_string();
+            let last_error = (status == "failed").then(|| "remote-server-timeout".to_string());
+            manager
+                .db
+                .execute(
+                    "INSERT INTO offline_queue (stanza_type, payload, created_at, status, last_error) \
+                     VALUES (?1, ?2, ?3, ?4, ?5)",
+                    &[&stanza_type, &payload, &created_at, &status, &last_error],
+                )
+                .await
+                .unwrap();
+        }
+
+        let requeued = manager
+            .resend_conversation("bob@example.com")
+            .await
+            .unwrap();
+
+        assert_eq!(requeued, 2);
+        let rows: Vec<Row> = manager
+            .db
+            .query(
+                "SELECT status, last_error FROM offline_queue ORDER BY id ASC",
+                &[],
+            )
+            .await
+            .unwrap();
+        let statuses: Vec<_> = rows.iter().map(|row| row.get(0).cloned()).collect();
+        let text = |s: &str| Some(SqlValue::Text(s.to_string()));
+        assert_eq!(
+            statuses,
+            vec![
+                text("pending"),
+                text("pending"),
+                text("confirmed"),
+                text("failed"),
+                text("sent"),
+            ]
+        );
+        assert_eq!(rows[0].get(1), Some(&SqlValue::Null));
+        assert_eq!(rows[3].get(1), text("remote-server-timeout").as_ref());
+    }
+
+    #[tokio::test]
+    async fn unknown_queued_channel_id_fails_with_reason() {
+        let (manager, event_bus, _dir) = setup().await;
+        insert_queued_json(
+            &manager,
+            serde_json::json!({
+                "channel_id": "legacy_send",
+                "channel": "ui.legacy.send",
+                "payload": queued_send(),
+                "correlation_id": null,
+            }),
+        )
+        .await;
+
+        let mut sub = event_bus.subscribe("ui.**").unwrap();
+        set_connection_online(manager.as_ref()).await;
+
+        let published =
+            tokio::time::timeout(std::time::Duration::from_millis(100), sub.recv()).await;
+        assert!(published.is_err(), "unknown channel must not be published");
+        let pending = manager.pending_outbound().await.unwrap();
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending[0].status, "failed");
+        let reason = pending[0].last_error.as_deref().unwrap();
+        assert!(
+            reason.contains("unknown queued channel id `legacy_send`"),
+            "{reason}"
+        );
+    }
+
+    #[tokio::test]
+    async fn queued_command_drains_on_current_channel_name() {
+        let (manager, event_bus, _dir) = setup().await;
+        insert_queued_json(
+            &manager,
+            serde_json::json!({
+                "channel_id": "message_send",
+                "channel": "ui.msg.send",
+                "payload": queued_send(),
+                "correlation_id": null,
+            }),
+        )
+        .await;
+
+        let mut sub = event_bus.subscribe("ui.**").unwrap();
+        set_connection_online(manager.as_ref()).await;
+
+        let drained = tokio::time::timeout(std::time::Duration::from_millis(100), sub.recv())
+            .await
+            .expect("timed out waiting for drained item")
+            .expect("expected drained item");
+        assert_eq!(drained.channel.as_str(), "ui.message.send");
+    }
+
+    #[tokio::test]
+    async fn item_queued_without_channel_id_drains_on_its_channel() {
+        let (manager, event_bus, _dir) = setup().await;
+        insert_queued_json(
+            &manager,
+            serde_json::json!({
+                "channel": "ui.message.send",
+                "payload": queued_send(),
+                "correlation_id": null,
+            }),
+        )
+        .await;
+
+        let mut sub = event_bus.subscribe("ui.**").unwrap();
+        set_connection_online(manager.as_ref()).await;
+
+        let drained = tokio::time::timeout(std::time::Duration::from_millis(100), sub.recv())
+            .await
+            .expect("timed out waiting for drained item")
+            .expect("expected drained item");
+        assert_eq!(drained.channel.as_str(), "ui.message.send");
+        assert!(matches!(
+            drained.payload,
+            EventPayload::MessageSendRequested { .. }
+        ));
+    }
+
+    #[tokio::test]
+    async fn enqueue_rejects_unknown_channel() {
+        let (manager, _event_bus, _dir) = setup().await;
+        let result = manager
+            .enqueue_command_event("ui.unknown.send", queued_send(), None)
+            .await;
+
+        assert!(matches!(result, Err(MessagingError::SendFailed(_))));
+        assert!(manager.pending_outbound().await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn repeated_offline_roster_add_queues_one_command() {
+        let (manager, _event_bus, _dir) = setup().await;
+        for name in ["Bob", "Bobby"] {
+            manager
+                .handle_event(&make_event(
+                    "ui.roster.add",
+                    EventPayload::RosterAddRequested {
+                        jid: "bob@example.com".into(),
+                        name: Some(name.into()),
+                        groups: vec![],
+                    },
+                ))
+                .await;
+        }
+        manager
+            .handle_event(&make_event(
+                "ui.roster.remove",
+                EventPayload::RosterRemoveRequested {
+                    jid: "bob@example.com".into(),
+                },
+            ))
+            .await;
+
+        let pending = manager
+            .load_offline_queue_by_status(OFFLINE_STATUS_PENDING)
+            .await
+            .unwrap();
+        let queued: Vec<QueuedOutboundEvent> = pending
+            .iter()
+            .map(|item| serde_json::from_str(&item.payload).unwrap())
+            .collect();
+        assert_eq!(queued.len(), 2);
+        assert!(matches!(
+            &queued[0].payload,
+            EventPayload::RosterAddRequested { name: Some(name), .. } if name == "Bobby"
+        ));
+        assert!(matches!(
+            queued[1].payload,
+            EventPayload::RosterRemoveRequested { .. }
+        ));
+    }
+}
+
+#[cfg(all(test, feature = "native"))]
+mod muc_tests {
+    use super::*;
+    use std::sync::Arc;
+    use tempfile::TempDir;
+    use waddle_core::event::{
+        BroadcastEventBus, Channel, EventBus, EventSource, MucAffiliation, MucRemoval,
+    };
+    use waddle_storage::ToSql;
+
+    async fn setup_muc() -> (Arc<MucManager<impl Database>>, Arc<dyn EventBus>, TempDir) {
+        let dir = TempDir::new().expect("failed to create temp dir");
+        let db_path = dir.path().join("test.db");
+        let db = waddle_storage::open_database(&db_path)
+            .await
+            .expect("failed to open database");
+        let db = Arc::new(db);
+        let event_bus: Arc<dyn EventBus> = Arc::new(BroadcastEventBus::default());
+        let manager = Arc::new(MucManager::new(db, event_bus.clone()));
+        (manager, event_bus, dir)
+    }
+
+    fn make_event(channel: &str, payload: EventPayload) -> Event {
+        Event::new(
+            Channel::new(channel).unwrap(),
+            EventSource::System("test".into()),
+            payload,
+        )
+    }
+
+    fn connection_established() -> Event {
+        make_event(
+            "system.connection.established",
+            EventPayload::ConnectionEstablished {
+                jid: "alice@example.com".to_string(),
+            },
+        )
+    }
+
+    fn make_muc_message(id: &str, from: &str, room: &str, body: &str) -> ChatMessage {
+        ChatMessage {
+            id: id.to_string(),
+            from: from.to_string(),
+            to: room.to_string(),
+            body: body.to_string(),
+            timestamp: Utc::now(),
+            message_type: MessageType::Groupchat,
+            thread: None,
+            embeds: vec![],
+            bodies: BTreeMap::new(),
+            origin_id: None,
+            spoiler: None,
+            stanza_id: None,
+        }
+    }
+
+    fn make_occupant(nick: &str, role: MucRole, affiliation: MucAffiliation) -> MucOccupant {
+        MucOccupant {
+            nick: nick.to_string(),
+            jid: None,
+            affiliation,
+            role,
+        }
+    }
+
+    #[tokio::test]
+    async fn join_room_emits_event_and_persists() {
+        let (manager, event_bus, _dir) = setup_muc().await;
+        let mut sub = event_bus.subscribe("ui.**").unwrap();
+
+        manager
+            .join_room("room@conference.example.com", "Alice")
+            .await
+            .unwrap();
+
+        let received = tokio::time::timeout(std::time::Duration::from_millis(100), sub.recv())
+            .await
+            .expect("timed out")
+            .expect("should receive event");
+
+        assert!(matches!(
+            received.payload,
+            EventPayload::MucJoinRequested {
+                ref room,
+                ref nick,
+            } if room == "room@conference.example.com" && nick == "Alice"
+        ));
+
+        let rooms = manager.get_rooms().await.unwrap();
+        assert_eq!(rooms.len(), 1);
+        assert_eq!(rooms[0].room_jid, "room@conference.example.com");
+        assert_eq!(rooms[0].nick, "Alice");
+        assert!(!rooms[0].joined);
+    }
+
+    #[tokio::test]
+    async fn handle_muc_joined_marks_room_joined() {
+        let (manager, _, _dir) = setup_muc().await;
+
+        manager
+            .join_room("room@conference.example.com", "Alice")
+            .await
+            .unwrap();
+
+        let event = make_event(
+            "xmpp.muc.joined",
+            EventPayload::MucJoined {
+                room: "room@conference.example.com".to_string(),
+                nick: "Alice".to_string(),
+            },
+        );
+        manager.handle_event(&event).await;
+
+        let rooms = manager.get_joined_rooms().await.unwrap();
+        assert_eq!(rooms.len(), 1);
+        assert!(rooms[0].joined);
+    }
+
+    #[tokio::test]
+    async fn nick_conflict_surfaces_as_muc_error() {
+        let (manager, event_bus, _dir) = setup_muc().await;
+        let mut sub = event_bus.subscribe("ui.muc.join.failed").unwrap();
+
+        manager
+            .join_room("room@conference.example.com", "Alice")
+            .await
+            .unwrap();
+        let event = make_event(
+            "xmpp.muc.join.failed",
+            EventPayload::MucJoinFailed {
+                room: "room@conference.example.com".to_string(),
+                nick: "Alice".to_string(),
+                failure: MucJoinFailure::NickConflict,
+            },
+        );
+        manager.handle_event(&event).await;
+
+        let received = tokio::time::timeout(std::time::Duration::from_millis(100), sub.recv())
+            .await
+            .expect("timed out")
+            .expect("recv error");
+        assert!(matches!(
+            received.payload,
+            EventPayload::MucJoinFailed {
+                failure: MucJoinFailure::NickConflict,
+                ..
+            }
+        ));
+        match manager.take_join_error("room@conference.example.com") {
+            Some(MucError::NickConflict { room, nick }) => {
+                assert_eq!(room, "room@conference.example.com");
+                assert_eq!(nick, "Alice");
+            }
+            other => panic!("expected NickConflict, got {other:?}"),
+        }
+        assert!(
+            manager
+                .take_join_error("room@conference.example.com")
+                .is_none()
+        );
+        assert!(manager.get_joined_rooms().await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn nick_change_updates_stored_nick_after_confirmation() {
+        let (manager, event_bus, _dir) = setup_muc().await;
+        let mut sub = event_bus.subscribe("ui.muc.nick").unwrap();
+        let room = "room@conference.example.com";
+        let occupant_changed = |nick: &str| {
+            make_event(
+                "xmpp.muc.occupant.changed",
+                EventPayload::MucOccupantChanged {
+                    room: room.to_string(),
+                    occupant: make_occupant(nick, MucRole::Participant, MucAffiliation::Member),
+                },
+            )
+        };
+        let joined = |nick: &str| {
+            make_event(
+                "xmpp.muc.joined",
+                EventPayload::MucJoined {
+                    room: room.to_string(),
+                    nick: nick.to_string(),
+                },
+            )
+        };
+        let stored_nick = || async {
+            manager
+                .get_joined_rooms()
+                .await
+                .unwrap()
+                .into_iter()
+                .find(|r| r.room_jid == room)
+                .map(|r| r.nick)
+        };
+
+        manager.join_room(room, "Alice").await.unwrap();
+        manager.handle_event(&joined("Alice")).await;
+        manager.handle_event(&occupant_changed("Alice")).await;
+        manager
+            .handle_event(&make_event(
+                "xmpp.muc.subject",
+                EventPayload::MucSubjectChanged {
+                    room: room.to_string(),
+                    subject: "Hello".to_string(),
+                },
+            ))
+            .await;
+
+        manager.change_nick(room, "Alicia").await.unwrap();
+        let event = tokio::time::timeout(std::time::Duration::from_millis(100), sub.recv())
+            .await
+            .expect("timed out")
+            .expect("recv error");
+        assert!(matches!(
+            event.payload,
+            EventPayload::MucNickChangeRequested { ref nick, .. } if nick == "Alicia"
+        ));
+        assert_eq!(stored_nick().await.as_deref(), Some("Alice"));
+
+        manager
+            .handle_event(&make_event(
+                "xmpp.muc.nick.changed",
+                EventPayload::MucNickChanged {
+                    room: room.to_string(),
+                    old_nick: "Alice".to_string(),
+                    new_nick: "Alicia".to_string(),
+                    own: true,
+                },
+            ))
+            .await;
+        assert_eq!(stored_nick().await.as_deref(), Some("Alicia"));
+        let nicks: Vec<String> = manager
+            .get_occupants(room)
+            .into_iter()
+            .map(|o| o.nick)
+            .collect();
+        assert_eq!(nicks, vec!["Alicia".to_string()]);
+
+        // The self-presence under the new nick does not count as a rejoin.
+        manager.handle_event(&joined("Alicia")).await;
+        manager.handle_event(&occupant_changed("Alicia")).await;
+        assert!(!manager.replaying_rooms.read().unwrap().contains(room));
+        assert_eq!(manager.get_occupants(room).len(), 1);
+    }
+
+    #[tokio::test]
+    async fn nick_change_cut_off_by_a_dropped_connection_is_forgotten() {
+        let (manager, _event_bus, _dir) = setup_muc().await;
+        let room = "room@conference.example.com";
+        let joined = |nick: &str| {
+            make_event(
+                "xmpp.muc.joined",
+                EventPayload::MucJoined {
+                    room: room.to_string(),
+                    nick: nick.to_string(),
+                },
+            )
+        };
+
+        manager.join_room(room, "Alice").await.unwrap();
+        manager.handle_event(&joined("Alice")).await;
+        manager
+            .handle_event(&make_event(
+                "xmpp.muc.nick.changed",
+                EventPayload::MucNickChanged {
+                    room: room.to_string(),
+                    old_nick: "Alice".to_string(),
+                    new_nick: "Alicia".to_string(),
+                    own: true,
+                },
+            ))
+            .await;
+        manager
+            .handle_event(&make_event(
+                "system.connection.lost",
+                EventPayload::ConnectionLost {
+                    reason: "network error".to_string(),
+                    will_retry: true,
+                    resumable: false,
+                },
+            ))
+            .await;
+        assert!(manager.renamed_rooms.read().unwrap().is_empty());
+
+        // The rejoin under the new nick is a fresh join with history replay.
+        manager.handle_event(&joined("Alicia")).await;
+        assert!(manager.replaying_rooms.read().unwrap().contains(room));
+    }
+
+    #[tokio::test]
+    async fn self_presence_completes_the_joined_roster() {
+        let (manager, event_bus, _dir) = setup_muc().await;
+        let mut complete = event_bus.subscribe("ui.muc.roster_complete").unwrap();
+        let room = "room@conference.example.com";
+        let occupant_changed = |nick: &str| {
+            make_event(
+                "xmpp.muc.occupant.changed",
+                EventPayload::MucOccupantChanged {
+                    room: room.to_string(),
+                    occupant: make_occupant(nick, MucRole::Participant, MucAffiliation::Member),
+                },
+            )
+        };
+
+        manager.join_room(room, "Alice").await.unwrap();
+        for nick in ["Bob", "Carol"] {
+            manager.handle_event(&occupant_changed(nick)).await;
+        }
+        assert!(
+            tokio::time::timeout(std::time::Duration::from_millis(100), complete.recv())
+                .await
+                .is_err()
+        );
+
+        // The self-presence arrives as a join followed by our own occupant.
+        manager
+            .handle_event(&make_event(
+                "xmpp.muc.joined",
+                EventPayload::MucJoined {
+                    room: room.to_string(),
+                    nick: "Alice".to_string(),
+                },
+            ))
+            .await;
+        manager.handle_event(&occupant_changed("Alice")).await;
+
+        let event = tokio::time::timeout(std::time::Duration::from_millis(100), complete.recv())
+            .await
+            .expect("timed out")
+            .expect("recv error");
+        assert!(matches!(
+            event.payload,
+            EventPayload::MucRosterComplete { room: ref completed } if completed == room
+        ));
+        assert_eq!(manager.get_occupants(room).len(), 3);
+
+        manager.handle_event(&occupant_changed("Dave")).await;
+        manager.handle_event(&occupant_changed("Alice")).await;
+        assert!(
+            tokio::time::timeout(std::time::Duration::from_millis(100), complete.recv())
+                .await
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn joins_cut_off_by_a_dropped_connection_are_retried_on_reconnect() {
+        let (manager, event_bus, _dir) = setup_muc().await;
+        let joined = "one@conference.example.com";
+        let fresh = "two@conference.example.com";
+        manager
+            .handle_event(&make_event(
+                "xmpp.muc.joined",
+                EventPayload::MucJoined {
+                    room: joined.to_string(),
+                    nick: "Alice".to_string(),
+                },
+            ))
+            .await;
+        // The rejoin after the first reconnect and a fresh join are both
+        // in flight when the connection drops again.
+        manager.handle_event(&connection_established()).await;
+        manager
+            .handle_event(&make_event(
+                "xmpp.muc.occupant.changed",
+                EventPayload::MucOccupantChanged {
+                    room: joined.to_string(),
+                    occupant: make_occupant("Bob", MucRole::Participant, MucAffiliation::Member),
+                },
+            ))
+            .await;
+        assert_eq!(manager.get_occupants(joined).len(), 1);
+        manager.join_room(fresh, "Al").await.unwrap();
+        manager
+            .handle_event(&make_event(
+                "system.connection.lost",
+                EventPayload::ConnectionLost {
+                    reason: "timeout".to_string(),
+                    will_retry: true,
+                    resumable: false,
+                },
+            ))
+            .await;
+        assert!(manager.get_occupants(joined).is_empty());
+        assert!(!manager.replaying_rooms.read().unwrap().contains(joined));
+
+        let mut joins = event_bus.subscribe("ui.muc.join").unwrap();
+        manager.handle_event(&connection_established()).await;
+
+        let mut requested = Vec::new();
+        for _ in 0..2 {
+            let event = tokio::time::timeout(std::time::Duration::from_millis(100), joins.recv())
+                .await
+                .expect("timed out")
+                .expect("recv error");
+            let EventPayload::MucJoinRequested { room, nick } = event.payload else {
+                panic!("expected MucJoinRequested");
+            };
+            requested.push((room, nick));
+        }
+        requested.sort();
+        assert_eq!(
+            requested,
+            vec![
+                (joined.to_string(), "Alice".to_string()),
+                (fresh.to_string(), "Al".to_string()),
+            ]
+        );
+        assert!(
+            tokio::time::timeout(std::time::Duration::from_millis(50), joins.recv())
+                .await
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn reconnect_rejoins_stored_rooms_with_their_nicks() {
+        let (manager, event_bus, _dir) = setup_muc().await;
+        for (room, nick) in [
+            ("one@conference.example.com", "Alice"),
+            ("two@conference.example.com", "Al"),
+        ] {
+            manager
+                .handle_event(&make_event(
+                    "xmpp.muc.joined",
+                    EventPayload::MucJoined {
+                        room: room.to_string(),
+                        nick: nick.to_string(),
+                    },
+                ))
+                .await;
+        }
+        manager
+            .handle_event(&make_event(
+                "xmpp.muc.joined",
+                EventPayload::MucJoined {
+                    room: "gone@conference.example.com".to_string(),
+                    nick: "Alice".to_string(),
+                },
+            ))
+            .await;
+        manager
+            .handle_event(&make_event(
+                "xmpp.muc.left",
+                EventPayload::MucLeft {
+                    room: "gone@conference.example.com".to_string(),
+                    removal: None,
+                },
+            ))
+            .await;
+
+        let mut joins = event_bus.subscribe("ui.muc.join").unwrap();
+        manager
+            .handle_event(&make_event(
+                "system.connection.established",
+                EventPayload::ConnectionEstablished {
+                    jid: "alice@example.com".to_string(),
+                },
+            ))
+            .await;
+
+        let mut requested = Vec::new();
+        for _ in 0..2 {
+            let event = tokio::time::timeout(std::time::Duration::from_millis(100), joins.recv())
+                .await
+                .expect("timed out")
+                .expect("recv error");
+            let EventPayload::MucJoinRequested { room, nick } = event.payload else {
+                panic!("expected MucJoinRequested");
+            };
+            requested.push((room, nick));
+        }
+        requested.sort();
+        assert_eq!(
+            requested,
+            vec![
+                (
+                    "one@conference.example.com".to_string(),
+                    "Alice".to_string()
+                ),
+                ("two@conference.example.com".to_string(), "Al".to_string()),
+            ]
+        );
+        assert!(
+            tokio::time::timeout(std::time::Duration::from_millis(100), joins.recv())
+                .await
+                .is_err()
+        );
+
+        // A refused rejoin drops the room so the next reconnect skips it.
+        manager
+            .handle_event(&make_event(
+                "xmpp.muc.join.failed",
+                EventPayload::MucJoinFailed {
+                    room: "two@conference.example.com".to_string(),
+                    nick: "Al".to_string(),
+                    failure: MucJoinFailure::Banned,
+                },
+            ))
+            .await;
+        let joined: Vec<String> = manager
+            .get_joined_rooms()
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|room| room.room_jid)
+            .collect();
+        assert_eq!(joined, vec!["one@conference.example.com".to_string()]);
+    }
+
+    #[tokio::test]
+    async fn nick_conflict_retries_with_suffixed_nick() {
+        let (manager, event_bus, _dir) = setup_muc().await;
+        manager.set_nick_conflict_retries(3);
+        let mut joins = event_bus.subscribe("ui.muc.join").unwrap();
+        let mut assigned = event_bus.subscribe("ui.muc.nick_assigned").unwrap();
+        let room = "room@conference.example.com";
+
+        manager.join_room(room, "Alice").await.unwrap();
+        for (taken, expected_retry) in [("Alice", "Alice_2"), ("Alice_2", "Alice_3")] {
+            let requested =
+                tokio::time::timeout(std::time::Duration::from_millis(100), joins.recv())
+                    .await
+                    .expect("timed out")
+                    .expect("recv error");
+            let EventPayload::MucJoinRequested { nick, .. } = requested.payload else {
+                panic!("expected MucJoinRequested");
+            };
+            assert_eq!(nick, taken);
+
+            let event = make_event(
+                "xmpp.muc.join.failed",
+                EventPayload::MucJoinFailed {
+                    room: room.to_string(),
+                    nick: taken.to_string(),
+                    failure: MucJoinFailure::NickConflict,
+                },
+            );
+            manager.handle_event(&event).await;
+            assert!(manager.take_join_error(room).is_none());
+            assert_eq!(manager.get_rooms().await.unwrap()[0].nick, expected_retry);
+        }
+
+        let event = make_event(
+            "xmpp.muc.joined",
+            EventPayload::MucJoined {
+                room: room.to_string(),
+                nick: "Alice_3".to_string(),
+            },
+        );
+        manager.handle_event(&event).await;
+
+        let received = tokio::time::timeout(std::time::Duration::from_millis(100), assigned.recv())
+            .await
+            .expect("timed out")
+            .expect("recv error");
+        let EventPayload::MucJoined { nick, .. } = received.payload else {
+            panic!("expected MucJoined");
+        };
+        assert_eq!(nick, "Alice_3");
+        let rooms = manager.get_joined_rooms().await.unwrap();
+        assert_eq!(rooms[0].nick, "Alice_3");
+    }
+
+    #[tokio::test]
+    async fn nick_conflict_surfaces_once_retries_run_out() {
+        let (manager, _, _dir) = setup_muc().await;
+        manager.set_nick_conflict_retries(1);
+        let room = "room@conference.example.com";
+
+        manager.join_room(room, "Alice").await.unwrap();
+        for taken in ["Alice", "Alice_2"] {
+            let event = make_event(
+                "xmpp.muc.join.failed",
+                EventPayload::MucJoinFailed {
+                    room: room.to_string(),
+                    nick: taken.to_string(),
+                    failure: MucJoinFailure::NickConflict,
+                },
+            );
+            manager.handle_event(&event).await;
+        }
+
+        assert!(matches!(
+            manager.take_join_error(room),
+            Some(MucError::NickConflict { nick, .. }) if nick == "Alice_2"
+        ));
+    }
+
+    #[tokio::test]
+    async fn handle_muc_left_marks_room_not_joined() {
+        let (manager, _, _dir) = setup_muc().await;
+
+        manager
+            .join_room("room@conference.example.com", "Alice")
+            .await
+            .unwrap();
+
+        let join_event = make_event(
+            "xmpp.muc.joined",
+            EventPayload::MucJoined {
+                room: "room@conference.example.com".to_string(),
+                nick: "Alice".to_string(),
+            },
+        );
+        manager.handle_event(&join_event).await;
+
+        let leave_event = make_event(
+            "xmpp.muc.left",
+            EventPayload::MucLeft {
+                room: "room@conference.example.com".to_string(),
+                removal: None,
+            },
+        );
+        manager.handle_event(&leave_event).await;
+
+        let joined_rooms = manager.get_joined_rooms().await.unwrap();
+        assert!(joined_rooms.is_empty());
+
+        let all_rooms = manager.get_rooms().await.unwrap();
+        assert_eq!(all_rooms.len(), 1);
+        assert!(!all_rooms[0].joined);
+    }
+
+    #[tokio::test]
+    async fn kick_self_presence_surfaces_removed_event() {
+        let (manager, event_bus, _dir) = setup_muc().await;
+        let mut sub = event_bus.subscribe("ui.muc.removed").unwrap();
+
+        manager
+            .handle_event(&make_event(
+                "xmpp.muc.joined",
+                EventPayload::MucJoined {
+                    room: "room@conference.example.com".to_string(),
+                    nick: "Alice".to_string(),
+                },
+            ))
+            .await;
+
+        manager
+            .handle_event(&make_event(
+                "xmpp.muc.left",
+                EventPayload::MucLeft {
+                    room: "room@conference.example.com".to_string(),
+                    removal: Some(MucRemoval {
+                        code: 307,
+                        reason: Some("Spamming".to_string()),
+                        actor: Some("moderator".to_string()),
+                    }),
+                },
+            ))
+            .await;
+
+        let received = tokio::time::timeout(std::time::Duration::from_millis(100), sub.recv())
+            .await
+            .expect("timed out")
+            .expect("should receive event");
+        assert!(matches!(
+            received.payload,
+            EventPayload::MucRemoved {
+                ref room,
+                code: 307,
+                ref reason,
+                ref actor,
+            } if room == "room@conference.example.com"
+                && reason.as_deref() == Some("Spamming")
+                && actor.as_deref() == Some("moderator")
+        ));
+
+        assert!(manager.get_joined_rooms().await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn destroyed_room_is_not_rejoined_and_emits_event() {
+        let (manager, event_bus, _dir) = setup_muc().await;
+        let mut sub = event_bus.subscribe("ui.muc.destroyed").unwrap();
+
+        manager
+            .handle_event(&make_event(
+                "xmpp.muc.joined",
+                EventPayload::MucJoined {
+                    room: "room@conference.example.com".to_string(),
+                    nick: "Alice".to_string(),
+                },
+            ))
+            .await;
+
+        manager
+            .handle_event(&make_event(
+                "xmpp.muc.destroyed",
+                EventPayload::MucDestroyed {
+                    room: "room@conference.example.com".to_string(),
+                    reason: Some("Moved".to_string()),
+                    alternate: Some("new@conference.example.com".to_string()),
+                },
+            ))
+            .await;
+
+        let received = tokio::time::timeout(std::time::Duration::from_millis(100), sub.recv())
+            .await
+            .expect("timed out")
+            .expect("should receive event");
+        assert!(matches!(
+            received.payload,
+            EventPayload::MucDestroyed {
+                ref room,
+                ref alternate,
+                ..
+            } if room == "room@conference.example.com"
+                && alternate.as_deref() == Some("new@conference.example.com")
+        ));
+
+        assert!(manager.get_joined_rooms().await.unwrap().is_empty());
+        let rooms = manager.get_rooms().await.unwrap();
+        assert_eq!(rooms.len(), 1);
+        assert!(rooms[0].destroyed);
+    }
+
+    /// A room message whose local id is the sender's origin-id and whose
+    /// room stanza-id differs, as rooms assign it.
+    fn stamped_room_message(origin_id: &str, stanza_id: &str, body: &str) -> ChatMessage {
+        let room = "room@conference.example.com";
+        let mut message = make_muc_message(origin_id, &format!("{room}/Mallory"), room, body);
+        message.origin_id = Some(origin_id.to_string());
+        message.stanza_id = Some(stanza_id.to_string());
+        message
+    }
+
+    #[tokio::test]
+    async fn moderation_tombstones_targeted_room_message() {
+        let (manager, event_bus, _dir) = setup_muc().await;
+        let mut sub = event_bus.subscribe("ui.muc.moderated").unwrap();
+        let room = "room@conference.example.com";
+
+        for (origin_id, stanza_id, body) in [
+            ("origin-spam", "room-sid-1", "Buy now!"),
+            ("origin-keep", "room-sid-2", "Hello"),
+        ] {
+            manager
+                .handle_event(&make_event(
+                    "xmpp.muc.message.received",
+                    EventPayload::MucMessageReceived {
+                        room: room.to_string(),
+                        message: stamped_room_message(origin_id, stanza_id, body),
+                    },
+                ))
+                .await;
+        }
+
+        manager
+            .handle_event(&make_event(
+                "xmpp.muc.message.moderated",
+                EventPayload::MessageModerated {
+                    room: room.to_string(),
+                    id: "room-sid-1".to_string(),
+                    by: Some(format!("{room}/mod")),
+                    reason: Some("Spam".to_string()),
+                },
+            ))
+            .await;
+
+        let received = tokio::time::timeout(std::time::Duration::from_millis(100), sub.recv())
+            .await
+            .expect("timed out")
+            .expect("should receive event");
+        assert!(matches!(
+            received.payload,
+            EventPayload::MessageModerated { ref id, ref reason, .. }
+                if id == "origin-spam" && reason.as_deref() == Some("Spam")
+        ));
+
+        let messages = manager.get_room_messages(room, 10, None).await.unwrap();
+        assert_eq!(messages.len(), 2);
+        let spam = messages.iter().find(|m| m.id == "origin-spam").unwrap();
+        assert!(spam.body.is_empty());
+        let kept = messages.iter().find(|m| m.id == "origin-keep").unwrap();
+        assert_eq!(kept.body, "Hello");
+        assert_eq!(kept.stanza_id.as_deref(), Some("room-sid-2"));
+    }
+
+    #[tokio::test]
+    async fn moderate_requests_the_room_stanza_id() {
+        let (manager, event_bus, _dir) = setup_muc().await;
+        let mut sub = event_bus.subscribe("ui.**").unwrap();
+        let room = "room@conference.example.com";
+        manager
+            .handle_event(&make_event(
+                "xmpp.muc.message.received",
+                EventPayload::MucMessageReceived {
+                    room: room.to_string(),
+                    message: stamped_room_message("origin-spam", "room-sid-1", "Buy now!"),
+                },
+            ))
+            .await;
+
+        manager
+            .moderate(room, "origin-spam", Some("Spam"))
             .await
             .unwrap();
 
-        assert_eq!(rows.len(), 2);
-        assert_eq!(rows[0].get(0), Some(&SqlValue::Text("sent".to_string())));
-        assert_eq!(rows[1].get(0), Some(&SqlValue::Text("sent".to_string())));
+        let received = tokio::time::timeout(std::time::Duration::from_millis(100), sub.recv())
+            .await
+            .expect("timed out")
+            .expect("should receive event");
+        assert!(matches!(
+            received.payload,
+            EventPayload::MucModerateRequested {
+                room: ref requested_room,
+                ref id,
+                ref reason,
+            } if requested_room == room
+                && id == "room-sid-1"
+                && reason.as_deref() == Some("Spam")
+        ));
+
+        let result = manager.moderate(room, "never-stored", None).await;
+        assert!(matches!(result, Err(MucError::NoStanzaId { ref id, .. }) if id == "never-stored"));
     }
 
     #[tokio::test]
-    async fn delivery_receipt_marks_queued_message_confirmed() {
-        let (manager, _event_bus, _dir) = setup().await;
-
-        let queued = manager
-            .send_message("bob@example.com", "needs confirmation")
-            .await
-            .unwrap();
-        set_connection_online(manager.as_ref()).await;
+    async fn composing_occupant_appears_in_typers_and_expires() {
+        let (manager, _event_bus, _dir) = setup_muc().await;
+        let room = "room@conference.example.com";
 
         manager
             .handle_event(&make_event(
-                "xmpp.message.sent",
-                EventPayload::MessageSent {
-                    message: make_chat_message(
-                        &queued.id,
-                        "alice@example.com",
-                        "bob@example.com",
-                        "needs confirmation",
-                    ),
+                "xmpp.muc.joined",
+                EventPayload::MucJoined {
+                    room: room.to_string(),
+                    nick: "Alice".to_string(),
                 },
             ))
             .await;
 
+        for nick in ["Carol", "Bob", "Alice"] {
+            manager
+                .handle_event(&make_event(
+                    "xmpp.muc.chatstate.received",
+                    EventPayload::MucChatStateReceived {
+                        room: room.to_string(),
+                        nick: nick.to_string(),
+                        state: ChatState::Composing,
+                    },
+                ))
+                .await;
+        }
+        // Our own reflected composing is not reported.
+        assert_eq!(manager.typers(room), vec!["Bob", "Carol"]);
+
         manager
             .handle_event(&make_event(
-                "xmpp.message.delivered",
-                EventPayload::MessageDelivered {
-                    id: queued.id.clone(),
-                    to: "bob@example.com".to_string(),
+                "xmpp.muc.chatstate.received",
+                EventPayload::MucChatStateReceived {
+                    room: room.to_string(),
+                    nick: "Carol".to_string(),
+                    state: ChatState::Paused,
                 },
             ))
             .await;
+        assert_eq!(manager.typers(room), vec!["Bob"]);
 
-        let row: Row = manager
-            .db
-            .query_one(
-                "SELECT status FROM offline_queue ORDER BY id ASC LIMIT 1",
-                &[],
-            )
-            .await
-            .unwrap();
-        assert_eq!(row.get(0), Some(&SqlValue::Text("confirmed".to_string())));
+        // Backdate Bob's composing past the expiry.
+        let stale = Utc::now() - chrono::Duration::seconds(MUC_TYPING_EXPIRY_SECS + 1);
+        manager
+            .typers
+            .write()
+            .unwrap()
+            .get_mut(room)
+            .unwrap()
+            .insert("Bob".to_string(), stale);
+        assert!(manager.typers(room).is_empty());
+        // The stale entry is gone, not just hidden.
+        assert!(manager.typers.read().unwrap().is_empty());
     }
 
     #[tokio::test]
-    async fn mam_result_reconciles_sent_queue_item_by_content() {
-        let (manager, _event_bus, _dir) = setup().await;
+    async fn typers_in_other_rooms_are_pruned_on_write() {
+        let (manager, _event_bus, _dir) = setup_muc().await;
+        let quiet = "quiet@conference.example.com";
+        let busy = "busy@conference.example.com";
 
-        let queued = manager
-            .send_message("bob@example.com", "reconcile me")
-            .await
-            .unwrap();
-        set_connection_online(manager.as_ref()).await;
+        let stale = Utc::now() - chrono::Duration::seconds(MUC_TYPING_EXPIRY_SECS + 1);
+        manager
+            .typers
+            .write()
+            .unwrap()
+            .entry(quiet.to_string())
+            .or_default()
+            .insert("Bob".to_string(), stale);
 
         manager
             .handle_event(&make_event(
-                "xmpp.message.sent",
-                EventPayload::MessageSent {
-                    message: make_chat_message(
-                        &queued.id,
-                        "alice@example.com",
-                        "bob@example.com",
-                        "reconcile me",
-                    ),
+                "xmpp.muc.chatstate.received",
+                EventPayload::MucChatStateReceived {
+                    room: busy.to_string(),
+                    nick: "Carol".to_string(),
+                    state: ChatState::Composing,
                 },
             ))
             .await;
 
-        let mam_message = ChatMessage {
-            id: "archive-id-42".to_string(),
-            from: "alice@example.com".to_string(),
-            to: "bob@example.com".to_string(),
-            body: "reconcile me".to_string(),
-            timestamp: Utc::now(),
-            message_type: MessageType::Chat,
-            thread: None,
-            embeds: vec![],
-        };
+        let typers = manager.typers.read().unwrap();
+        assert!(!typers.contains_key(quiet));
+        assert!(typers[busy].contains_key("Carol"));
+    }
 
-        manager
-            .handle_event(&make_event(
-                "xmpp.mam.result.received",
-                EventPayload::MamResultReceived {
-                    query_id: "q1".to_string(),
-                    messages: vec![mam_message],
-                    complete: true,
-                },
-            ))
-            .await;
+    #[tokio::test]
+    async fn send_muc_chat_state_emits_event() {
+        let (manager, event_bus, _dir) = setup_muc().await;
+        let mut sub = event_bus.subscribe("ui.**").unwrap();
 
-        let row: Row = manager
-            .db
-            .query_one(
-                "SELECT status FROM offline_queue ORDER BY id ASC LIMIT 1",
-                &[],
-            )
+        manager
+            .send_chat_state("room@conference.example.com", ChatState::Composing)
             .await
             .unwrap();
-        assert_eq!(row.get(0), Some(&SqlValue::Text("confirmed".to_string())));
+
+        let received = tokio::time::timeout(std::time::Duration::from_millis(100), sub.recv())
+            .await
+            .expect("timed out")
+            .expect("should receive event");
+        assert!(matches!(
+            received.payload,
+            EventPayload::MucChatStateSendRequested {
+                ref room,
+                state: ChatState::Composing,
+            } if room == "room@conference.example.com"
+        ));
     }
-}
 
-#[cfg(all(test, feature = "native"))]
-mod muc_tests {
-    use super::*;
-    use std::sync::Arc;
-    use tempfile::TempDir;
-    use waddle_core::event::{BroadcastEventBus, Channel, EventBus, EventSource, MucAffiliation};
+    #[tokio::test]
+    async fn leave_room_emits_event() {
+        let (manager, event_bus, _dir) = setup_muc().await;
+        let mut sub = event_bus.subscribe("ui.**").unwrap();
 
-    async fn setup_muc() -> (Arc<MucManager<impl Database>>, Arc<dyn EventBus>, TempDir) {
-        let dir = TempDir::new().expect("failed to create temp dir");
-        let db_path = dir.path().join("test.db");
-        let db = waddle_storage::open_database(&db_path)
+        manager
+            .leave_room("room@conference.example.com")
             .await
-            .expect("failed to open database");
-        let db = Arc::new(db);
-        let event_bus: Arc<dyn EventBus> = Arc::new(BroadcastEventBus::default());
-        let manager = Arc::new(MucManager::new(db, event_bus.clone()));
-        (manager, event_bus, dir)
-    }
+            .unwrap();
 
-    fn make_event(channel: &str, payload: EventPayload) -> Event {
-        Event::new(
-            Channel::new(channel).unwrap(),
-            EventSource::System("test".into()),
-            payload,
-        )
-    }
+        let received = tokio::time::timeout(std::time::Duration::from_millis(100), sub.recv())
+            .await
+            .expect("timed out")
+            .expect("should receive event");
 
-    fn make_muc_message(id: &str, from: &str, room: &str, body: &str) -> ChatMessage {
-        ChatMessage {
-            id: id.to_string(),
-            from: from.to_string(),
-            to: room.to_string(),
-            body: body.to_string(),
-            timestamp: Utc::now(),
-            message_type: MessageType::Groupchat,
-            thread: None,
-            embeds: vec![],
-        }
+        assert!(matches!(
+            received.payload,
+            EventPayload::MucLeaveRequested {
+                ref room,
+            } if room == "room@conference.example.com"
+        ));
     }
 
-    fn make_occupant(nick: &str, role: MucRole, affiliation: MucAffiliation) -> MucOccupant {
-        MucOccupant {
-            nick: nick.to_string(),
-            jid: None,
-            affiliation,
-            role,
+    #[tokio::test]
+    async fn room_history_queries_use_composite_index() {
+        let (manager, _event_bus, _dir) = setup_muc().await;
+
+        let room = "room@conference.example.com".to_string();
+        let groupchat = message_type_param(&MessageType::Groupchat);
+        let cursor = 0_i64;
+        let limit = 10_i64;
+        let latest: [&dyn ToSql; 3] = [&room, &groupchat, &limit];
+        let before: [&dyn ToSql; 4] = [&room, &groupchat, &cursor, &limit];
+
+        for (sql, params) in [
+            (history_page_sql(ROOM_HISTORY_FILTER, false), &latest[..]),
+            (history_page_sql(ROOM_HISTORY_FILTER, true), &before[..]),
+        ] {
+            let plan: Vec<Row> = manager
+                .db
+                .query(&format!("EXPLAIN QUERY PLAN {sql}"), params)
+                .await
+                .unwrap();
+            let details: Vec<&str> = plan
+                .iter()
+                .filter_map(|row| match row.get(3) {
+                    Some(SqlValue::Text(detail)) => Some(detail.as_str()),
+                    _ => None,
+                })
+                .collect();
+            assert!(
+                details
+                    .iter()
+                    .any(|d| d.contains("USING INDEX idx_messages_room_timeline")),
+                "room history should use the composite index, plan: {details:?}"
+            );
+            assert!(
+                !details.iter().any(|d| d.contains("TEMP B-TREE")),
+                "room history should not sort in a temp b-tree, plan: {details:?}"
+            );
         }
     }
 
     #[tokio::test]
-    async fn join_room_emits_event_and_persists() {
+    async fn forget_room_removes_room_and_its_messages() {
         let (manager, event_bus, _dir) = setup_muc().await;
         let mut sub = event_bus.subscribe("ui.**").unwrap();
 
+        for room in [
+            "room@conference.example.com",
+            "other@conference.example.com",
+        ] {
+            manager
+                .handle_event(&make_event(
+                    "xmpp.muc.joined",
+                    EventPayload::MucJoined {
+                        room: room.to_string(),
+                        nick: "Alice".to_string(),
+                    },
+                ))
+                .await;
+            manager
+                .handle_event(&make_event(
+                    "xmpp.muc.message.received",
+                    EventPayload::MucMessageReceived {
+                        room: room.to_string(),
+                        message: make_muc_message(
+                            &format!("{room}-1"),
+                            &format!("{room}/Bob"),
+                            room,
+                            "Hello",
+                        ),
+                    },
+                ))
+                .await;
+        }
+
         manager
-            .join_room("room@conference.example.com", "Alice")
+            .forget_room("room@conference.example.com")
             .await
             .unwrap();
 
@@ -2049,123 +7993,191 @@ mod muc_tests {
             .await
             .expect("timed out")
             .expect("should receive event");
-
         assert!(matches!(
             received.payload,
-            EventPayload::MucJoinRequested {
-                ref room,
-                ref nick,
-            } if room == "room@conference.example.com" && nick == "Alice"
+            EventPayload::MucLeaveRequested { ref room } if room == "room@conference.example.com"
         ));
 
         let rooms = manager.get_rooms().await.unwrap();
         assert_eq!(rooms.len(), 1);
-        assert_eq!(rooms[0].room_jid, "room@conference.example.com");
-        assert_eq!(rooms[0].nick, "Alice");
-        assert!(!rooms[0].joined);
+        assert_eq!(rooms[0].room_jid, "other@conference.example.com");
+        assert!(
+            manager
+                .get_room_messages("room@conference.example.com", 10, None)
+                .await
+                .unwrap()
+                .is_empty()
+        );
+        assert_eq!(
+            manager
+                .get_room_messages("other@conference.example.com", 10, None)
+                .await
+                .unwrap()
+                .len(),
+            1
+        );
     }
 
     #[tokio::test]
-    async fn handle_muc_joined_marks_room_joined() {
-        let (manager, _, _dir) = setup_muc().await;
+    async fn send_muc_message_emits_event() {
+        let (manager, event_bus, _dir) = setup_muc().await;
+        let mut sub = event_bus.subscribe("ui.**").unwrap();
 
         manager
-            .join_room("room@conference.example.com", "Alice")
+            .send_message("room@conference.example.com", "Hello everyone!")
             .await
             .unwrap();
 
-        let event = make_event(
-            "xmpp.muc.joined",
-            EventPayload::MucJoined {
-                room: "room@conference.example.com".to_string(),
-                nick: "Alice".to_string(),
-            },
-        );
-        manager.handle_event(&event).await;
+        let received = tokio::time::timeout(std::time::Duration::from_millis(100), sub.recv())
+            .await
+            .expect("timed out")
+            .expect("should receive event");
 
-        let rooms = manager.get_joined_rooms().await.unwrap();
-        assert_eq!(rooms.len(), 1);
-        assert!(rooms[0].joined);
+        assert!(matches!(
+            received.payload,
+            EventPayload::MucSendRequested {
+                ref room,
+                ref body,
+            } if room == "room@conference.example.com" && body == "Hello everyone!"
+        ));
     }
 
     #[tokio::test]
-    async fn handle_muc_left_marks_room_not_joined() {
-        let (manager, _, _dir) = setup_muc().await;
+    async fn optimistic_send_is_reconciled_by_reflection() {
+        let (manager, event_bus, _dir) = setup_muc().await;
+        let mut sub = event_bus.subscribe("ui.muc.delivered").unwrap();
 
         manager
-            .join_room("room@conference.example.com", "Alice")
+            .handle_event(&make_event(
+                "xmpp.muc.joined",
+                EventPayload::MucJoined {
+                    room: "room@conference.example.com".to_string(),
+                    nick: "Alice".to_string(),
+                },
+            ))
+            .await;
+
+        let sent = manager
+            .send_message("room@conference.example.com", "Hello everyone!")
             .await
             .unwrap();
+        assert_eq!(sent.from, "room@conference.example.com/Alice");
 
-        let join_event = make_event(
-            "xmpp.muc.joined",
-            EventPayload::MucJoined {
-                room: "room@conference.example.com".to_string(),
-                nick: "Alice".to_string(),
-            },
-        );
-        manager.handle_event(&join_event).await;
-
-        let leave_event = make_event(
-            "xmpp.muc.left",
-            EventPayload::MucLeft {
-                room: "room@conference.example.com".to_string(),
-            },
+        let reflection = make_muc_message(
+            &sent.id,
+            "room@conference.example.com/Alice",
+            "alice@example.com/desktop",
+            "Hello everyone!",
         );
-        manager.handle_event(&leave_event).await;
+        manager
+            .handle_event(&make_event(
+                "xmpp.muc.message.received",
+                EventPayload::MucMessageReceived {
+                    room: "room@conference.example.com".to_string(),
+                    message: reflection,
+                },
+            ))
+            .await;
 
-        let joined_rooms = manager.get_joined_rooms().await.unwrap();
-        assert!(joined_rooms.is_empty());
+        let received = tokio::time::timeout(std::time::Duration::from_millis(100), sub.recv())
+            .await
+            .expect("timed out")
+            .expect("should receive event");
+        assert!(matches!(
+            received.payload,
+            EventPayload::MessageDelivered { ref id, ref to, .. }
+                if id == &sent.id && to == "room@conference.example.com"
+        ));
 
-        let all_rooms = manager.get_rooms().await.unwrap();
-        assert_eq!(all_rooms.len(), 1);
-        assert!(!all_rooms[0].joined);
+        let messages = manager
+            .get_room_messages("room@conference.example.com", 50, None)
+            .await
+            .unwrap();
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].id, sent.id);
     }
 
     #[tokio::test]
-    async fn leave_room_emits_event() {
+    async fn unreflected_send_is_not_delivered_after_timeout() {
         let (manager, event_bus, _dir) = setup_muc().await;
-        let mut sub = event_bus.subscribe("ui.**").unwrap();
+        let mut sub = event_bus.subscribe("ui.muc.not_delivered").unwrap();
+        manager.set_echo_timeout(std::time::Duration::from_millis(50));
+        manager.handle_event(&connection_established()).await;
 
         manager
-            .leave_room("room@conference.example.com")
+            .handle_event(&make_event(
+                "xmpp.muc.joined",
+                EventPayload::MucJoined {
+                    room: "room@conference.example.com".to_string(),
+                    nick: "Alice".to_string(),
+                },
+            ))
+            .await;
+
+        let reflected = manager
+            .send_message("room@conference.example.com", "Echoed")
             .await
             .unwrap();
+        let dropped = manager
+            .send_message("room@conference.example.com", "Dropped")
+            .await
+            .unwrap();
+        manager
+            .handle_event(&make_event(
+                "xmpp.muc.message.received",
+                EventPayload::MucMessageReceived {
+                    room: "room@conference.example.com".to_string(),
+                    message: make_muc_message(
+                        &reflected.id,
+                        "room@conference.example.com/Alice",
+                        "alice@example.com/desktop",
+                        "Echoed",
+                    ),
+                },
+            ))
+            .await;
 
-        let received = tokio::time::timeout(std::time::Duration::from_millis(100), sub.recv())
+        let received = tokio::time::timeout(std::time::Duration::from_millis(500), sub.recv())
             .await
             .expect("timed out")
             .expect("should receive event");
-
         assert!(matches!(
             received.payload,
-            EventPayload::MucLeaveRequested {
-                ref room,
-            } if room == "room@conference.example.com"
+            EventPayload::MessageNotDelivered { ref id, ref to }
+                if id == &dropped.id && to == "room@conference.example.com"
         ));
+        assert!(
+            tokio::time::timeout(std::time::Duration::from_millis(100), sub.recv())
+                .await
+                .is_err()
+        );
     }
 
     #[tokio::test]
-    async fn send_muc_message_emits_event() {
+    async fn queued_send_starts_its_echo_timer_on_reconnect() {
         let (manager, event_bus, _dir) = setup_muc().await;
-        let mut sub = event_bus.subscribe("ui.**").unwrap();
+        let mut sub = event_bus.subscribe("ui.muc.not_delivered").unwrap();
+        manager.set_echo_timeout(std::time::Duration::from_millis(50));
 
-        manager
-            .send_message("room@conference.example.com", "Hello everyone!")
+        let queued = manager
+            .send_message("room@conference.example.com", "Sent offline")
             .await
             .unwrap();
+        assert!(
+            tokio::time::timeout(std::time::Duration::from_millis(150), sub.recv())
+                .await
+                .is_err(),
+            "a queued send must not time out before it is sent"
+        );
 
-        let received = tokio::time::timeout(std::time::Duration::from_millis(100), sub.recv())
+        manager.handle_event(&connection_established()).await;
+        let received = tokio::time::timeout(std::time::Duration::from_millis(500), sub.recv())
             .await
             .expect("timed out")
             .expect("should receive event");
-
         assert!(matches!(
             received.payload,
-            EventPayload::MucSendRequested {
-                ref room,
-                ref body,
-            } if room == "room@conference.example.com" && body == "Hello everyone!"
+            EventPayload::MessageNotDelivered { ref id, .. } if id == &queued.id
         ));
     }
 
@@ -2197,7 +8209,7 @@ mod muc_tests {
         assert_eq!(messages.len(), 1);
         assert_eq!(messages[0].id, "muc-msg-1");
         assert_eq!(messages[0].body, "Hi all!");
-        assert!(matches!(messages[0].message_type, MessageType::Groupchat));
+        assert!(messages[0].message_type.is_groupchat());
     }
 
     #[tokio::test]
@@ -2213,6 +8225,10 @@ mod muc_tests {
             message_type: MessageType::Groupchat,
             thread: None,
             embeds: vec![],
+            bodies: BTreeMap::new(),
+            origin_id: None,
+            spoiler: None,
+            stanza_id: None,
         };
 
         let event = make_event(
@@ -2269,6 +8285,258 @@ mod muc_tests {
         );
     }
 
+    #[tokio::test]
+    async fn room_messages_before_subject_record_history_source() {
+        let (manager, _, _dir) = setup_muc().await;
+        let room = "room@conference.example.com";
+        manager.join_room(room, "Alice").await.unwrap();
+
+        let received = |id: &str| {
+            make_event(
+                "xmpp.muc.message.received",
+                EventPayload::MucMessageReceived {
+                    room: room.to_string(),
+                    message: make_muc_message(id, &format!("{room}/Bob"), room, "hi"),
+                },
+            )
+        };
+        manager
+            .handle_event(&make_event(
+                "xmpp.muc.joined",
+                EventPayload::MucJoined {
+                    room: room.to_string(),
+                    nick: "Alice".to_string(),
+                },
+            ))
+            .await;
+        manager.handle_event(&received("replayed-1")).await;
+        manager
+            .handle_event(&make_event(
+                "xmpp.muc.subject.changed",
+                EventPayload::MucSubjectChanged {
+                    room: room.to_string(),
+                    subject: "Standup".to_string(),
+                },
+            ))
+            .await;
+        manager.handle_event(&received("live-1")).await;
+
+        for (id, source) in [("replayed-1", "muc_history"), ("live-1", "live")] {
+            let rows: Vec<Row> = manager
+                .db
+                .query(
+                    "SELECT source FROM messages WHERE id = ?1",
+                    &[&id.to_string()],
+                )
+                .await
+                .unwrap();
+            assert_eq!(rows[0].get(0), Some(&SqlValue::Text(source.to_string())));
+        }
+    }
+
+    #[tokio::test]
+    async fn message_before_join_confirmation_surfaces_room() {
+        let (manager, _, _dir) = setup_muc().await;
+        let room = "room@conference.example.com";
+        manager.join_room(room, "Alice").await.unwrap();
+        assert!(manager.get_joined_rooms().await.unwrap().is_empty());
+
+        manager
+            .handle_event(&make_event(
+                "xmpp.muc.message.received",
+                EventPayload::MucMessageReceived {
+                    room: room.to_string(),
+                    message: make_muc_message("early-1", &format!("{room}/Bob"), room, "hi"),
+                },
+            ))
+            .await;
+
+        let joined = manager.get_joined_rooms().await.unwrap();
+        assert_eq!(joined.len(), 1);
+        assert_eq!(joined[0].room_jid, room);
+        assert_eq!(joined[0].nick, "Alice");
+        let messages = manager.get_room_messages(room, 50, None).await.unwrap();
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].id, "early-1");
+        let rows: Vec<Row> = manager
+            .db
+            .query("SELECT source FROM messages WHERE id = 'early-1'", &[])
+            .await
+            .unwrap();
+        assert_eq!(
+            rows[0].get(0),
+            Some(&SqlValue::Text("muc_history".to_string()))
+        );
+
+        manager
+            .handle_event(&make_event(
+                "xmpp.muc.joined",
+                EventPayload::MucJoined {
+                    room: room.to_string(),
+                    nick: "Alice".to_string(),
+                },
+            ))
+            .await;
+        assert_eq!(manager.get_joined_rooms().await.unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn refused_join_undoes_early_message_join() {
+        let (manager, _, _dir) = setup_muc().await;
+        let room = "room@conference.example.com";
+        manager.join_room(room, "Alice").await.unwrap();
+        manager
+            .handle_event(&make_event(
+                "xmpp.muc.message.received",
+                EventPayload::MucMessageReceived {
+                    room: room.to_string(),
+                    message: make_muc_message("early-1", &format!("{room}/Bob"), room, "hi"),
+                },
+            ))
+            .await;
+        manager
+            .handle_event(&make_event(
+                "xmpp.muc.join.failed",
+                EventPayload::MucJoinFailed {
+                    room: room.to_string(),
+                    nick: "Alice".to_string(),
+                    failure: MucJoinFailure::Banned,
+                },
+            ))
+            .await;
+
+        assert!(manager.get_joined_rooms().await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn affiliation_list_query_targets_room_and_affiliation() {
+        let (manager, event_bus, _dir) = setup_muc().await;
+        let mut requests = event_bus.subscribe("ui.muc.affiliations.query").unwrap();
+
+        let responder = {
+            let event_bus = event_bus.clone();
+            tokio::spawn(async move {
+                let event = requests.recv().await.unwrap();
+                let EventPayload::MucAffiliationListRequested {
+                    query_id,
+                    room,
+                    affiliation,
+                } = event.payload
+                else {
+                    panic!("expected MucAffiliationListRequested");
+                };
+                event_bus
+                    .publish(make_event(
+                        "xmpp.muc.affiliations.received",
+                        EventPayload::MucAffiliationListReceived {
+                            query_id,
+                            room: room.clone(),
+                            items: vec![(
+                                "troll@example.com".to_string(),
+                                Some("Spam".to_string()),
+                            )],
+                        },
+                    ))
+                    .unwrap();
+                (room, affiliation)
+            })
+        };
+
+        let items = manager
+            .fetch_affiliation_list("room@conference.example.com", MucAffiliation::Outcast)
+            .await
+            .unwrap();
+
+        let (room, affiliation) = responder.await.unwrap();
+        assert_eq!(room, "room@conference.example.com");
+        assert_eq!(affiliation, MucAffiliation::Outcast);
+        assert_eq!(
+            items,
+            vec![("troll@example.com".to_string(), Some("Spam".to_string()))]
+        );
+    }
+
+    #[tokio::test]
+    async fn room_info_queries_once_then_serves_cache() {
+        let (manager, event_bus, _dir) = setup_muc().await;
+        let mut requests = event_bus.subscribe("ui.muc.info.query").unwrap();
+        let room = "room@conference.example.com";
+
+        let responder = {
+            let event_bus = event_bus.clone();
+            tokio::spawn(async move {
+                let event = requests.recv().await.unwrap();
+                let EventPayload::MucRoomInfoRequested { query_id, room } = event.payload else {
+                    panic!("expected MucRoomInfoRequested");
+                };
+                event_bus
+                    .publish(make_event(
+                        "xmpp.muc.info.received",
+                        EventPayload::MucRoomInfoReceived {
+                            query_id,
+                            room: room.clone(),
+                            info: MucRoomInfo {
+                                exists: true,
+                                password_protected: true,
+                                features: vec!["muc_passwordprotected".to_string()],
+                                ..Default::default()
+                            },
+                        },
+                    ))
+                    .unwrap();
+                (room, requests)
+            })
+        };
+
+        let info = manager.room_info(room).await.unwrap();
+        let (queried, mut requests) = responder.await.unwrap();
+        assert_eq!(queried, room);
+        assert!(info.exists);
+        assert!(info.password_protected);
+
+        let cached = manager.room_info(room).await.unwrap();
+        assert_eq!(cached, info);
+        assert!(
+            tokio::time::timeout(std::time::Duration::from_millis(100), requests.recv())
+                .await
+                .is_err(),
+            "cached room info should not query again"
+        );
+    }
+
+    #[tokio::test]
+    async fn missing_room_info_reports_not_existing() {
+        let (manager, event_bus, _dir) = setup_muc().await;
+        let mut requests = event_bus.subscribe("ui.muc.info.query").unwrap();
+
+        let responder = {
+            let event_bus = event_bus.clone();
+            tokio::spawn(async move {
+                let event = requests.recv().await.unwrap();
+                let EventPayload::MucRoomInfoRequested { query_id, room } = event.payload else {
+                    panic!("expected MucRoomInfoRequested");
+                };
+                event_bus
+                    .publish(make_event(
+                        "xmpp.muc.query.failed",
+                        EventPayload::MucQueryFailed {
+                            query_id,
+                            room,
+                            condition: "item-not-found".to_string(),
+                        },
+                    ))
+                    .unwrap();
+            })
+        };
+
+        let info = manager
+            .room_info("missing@conference.example.com")
+            .await
+            .unwrap();
+        responder.await.unwrap();
+        assert!(!info.exists);
+    }
+
     #[tokio::test]
     async fn occupant_tracking_add_and_remove() {
         let (manager, _, _dir) = setup_muc().await;
@@ -2366,6 +8634,7 @@ mod muc_tests {
             "xmpp.muc.left",
             EventPayload::MucLeft {
                 room: "room@conference.example.com".to_string(),
+                removal: None,
             },
         );
         manager.handle_event(&leave_event).await;
@@ -2432,6 +8701,10 @@ mod muc_tests {
                 message_type: MessageType::Groupchat,
                 thread: None,
                 embeds: vec![],
+                bodies: BTreeMap::new(),
+                origin_id: None,
+                spoiler: None,
+                stanza_id: None,
             };
             let event = make_event(
                 "xmpp.muc.message.received",
@@ -2452,6 +8725,164 @@ mod muc_tests {
         assert_eq!(messages.len(), 3);
     }
 
+    #[tokio::test]
+    async fn room_without_occupant_jids_is_semi_anonymous() {
+        let (manager, _, _dir) = setup_muc().await;
+        let room = "room@conference.example.com";
+        manager
+            .handle_event(&make_event(
+                "xmpp.muc.joined",
+                EventPayload::MucJoined {
+                    room: room.to_string(),
+                    nick: "Alice".to_string(),
+                },
+            ))
+            .await;
+        assert_eq!(manager.room_anonymity(room), RoomAnonymity::Unknown);
+
+        manager
+            .handle_event(&make_event(
+                "xmpp.muc.occupant.changed",
+                EventPayload::MucOccupantChanged {
+                    room: room.to_string(),
+                    occupant: make_occupant("Bob", MucRole::Participant, MucAffiliation::Member),
+                },
+            ))
+            .await;
+
+        let rooms = manager.get_joined_rooms().await.unwrap();
+        assert_eq!(rooms[0].anonymity, RoomAnonymity::SemiAnonymous);
+
+        let mut carol = make_occupant("Carol", MucRole::Participant, MucAffiliation::None);
+        carol.jid = Some("carol@example.com/laptop".to_string());
+        manager
+            .handle_event(&make_event(
+                "xmpp.muc.occupant.changed",
+                EventPayload::MucOccupantChanged {
+                    room: room.to_string(),
+                    occupant: carol,
+                },
+            ))
+            .await;
+
+        let rooms = manager.get_joined_rooms().await.unwrap();
+        assert_eq!(rooms[0].anonymity, RoomAnonymity::NonAnonymous);
+    }
+
+    #[tokio::test]
+    async fn occupant_cap_evicts_least_recently_active_visitors() {
+        let (manager, _, _dir) = setup_muc().await;
+        let room = "room@conference.example.com";
+        manager.set_max_occupants(Some(3));
+
+        let arrive = |nick: &str, role: MucRole, affiliation: MucAffiliation| {
+            make_event(
+                "xmpp.muc.occupant.changed",
+                EventPayload::MucOccupantChanged {
+                    room: room.to_string(),
+                    occupant: make_occupant(nick, role, affiliation),
+                },
+            )
+        };
+        manager
+            .handle_event(&arrive("Olivia", MucRole::Moderator, MucAffiliation::Owner))
+            .await;
+        for nick in ["Alice", "Bob"] {
+            manager
+                .handle_event(&arrive(nick, MucRole::Participant, MucAffiliation::None))
+                .await;
+        }
+        // Alice speaks, so Bob is now the least recently active.
+        manager
+            .handle_event(&make_event(
+                "xmpp.muc.message.received",
+                EventPayload::MucMessageReceived {
+                    room: room.to_string(),
+                    message: make_muc_message("m-1", &format!("{room}/Alice"), room, "hi"),
+                },
+            ))
+            .await;
+        manager
+            .handle_event(&arrive("Carol", MucRole::Visitor, MucAffiliation::None))
+            .await;
+
+        let nicks: Vec<String> = manager
+            .get_occupants(room)
+            .into_iter()
+            .map(|o| o.nick)
+            .collect();
+        assert_eq!(nicks, vec!["Olivia", "Alice", "Carol"]);
+    }
+
+    #[tokio::test]
+    async fn occupants_are_listed_by_affiliation_then_nick() {
+        let (manager, _, _dir) = setup_muc().await;
+        let room = "room@conference.example.com";
+        for (nick, affiliation) in [
+            ("zed", MucAffiliation::None),
+            ("Mallory", MucAffiliation::Member),
+            ("bob", MucAffiliation::Member),
+            ("Alice", MucAffiliation::None),
+            ("Olivia", MucAffiliation::Owner),
+            ("Adam", MucAffiliation::Admin),
+        ] {
+            manager
+                .handle_event(&make_event(
+                    "xmpp.muc.occupant.changed",
+                    EventPayload::MucOccupantChanged {
+                        room: room.to_string(),
+                        occupant: make_occupant(nick, MucRole::Participant, affiliation),
+                    },
+                ))
+                .await;
+        }
+
+        let nicks: Vec<String> = manager
+            .get_occupants(room)
+            .into_iter()
+            .map(|o| o.nick)
+            .collect();
+        assert_eq!(
+            nicks,
+            vec!["Olivia", "Adam", "bob", "Mallory", "Alice", "zed"]
+        );
+    }
+
+    #[tokio::test]
+    async fn occupants_matching_is_case_insensitive_prefix() {
+        let (manager, _, _dir) = setup_muc().await;
+        let room = "room@conference.example.com";
+        for nick in ["Alice", "alfred", "Bob", "ALBERT", "Malice"] {
+            manager
+                .handle_event(&make_event(
+                    "xmpp.muc.occupant.changed",
+                    EventPayload::MucOccupantChanged {
+                        room: room.to_string(),
+                        occupant: make_occupant(nick, MucRole::Participant, MucAffiliation::None),
+                    },
+                ))
+                .await;
+        }
+
+        let nicks = |prefix: &str| -> Vec<String> {
+            manager
+                .occupants_matching(room, prefix)
+                .into_iter()
+                .map(|o| o.nick)
+                .collect()
+        };
+        assert_eq!(nicks("al"), vec!["ALBERT", "alfred", "Alice"]);
+        assert_eq!(nicks("ALI"), vec!["Alice"]);
+        assert_eq!(nicks("b"), vec!["Bob"]);
+        assert!(nicks("z").is_empty());
+        assert_eq!(nicks("").len(), 5);
+        assert!(
+            manager
+                .occupants_matching("other@conference.example.com", "a")
+                .is_empty()
+        );
+    }
+
     #[tokio::test]
     async fn get_occupants_unknown_room_returns_empty() {
         let (manager, _, _dir) = setup_muc().await;
@@ -7,16 +7,16 @@ use std::time::{Duration, Instant};
 
 #[cfg(feature = "native")]
 use notify_rust::Notification;
-use tracing::error;
 #[cfg(feature = "native")]
 use tracing::{debug, warn};
+use tracing::{error, field, instrument};
 #[cfg(feature = "native")]
 use waddle_core::config::Config;
 #[cfg(feature = "native")]
 use waddle_core::error::EventBusError;
 #[cfg(feature = "native")]
 use waddle_core::event::{Channel, EventBus, EventSource};
-use waddle_core::event::{ChatMessage, Event, EventPayload};
+use waddle_core::event::{ChatMessage, Event, EventPayload, MessageType};
 
 const AGGREGATION_WINDOW: Duration = Duration::from_secs(2);
 const AGGREGATION_THRESHOLD: usize = 3;
@@ -36,6 +36,57 @@ pub enum NotificationError {
     EventBus(#[from] EventBusError),
 }
 
+/// How a conversation's notifications are muted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum MutePolicy {
+    #[default]
+    Unmuted,
+    /// Only messages mentioning us get through
+    MentionsOnly,
+    Muted,
+}
+
+/// How loudly a message should be surfaced.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NotificationClass {
+    /// Show a notification
+    Notify,
+    /// Count it as unread, but don't interrupt, e.g. room chatter
+    Badge,
+    /// Informational, such as a headline; a quiet, low-priority notice
+    Info,
+    /// Not worth surfacing: our own messages, errors, muted conversations
+    Silent,
+}
+
+/// Classifies `message` for notification. `own_jid` is our JID, bare or
+/// full, so our own carbons stay silent; `own_nick` is our nick in the room
+/// a groupchat message came from, so the room echoing our message back
+/// (`room/ournick`) stays silent too. `mentioned` says whether the body
+/// mentions us or one of our highlight keywords.
+pub fn should_notify(
+    message: &ChatMessage,
+    own_jid: &str,
+    own_nick: Option<&str>,
+    mute_policy: MutePolicy,
+    mentioned: bool,
+) -> NotificationClass {
+    if normalize_jid(&message.from).eq_ignore_ascii_case(&normalize_jid(own_jid)) {
+        return NotificationClass::Silent;
+    }
+    if message.message_type == MessageType::Groupchat && is_own_room_echo(&message.from, own_nick) {
+        return NotificationClass::Silent;
+    }
+    match (mute_policy, &message.message_type) {
+        (MutePolicy::Muted, _) | (_, MessageType::Error) => NotificationClass::Silent,
+        (_, MessageType::Headline) => NotificationClass::Info,
+        (_, _) if mentioned => NotificationClass::Notify,
+        (MutePolicy::MentionsOnly, _) => NotificationClass::Silent,
+        (MutePolicy::Unmuted, MessageType::Groupchat) => NotificationClass::Badge,
+        (MutePolicy::Unmuted, MessageType::Chat | MessageType::Normal) => NotificationClass::Notify,
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, Eq)]
 struct NotificationRequest {
     title: String,
@@ -168,6 +219,7 @@ impl NotificationManager {
         *self.highlight_keywords.write().unwrap() = normalized;
     }
 
+    #[instrument(skip_all, fields(channel = %event.channel, correlation_id = event.correlation_id.map(field::display)))]
     pub fn handle_event(&self, event: &Event) {
         match &event.payload {
             EventPayload::ConnectionEstablished { jid } => {
@@ -189,7 +241,7 @@ impl NotificationManager {
                     .unwrap()
                     .insert(normalize_jid(room), nick.clone());
             }
-            EventPayload::MucLeft { room } => {
+            EventPayload::MucLeft { room, .. } | EventPayload::MucDestroyed { room, .. } => {
                 self.room_nicks
                     .write()
                     .unwrap()
@@ -240,6 +292,11 @@ impl NotificationManager {
             return;
         }
 
+        let own_nick = self.room_nicks.read().unwrap().get(&room_jid).cloned();
+        if is_own_room_echo(&message.from, own_nick.as_deref()) {
+            return;
+        }
+
         if !self.is_muc_highlight(&room_jid, message) {
             return;
         }
@@ -372,6 +429,11 @@ impl NotificationManager {
     }
 }
 
+/// Whether `from` (`room/nick`) is the room echoing our own message.
+fn is_own_room_echo(from: &str, own_nick: Option<&str>) -> bool {
+    own_nick.is_some() && from.split_once('/').map(|(_, nick)| nick) == own_nick
+}
+
 fn normalize_jid(jid: &str) -> String {
     jid.split('/').next().unwrap_or(jid).to_string()
 }
@@ -390,6 +452,7 @@ fn extract_localpart(jid: &str) -> Option<String> {
 mod tests {
     use super::*;
     use chrono::Utc;
+    use std::collections::BTreeMap;
     use std::sync::atomic::{AtomicBool, Ordering};
     use waddle_core::event::{
         BroadcastEventBus, Channel, Event, EventBus, EventPayload, EventSource, MessageType,
@@ -456,6 +519,10 @@ mod tests {
                     message_type: MessageType::Chat,
                     thread: None,
                     embeds: vec![],
+                    bodies: BTreeMap::new(),
+                    origin_id: None,
+                    spoiler: None,
+                    stanza_id: None,
                 },
             },
         )
@@ -475,6 +542,10 @@ mod tests {
                     message_type: MessageType::Groupchat,
                     thread: None,
                     embeds: vec![],
+                    bodies: BTreeMap::new(),
+                    origin_id: None,
+                    spoiler: None,
+                    stanza_id: None,
                 },
             },
         )
@@ -583,6 +654,140 @@ mod tests {
         );
     }
 
+    #[test]
+    fn our_own_room_echo_does_not_notify() {
+        let (manager, dispatcher) = make_manager(true);
+        manager.handle_event(&make_event(
+            "xmpp.muc.joined",
+            EventPayload::MucJoined {
+                room: "dev@conference.example.com".to_string(),
+                nick: "user".to_string(),
+            },
+        ));
+
+        let mut echo = make_muc_message_event("dev@conference.example.com", "ping @user", "m1");
+        if let EventPayload::MucMessageReceived { message, .. } = &mut echo.payload {
+            message.from = "dev@conference.example.com/user".to_string();
+        }
+        manager.handle_event(&echo);
+        assert!(dispatcher.notifications().is_empty());
+
+        manager.handle_event(&make_muc_message_event(
+            "dev@conference.example.com",
+            "ping @user",
+            "m2",
+        ));
+        assert_eq!(dispatcher.notifications().len(), 1);
+    }
+
+    fn make_message(from: &str, body: &str, message_type: MessageType) -> ChatMessage {
+        ChatMessage {
+            id: "m1".to_string(),
+            from: from.to_string(),
+            to: "user@example.com".to_string(),
+            body: body.to_string(),
+            timestamp: Utc::now(),
+            message_type,
+            thread: None,
+            embeds: vec![],
+            bodies: BTreeMap::new(),
+            origin_id: None,
+            spoiler: None,
+            stanza_id: None,
+        }
+    }
+
+    #[test]
+    fn should_notify_classifies_messages() {
+        let own = "user@example.com/desktop";
+        let chat = make_message("alice@example.com/phone", "hi", MessageType::Chat);
+        let carbon = make_message("user@example.com/phone", "hi", MessageType::Chat);
+        let room = make_message(
+            "dev@conference.example.com/alice",
+            "hi",
+            MessageType::Groupchat,
+        );
+        let headline = make_message("news.example.com", "hi", MessageType::Headline);
+        let error = make_message("alice@example.com", "", MessageType::Error);
+
+        assert_eq!(
+            should_notify(&chat, own, None, MutePolicy::Unmuted, false),
+            NotificationClass::Notify
+        );
+        assert_eq!(
+            should_notify(&carbon, own, None, MutePolicy::Unmuted, true),
+            NotificationClass::Silent
+        );
+        assert_eq!(
+            should_notify(&room, own, None, MutePolicy::Unmuted, false),
+            NotificationClass::Badge
+        );
+        assert_eq!(
+            should_notify(&room, own, None, MutePolicy::Unmuted, true),
+            NotificationClass::Notify
+        );
+        assert_eq!(
+            should_notify(&headline, own, None, MutePolicy::Unmuted, false),
+            NotificationClass::Info
+        );
+        assert_eq!(
+            should_notify(&error, own, None, MutePolicy::Unmuted, false),
+            NotificationClass::Silent
+        );
+    }
+
+    #[test]
+    fn should_notify_silences_our_own_room_echo() {
+        let own = "user@example.com/desktop";
+        let echo = make_message(
+            "dev@conference.example.com/user",
+            "hi @user",
+            MessageType::Groupchat,
+        );
+        let other = make_message(
+            "dev@conference.example.com/alice",
+            "hi @user",
+            MessageType::Groupchat,
+        );
+
+        assert_eq!(
+            should_notify(&echo, own, Some("user"), MutePolicy::Unmuted, true),
+            NotificationClass::Silent
+        );
+        assert_eq!(
+            should_notify(&other, own, Some("user"), MutePolicy::Unmuted, true),
+            NotificationClass::Notify
+        );
+    }
+
+    #[test]
+    fn should_notify_honours_mute_policy() {
+        let own = "user@example.com";
+        let chat = make_message("alice@example.com", "hi", MessageType::Chat);
+        let room = make_message(
+            "dev@conference.example.com/alice",
+            "hi",
+            MessageType::Groupchat,
+        );
+
+        assert_eq!(
+            should_notify(&chat, own, None, MutePolicy::Muted, true),
+            NotificationClass::Silent
+        );
+        assert_eq!(
+            should_notify(&chat, own, None, MutePolicy::MentionsOnly, false),
+            NotificationClass::Silent
+        );
+        assert_eq!(
+            should_notify(&room, own, None, MutePolicy::MentionsOnly, false),
+            NotificationClass::Silent
+        );
+        assert_eq!(
+            should_notify(&room, own, None, MutePolicy::MentionsOnly, true),
+            NotificationClass::Notify
+        );
+    }
+
     #[test]
     fn dispatch_failures_are_non_fatal() {
         let (manager, dispatcher) = make_manager(true);
@@ -1,18 +1,32 @@
+mod personal;
+
 use std::collections::HashMap;
-use std::sync::RwLock;
+use std::sync::{Arc, RwLock};
 
 use chrono::{DateTime, Utc};
-use tracing::{debug, error, warn};
+use serde::{Deserialize, Serialize};
+use tracing::{debug, error, field, instrument, warn};
 
 use waddle_core::event::{Event, EventPayload, PresenceShow};
+use waddle_storage::{Database, FromRow, Row, SqlValue, StorageError, format_timestamp};
 
 #[cfg(feature = "native")]
-use std::sync::Arc;
+use std::sync::Mutex;
 #[cfg(feature = "native")]
 use std::sync::atomic::{AtomicBool, Ordering};
 
+#[cfg(feature = "native")]
+use tokio::sync::mpsc;
+
 #[cfg(feature = "native")]
 use waddle_core::event::{Channel, EventBus, EventSource};
+#[cfg(feature = "native")]
+use waddle_xmpp::{PepItem, PepManager};
+
+pub use personal::{
+    NS_ACTIVITY, NS_MOOD, NS_TUNE, TuneInfo, UserActivity, UserMood, activity_element,
+    mood_element, parse_activity, parse_mood, parse_tune, tune_element,
+};
 
 #[derive(Debug, thiserror::Error)]
 pub enum PresenceError {
@@ -22,20 +36,41 @@ pub enum PresenceError {
     #[error("invalid priority value: {0} (must be -128..127)")]
     InvalidPriority(i16),
 
+    #[error("storage error: {0}")]
+    Storage(#[from] StorageError),
+
     #[error("event bus error: {0}")]
     EventBus(String),
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
 pub struct PresenceInfo {
     pub jid: String,
     pub show: PresenceShow,
     pub status: Option<String>,
     pub priority: i8,
     pub last_updated: DateTime<Utc>,
+    /// Resource this presence was received from; `None` for our own
+    /// presence and for contacts with no available resource.
+    pub resource: Option<String>,
+    /// Mood last published over PEP, shared by all of the JID's resources.
+    pub mood: Option<UserMood>,
+    /// Activity last published over PEP, shared by all of the JID's resources.
+    pub activity: Option<UserActivity>,
+    /// Tune now playing, published over PEP and shared by all of the JID's
+    /// resources.
+    pub tune: Option<TuneInfo>,
 }
 
 impl PresenceInfo {
+    /// Full JID (`bare/resource`) of the resource this presence belongs to.
+    pub fn full_jid(&self) -> Option<String> {
+        self.resource
+            .as_ref()
+            .map(|resource| format!("{}/{}", self.jid, resource))
+    }
+
     fn unavailable(jid: &str) -> Self {
         Self {
             jid: jid.to_string(),
@@ -43,36 +78,108 @@ impl PresenceInfo {
             status: None,
             priority: 0,
             last_updated: Utc::now(),
+            resource: None,
+            mood: None,
+            activity: None,
+            tune: None,
         }
     }
 }
 
+/// Our own presence and the best resource of every contact we have seen
+/// presence from, for hydrating a client in one call.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PresenceSnapshot {
+    pub own: PresenceInfo,
+    /// Sorted by bare JID.
+    pub contacts: Vec<PresenceInfo>,
+}
+
+/// Mood, activity and tune of one bare JID, kept apart from its resources
+/// because PEP items belong to the account.
+#[derive(Debug, Clone, Default)]
+struct PersonalEvents {
+    mood: Option<UserMood>,
+    activity: Option<UserActivity>,
+    tune: Option<TuneInfo>,
+}
+
 /// Per-resource presence map for a single bare JID.
 type ResourceMap = HashMap<String, PresenceInfo>;
 
+/// Items from the PEP nodes we track, read by [`PresenceManager::run`].
+#[cfg(feature = "native")]
+struct PepFeeds {
+    mood: mpsc::UnboundedReceiver<PepItem>,
+    activity: mpsc::UnboundedReceiver<PepItem>,
+    tune: mpsc::UnboundedReceiver<PepItem>,
+}
+
 pub struct PresenceManager {
     own_presence: RwLock<PresenceInfo>,
     /// Bare JID -> (resource -> PresenceInfo)
     contacts: RwLock<HashMap<String, ResourceMap>>,
+    /// Bare JID -> mood, activity and tune received over PEP
+    personal: RwLock<HashMap<String, PersonalEvents>>,
+    /// Show and status sent once the roster arrives after connecting
+    #[cfg(feature = "native")]
+    initial_presence: PresenceInfo,
     #[cfg(feature = "native")]
     awaiting_initial_presence: AtomicBool,
+    /// Set while the user has chosen to appear offline on a live connection
+    #[cfg(feature = "native")]
+    appear_offline: AtomicBool,
+    /// Set between losing a resumable stream and reconnecting, so the
+    /// contact map outlives the blip
+    #[cfg(feature = "native")]
+    resuming: AtomicBool,
+    /// Show and status last announced as `OwnPresenceChanged`
+    #[cfg(feature = "native")]
+    announced: RwLock<Option<(PresenceShow, Option<String>)>>,
     #[cfg(feature = "native")]
     event_bus: Arc<dyn EventBus>,
+    #[cfg(feature = "native")]
+    pep: Arc<PepManager>,
+    #[cfg(feature = "native")]
+    pep_feeds: Mutex<Option<PepFeeds>>,
 }
 
 impl PresenceManager {
     #[cfg(feature = "native")]
-    pub fn new(event_bus: Arc<dyn EventBus>) -> Self {
+    pub fn new(event_bus: Arc<dyn EventBus>, pep: Arc<PepManager>) -> Self {
+        let mut initial_presence = PresenceInfo::unavailable("");
+        initial_presence.show = PresenceShow::Available;
+        Self::with_initial_presence(event_bus, pep, initial_presence)
+    }
+
+    /// Creates a manager that comes online with `initial_presence` (e.g. Away
+    /// or a saved status) instead of plain Available. Its priority seeds our
+    /// own priority; its JID and resource are ignored.
+    #[cfg(feature = "native")]
+    pub fn with_initial_presence(
+        event_bus: Arc<dyn EventBus>,
+        pep: Arc<PepManager>,
+        initial_presence: PresenceInfo,
+    ) -> Self {
+        let mut own_presence = PresenceInfo::unavailable("");
+        own_presence.priority = initial_presence.priority;
+        let pep_feeds = PepFeeds {
+            mood: pep.subscribe(NS_MOOD),
+            activity: pep.subscribe(NS_ACTIVITY),
+            tune: pep.subscribe(NS_TUNE),
+        };
         Self {
-            own_presence: RwLock::new(PresenceInfo {
-                jid: String::new(),
-                show: PresenceShow::Unavailable,
-                status: None,
-                priority: 0,
-                last_updated: Utc::now(),
-            }),
+            own_presence: RwLock::new(own_presence),
             contacts: RwLock::new(HashMap::new()),
+            personal: RwLock::new(HashMap::new()),
+            initial_presence,
             awaiting_initial_presence: AtomicBool::new(false),
+            appear_offline: AtomicBool::new(false),
+            resuming: AtomicBool::new(false),
+            announced: RwLock::new(None),
+            pep,
+            pep_feeds: Mutex::new(Some(pep_feeds)),
             event_bus,
         }
     }
@@ -81,14 +188,76 @@ impl PresenceManager {
         self.own_presence.read().unwrap().clone()
     }
 
+    /// Our bare JID, from the last `ConnectionEstablished`. `None` before
+    /// the first connection.
+    pub fn own_bare_jid(&self) -> Option<String> {
+        let own = self.own_presence.read().unwrap();
+        (!own.jid.is_empty()).then(|| bare_jid(&own.jid))
+    }
+
+    /// Our server's domain, for flows addressed to it (registration,
+    /// server-side archive, service notices). `None` before the first
+    /// connection.
+    pub fn own_domain(&self) -> Option<String> {
+        self.own_bare_jid().map(|jid| domain_part(&jid))
+    }
+
     /// Get the current presence of a JID. Returns the highest-priority
-    /// resource's presence, or Unavailable if no presence is known.
+    /// resource's presence (see [`PresenceInfo::full_jid`] for which
+    /// resource won), or Unavailable if no presence is known.
     pub fn get_presence(&self, jid: &str) -> PresenceInfo {
         let bare = bare_jid(jid);
         let contacts = self.contacts.read().unwrap();
-        match contacts.get(&bare) {
+        let mut info = match contacts.get(&bare) {
             Some(resources) => best_presence(&bare, resources),
             None => PresenceInfo::unavailable(&bare),
+        };
+        if let Some(personal) = self.personal.read().unwrap().get(&bare) {
+            info.mood = personal.mood.clone();
+            info.activity = personal.activity.clone();
+            info.tune = personal.tune.clone();
+        }
+        info
+    }
+
+    /// Forgets every resource of `jid`, leaving other contacts' presence
+    /// alone. The contact reads as Unavailable until presence arrives again.
+    pub fn clear_presence(&self, jid: &str) {
+        self.contacts.write().unwrap().remove(&bare_jid(jid));
+    }
+
+    /// Number of contacts with at least one available resource.
+    pub fn online_count(&self) -> usize {
+        self.contacts
+            .read()
+            .unwrap()
+            .values()
+            .filter(|resources| !resources.is_empty())
+            .count()
+    }
+
+    /// Contacts we have seen presence from, counted by the show value of
+    /// their best resource (as returned by [`Self::get_presence`]). Contacts
+    /// whose resources all went offline count as `Unavailable`.
+    pub fn counts_by_show(&self) -> HashMap<PresenceShow, usize> {
+        let contacts = self.contacts.read().unwrap();
+        let mut counts = HashMap::new();
+        for (bare, resources) in contacts.iter() {
+            *counts
+                .entry(best_presence(bare, resources).show)
+                .or_insert(0) += 1;
+        }
+        counts
+    }
+
+    /// Our own presence alongside every contact's, as
+    /// [`Self::own_presence`] and [`Self::get_presence`] report them.
+    pub fn snapshot(&self) -> PresenceSnapshot {
+        let mut jids: Vec<String> = self.contacts.read().unwrap().keys().cloned().collect();
+        jids.sort();
+        PresenceSnapshot {
+            own: self.own_presence(),
+            contacts: jids.iter().map(|jid| self.get_presence(jid)).collect(),
         }
     }
 
@@ -99,7 +268,7 @@ impl PresenceManager {
         status: Option<&str>,
         priority: Option<i8>,
     ) -> Result<(), PresenceError> {
-        {
+        let priority = {
             let mut own = self.own_presence.write().unwrap();
             own.show = show.clone();
             own.status = status.map(String::from);
@@ -107,7 +276,10 @@ impl PresenceManager {
                 own.priority = p;
             }
             own.last_updated = Utc::now();
-        }
+            own.priority
+        };
+        self.appear_offline
+            .store(matches!(show, PresenceShow::Unavailable), Ordering::Relaxed);
 
         let _ = self.event_bus.publish(Event::new(
             Channel::new("ui.presence.set").unwrap(),
@@ -115,13 +287,64 @@ impl PresenceManager {
             EventPayload::PresenceSetRequested {
                 show,
                 status: status.map(String::from),
+                priority,
             },
         ));
+        self.announce_own_presence();
+
+        Ok(())
+    }
 
+    /// Publishes our mood over PEP, or clears it with `None`.
+    #[cfg(feature = "native")]
+    pub fn set_mood(&self, mood: Option<UserMood>) -> Result<(), PresenceError> {
+        self.pep
+            .publish(NS_MOOD, None, &mood_element(mood.as_ref()))
+            .map_err(|e| PresenceError::EventBus(e.to_string()))?;
+        self.own_presence.write().unwrap().mood = mood;
+        Ok(())
+    }
+
+    /// Publishes our activity over PEP, or clears it with `None`.
+    #[cfg(feature = "native")]
+    pub fn set_activity(&self, activity: Option<UserActivity>) -> Result<(), PresenceError> {
+        self.pep
+            .publish(NS_ACTIVITY, None, &activity_element(activity.as_ref()))
+            .map_err(|e| PresenceError::EventBus(e.to_string()))?;
+        self.own_presence.write().unwrap().activity = activity;
         Ok(())
     }
 
+    /// Publishes the tune now playing over PEP.
     #[cfg(feature = "native")]
+    pub fn publish_tune(&self, tune: TuneInfo) -> Result<(), PresenceError> {
+        self.pep
+            .publish(NS_TUNE, None, &tune_element(Some(&tune)))
+            .map_err(|e| PresenceError::EventBus(e.to_string()))?;
+        self.own_presence.write().unwrap().tune = Some(tune);
+        Ok(())
+    }
+
+    /// Publishes an empty tune, telling contacts playback stopped.
+    #[cfg(feature = "native")]
+    pub fn clear_tune(&self) -> Result<(), PresenceError> {
+        self.pep
+            .publish(NS_TUNE, None, &tune_element(None))
+            .map_err(|e| PresenceError::EventBus(e.to_string()))?;
+        self.own_presence.write().unwrap().tune = None;
+        Ok(())
+    }
+
+    /// Sends unavailable presence while keeping the connection up, so the
+    /// user appears offline. Stays in effect across reconnects until another
+    /// presence is set.
+    #[cfg(feature = "native")]
+    pub fn go_unavailable(&self, status: Option<&str>) -> Result<(), PresenceError> {
+        self.set_own_presence(PresenceShow::Unavailable, status, None)
+    }
+
+    #[cfg(feature = "native")]
+    #[instrument(skip_all, fields(channel = %event.channel, correlation_id = event.correlation_id.map(field::display)))]
     pub async fn handle_event(&self, event: &Event) {
         match &event.payload {
             EventPayload::ConnectionEstablished { jid } => {
@@ -131,10 +354,13 @@ impl PresenceManager {
                     own.jid = jid.clone();
                     own.show = PresenceShow::Unavailable;
                     own.status = None;
-                    own.priority = 0;
                     own.last_updated = Utc::now();
                 }
-                self.contacts.write().unwrap().clear();
+                if !self.resuming.swap(false, Ordering::Relaxed) {
+                    self.contacts.write().unwrap().clear();
+                    self.personal.write().unwrap().clear();
+                }
+                self.announced.write().unwrap().take();
                 self.awaiting_initial_presence
                     .store(true, Ordering::Relaxed);
             }
@@ -145,23 +371,44 @@ impl PresenceManager {
                 {
                     return;
                 }
+                if self.appear_offline.load(Ordering::Relaxed) {
+                    debug!("roster received while appearing offline, skipping initial presence");
+                    return;
+                }
 
-                debug!("roster received, sending initial presence");
+                debug!(show = ?self.initial_presence.show, "roster received, sending initial presence");
                 {
                     let mut own = self.own_presence.write().unwrap();
-                    own.show = PresenceShow::Available;
-                    own.status = None;
-                    own.priority = 0;
+                    own.show = self.initial_presence.show.clone();
+                    own.status = self.initial_presence.status.clone();
                     own.last_updated = Utc::now();
                 }
                 self.send_initial_presence();
+                self.announce_own_presence();
+            }
+            EventPayload::ConnectionLost {
+                resumable: true, ..
+            } => {
+                debug!("connection lost with a resumable stream, keeping presence map");
+                self.awaiting_initial_presence
+                    .store(false, Ordering::Relaxed);
+                self.resuming.store(true, Ordering::Relaxed);
+            }
+            EventPayload::StreamResumeFailed => {
+                debug!("stream was not resumed, clearing presence map");
+                self.resuming.store(false, Ordering::Relaxed);
+                self.contacts.write().unwrap().clear();
+                self.personal.write().unwrap().clear();
             }
             EventPayload::ConnectionLost { .. } => {
                 debug!("connection lost, sending unavailable and clearing presence map");
+                self.resuming.store(false, Ordering::Relaxed);
                 self.awaiting_initial_presence
                     .store(false, Ordering::Relaxed);
                 self.send_unavailable_presence();
                 self.contacts.write().unwrap().clear();
+                self.personal.write().unwrap().clear();
+                self.announced.write().unwrap().take();
                 {
                     let mut own = self.own_presence.write().unwrap();
                     own.show = PresenceShow::Unavailable;
@@ -184,6 +431,10 @@ impl PresenceManager {
                     status: status.clone(),
                     priority: *priority,
                     last_updated: Utc::now(),
+                    resource: (!resource.is_empty()).then(|| resource.clone()),
+                    mood: None,
+                    activity: None,
+                    tune: None,
                 };
                 let mut contacts = self.contacts.write().unwrap();
                 let resources = contacts.entry(bare).or_default();
@@ -204,14 +455,55 @@ impl PresenceManager {
         }
     }
 
+    /// Records a contact's mood, activity or tune delivered by the shared
+    /// [`PepManager`].
+    #[cfg(feature = "native")]
+    fn handle_pep_item(&self, item: &PepItem) {
+        debug!(from = %item.from, node = %item.node, "contact personal event changed");
+        let mut personal = self.personal.write().unwrap();
+        let entry = personal.entry(bare_jid(&item.from)).or_default();
+        if item.node == NS_MOOD {
+            entry.mood = parse_mood(&item.payload);
+        } else if item.node == NS_ACTIVITY {
+            entry.activity = parse_activity(&item.payload);
+        } else if item.node == NS_TUNE {
+            entry.tune = parse_tune(&item.payload);
+        }
+    }
+
+    /// Publishes `OwnPresenceChanged` on `system.presence.own_changed` once
+    /// our show or status differs from what was last announced, so MAM and
+    /// the UI need not wait for the server to confirm it.
+    #[cfg(feature = "native")]
+    fn announce_own_presence(&self) {
+        let own = self.own_presence();
+        let current = (own.show, own.status);
+        {
+            let mut announced = self.announced.write().unwrap();
+            if announced.as_ref() == Some(&current) {
+                return;
+            }
+            *announced = Some(current.clone());
+        }
+        let (show, status) = current;
+        debug!(?show, "announcing own presence change");
+        let _ = self.event_bus.publish(Event::new(
+            Channel::new("system.presence.own_changed").unwrap(),
+            EventSource::System("presence".into()),
+            EventPayload::OwnPresenceChanged { show, status },
+        ));
+    }
+
     #[cfg(feature = "native")]
     fn send_initial_presence(&self) {
+        let own = self.own_presence();
         let _ = self.event_bus.publish(Event::new(
             Channel::new("ui.presence.set").unwrap(),
             EventSource::System("presence".into()),
             EventPayload::PresenceSetRequested {
-                show: PresenceShow::Available,
-                status: None,
+                show: own.show,
+                status: own.status,
+                priority: own.priority,
             },
         ));
     }
@@ -224,6 +516,7 @@ impl PresenceManager {
             EventPayload::PresenceSetRequested {
                 show: PresenceShow::Unavailable,
                 status: None,
+                priority: self.own_presence.read().unwrap().priority,
             },
         ));
     }
@@ -234,6 +527,152 @@ impl PresenceManager {
             .event_bus
             .subscribe("{system,xmpp}.**")
             .map_err(|e| PresenceError::EventBus(e.to_string()))?;
+        let Some(mut feeds) = self.pep_feeds.lock().unwrap().take() else {
+            return Err(PresenceError::EventBus(
+                "presence manager is already running".to_string(),
+            ));
+        };
+
+        loop {
+            tokio::select! {
+                received = sub.recv() => match received {
+                    Ok(event) => {
+                        self.handle_event(&event).await;
+                    }
+                    Err(waddle_core::error::EventBusError::ChannelClosed) => {
+                        debug!("event bus closed, presence manager stopping");
+                        return Ok(());
+                    }
+                    Err(waddle_core::error::EventBusError::Lagged(count)) => {
+                        warn!(count, "presence manager lagged, some events dropped");
+                    }
+                    Err(e) => {
+                        error!(error = %e, "presence manager subscription error");
+                        return Err(PresenceError::EventBus(e.to_string()));
+                    }
+                },
+                Some(item) = feeds.mood.recv() => self.handle_pep_item(&item),
+                Some(item) = feeds.activity.recv() => self.handle_pep_item(&item),
+                Some(item) = feeds.tune.recv() => self.handle_pep_item(&item),
+            }
+        }
+    }
+}
+
+/// One recorded contact presence change.
+#[derive(Debug, Clone)]
+pub struct PresenceLogEntry {
+    pub jid: String,
+    pub show: PresenceShow,
+    pub timestamp: DateTime<Utc>,
+}
+
+impl FromRow for PresenceLogEntry {
+    fn from_row(row: &Row) -> Result<Self, StorageError> {
+        let jid = match row.get(0) {
+            Some(SqlValue::Text(s)) => s.clone(),
+            _ => return Err(StorageError::QueryFailed("missing jid column".to_string())),
+        };
+        let show = match row.get(1) {
+            Some(SqlValue::Text(s)) => s.parse::<PresenceShow>().unwrap(),
+            _ => return Err(StorageError::QueryFailed("missing show column".to_string())),
+        };
+        let timestamp = match row.get(2) {
+            Some(SqlValue::Text(s)) => DateTime::parse_from_rfc3339(s)
+                .map(|dt| dt.with_timezone(&Utc))
+                .map_err(|e| StorageError::QueryFailed(format!("invalid timestamp: {e}")))?,
+            _ => {
+                return Err(StorageError::QueryFailed(
+                    "missing timestamp column".to_string(),
+                ));
+            }
+        };
+        Ok(PresenceLogEntry {
+            jid,
+            show,
+            timestamp,
+        })
+    }
+}
+
+/// Opt-in log of contact presence changes, kept to the most recent
+/// `capacity` entries across all contacts. Not started by default because
+/// every presence change becomes a database write.
+pub struct PresenceLog<D: Database> {
+    db: Arc<D>,
+    capacity: usize,
+    #[cfg(feature = "native")]
+    event_bus: Arc<dyn EventBus>,
+}
+
+impl<D: Database> PresenceLog<D> {
+    #[cfg(feature = "native")]
+    pub fn new(db: Arc<D>, event_bus: Arc<dyn EventBus>, capacity: usize) -> Self {
+        Self {
+            db,
+            capacity,
+            event_bus,
+        }
+    }
+
+    /// The most recent `limit` presence changes for `jid`, oldest first.
+    pub async fn presence_history(
+        &self,
+        jid: &str,
+        limit: u32,
+    ) -> Result<Vec<PresenceLogEntry>, PresenceError> {
+        let bare = bare_jid(jid);
+        let limit_i = i64::from(limit);
+        let mut entries: Vec<PresenceLogEntry> = self
+            .db
+            .query(
+                "SELECT jid, show, timestamp FROM presence_log \
+                 WHERE jid = ?1 ORDER BY id DESC LIMIT ?2",
+                &[&bare, &limit_i],
+            )
+            .await?;
+        entries.reverse();
+        Ok(entries)
+    }
+
+    async fn record(&self, jid: &str, show: &PresenceShow) -> Result<(), PresenceError> {
+        let bare = bare_jid(jid);
+        let show_s = show.as_str().to_string();
+        let timestamp = format_timestamp(&Utc::now());
+        self.db
+            .execute(
+                "INSERT INTO presence_log (jid, show, timestamp) VALUES (?1, ?2, ?3)",
+                &[&bare, &show_s, &timestamp],
+            )
+            .await?;
+
+        let capacity = i64::try_from(self.capacity).unwrap_or(i64::MAX);
+        self.db
+            .execute(
+                "DELETE FROM presence_log WHERE id NOT IN \
+                 (SELECT id FROM presence_log ORDER BY id DESC LIMIT ?1)",
+                &[&capacity],
+            )
+            .await?;
+        Ok(())
+    }
+
+    #[cfg(feature = "native")]
+    #[instrument(skip_all, fields(channel = %event.channel, correlation_id = event.correlation_id.map(field::display)))]
+    pub async fn handle_event(&self, event: &Event) {
+        if let EventPayload::PresenceChanged { jid, show, .. } = &event.payload
+            && let Err(e) = self.record(jid, show).await
+        {
+            error!(error = %e, jid = %jid, "failed to log presence change");
+        }
+    }
+
+    #[cfg(feature = "native")]
+    pub async fn run(self: Arc<Self>) -> Result<(), PresenceError> {
+        let mut sub = self
+            .event_bus
+            .subscribe("xmpp.presence.**")
+            .map_err(|e| PresenceError::EventBus(e.to_string()))?;
 
         loop {
             match sub.recv().await {
@@ -241,14 +680,14 @@ impl PresenceManager {
                     self.handle_event(&event).await;
                 }
                 Err(waddle_core::error::EventBusError::ChannelClosed) => {
-                    debug!("event bus closed, presence manager stopping");
+                    debug!("event bus closed, presence log stopping");
                     return Ok(());
                 }
                 Err(waddle_core::error::EventBusError::Lagged(count)) => {
-                    warn!(count, "presence manager lagged, some events dropped");
+                    warn!(count, "presence log lagged, some events dropped");
                 }
                 Err(e) => {
-                    error!(error = %e, "presence manager subscription error");
+                    error!(error = %e, "presence log subscription error");
                     return Err(PresenceError::EventBus(e.to_string()));
                 }
             }
@@ -256,7 +695,8 @@ impl PresenceManager {
     }
 }
 
-/// Select the highest-priority resource's presence. Ties broken by most
+/// Select the highest-priority resource's presence. Ties broken by
+/// availability (see [`PresenceShow::availability_rank`]), then by most
 /// recent update. Returns Unavailable if the resource map is empty.
 fn best_presence(bare: &str, resources: &ResourceMap) -> PresenceInfo {
     resources
@@ -264,6 +704,7 @@ fn best_presence(bare: &str, resources: &ResourceMap) -> PresenceInfo {
         .max_by(|a, b| {
             a.priority
                 .cmp(&b.priority)
+                .then(a.show.availability_rank().cmp(&b.show.availability_rank()))
                 .then(a.last_updated.cmp(&b.last_updated))
         })
         .cloned()
@@ -277,6 +718,14 @@ fn bare_jid(jid: &str) -> String {
     }
 }
 
+fn domain_part(jid: &str) -> String {
+    let bare = bare_jid(jid);
+    match bare.find('@') {
+        Some(pos) => bare[pos + 1..].to_string(),
+        None => bare,
+    }
+}
+
 fn resource_part(jid: &str) -> String {
     match jid.find('/') {
         Some(pos) => jid[pos + 1..].to_string(),
@@ -293,7 +742,8 @@ mod tests {
 
     fn make_manager() -> (Arc<PresenceManager>, Arc<dyn EventBus>) {
         let event_bus: Arc<dyn EventBus> = Arc::new(BroadcastEventBus::default());
-        let manager = Arc::new(PresenceManager::new(event_bus.clone()));
+        let pep = Arc::new(PepManager::new(event_bus.clone()));
+        let manager = Arc::new(PresenceManager::new(event_bus.clone(), pep));
         (manager, event_bus)
     }
 
@@ -319,6 +769,156 @@ mod tests {
         }
     }
 
+    fn pep_item(from: &str, node: &str, payload: &str) -> PepItem {
+        PepItem {
+            from: from.to_string(),
+            node: node.to_string(),
+            item_id: Some("current".to_string()),
+            payload: payload.parse().unwrap(),
+        }
+    }
+
+    #[tokio::test]
+    async fn inbound_mood_item_updates_contact_mood() {
+        let (manager, _) = make_manager();
+        manager
+            .handle_event(&make_event(
+                "xmpp.presence.changed",
+                presence_changed("alice@example.com/phone", PresenceShow::Available, None, 0),
+            ))
+            .await;
+
+        manager.handle_pep_item(&pep_item(
+            "alice@example.com",
+            NS_MOOD,
+            "<mood xmlns='http://jabber.org/protocol/mood'>\
+                <happy/><text>Weekend</text></mood>",
+        ));
+        manager.handle_pep_item(&pep_item(
+            "alice@example.com",
+            NS_ACTIVITY,
+            "<activity xmlns='http://jabber.org/protocol/activity'>\
+                <relaxing><partying/></relaxing></activity>",
+        ));
+
+        let presence = manager.get_presence("alice@example.com");
+        assert_eq!(presence.resource.as_deref(), Some("phone"));
+        assert_eq!(
+            presence.mood,
+            Some(UserMood {
+                value: "happy".into(),
+                text: Some("Weekend".into()),
+            })
+        );
+        let activity = presence.activity.unwrap();
+        assert_eq!(activity.general, "relaxing");
+        assert_eq!(activity.specific.as_deref(), Some("partying"));
+        assert!(manager.get_presence("bob@example.com").mood.is_none());
+
+        manager.handle_pep_item(&pep_item(
+            "alice@example.com",
+            NS_MOOD,
+            "<mood xmlns='http://jabber.org/protocol/mood'/>",
+        ));
+        assert!(manager.get_presence("alice@example.com").mood.is_none());
+    }
+
+    #[tokio::test]
+    async fn mood_arrives_through_the_shared_pep_manager() {
+        let event_bus: Arc<dyn EventBus> = Arc::new(BroadcastEventBus::default());
+        let pep = Arc::new(PepManager::new(event_bus.clone()));
+        let manager = Arc::new(PresenceManager::new(event_bus.clone(), pep.clone()));
+        let handle = tokio::spawn(manager.clone().run());
+
+        pep.handle_event(&make_event(
+            "xmpp.pep.item.received",
+            EventPayload::PepItemReceived {
+                from: "alice@example.com".to_string(),
+                node: NS_MOOD.to_string(),
+                item_id: Some("current".to_string()),
+                payload: "<mood xmlns='http://jabber.org/protocol/mood'><happy/></mood>"
+                    .to_string(),
+            },
+        ))
+        .await;
+
+        tokio::time::timeout(Duration::from_secs(1), async {
+            while manager.get_presence("alice@example.com").mood.is_none() {
+                tokio::time::sleep(Duration::from_millis(5)).await;
+            }
+        })
+        .await
+        .expect("mood should reach the presence manager");
+        assert_eq!(
+            manager
+                .get_presence("alice@example.com")
+                .mood
+                .unwrap()
+                .value,
+            "happy"
+        );
+        handle.abort();
+    }
+
+    #[tokio::test]
+    async fn set_mood_publishes_pep_item() {
+        let (manager, event_bus) = make_manager();
+        let mut sub = event_bus.subscribe("ui.pep.publish").unwrap();
+        let mood = UserMood {
+            value: "tired".into(),
+            text: None,
+        };
+
+        manager.set_mood(Some(mood.clone())).unwrap();
+
+        let event = tokio::time::timeout(Duration::from_millis(100), sub.recv())
+            .await
+            .expect("timed out")
+            .unwrap();
+        let EventPayload::PepPublishRequested { node, payload, .. } = event.payload else {
+            panic!("expected PepPublishRequested, got {:?}", event.payload);
+        };
+        assert_eq!(node, NS_MOOD);
+        assert_eq!(parse_mood(&payload.parse().unwrap()), Some(mood.clone()));
+        assert_eq!(manager.own_presence().mood, Some(mood));
+    }
+
+    #[tokio::test]
+    async fn published_tune_roundtrips_as_contact_tune() {
+        let (manager, event_bus) = make_manager();
+        let mut sub = event_bus.subscribe("ui.pep.publish").unwrap();
+        let tune = TuneInfo {
+            artist: Some("Yes".into()),
+            title: Some("Roundabout".into()),
+            length: Some(508),
+            ..TuneInfo::default()
+        };
+
+        manager.publish_tune(tune.clone()).unwrap();
+        assert_eq!(manager.own_presence().tune, Some(tune.clone()));
+
+        let event = tokio::time::timeout(Duration::from_millis(100), sub.recv())
+            .await
+            .expect("timed out")
+            .unwrap();
+        let EventPayload::PepPublishRequested { node, payload, .. } = event.payload else {
+            panic!("expected PepPublishRequested, got {:?}", event.payload);
+        };
+        assert_eq!(node, NS_TUNE);
+
+        manager.handle_pep_item(&pep_item("alice@example.com", NS_TUNE, &payload));
+        assert_eq!(manager.get_presence("alice@example.com").tune, Some(tune));
+
+        manager.clear_tune().unwrap();
+        assert!(manager.own_presence().tune.is_none());
+        manager.handle_pep_item(&pep_item(
+            "alice@example.com",
+            NS_TUNE,
+            "<tune xmlns='http://jabber.org/protocol/tune'/>",
+        ));
+        assert!(manager.get_presence("alice@example.com").tune.is_none());
+    }
+
     #[tokio::test]
     async fn initial_own_presence_is_unavailable() {
         let (manager, _) = make_manager();
@@ -326,54 +926,341 @@ mod tests {
         assert!(matches!(own.show, PresenceShow::Unavailable));
     }
 
-    #[tokio::test]
-    async fn unknown_contact_returns_unavailable() {
-        let (manager, _) = make_manager();
-        let info = manager.get_presence("unknown@example.com");
-        assert!(matches!(info.show, PresenceShow::Unavailable));
-        assert_eq!(info.jid, "unknown@example.com");
+    #[tokio::test]
+    async fn snapshot_includes_own_and_contact_presence() {
+        let (manager, _) = make_manager();
+        manager
+            .set_own_presence(PresenceShow::Dnd, Some("Focusing"), Some(5))
+            .unwrap();
+        manager
+            .handle_event(&make_event(
+                "xmpp.presence.changed",
+                presence_changed("bob@example.com/desk", PresenceShow::Away, None, 0),
+            ))
+            .await;
+        manager
+            .handle_event(&make_event(
+                "xmpp.presence.changed",
+                presence_changed("alice@example.com/phone", PresenceShow::Available, None, 0),
+            ))
+            .await;
+
+        let snapshot = manager.snapshot();
+        assert_eq!(snapshot.own.show, PresenceShow::Dnd);
+        assert_eq!(snapshot.own.status.as_deref(), Some("Focusing"));
+        let contacts: Vec<(&str, &PresenceShow)> = snapshot
+            .contacts
+            .iter()
+            .map(|c| (c.jid.as_str(), &c.show))
+            .collect();
+        assert_eq!(
+            contacts,
+            vec![
+                ("alice@example.com", &PresenceShow::Available),
+                ("bob@example.com", &PresenceShow::Away),
+            ]
+        );
+
+        let json = serde_json::to_value(&snapshot).unwrap();
+        assert_eq!(json["own"]["status"], "Focusing");
+        assert_eq!(json["contacts"][1]["resource"], "desk");
+    }
+
+    #[tokio::test]
+    async fn set_own_presence_announces_change_once() {
+        let (manager, event_bus) = make_manager();
+        let mut sub = event_bus.subscribe("system.presence.own_changed").unwrap();
+
+        manager
+            .set_own_presence(PresenceShow::Away, Some("Lunch"), None)
+            .unwrap();
+        let event = tokio::time::timeout(Duration::from_millis(100), sub.recv())
+            .await
+            .expect("timed out")
+            .unwrap();
+        assert!(matches!(event.source, EventSource::System(ref s) if s == "presence"));
+        match event.payload {
+            EventPayload::OwnPresenceChanged { show, status } => {
+                assert_eq!(show, PresenceShow::Away);
+                assert_eq!(status.as_deref(), Some("Lunch"));
+            }
+            other => panic!("unexpected payload: {other:?}"),
+        }
+
+        manager
+            .set_own_presence(PresenceShow::Away, Some("Lunch"), Some(5))
+            .unwrap();
+        let repeated = tokio::time::timeout(Duration::from_millis(100), sub.recv()).await;
+        assert!(
+            repeated.is_err(),
+            "unchanged presence must not be announced"
+        );
+    }
+
+    #[tokio::test]
+    async fn initial_presence_is_announced() {
+        let (manager, event_bus) = make_manager();
+        let mut sub = event_bus.subscribe("system.presence.own_changed").unwrap();
+        manager
+            .handle_event(&make_event(
+                "system.connection.established",
+                EventPayload::ConnectionEstablished {
+                    jid: "user@example.com".to_string(),
+                },
+            ))
+            .await;
+        manager
+            .handle_event(&make_event(
+                "xmpp.roster.received",
+                EventPayload::RosterReceived {
+                    items: vec![],
+                    version: None,
+                },
+            ))
+            .await;
+
+        let event = tokio::time::timeout(Duration::from_millis(100), sub.recv())
+            .await
+            .expect("timed out")
+            .unwrap();
+        assert!(matches!(
+            event.payload,
+            EventPayload::OwnPresenceChanged {
+                show: PresenceShow::Available,
+                ..
+            }
+        ));
+    }
+
+    #[tokio::test]
+    async fn unknown_contact_returns_unavailable() {
+        let (manager, _) = make_manager();
+        let info = manager.get_presence("unknown@example.com");
+        assert!(matches!(info.show, PresenceShow::Unavailable));
+        assert_eq!(info.jid, "unknown@example.com");
+    }
+
+    #[tokio::test]
+    async fn own_jid_and_domain_follow_the_session() {
+        let (manager, _) = make_manager();
+        assert_eq!(manager.own_bare_jid(), None);
+        assert_eq!(manager.own_domain(), None);
+
+        manager
+            .handle_event(&make_event(
+                "system.connection.established",
+                EventPayload::ConnectionEstablished {
+                    jid: "alice@example.com".to_string(),
+                },
+            ))
+            .await;
+
+        assert_eq!(manager.own_bare_jid().as_deref(), Some("alice@example.com"));
+        assert_eq!(manager.own_domain().as_deref(), Some("example.com"));
+    }
+
+    #[tokio::test]
+    async fn connection_established_waits_for_roster_before_initial_presence() {
+        let (manager, event_bus) = make_manager();
+        let mut sub = event_bus.subscribe("ui.**").unwrap();
+
+        let event = make_event(
+            "system.connection.established",
+            EventPayload::ConnectionEstablished {
+                jid: "user@example.com".to_string(),
+            },
+        );
+        manager.handle_event(&event).await;
+
+        let own = manager.own_presence();
+        assert!(matches!(own.show, PresenceShow::Unavailable));
+        assert_eq!(own.jid, "user@example.com");
+
+        let no_event = tokio::time::timeout(Duration::from_millis(100), sub.recv()).await;
+        assert!(no_event.is_err(), "initial presence should wait for roster");
+
+        let event = Event::new(
+            Channel::new("xmpp.roster.received").unwrap(),
+            EventSource::Xmpp,
+            EventPayload::RosterReceived {
+                items: Vec::new(),
+                version: None,
+            },
+        );
+        manager.handle_event(&event).await;
+
+        let own = manager.own_presence();
+        assert!(matches!(own.show, PresenceShow::Available));
+
+        let received = tokio::time::timeout(Duration::from_millis(200), sub.recv())
+            .await
+            .expect("timed out")
+            .expect("should receive event");
+
+        assert!(matches!(
+            received.payload,
+            EventPayload::PresenceSetRequested {
+                show: PresenceShow::Available,
+                status: None,
+                priority: 0,
+            }
+        ));
+    }
+
+    #[tokio::test]
+    async fn configured_initial_presence_is_sent_after_roster() {
+        let event_bus: Arc<dyn EventBus> = Arc::new(BroadcastEventBus::default());
+        let mut initial = PresenceInfo::unavailable("");
+        initial.show = PresenceShow::Dnd;
+        initial.status = Some("focusing".to_string());
+        initial.priority = 3;
+        let pep = Arc::new(PepManager::new(event_bus.clone()));
+        let manager = PresenceManager::with_initial_presence(event_bus.clone(), pep, initial);
+        let mut sub = event_bus.subscribe("ui.**").unwrap();
+
+        manager
+            .handle_event(&make_event(
+                "system.connection.established",
+                EventPayload::ConnectionEstablished {
+                    jid: "user@example.com".to_string(),
+                },
+            ))
+            .await;
+        manager
+            .handle_event(&make_event(
+                "xmpp.roster.received",
+                EventPayload::RosterReceived {
+                    items: Vec::new(),
+                    version: None,
+                },
+            ))
+            .await;
+
+        let received = tokio::time::timeout(Duration::from_millis(200), sub.recv())
+            .await
+            .expect("timed out")
+            .expect("should receive initial presence");
+        assert!(matches!(
+            received.payload,
+            EventPayload::PresenceSetRequested {
+                show: PresenceShow::Dnd,
+                status: Some(ref status),
+                priority: 3,
+            } if status == "focusing"
+        ));
+        let own = manager.own_presence();
+        assert!(matches!(own.show, PresenceShow::Dnd));
+        assert_eq!(own.jid, "user@example.com");
     }
 
     #[tokio::test]
-    async fn connection_established_waits_for_roster_before_initial_presence() {
+    async fn go_unavailable_survives_roster_received() {
         let (manager, event_bus) = make_manager();
+        manager
+            .handle_event(&make_event(
+                "system.connection.established",
+                EventPayload::ConnectionEstablished {
+                    jid: "user@example.com".to_string(),
+                },
+            ))
+            .await;
         let mut sub = event_bus.subscribe("ui.**").unwrap();
 
-        let event = make_event(
-            "system.connection.established",
-            EventPayload::ConnectionEstablished {
-                jid: "user@example.com".to_string(),
-            },
-        );
-        manager.handle_event(&event).await;
+        manager.go_unavailable(Some("away from desk")).unwrap();
 
         let own = manager.own_presence();
         assert!(matches!(own.show, PresenceShow::Unavailable));
-        assert_eq!(own.jid, "user@example.com");
+        assert_eq!(own.status.as_deref(), Some("away from desk"));
+        let received = tokio::time::timeout(Duration::from_millis(100), sub.recv())
+            .await
+            .expect("timed out")
+            .expect("should receive unavailable presence");
+        assert!(matches!(
+            received.payload,
+            EventPayload::PresenceSetRequested {
+                show: PresenceShow::Unavailable,
+                ..
+            }
+        ));
+
+        manager
+            .handle_event(&make_event(
+                "xmpp.roster.received",
+                EventPayload::RosterReceived {
+                    items: Vec::new(),
+                    version: None,
+                },
+            ))
+            .await;
 
+        assert!(matches!(
+            manager.own_presence().show,
+            PresenceShow::Unavailable
+        ));
         let no_event = tokio::time::timeout(Duration::from_millis(100), sub.recv()).await;
-        assert!(no_event.is_err(), "initial presence should wait for roster");
+        assert!(no_event.is_err(), "roster must not bring us back online");
+    }
 
-        let event = Event::new(
-            Channel::new("xmpp.roster.received").unwrap(),
-            EventSource::Xmpp,
-            EventPayload::RosterReceived { items: Vec::new() },
-        );
-        manager.handle_event(&event).await;
+    #[tokio::test]
+    async fn reconnect_presence_keeps_chosen_priority() {
+        let (manager, event_bus) = make_manager();
+        manager
+            .set_own_presence(PresenceShow::Available, None, Some(-1))
+            .unwrap();
+        let mut sub = event_bus.subscribe("ui.**").unwrap();
 
-        let own = manager.own_presence();
-        assert!(matches!(own.show, PresenceShow::Available));
+        manager
+            .handle_event(&make_event(
+                "system.connection.established",
+                EventPayload::ConnectionEstablished {
+                    jid: "user@example.com".to_string(),
+                },
+            ))
+            .await;
+        manager
+            .handle_event(&make_event(
+                "xmpp.roster.received",
+                EventPayload::RosterReceived {
+                    items: Vec::new(),
+                    version: None,
+                },
+            ))
+            .await;
 
         let received = tokio::time::timeout(Duration::from_millis(200), sub.recv())
             .await
             .expect("timed out")
-            .expect("should receive event");
-
+            .expect("should receive initial presence");
         assert!(matches!(
             received.payload,
             EventPayload::PresenceSetRequested {
                 show: PresenceShow::Available,
-                status: None,
+                priority: -1,
+                ..
+            }
+        ));
+        assert_eq!(manager.own_presence().priority, -1);
+
+        manager
+            .handle_event(&make_event(
+                "system.connection.lost",
+                EventPayload::ConnectionLost {
+                    reason: "network error".to_string(),
+                    will_retry: true,
+                    resumable: false,
+                },
+            ))
+            .await;
+        let received = tokio::time::timeout(Duration::from_millis(200), sub.recv())
+            .await
+            .expect("timed out")
+            .expect("should receive unavailable presence");
+        assert!(matches!(
+            received.payload,
+            EventPayload::PresenceSetRequested {
+                show: PresenceShow::Unavailable,
+                priority: -1,
+                ..
             }
         ));
     }
@@ -412,6 +1299,7 @@ mod tests {
             EventPayload::ConnectionLost {
                 reason: "network error".to_string(),
                 will_retry: true,
+                resumable: false,
             },
         );
         manager.handle_event(&event).await;
@@ -434,10 +1322,124 @@ mod tests {
             EventPayload::PresenceSetRequested {
                 show: PresenceShow::Unavailable,
                 status: None,
+                priority: 0,
             }
         ));
     }
 
+    #[tokio::test]
+    async fn resumable_connection_loss_keeps_contacts() {
+        let (manager, event_bus) = make_manager();
+        let established = make_event(
+            "system.connection.established",
+            EventPayload::ConnectionEstablished {
+                jid: "user@example.com".to_string(),
+            },
+        );
+        manager.handle_event(&established).await;
+        manager
+            .handle_event(&make_event(
+                "xmpp.presence.changed",
+                presence_changed("alice@example.com/desktop", PresenceShow::Away, None, 0),
+            ))
+            .await;
+        let mut sub = event_bus.subscribe("ui.**").unwrap();
+
+        manager
+            .handle_event(&make_event(
+                "system.connection.lost",
+                EventPayload::ConnectionLost {
+                    reason: "network error".to_string(),
+                    will_retry: true,
+                    resumable: true,
+                },
+            ))
+            .await;
+        manager.handle_event(&established).await;
+
+        assert!(matches!(
+            manager.get_presence("alice@example.com").show,
+            PresenceShow::Away
+        ));
+        let no_event = tokio::time::timeout(Duration::from_millis(100), sub.recv()).await;
+        assert!(
+            no_event.is_err(),
+            "a resumable blip must not send unavailable"
+        );
+
+        // A later reconnect without resumption starts from a clean map.
+        manager.handle_event(&established).await;
+        assert!(matches!(
+            manager.get_presence("alice@example.com").show,
+            PresenceShow::Unavailable
+        ));
+    }
+
+    #[tokio::test]
+    async fn refused_resume_drops_contacts_kept_for_it() {
+        let (manager, _) = make_manager();
+        manager
+            .handle_event(&make_event(
+                "xmpp.presence.changed",
+                presence_changed("alice@example.com/desktop", PresenceShow::Away, None, 0),
+            ))
+            .await;
+        manager
+            .handle_event(&make_event(
+                "system.connection.lost",
+                EventPayload::ConnectionLost {
+                    reason: "network error".to_string(),
+                    will_retry: true,
+                    resumable: true,
+                },
+            ))
+            .await;
+        manager
+            .handle_event(&make_event(
+                "system.connection.established",
+                EventPayload::ConnectionEstablished {
+                    jid: "user@example.com".to_string(),
+                },
+            ))
+            .await;
+
+        manager
+            .handle_event(&make_event(
+                "system.connection.resume_failed",
+                EventPayload::StreamResumeFailed,
+            ))
+            .await;
+        assert!(matches!(
+            manager.get_presence("alice@example.com").show,
+            PresenceShow::Unavailable
+        ));
+    }
+
+    #[tokio::test]
+    async fn clear_presence_leaves_other_contacts_intact() {
+        let (manager, _) = make_manager();
+        for jid in ["alice@example.com/desktop", "bob@example.com/phone"] {
+            manager
+                .handle_event(&make_event(
+                    "xmpp.presence.changed",
+                    presence_changed(jid, PresenceShow::Available, None, 0),
+                ))
+                .await;
+        }
+
+        manager.clear_presence("alice@example.com/desktop");
+
+        assert!(matches!(
+            manager.get_presence("alice@example.com").show,
+            PresenceShow::Unavailable
+        ));
+        assert!(matches!(
+            manager.get_presence("bob@example.com").show,
+            PresenceShow::Available
+        ));
+        assert_eq!(manager.online_count(), 1);
+    }
+
     #[tokio::test]
     async fn presence_changed_updates_contact_map() {
         let (manager, _) = make_manager();
@@ -508,6 +1510,52 @@ mod tests {
         assert_eq!(info.priority, 10);
     }
 
+    #[tokio::test]
+    async fn equal_priority_prefers_available_and_exposes_full_jid() {
+        let (manager, _) = make_manager();
+
+        let event = make_event(
+            "xmpp.presence.changed",
+            presence_changed(
+                "alice@example.com/desktop",
+                PresenceShow::Available,
+                None,
+                5,
+            ),
+        );
+        manager.handle_event(&event).await;
+
+        let event = make_event(
+            "xmpp.presence.changed",
+            presence_changed(
+                "alice@example.com/mobile",
+                PresenceShow::Away,
+                Some("on phone"),
+                5,
+            ),
+        );
+        manager.handle_event(&event).await;
+
+        let info = manager.get_presence("alice@example.com");
+        assert!(matches!(info.show, PresenceShow::Available));
+        assert_eq!(info.jid, "alice@example.com");
+        assert_eq!(
+            info.full_jid().as_deref(),
+            Some("alice@example.com/desktop")
+        );
+    }
+
+    #[tokio::test]
+    async fn unknown_contact_has_no_full_jid() {
+        let (manager, _) = make_manager();
+        assert!(
+            manager
+                .get_presence("nobody@example.com")
+                .full_jid()
+                .is_none()
+        );
+    }
+
     #[tokio::test]
     async fn multi_resource_updates_one_resource() {
         let (manager, _) = make_manager();
@@ -607,8 +1655,8 @@ mod tests {
         let (manager, _) = make_manager();
 
         let event = Event::new(
-            Channel::new("xmpp.presence.own_changed").unwrap(),
-            EventSource::Xmpp,
+            Channel::new("system.presence.own_changed").unwrap(),
+            EventSource::System("presence".into()),
             EventPayload::OwnPresenceChanged {
                 show: PresenceShow::Dnd,
                 status: Some("do not disturb".to_string()),
@@ -644,6 +1692,25 @@ mod tests {
         ));
     }
 
+    #[tokio::test]
+    async fn set_own_presence_emits_priority() {
+        let (manager, event_bus) = make_manager();
+        let mut sub = event_bus.subscribe("ui.**").unwrap();
+
+        manager
+            .set_own_presence(PresenceShow::Available, None, Some(-5))
+            .unwrap();
+
+        let received = tokio::time::timeout(Duration::from_millis(100), sub.recv())
+            .await
+            .expect("timed out")
+            .expect("should receive event");
+        assert!(matches!(
+            received.payload,
+            EventPayload::PresenceSetRequested { priority: -5, .. }
+        ));
+    }
+
     #[tokio::test]
     async fn set_own_presence_updates_local_state() {
         let (manager, _) = make_manager();
@@ -689,6 +1756,38 @@ mod tests {
         assert_eq!(carol.status, Some("busy".to_string()));
     }
 
+    #[tokio::test]
+    async fn counts_summarise_best_presence_per_contact() {
+        let (manager, _) = make_manager();
+
+        for (jid, show, priority) in [
+            ("alice@example.com/desktop", PresenceShow::Available, 5),
+            ("alice@example.com/phone", PresenceShow::Away, 0),
+            ("bob@example.com/laptop", PresenceShow::Away, 0),
+            ("carol@example.com/home", PresenceShow::Dnd, 0),
+            ("dave@example.com/work", PresenceShow::Away, 0),
+            ("erin@example.com/home", PresenceShow::Available, 0),
+            ("erin@example.com/home", PresenceShow::Unavailable, 0),
+        ] {
+            let event = make_event(
+                "xmpp.presence.changed",
+                presence_changed(jid, show, None, priority),
+            );
+            manager.handle_event(&event).await;
+        }
+
+        assert_eq!(manager.online_count(), 4);
+        assert_eq!(
+            manager.counts_by_show(),
+            HashMap::from([
+                (PresenceShow::Available, 1),
+                (PresenceShow::Away, 2),
+                (PresenceShow::Dnd, 1),
+                (PresenceShow::Unavailable, 1),
+            ])
+        );
+    }
+
     #[tokio::test]
     async fn presence_updates_overwrite_same_resource() {
         let (manager, _) = make_manager();
@@ -752,6 +1851,56 @@ mod tests {
         handle.abort();
     }
 
+    async fn setup_log(
+        capacity: usize,
+    ) -> (
+        Arc<PresenceLog<impl Database>>,
+        Arc<dyn EventBus>,
+        tempfile::TempDir,
+    ) {
+        let dir = tempfile::TempDir::new().expect("failed to create temp dir");
+        let db = waddle_storage::open_database(&dir.path().join("test.db"))
+            .await
+            .expect("failed to open database");
+        let event_bus: Arc<dyn EventBus> = Arc::new(BroadcastEventBus::default());
+        let log = Arc::new(PresenceLog::new(Arc::new(db), event_bus.clone(), capacity));
+        (log, event_bus, dir)
+    }
+
+    #[tokio::test]
+    async fn presence_log_records_changes_in_order_and_caps() {
+        let (log, _, _dir) = setup_log(3).await;
+
+        let shows = [
+            PresenceShow::Available,
+            PresenceShow::Away,
+            PresenceShow::Xa,
+            PresenceShow::Dnd,
+            PresenceShow::Unavailable,
+        ];
+        for show in shows {
+            let event = make_event(
+                "xmpp.presence.changed",
+                presence_changed("bob@example.com/desktop", show, None, 0),
+            );
+            log.handle_event(&event).await;
+        }
+
+        let history = log.presence_history("bob@example.com", 10).await.unwrap();
+        let logged: Vec<&str> = history.iter().map(|e| e.show.as_str()).collect();
+        assert_eq!(logged, vec!["xa", "dnd", "unavailable"]);
+        assert!(history.iter().all(|e| e.jid == "bob@example.com"));
+        assert!(
+            history
+                .windows(2)
+                .all(|pair| pair[0].timestamp <= pair[1].timestamp)
+        );
+
+        let latest = log.presence_history("bob@example.com", 1).await.unwrap();
+        assert_eq!(latest.len(), 1);
+        assert!(matches!(latest[0].show, PresenceShow::Unavailable));
+    }
+
     #[test]
     fn bare_jid_strips_resource() {
         assert_eq!(bare_jid("user@example.com/resource"), "user@example.com");
@@ -759,6 +1908,12 @@ mod tests {
         assert_eq!(bare_jid("user@example.com/res/extra"), "user@example.com");
     }
 
+    #[test]
+    fn domain_part_strips_localpart_and_resource() {
+        assert_eq!(domain_part("alice@example.com/desktop"), "example.com");
+        assert_eq!(domain_part("example.com"), "example.com");
+    }
+
     #[test]
     fn resource_part_extracts_resource() {
         assert_eq!(resource_part("user@example.com/desktop"), "desktop");
@@ -777,6 +1932,10 @@ mod tests {
                 status: None,
                 priority: 5,
                 last_updated: Utc::now(),
+                resource: None,
+                mood: None,
+                activity: None,
+                tune: None,
             },
         );
         resources.insert(
@@ -787,6 +1946,10 @@ mod tests {
                 status: Some("on phone".to_string()),
                 priority: 10,
                 last_updated: Utc::now(),
+                resource: None,
+                mood: None,
+                activity: None,
+                tune: None,
             },
         );
 
@@ -795,6 +1958,43 @@ mod tests {
         assert_eq!(best.priority, 10);
     }
 
+    #[test]
+    fn best_presence_breaks_priority_ties_by_availability() {
+        let now = Utc::now();
+        let mut resources = HashMap::new();
+        resources.insert(
+            "desktop".to_string(),
+            PresenceInfo {
+                jid: "alice@example.com".to_string(),
+                show: PresenceShow::Available,
+                status: None,
+                priority: 5,
+                last_updated: now - chrono::Duration::seconds(60),
+                resource: None,
+                mood: None,
+                activity: None,
+                tune: None,
+            },
+        );
+        resources.insert(
+            "laptop".to_string(),
+            PresenceInfo {
+                jid: "alice@example.com".to_string(),
+                show: PresenceShow::Dnd,
+                status: Some("focusing".to_string()),
+                priority: 5,
+                last_updated: now,
+                resource: None,
+                mood: None,
+                activity: None,
+                tune: None,
+            },
+        );
+
+        let best = best_presence("alice@example.com", &resources);
+        assert!(matches!(best.show, PresenceShow::Available));
+    }
+
     #[test]
     fn best_presence_empty_returns_unavailable() {
         let resources = HashMap::new();
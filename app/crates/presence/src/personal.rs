@@ -0,0 +1,239 @@
+//! User mood (XEP-0107), activity (XEP-0108) and tune (XEP-0118), shared
+//! over PEP.
+
+use serde::{Deserialize, Serialize};
+use xmpp_parsers::minidom::Element;
+
+pub const NS_MOOD: &str = "http://jabber.org/protocol/mood";
+pub const NS_ACTIVITY: &str = "http://jabber.org/protocol/activity";
+pub const NS_TUNE: &str = "http://jabber.org/protocol/tune";
+
+/// A mood such as `happy`, with optional free text.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UserMood {
+    /// Mood element name from XEP-0107, e.g. `happy` or `tired`.
+    pub value: String,
+    pub text: Option<String>,
+}
+
+/// An activity such as `relaxing`/`partying`, with optional free text.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UserActivity {
+    /// General category from XEP-0108, e.g. `relaxing`.
+    pub general: String,
+    /// Specific activity within the category, e.g. `partying`.
+    pub specific: Option<String>,
+    pub text: Option<String>,
+}
+
+/// What a user is listening to. Every field is optional in XEP-0118.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TuneInfo {
+    pub artist: Option<String>,
+    pub title: Option<String>,
+    /// Album or other collection the track belongs to.
+    pub source: Option<String>,
+    /// Track number or other position within `source`.
+    pub track: Option<String>,
+    /// Duration in seconds.
+    pub length: Option<u32>,
+    /// Rating from 1 to 10.
+    pub rating: Option<u8>,
+    pub uri: Option<String>,
+}
+
+/// The mood in a `<mood/>` item. An empty element means the contact
+/// cleared their mood.
+pub fn parse_mood(element: &Element) -> Option<UserMood> {
+    if !element.is("mood", NS_MOOD) {
+        return None;
+    }
+    let value = element
+        .children()
+        .find(|child| child.ns() == NS_MOOD && child.name() != "text")?
+        .name()
+        .to_string();
+    Some(UserMood {
+        value,
+        text: text_child(element, NS_MOOD),
+    })
+}
+
+/// The activity in an `<activity/>` item. An empty element means the
+/// contact cleared their activity.
+pub fn parse_activity(element: &Element) -> Option<UserActivity> {
+    if !element.is("activity", NS_ACTIVITY) {
+        return None;
+    }
+    let general = element
+        .children()
+        .find(|child| child.ns() == NS_ACTIVITY && child.name() != "text")?;
+    Some(UserActivity {
+        general: general.name().to_string(),
+        specific: general
+            .children()
+            .find(|child| child.ns() == NS_ACTIVITY)
+            .map(|child| child.name().to_string()),
+        text: text_child(element, NS_ACTIVITY),
+    })
+}
+
+/// The `<mood/>` item to publish; `None` builds the empty element that
+/// clears it.
+pub fn mood_element(mood: Option<&UserMood>) -> Element {
+    let mut builder = Element::builder("mood", NS_MOOD);
+    if let Some(mood) = mood {
+        builder = builder.append(Element::builder(mood.value.as_str(), NS_MOOD).build());
+        if let Some(text) = &mood.text {
+            builder = builder.append(
+                Element::builder("text", NS_MOOD)
+                    .append(text.as_str())
+                    .build(),
+            );
+        }
+    }
+    builder.build()
+}
+
+/// The `<activity/>` item to publish; `None` builds the empty element that
+/// clears it.
+pub fn activity_element(activity: Option<&UserActivity>) -> Element {
+    let mut builder = Element::builder("activity", NS_ACTIVITY);
+    if let Some(activity) = activity {
+        let mut general = Element::builder(activity.general.as_str(), NS_ACTIVITY);
+        if let Some(specific) = &activity.specific {
+            general = general.append(Element::builder(specific.as_str(), NS_ACTIVITY).build());
+        }
+        builder = builder.append(general.build());
+        if let Some(text) = &activity.text {
+            builder = builder.append(
+                Element::builder("text", NS_ACTIVITY)
+                    .append(text.as_str())
+                    .build(),
+            );
+        }
+    }
+    builder.build()
+}
+
+/// The tune in a `<tune/>` item. An empty element means the contact
+/// stopped playing.
+pub fn parse_tune(element: &Element) -> Option<TuneInfo> {
+    if !element.is("tune", NS_TUNE) {
+        return None;
+    }
+    let field = |name: &str| {
+        element
+            .get_child(name, NS_TUNE)
+            .map(|child| child.text().trim().to_string())
+            .filter(|text| !text.is_empty())
+    };
+    let tune = TuneInfo {
+        artist: field("artist"),
+        title: field("title"),
+        source: field("source"),
+        track: field("track"),
+        length: field("length").and_then(|length| length.parse().ok()),
+        rating: field("rating")
+            .and_then(|rating| rating.parse().ok())
+            .filter(|rating| (1..=10).contains(rating)),
+        uri: field("uri"),
+    };
+    (tune != TuneInfo::default()).then_some(tune)
+}
+
+/// The `<tune/>` item to publish; `None` builds the empty element that
+/// says playback stopped.
+pub fn tune_element(tune: Option<&TuneInfo>) -> Element {
+    let mut builder = Element::builder("tune", NS_TUNE);
+    if let Some(tune) = tune {
+        let length = tune.length.map(|length| length.to_string());
+        let rating = tune.rating.map(|rating| rating.to_string());
+        let fields = [
+            ("artist", tune.artist.as_deref()),
+            ("length", length.as_deref()),
+            ("rating", rating.as_deref()),
+            ("source", tune.source.as_deref()),
+            ("title", tune.title.as_deref()),
+            ("track", tune.track.as_deref()),
+            ("uri", tune.uri.as_deref()),
+        ];
+        for (name, value) in fields {
+            if let Some(value) = value {
+                builder = builder.append(Element::builder(name, NS_TUNE).append(value).build());
+            }
+        }
+    }
+    builder.build()
+}
+
+fn text_child(element: &Element, ns: &str) -> Option<String> {
+    element
+        .get_child("text", ns)
+        .map(|text| text.text())
+        .filter(|text| !text.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mood_roundtrips_and_clears() {
+        let mood = UserMood {
+            value: "happy".into(),
+            text: Some("Friday!".into()),
+        };
+        assert_eq!(parse_mood(&mood_element(Some(&mood))), Some(mood));
+        assert_eq!(parse_mood(&mood_element(None)), None);
+    }
+
+    #[test]
+    fn activity_roundtrips_with_and_without_specific() {
+        let activity = UserActivity {
+            general: "relaxing".into(),
+            specific: Some("partying".into()),
+            text: None,
+        };
+        assert_eq!(
+            parse_activity(&activity_element(Some(&activity))),
+            Some(activity)
+        );
+
+        let xml = "<activity xmlns='http://jabber.org/protocol/activity'>\
+            <working/><text>Deadline</text></activity>";
+        let parsed = parse_activity(&xml.parse().unwrap()).unwrap();
+        assert_eq!(parsed.general, "working");
+        assert_eq!(parsed.specific, None);
+        assert_eq!(parsed.text.as_deref(), Some("Deadline"));
+    }
+
+    #[test]
+    fn tune_roundtrips_and_stops() {
+        let tune = TuneInfo {
+            artist: Some("Yes".into()),
+            title: Some("Heart of the Sunrise".into()),
+            source: Some("Fragile".into()),
+            track: Some("3".into()),
+            length: Some(686),
+            rating: Some(8),
+            uri: Some("http://www.yesworld.com/lyrics/Fragile.html#9".into()),
+        };
+        let element = tune_element(Some(&tune));
+        assert_eq!(
+            element.get_child("length", NS_TUNE).map(|e| e.text()),
+            Some("686".to_string())
+        );
+        assert_eq!(parse_tune(&element), Some(tune));
+        assert_eq!(parse_tune(&tune_element(None)), None);
+
+        let partial = TuneInfo {
+            title: Some("Untitled".into()),
+            ..TuneInfo::default()
+        };
+        assert_eq!(parse_tune(&tune_element(Some(&partial))), Some(partial));
+    }
+}
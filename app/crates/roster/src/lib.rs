@@ -1,6 +1,8 @@
-use std::sync::Arc;
+use std::collections::HashSet;
+use std::sync::{Arc, RwLock};
 
-use tracing::{debug, error, warn};
+use serde::{Deserialize, Serialize};
+use tracing::{debug, error, field, instrument, warn};
 
 use waddle_core::event::{Channel, Event, EventPayload, EventSource, RosterItem, Subscription};
 use waddle_storage::{Database, FromRow, Row, SqlValue, StorageError};
@@ -82,8 +84,142 @@ impl StoredRosterItem {
     }
 }
 
+/// A change the user wants to make to a contact's presence subscription.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SubscriptionAction {
+    /// Ask to see the contact's presence.
+    Request,
+    /// Let the contact see our presence.
+    Approve,
+    /// Refuse a pending request, or stop the contact seeing our presence.
+    Deny,
+    /// Stop seeing the contact's presence, or cancel a pending request.
+    Unsubscribe,
+}
+
+/// Outbound subscription presence types (RFC 6121 section 3).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SubscriptionStanza {
+    Subscribe,
+    Subscribed,
+    Unsubscribe,
+    Unsubscribed,
+}
+
+impl SubscriptionStanza {
+    #[cfg(feature = "native")]
+    fn command(self, jid: &str) -> (&'static str, EventPayload) {
+        let jid = jid.to_string();
+        match self {
+            SubscriptionStanza::Subscribe => (
+                "ui.subscription.send",
+                EventPayload::SubscriptionSendRequested {
+                    jid,
+                    subscribe: true,
+                },
+            ),
+            SubscriptionStanza::Unsubscribe => (
+                "ui.subscription.send",
+                EventPayload::SubscriptionSendRequested {
+                    jid,
+                    subscribe: false,
+                },
+            ),
+            SubscriptionStanza::Subscribed => (
+                "ui.subscription.respond",
+                EventPayload::SubscriptionRespondRequested { jid, accept: true },
+            ),
+            SubscriptionStanza::Unsubscribed => (
+                "ui.subscription.respond",
+                EventPayload::SubscriptionRespondRequested { jid, accept: false },
+            ),
+        }
+    }
+}
+
+/// How [`RosterManager::import`] treats contacts already in the roster.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImportMode {
+    /// Add the imported contacts that are missing; existing contacts keep
+    /// their name and groups.
+    Merge,
+    /// Make the roster match the import: every imported contact is added or
+    /// updated on the server, and contacts not in the import are removed.
+    Replace,
+}
+
+/// The stored roster and its version, for hydrating a client in one call.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RosterSnapshot {
+    pub items: Vec<RosterItem>,
+    pub version: Option<String>,
+}
+
+/// The stanzas to send for a [`SubscriptionAction`] and the subscription
+/// state the server will push once the exchange completes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SubscriptionTransition {
+    pub outbound: Vec<SubscriptionStanza>,
+    pub expected: Subscription,
+}
+
+/// Computes the subscription state machine step for `action` from `current`.
+///
+/// Requests and approvals that are already in effect send nothing. Deny and
+/// unsubscribe are always sent because they also cancel pending requests,
+/// which the roster subscription state does not record.
+pub fn subscription_transition(
+    current: &Subscription,
+    action: SubscriptionAction,
+) -> SubscriptionTransition {
+    use SubscriptionAction as A;
+    use SubscriptionStanza as S;
+
+    let current = match current {
+        Subscription::Remove => Subscription::None,
+        other => other.clone(),
+    };
+
+    let (outbound, expected) = match (action, current) {
+        (A::Request, Subscription::None) => (Some(S::Subscribe), Subscription::To),
+        (A::Request, Subscription::From) => (Some(S::Subscribe), Subscription::Both),
+        (A::Request, state) => (None, state),
+
+        (A::Approve, Subscription::None) => (Some(S::Subscribed), Subscription::From),
+        (A::Approve, Subscription::To) => (Some(S::Subscribed), Subscription::Both),
+        (A::Approve, state) => (None, state),
+
+        (A::Deny, Subscription::From) => (Some(S::Unsubscribed), Subscription::None),
+        (A::Deny, Subscription::Both) => (Some(S::Unsubscribed), Subscription::To),
+        (A::Deny, state) => (Some(S::Unsubscribed), state),
+
+        (A::Unsubscribe, Subscription::To) => (Some(S::Unsubscribe), Subscription::None),
+        (A::Unsubscribe, Subscription::Both) => (Some(S::Unsubscribe), Subscription::From),
+        (A::Unsubscribe, state) => (Some(S::Unsubscribe), state),
+    };
+
+    SubscriptionTransition {
+        outbound: outbound.into_iter().collect(),
+        expected,
+    }
+}
+
+/// Whether a roster push tagged `incoming` predates the roster we already
+/// hold at `current`. Versions are opaque (RFC 6121 section 2.6), so only
+/// numeric versions, as issued by common servers, can be ordered; anything
+/// else is never considered stale.
+pub fn is_stale_roster_version(current: &str, incoming: &str) -> bool {
+    match (current.parse::<u64>(), incoming.parse::<u64>()) {
+        (Ok(current), Ok(incoming)) => incoming < current,
+        _ => false,
+    }
+}
+
 pub struct RosterManager<D: Database> {
     db: Arc<D>,
+    /// Version of the roster we hold, from the last full roster or push
+    version: RwLock<Option<String>>,
     #[cfg(feature = "native")]
     event_bus: Arc<dyn EventBus>,
 }
@@ -91,7 +227,32 @@ pub struct RosterManager<D: Database> {
 impl<D: Database> RosterManager<D> {
     #[cfg(feature = "native")]
     pub fn new(db: Arc<D>, event_bus: Arc<dyn EventBus>) -> Self {
-        Self { db, event_bus }
+        Self {
+            db,
+            version: RwLock::new(None),
+            event_bus,
+        }
+    }
+
+    /// Roster version of the stored roster, if the server supports versioning.
+    pub fn roster_version(&self) -> Option<String> {
+        self.version.read().unwrap().clone()
+    }
+
+    /// Records a push's version, or returns `false` if the push is older
+    /// than the roster we hold and must be ignored.
+    fn accept_push_version(&self, incoming: Option<&str>) -> bool {
+        let Some(incoming) = incoming else {
+            return true;
+        };
+        let mut version = self.version.write().unwrap();
+        if let Some(current) = version.as_deref()
+            && is_stale_roster_version(current, incoming)
+        {
+            return false;
+        }
+        *version = Some(incoming.to_string());
+        true
     }
 
     pub async fn get_roster(&self) -> Result<Vec<RosterItem>, RosterError> {
@@ -105,6 +266,59 @@ impl<D: Database> RosterManager<D> {
         Ok(rows.into_iter().map(|r| r.into_roster_item()).collect())
     }
 
+    pub async fn snapshot(&self) -> Result<RosterSnapshot, RosterError> {
+        Ok(RosterSnapshot {
+            items: self.get_roster().await?,
+            version: self.roster_version(),
+        })
+    }
+
+    /// The roster as portable items, e.g. for moving contacts to another
+    /// account with [`Self::import`].
+    pub async fn export(&self) -> Result<Vec<RosterItem>, RosterError> {
+        self.get_roster().await
+    }
+
+    /// Imports contacts with their names and groups, sending a roster set for
+    /// each change. Subscriptions are not carried over: they have to be
+    /// requested from the new account. Returns how many contacts were added
+    /// or updated.
+    pub async fn import(
+        &self,
+        items: &[RosterItem],
+        mode: ImportMode,
+    ) -> Result<usize, RosterError> {
+        let roster = self.get_roster().await?;
+        let existing: HashSet<&str> = roster.iter().map(|item| item.jid.as_str()).collect();
+
+        let mut imported = 0;
+        for item in items {
+            if !existing.contains(item.jid.as_str()) {
+                self.add_contact(&item.jid, item.name.as_deref(), &item.groups)
+                    .await?;
+            } else if mode == ImportMode::Replace {
+                self.update_contact(&item.jid, item.name.as_deref(), &item.groups)
+                    .await?;
+            } else {
+                continue;
+            }
+            imported += 1;
+        }
+
+        if mode == ImportMode::Replace {
+            let kept: HashSet<&str> = items.iter().map(|item| item.jid.as_str()).collect();
+            for stale in roster
+                .iter()
+                .filter(|item| !kept.contains(item.jid.as_str()))
+            {
+                self.remove_contact(&stale.jid).await?;
+            }
+        }
+
+        debug!(imported, mode = ?mode, "roster imported");
+        Ok(imported)
+    }
+
     pub async fn add_contact(
         &self,
         jid: &str,
@@ -217,65 +431,135 @@ impl<D: Database> RosterManager<D> {
     }
 
     pub async fn approve_subscription(&self, jid: &str) -> Result<(), RosterError> {
-        #[cfg(feature = "native")]
-        {
-            let _ = self.event_bus.publish(Event::new(
-                Channel::new("ui.subscription.respond").unwrap(),
-                EventSource::System("roster".into()),
-                EventPayload::SubscriptionRespondRequested {
-                    jid: jid.to_string(),
-                    accept: true,
-                },
-            ));
-        }
-        Ok(())
+        self.apply_subscription_action(jid, SubscriptionAction::Approve)
+            .await
+            .map(|_| ())
     }
 
     pub async fn deny_subscription(&self, jid: &str) -> Result<(), RosterError> {
-        #[cfg(feature = "native")]
-        {
-            let _ = self.event_bus.publish(Event::new(
-                Channel::new("ui.subscription.respond").unwrap(),
-                EventSource::System("roster".into()),
-                EventPayload::SubscriptionRespondRequested {
-                    jid: jid.to_string(),
-                    accept: false,
-                },
-            ));
-        }
-        Ok(())
+        self.apply_subscription_action(jid, SubscriptionAction::Deny)
+            .await
+            .map(|_| ())
     }
 
     pub async fn request_subscription(&self, jid: &str) -> Result<(), RosterError> {
-        #[cfg(feature = "native")]
-        {
-            let _ = self.event_bus.publish(Event::new(
-                Channel::new("ui.subscription.send").unwrap(),
-                EventSource::System("roster".into()),
-                EventPayload::SubscriptionSendRequested {
-                    jid: jid.to_string(),
-                    subscribe: true,
-                },
-            ));
-        }
-        Ok(())
+        self.apply_subscription_action(jid, SubscriptionAction::Request)
+            .await
+            .map(|_| ())
     }
 
     pub async fn unsubscribe(&self, jid: &str) -> Result<(), RosterError> {
+        self.apply_subscription_action(jid, SubscriptionAction::Unsubscribe)
+            .await
+            .map(|_| ())
+    }
+
+    /// Runs `action` through [`subscription_transition`] against the stored
+    /// subscription state, sends the resulting stanzas, and returns the
+    /// state expected once the server confirms.
+    pub async fn apply_subscription_action(
+        &self,
+        jid: &str,
+        action: SubscriptionAction,
+    ) -> Result<Subscription, RosterError> {
+        let current = self.stored_subscription(jid).await?;
+        let transition = subscription_transition(&current, action);
+
         #[cfg(feature = "native")]
-        {
+        for stanza in &transition.outbound {
+            let (channel, payload) = stanza.command(jid);
             let _ = self.event_bus.publish(Event::new(
-                Channel::new("ui.subscription.send").unwrap(),
+                Channel::new(channel).unwrap(),
                 EventSource::System("roster".into()),
-                EventPayload::SubscriptionSendRequested {
-                    jid: jid.to_string(),
-                    subscribe: false,
-                },
+                payload,
             ));
         }
+
+        Ok(transition.expected)
+    }
+
+    async fn stored_subscription(&self, jid: &str) -> Result<Subscription, RosterError> {
+        let jid_s = jid.to_string();
+        let rows: Vec<StoredRosterItem> = self
+            .db
+            .query(
+                "SELECT jid, name, subscription, groups FROM roster WHERE jid = ?1",
+                &[&jid_s],
+            )
+            .await?;
+        Ok(rows
+            .into_iter()
+            .next()
+            .map(|row| row.subscription.parse::<Subscription>().unwrap())
+            .unwrap_or(Subscription::None))
+    }
+
+    /// Stores a private note on a contact. Notes are local-only and never
+    /// sent to the server; an empty note removes it.
+    pub async fn set_note(&self, jid: &str, text: &str) -> Result<(), RosterError> {
+        let jid_s = jid.to_string();
+        if text.trim().is_empty() {
+            self.db
+                .execute("DELETE FROM contact_notes WHERE jid = ?1", &[&jid_s])
+                .await?;
+            return Ok(());
+        }
+
+        let text_s = text.to_string();
+        self.db
+            .execute(
+                "INSERT OR REPLACE INTO contact_notes (jid, note) VALUES (?1, ?2)",
+                &[&jid_s, &text_s],
+            )
+            .await?;
         Ok(())
     }
 
+    /// People we have 1:1 conversations with who are not in the roster,
+    /// most recently active first, so the UI can offer to add them. Our own
+    /// bare JID `own_jid`, rooms (including private messages through them)
+    /// and server domains are left out.
+    pub async fn ad_hoc_contacts(&self, own_jid: &str) -> Result<Vec<String>, RosterError> {
+        let own_s = own_jid.to_string();
+        let rows: Vec<Row> = self
+            .db
+            .query(
+                "WITH peers AS ( \
+                    SELECT CASE WHEN INSTR(jid, '/') > 0 \
+                        THEN SUBSTR(jid, 1, INSTR(jid, '/') - 1) ELSE jid END AS peer, \
+                        last_activity \
+                    FROM conversation_meta WHERE last_activity IS NOT NULL \
+                 ) \
+                 SELECT peer FROM peers \
+                 WHERE peer != ?1 AND INSTR(peer, '@') > 0 \
+                   AND peer NOT IN (SELECT jid FROM roster) \
+                   AND peer NOT IN (SELECT room_jid FROM muc_rooms) \
+                 GROUP BY peer \
+                 ORDER BY MAX(last_activity) DESC, peer",
+                &[&own_s],
+            )
+            .await?;
+        Ok(rows
+            .iter()
+            .filter_map(|row| match row.get(0) {
+                Some(SqlValue::Text(jid)) => Some(jid.clone()),
+                _ => None,
+            })
+            .collect())
+    }
+
+    pub async fn note(&self, jid: &str) -> Result<Option<String>, RosterError> {
+        let jid_s = jid.to_string();
+        let rows: Vec<Row> = self
+            .db
+            .query("SELECT note FROM contact_notes WHERE jid = ?1", &[&jid_s])
+            .await?;
+        Ok(rows.first().and_then(|row| match row.get(0) {
+            Some(SqlValue::Text(note)) => Some(note.clone()),
+            _ => None,
+        }))
+    }
+
     async fn upsert_item(&self, item: &RosterItem) -> Result<(), RosterError> {
         let groups_json =
             serde_json::to_string(&item.groups).map_err(|e| RosterError::SetFailed {
@@ -318,25 +602,35 @@ impl<D: Database> RosterManager<D> {
     }
 
     #[cfg(feature = "native")]
+    #[instrument(skip_all, fields(channel = %event.channel, correlation_id = event.correlation_id.map(field::display)))]
     pub async fn handle_event(&self, event: &Event) {
         match &event.payload {
             EventPayload::ConnectionEstablished { .. } => {
                 debug!("connection established, requesting roster fetch");
                 self.request_roster_fetch();
             }
-            EventPayload::RosterReceived { items } => {
+            EventPayload::RosterReceived { items, version } => {
                 debug!(count = items.len(), "full roster received, persisting");
+                *self.version.write().unwrap() = version.clone();
                 if let Err(e) = self.replace_all(items).await {
                     error!(error = %e, "failed to persist roster");
                 }
             }
-            EventPayload::RosterUpdated { item } => {
+            EventPayload::RosterUpdated { item, version } => {
+                if !self.accept_push_version(version.as_deref()) {
+                    warn!(jid = %item.jid, ?version, "ignoring stale roster push");
+                    return;
+                }
                 debug!(jid = %item.jid, "roster item updated, persisting");
                 if let Err(e) = self.upsert_item(item).await {
                     error!(error = %e, jid = %item.jid, "failed to persist roster update");
                 }
             }
-            EventPayload::RosterRemoved { jid } => {
+            EventPayload::RosterRemoved { jid, version } => {
+                if !self.accept_push_version(version.as_deref()) {
+                    warn!(jid = %jid, ?version, "ignoring stale roster removal");
+                    return;
+                }
                 debug!(jid = %jid, "roster item removed, deleting from storage");
                 if let Err(e) = self.delete_item(jid).await {
                     error!(error = %e, jid = %jid, "failed to delete roster item");
@@ -457,6 +751,116 @@ mod tests {
         assert_eq!(items[0].groups, vec!["Friends"]);
     }
 
+    fn import_item(jid: &str, name: &str, groups: &[&str]) -> RosterItem {
+        RosterItem {
+            jid: jid.to_string(),
+            name: Some(name.to_string()),
+            subscription: Subscription::Both,
+            groups: groups.iter().map(|g| g.to_string()).collect(),
+        }
+    }
+
+    #[tokio::test]
+    async fn import_merge_keeps_existing_contacts() {
+        let (manager, event_bus, _dir) = setup().await;
+        manager
+            .add_contact("alice@example.com", Some("Alice"), &["Friends".to_string()])
+            .await
+            .unwrap();
+        let mut sub = event_bus.subscribe("ui.roster.*").unwrap();
+
+        let imported = manager
+            .import(
+                &[
+                    import_item("alice@example.com", "Alicia", &["Work"]),
+                    import_item("bob@example.com", "Bob", &["Work", "Chess"]),
+                ],
+                ImportMode::Merge,
+            )
+            .await
+            .unwrap();
+        assert_eq!(imported, 1);
+
+        let event = tokio::time::timeout(std::time::Duration::from_millis(100), sub.recv())
+            .await
+            .expect("timed out")
+            .expect("recv error");
+        assert!(matches!(
+            event.payload,
+            EventPayload::RosterAddRequested { ref jid, .. } if jid == "bob@example.com"
+        ));
+        assert!(
+            tokio::time::timeout(std::time::Duration::from_millis(100), sub.recv())
+                .await
+                .is_err(),
+            "existing contacts should not be touched"
+        );
+
+        let roster = manager.export().await.unwrap();
+        assert_eq!(roster.len(), 2);
+        assert_eq!(roster[0].name.as_deref(), Some("Alice"));
+        assert_eq!(roster[0].groups, vec!["Friends"]);
+        assert_eq!(roster[1].name.as_deref(), Some("Bob"));
+        assert_eq!(roster[1].groups, vec!["Work", "Chess"]);
+        assert_eq!(roster[1].subscription, Subscription::None);
+    }
+
+    #[tokio::test]
+    async fn import_replace_pushes_each_contact() {
+        let (manager, event_bus, _dir) = setup().await;
+        for jid in ["alice@example.com", "carol@example.com"] {
+            manager.add_contact(jid, None, &[]).await.unwrap();
+        }
+        let mut sub = event_bus.subscribe("ui.roster.*").unwrap();
+
+        let imported = manager
+            .import(
+                &[
+                    import_item("alice@example.com", "Alicia", &["Work"]),
+                    import_item("bob@example.com", "Bob", &[]),
+                ],
+                ImportMode::Replace,
+            )
+            .await
+            .unwrap();
+        assert_eq!(imported, 2);
+
+        let mut pushed = Vec::new();
+        for _ in 0..3 {
+            let event = tokio::time::timeout(std::time::Duration::from_millis(100), sub.recv())
+                .await
+                .expect("timed out")
+                .expect("recv error");
+            pushed.push(match event.payload {
+                EventPayload::RosterUpdateRequested { jid, name, groups } => {
+                    assert_eq!(name.as_deref(), Some("Alicia"));
+                    assert_eq!(groups, vec!["Work"]);
+                    format!("update {jid}")
+                }
+                EventPayload::RosterAddRequested { jid, .. } => format!("add {jid}"),
+                EventPayload::RosterRemoveRequested { jid } => format!("remove {jid}"),
+                other => panic!("unexpected event: {other:?}"),
+            });
+        }
+        assert_eq!(
+            pushed,
+            vec![
+                "update alice@example.com",
+                "add bob@example.com",
+                "remove carol@example.com",
+            ]
+        );
+
+        let jids: Vec<String> = manager
+            .export()
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|item| item.jid)
+            .collect();
+        assert_eq!(jids, vec!["alice@example.com", "bob@example.com"]);
+    }
+
     #[tokio::test]
     async fn add_contact_no_name_no_groups() {
         let (manager, _, _dir) = setup().await;
@@ -560,7 +964,10 @@ mod tests {
         let event = Event::new(
             Channel::new("xmpp.roster.received").unwrap(),
             EventSource::Xmpp,
-            EventPayload::RosterReceived { items },
+            EventPayload::RosterReceived {
+                items,
+                version: None,
+            },
         );
         manager.handle_event(&event).await;
 
@@ -572,6 +979,34 @@ mod tests {
         assert!(matches!(stored[1].subscription, Subscription::To));
     }
 
+    #[tokio::test]
+    async fn snapshot_reflects_received_roster() {
+        let (manager, _, _dir) = setup().await;
+        let event = Event::new(
+            Channel::new("xmpp.roster.received").unwrap(),
+            EventSource::Xmpp,
+            EventPayload::RosterReceived {
+                items: vec![RosterItem {
+                    jid: "alice@example.com".to_string(),
+                    name: Some("Alice".to_string()),
+                    subscription: Subscription::Both,
+                    groups: vec!["Friends".to_string()],
+                }],
+                version: Some("7".to_string()),
+            },
+        );
+        manager.handle_event(&event).await;
+
+        let snapshot = manager.snapshot().await.unwrap();
+        assert_eq!(snapshot.version.as_deref(), Some("7"));
+        assert_eq!(snapshot.items.len(), 1);
+        assert_eq!(snapshot.items[0].jid, "alice@example.com");
+
+        let json = serde_json::to_value(&snapshot).unwrap();
+        assert_eq!(json["items"][0]["name"], "Alice");
+        assert_eq!(json["version"], "7");
+    }
+
     #[tokio::test]
     async fn handle_roster_received_replaces_existing() {
         let (manager, _, _dir) = setup().await;
@@ -593,7 +1028,10 @@ mod tests {
         let event = Event::new(
             Channel::new("xmpp.roster.received").unwrap(),
             EventSource::Xmpp,
-            EventPayload::RosterReceived { items },
+            EventPayload::RosterReceived {
+                items,
+                version: None,
+            },
         );
         manager.handle_event(&event).await;
 
@@ -616,7 +1054,10 @@ mod tests {
         let event = Event::new(
             Channel::new("xmpp.roster.updated").unwrap(),
             EventSource::Xmpp,
-            EventPayload::RosterUpdated { item },
+            EventPayload::RosterUpdated {
+                item,
+                version: None,
+            },
         );
         manager.handle_event(&event).await;
 
@@ -639,6 +1080,7 @@ mod tests {
             EventSource::Xmpp,
             EventPayload::RosterRemoved {
                 jid: "alice@example.com".to_string(),
+                version: None,
             },
         );
         manager.handle_event(&event).await;
@@ -710,7 +1152,10 @@ mod tests {
             let event = Event::new(
                 Channel::new("xmpp.roster.updated").unwrap(),
                 EventSource::Xmpp,
-                EventPayload::RosterUpdated { item },
+                EventPayload::RosterUpdated {
+                    item,
+                    version: None,
+                },
             );
             manager.handle_event(&event).await;
         }
@@ -781,4 +1226,213 @@ mod tests {
         );
         manager.handle_event(&event).await;
     }
+
+    #[tokio::test]
+    async fn contact_note_round_trips_without_roster_event() {
+        let (manager, event_bus, _dir) = setup().await;
+        let mut sub = event_bus.subscribe("**").unwrap();
+
+        assert_eq!(manager.note("alice@example.com").await.unwrap(), None);
+
+        manager
+            .set_note("alice@example.com", "met at conf 2024")
+            .await
+            .unwrap();
+        assert_eq!(
+            manager.note("alice@example.com").await.unwrap().as_deref(),
+            Some("met at conf 2024")
+        );
+
+        manager.set_note("alice@example.com", "").await.unwrap();
+        assert_eq!(manager.note("alice@example.com").await.unwrap(), None);
+
+        let received =
+            tokio::time::timeout(std::time::Duration::from_millis(100), sub.recv()).await;
+        assert!(received.is_err(), "notes must stay local");
+    }
+
+    #[test]
+    fn subscription_machine_walks_none_to_both() {
+        let step = subscription_transition(&Subscription::None, SubscriptionAction::Request);
+        assert_eq!(step.outbound, vec![SubscriptionStanza::Subscribe]);
+        assert_eq!(step.expected, Subscription::To);
+
+        let step = subscription_transition(&step.expected, SubscriptionAction::Approve);
+        assert_eq!(step.outbound, vec![SubscriptionStanza::Subscribed]);
+        assert_eq!(step.expected, Subscription::Both);
+
+        let step = subscription_transition(&step.expected, SubscriptionAction::Request);
+        assert!(step.outbound.is_empty(), "already subscribed");
+        assert_eq!(step.expected, Subscription::Both);
+    }
+
+    #[test]
+    fn subscription_machine_tears_down_both_directions() {
+        let step = subscription_transition(&Subscription::Both, SubscriptionAction::Unsubscribe);
+        assert_eq!(step.outbound, vec![SubscriptionStanza::Unsubscribe]);
+        assert_eq!(step.expected, Subscription::From);
+
+        let step = subscription_transition(&step.expected, SubscriptionAction::Deny);
+        assert_eq!(step.outbound, vec![SubscriptionStanza::Unsubscribed]);
+        assert_eq!(step.expected, Subscription::None);
+    }
+
+    #[tokio::test]
+    async fn request_for_subscribed_contact_sends_nothing() {
+        let (manager, event_bus, _dir) = setup().await;
+        manager
+            .handle_event(&Event::new(
+                Channel::new("xmpp.roster.updated").unwrap(),
+                EventSource::Xmpp,
+                EventPayload::RosterUpdated {
+                    item: RosterItem {
+                        jid: "alice@example.com".to_string(),
+                        name: None,
+                        subscription: Subscription::Both,
+                        groups: vec![],
+                    },
+                    version: None,
+                },
+            ))
+            .await;
+        let mut sub = event_bus.subscribe("ui.**").unwrap();
+
+        let expected = manager
+            .apply_subscription_action("alice@example.com", SubscriptionAction::Request)
+            .await
+            .unwrap();
+        assert_eq!(expected, Subscription::Both);
+
+        let received =
+            tokio::time::timeout(std::time::Duration::from_millis(100), sub.recv()).await;
+        assert!(received.is_err(), "no stanza expected");
+    }
+
+    #[tokio::test]
+    async fn stale_push_does_not_resurrect_removed_contact() {
+        let (manager, _, _dir) = setup().await;
+
+        let bob = RosterItem {
+            jid: "bob@example.com".to_string(),
+            name: Some("Bob".to_string()),
+            subscription: Subscription::Both,
+            groups: vec![],
+        };
+
+        // Newer full roster (version 7) no longer contains bob.
+        manager
+            .handle_event(&Event::new(
+                Channel::new("xmpp.roster.received").unwrap(),
+                EventSource::Xmpp,
+                EventPayload::RosterReceived {
+                    items: vec![],
+                    version: Some("7".to_string()),
+                },
+            ))
+            .await;
+
+        // A delayed push from before the removal arrives late.
+        manager
+            .handle_event(&Event::new(
+                Channel::new("xmpp.roster.updated").unwrap(),
+                EventSource::Xmpp,
+                EventPayload::RosterUpdated {
+                    item: bob.clone(),
+                    version: Some("5".to_string()),
+                },
+            ))
+            .await;
+        assert!(manager.get_roster().await.unwrap().is_empty());
+        assert_eq!(manager.roster_version().as_deref(), Some("7"));
+
+        // A genuinely newer push is applied.
+        manager
+            .handle_event(&Event::new(
+                Channel::new("xmpp.roster.updated").unwrap(),
+                EventSource::Xmpp,
+                EventPayload::RosterUpdated {
+                    item: bob,
+                    version: Some("8".to_string()),
+                },
+            ))
+            .await;
+        assert_eq!(manager.get_roster().await.unwrap().len(), 1);
+        assert_eq!(manager.roster_version().as_deref(), Some("8"));
+    }
+
+    #[test]
+    fn opaque_roster_versions_are_never_stale() {
+        assert!(is_stale_roster_version("7", "5"));
+        assert!(!is_stale_roster_version("7", "7"));
+        assert!(!is_stale_roster_version("7", "9"));
+        assert!(!is_stale_roster_version("abc", "5"));
+        assert!(!is_stale_roster_version("7", "ver-hash"));
+    }
+
+    #[tokio::test]
+    async fn ad_hoc_contacts_lists_non_roster_peers_until_added() {
+        use std::collections::BTreeMap;
+        use waddle_core::event::{ChatMessage, MessageSource, MessageType};
+        use waddle_storage::MessageStore;
+
+        let (manager, _, _dir) = setup().await;
+        manager
+            .add_contact("alice@example.com", Some("Alice"), &[])
+            .await
+            .unwrap();
+        manager
+            .db
+            .execute(
+                "INSERT INTO muc_rooms (room_jid, nick, joined) \
+                 VALUES ('room@conference.example.com', 'me', 1)",
+                &[],
+            )
+            .await
+            .unwrap();
+
+        let store = MessageStore::new(manager.db.clone());
+        let at = |secs: i64| chrono::DateTime::from_timestamp(1_700_000_000 + secs, 0).unwrap();
+        for (id, from, to, secs) in [
+            ("m-1", "alice@example.com", "me@example.com", 1),
+            ("m-2", "stranger@example.com/phone", "me@example.com", 2),
+            ("m-3", "me@example.com", "carol@example.com", 3),
+            (
+                "m-4",
+                "room@conference.example.com/bob",
+                "me@example.com",
+                4,
+            ),
+            ("m-5", "example.com", "me@example.com", 5),
+        ] {
+            let message = ChatMessage {
+                id: id.to_string(),
+                from: from.to_string(),
+                to: to.to_string(),
+                body: "hi".to_string(),
+                timestamp: at(secs),
+                message_type: MessageType::Chat,
+                thread: None,
+                embeds: vec![],
+                bodies: BTreeMap::new(),
+                origin_id: None,
+                spoiler: None,
+                stanza_id: None,
+            };
+            store.persist(&message, MessageSource::Live).await.unwrap();
+        }
+
+        assert_eq!(
+            manager.ad_hoc_contacts("me@example.com").await.unwrap(),
+            vec!["carol@example.com", "stranger@example.com"]
+        );
+
+        manager
+            .add_contact("stranger@example.com", None, &[])
+            .await
+            .unwrap();
+        assert_eq!(
+            manager.ad_hoc_contacts("me@example.com").await.unwrap(),
+            vec!["carol@example.com"]
+        );
+    }
 }
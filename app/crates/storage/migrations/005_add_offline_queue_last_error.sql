@@ -0,0 +1,2 @@
+-- Migration: Record why an offline queue item failed
+ALTER TABLE offline_queue ADD COLUMN last_error TEXT;
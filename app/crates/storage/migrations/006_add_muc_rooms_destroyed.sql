@@ -0,0 +1,2 @@
+-- Migration: Remember rooms that were destroyed so they are not rejoined
+ALTER TABLE muc_rooms ADD COLUMN destroyed INTEGER NOT NULL DEFAULT 0;
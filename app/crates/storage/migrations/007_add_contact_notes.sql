@@ -0,0 +1,5 @@
+-- Migration: Private, local-only notes on contacts
+CREATE TABLE IF NOT EXISTS contact_notes (
+    jid TEXT PRIMARY KEY,
+    note TEXT NOT NULL
+);
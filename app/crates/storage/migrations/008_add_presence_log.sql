@@ -0,0 +1,9 @@
+-- Migration: Opt-in log of contact presence changes
+CREATE TABLE IF NOT EXISTS presence_log (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    jid TEXT NOT NULL,
+    show TEXT NOT NULL,
+    timestamp TEXT NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS idx_presence_log_jid ON presence_log(jid);
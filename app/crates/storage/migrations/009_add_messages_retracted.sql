@@ -0,0 +1,2 @@
+-- Migration: Tombstone flag for messages retracted by a room moderator
+ALTER TABLE messages ADD COLUMN retracted INTEGER NOT NULL DEFAULT 0;
@@ -0,0 +1,17 @@
+-- Migration: Normalize stored timestamps to UTC with a `Z` suffix and
+-- millisecond precision so lexical order matches chronological order
+UPDATE messages
+SET timestamp = strftime('%Y-%m-%dT%H:%M:%fZ', timestamp)
+WHERE strftime('%Y-%m-%dT%H:%M:%fZ', timestamp) IS NOT NULL;
+
+UPDATE offline_queue
+SET created_at = strftime('%Y-%m-%dT%H:%M:%fZ', created_at)
+WHERE strftime('%Y-%m-%dT%H:%M:%fZ', created_at) IS NOT NULL;
+
+UPDATE mam_sync_state
+SET last_sync_at = strftime('%Y-%m-%dT%H:%M:%fZ', last_sync_at)
+WHERE strftime('%Y-%m-%dT%H:%M:%fZ', last_sync_at) IS NOT NULL;
+
+UPDATE presence_log
+SET timestamp = strftime('%Y-%m-%dT%H:%M:%fZ', timestamp)
+WHERE strftime('%Y-%m-%dT%H:%M:%fZ', timestamp) IS NOT NULL;
@@ -0,0 +1,8 @@
+-- Migration: Integer epoch-millisecond timestamp for ordering and pagination
+ALTER TABLE messages ADD COLUMN timestamp_ms INTEGER NOT NULL DEFAULT 0;
+
+UPDATE messages
+SET timestamp_ms = CAST(ROUND((julianday(timestamp) - 2440587.5) * 86400000.0) AS INTEGER)
+WHERE julianday(timestamp) IS NOT NULL;
+
+CREATE INDEX IF NOT EXISTS idx_messages_timestamp_ms ON messages(timestamp_ms);
@@ -0,0 +1,3 @@
+-- Migration: Composite index serving room history (to_jid + type, newest first)
+CREATE INDEX IF NOT EXISTS idx_messages_room_timeline
+    ON messages(to_jid, message_type, timestamp_ms);
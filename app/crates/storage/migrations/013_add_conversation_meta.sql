@@ -0,0 +1,5 @@
+-- Migration: Local per-conversation state, keyed by the peer's bare JID
+CREATE TABLE IF NOT EXISTS conversation_meta (
+    jid TEXT PRIMARY KEY,
+    archived INTEGER NOT NULL DEFAULT 0
+);
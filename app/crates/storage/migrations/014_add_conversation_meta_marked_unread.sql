@@ -0,0 +1,2 @@
+-- Migration: Manual "mark as unread" flag, cleared when the conversation is read
+ALTER TABLE conversation_meta ADD COLUMN marked_unread INTEGER NOT NULL DEFAULT 0;
@@ -0,0 +1,2 @@
+-- Migration: Whether the recipient has seen a message we sent
+ALTER TABLE messages ADD COLUMN displayed INTEGER NOT NULL DEFAULT 0;
@@ -0,0 +1,2 @@
+-- Migration: Which path first stored a message (live, local, mam, muc_history)
+ALTER TABLE messages ADD COLUMN source TEXT;
@@ -0,0 +1,24 @@
+-- Migration: Denormalized newest-message time (epoch millis) per conversation
+-- and room, so sidebars sort without scanning messages
+ALTER TABLE conversation_meta ADD COLUMN last_activity INTEGER;
+ALTER TABLE muc_rooms ADD COLUMN last_activity INTEGER;
+
+INSERT INTO conversation_meta (jid, last_activity)
+SELECT jid, MAX(timestamp_ms) FROM (
+    SELECT from_jid AS jid, timestamp_ms FROM messages
+    WHERE message_type = 'chat' AND from_jid != ''
+    UNION ALL
+    SELECT to_jid AS jid, timestamp_ms FROM messages
+    WHERE message_type = 'chat' AND to_jid != ''
+) WHERE true GROUP BY jid
+ON CONFLICT(jid) DO UPDATE SET last_activity = excluded.last_activity;
+
+UPDATE muc_rooms SET last_activity = (
+    SELECT MAX(timestamp_ms) FROM messages
+    WHERE to_jid = muc_rooms.room_jid AND message_type = 'groupchat'
+);
+
+CREATE INDEX IF NOT EXISTS idx_conversation_meta_last_activity
+    ON conversation_meta (last_activity DESC);
+CREATE INDEX IF NOT EXISTS idx_muc_rooms_last_activity
+    ON muc_rooms (last_activity DESC);
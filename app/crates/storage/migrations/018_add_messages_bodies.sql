@@ -0,0 +1,2 @@
+-- Migration: Body variants by xml:lang (JSON object) for multi-language messages
+ALTER TABLE messages ADD COLUMN bodies TEXT;
@@ -0,0 +1,6 @@
+-- Migration: Unsent text per conversation, keyed by bare JID
+CREATE TABLE IF NOT EXISTS drafts (
+    jid TEXT PRIMARY KEY,
+    body TEXT NOT NULL,
+    updated_at TEXT NOT NULL
+);
@@ -0,0 +1,9 @@
+-- Migration: Natural keys shared by every copy of one logical message (live,
+-- carbon, MAM), so copies that arrive under different ids collapse onto one
+-- row. Rows stored before this migration have neither key.
+ALTER TABLE messages ADD COLUMN origin_id TEXT;
+ALTER TABLE messages ADD COLUMN content_key TEXT;
+
+CREATE UNIQUE INDEX IF NOT EXISTS idx_messages_origin_id
+    ON messages (origin_id) WHERE origin_id IS NOT NULL;
+CREATE INDEX IF NOT EXISTS idx_messages_content_key ON messages (content_key);
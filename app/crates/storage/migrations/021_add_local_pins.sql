@@ -0,0 +1,8 @@
+-- Migration: Messages pinned in a 1:1 conversation on this device only.
+-- Never synced; room pins shared with other occupants live elsewhere.
+CREATE TABLE IF NOT EXISTS local_pins (
+    jid TEXT NOT NULL,
+    message_id TEXT NOT NULL,
+    pinned_at TEXT NOT NULL,
+    PRIMARY KEY (jid, message_id)
+);
@@ -0,0 +1,8 @@
+-- Migration: Debug log of chat states dropped while offline, written only
+-- when `logging.record_offline_chat_states` is enabled
+CREATE TABLE IF NOT EXISTS chat_state_log (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    to_jid TEXT NOT NULL,
+    state TEXT NOT NULL,
+    recorded_at TEXT NOT NULL
+);
@@ -0,0 +1,3 @@
+-- Migration: XEP-0382 spoiler hint. NULL for ordinary messages, '' for a
+-- spoiler without a hint.
+ALTER TABLE messages ADD COLUMN spoiler TEXT;
@@ -0,0 +1,8 @@
+-- Migration: Resources that acknowledged a message with a delivery receipt,
+-- so sends to a bare JID show which of the peer's devices got them
+CREATE TABLE IF NOT EXISTS message_deliveries (
+    message_id TEXT NOT NULL,
+    resource TEXT NOT NULL,
+    delivered_at TEXT NOT NULL,
+    PRIMARY KEY (message_id, resource)
+);
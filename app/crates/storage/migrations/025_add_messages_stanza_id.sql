@@ -0,0 +1,7 @@
+-- Migration: XEP-0359 stanza-id assigned by the room or archive that stored
+-- a message. Moderation (XEP-0425) and archive paging refer to messages by
+-- it, not by the sender's id.
+ALTER TABLE messages ADD COLUMN stanza_id TEXT;
+
+CREATE INDEX IF NOT EXISTS idx_messages_stanza_id
+    ON messages (to_jid, stanza_id) WHERE stanza_id IS NOT NULL;
@@ -0,0 +1,18 @@
+-- Migration: XEP-0359 origin-ids are only unique per sender, so the dedup
+-- key pairs each origin-id with whoever sent it: the occupant JID in a room,
+-- the bare JID otherwise. Our own direct sends are stored before `from` is
+-- known and keep a NULL sender until another copy fills it in.
+ALTER TABLE messages ADD COLUMN origin_sender TEXT;
+
+UPDATE messages SET origin_sender = CASE
+        WHEN TRIM(COALESCE(from_jid, '')) = '' THEN NULL
+        WHEN message_type = 'groupchat' THEN from_jid
+        WHEN instr(from_jid, '/') > 0 THEN substr(from_jid, 1, instr(from_jid, '/') - 1)
+        ELSE from_jid
+    END
+WHERE origin_id IS NOT NULL;
+
+DROP INDEX IF EXISTS idx_messages_origin_id;
+CREATE UNIQUE INDEX IF NOT EXISTS idx_messages_origin_id
+    ON messages (origin_sender, origin_id)
+    WHERE origin_id IS NOT NULL AND origin_sender IS NOT NULL;
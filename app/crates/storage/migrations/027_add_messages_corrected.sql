@@ -0,0 +1,11 @@
+-- Migration: an authoritative (archive) copy may only replace a stored body
+-- sent by the same sender, and never one replaced by a XEP-0308 correction.
+-- Every row gets its sender for the check, not just rows with an origin-id.
+ALTER TABLE messages ADD COLUMN corrected INTEGER NOT NULL DEFAULT 0;
+
+UPDATE messages SET origin_sender = CASE
+        WHEN message_type = 'groupchat' THEN from_jid
+        WHEN instr(from_jid, '/') > 0 THEN substr(from_jid, 1, instr(from_jid, '/') - 1)
+        ELSE from_jid
+    END
+WHERE origin_sender IS NULL AND TRIM(COALESCE(from_jid, '')) != '';
@@ -0,0 +1,18 @@
+-- Migration: Serve the 1:1 conversation list from conversation_meta. The
+-- newest message per peer is found through this index and
+-- idx_messages_room_timeline, and deleting chat messages moves last_activity
+-- back to what is still stored.
+CREATE INDEX IF NOT EXISTS idx_messages_from_timeline
+    ON messages (from_jid, message_type, timestamp_ms);
+
+CREATE TRIGGER IF NOT EXISTS messages_chat_delete_activity
+AFTER DELETE ON messages
+WHEN old.message_type = 'chat'
+BEGIN
+    UPDATE conversation_meta SET last_activity = (
+        SELECT MAX(timestamp_ms) FROM messages
+        WHERE message_type = 'chat'
+          AND (from_jid = conversation_meta.jid OR to_jid = conversation_meta.jid)
+    )
+    WHERE jid IN (old.from_jid, old.to_jid);
+END;
@@ -0,0 +1,8 @@
+-- Migration: Drop a message's local pins and delivery receipts with it, so
+-- deleted history leaves nothing behind that could reattach to a reused id
+CREATE TRIGGER IF NOT EXISTS messages_delete_cascade
+AFTER DELETE ON messages
+BEGIN
+    DELETE FROM message_deliveries WHERE message_id = old.id;
+    DELETE FROM local_pins WHERE message_id = old.id;
+END;
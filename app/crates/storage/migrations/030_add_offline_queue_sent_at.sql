@@ -0,0 +1,3 @@
+-- Migration: When a queued command went out, so reconciling an archived copy
+-- measures from the send rather than from when it was queued
+ALTER TABLE offline_queue ADD COLUMN sent_at TEXT;
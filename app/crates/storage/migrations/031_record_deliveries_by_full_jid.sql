@@ -0,0 +1,6 @@
+-- Migration: Record who acknowledged a message by full JID rather than by
+-- resource alone, so a receipt stays tied to the account that sent it
+ALTER TABLE message_deliveries RENAME COLUMN resource TO delivered_to;
+UPDATE message_deliveries
+SET delivered_to = (SELECT m.to_jid FROM messages m WHERE m.id = message_id) || '/' || delivered_to
+WHERE EXISTS (SELECT 1 FROM messages m WHERE m.id = message_id);
@@ -0,0 +1,117 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use tokio::sync::Mutex;
+
+use crate::{DEFAULT_WRITER_QUEUE_DEPTH, NativeDatabase, StorageError};
+
+/// One database per account under a shared directory. Each account's file
+/// is named after its bare JID, so accounts never see each other's data.
+pub struct AccountStore {
+    dir: PathBuf,
+    open: Mutex<HashMap<String, Arc<NativeDatabase>>>,
+}
+
+impl AccountStore {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self {
+            dir: dir.into(),
+            open: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Directory holding the account databases.
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Database file for `jid`'s account. The resource is ignored and the
+    /// JID is lowercased, so every session of an account shares one file.
+    pub fn path_for(&self, jid: &str) -> PathBuf {
+        self.dir
+            .join(format!("{}.db", file_stem(&account_key(jid))))
+    }
+
+    /// The database for `jid`'s account, opened and migrated on first use
+    /// and shared by later calls.
+    pub async fn open(&self, jid: &str) -> Result<Arc<NativeDatabase>, StorageError> {
+        let key = account_key(jid);
+        let mut open = self.open.lock().await;
+        if let Some(db) = open.get(&key) {
+            return Ok(db.clone());
+        }
+        let db =
+            Arc::new(NativeDatabase::open(&self.path_for(&key), DEFAULT_WRITER_QUEUE_DEPTH).await?);
+        open.insert(key, db.clone());
+        Ok(db)
+    }
+}
+
+fn account_key(jid: &str) -> String {
+    jid.split('/').next().unwrap_or(jid).trim().to_lowercase()
+}
+
+/// Escapes every byte outside `[a-z0-9.@_-]` as `%XX`, so distinct JIDs
+/// never share a file and no JID can leave the directory.
+fn file_stem(key: &str) -> String {
+    let mut stem = String::with_capacity(key.len());
+    for byte in key.bytes() {
+        match byte {
+            b'a'..=b'z' | b'0'..=b'9' | b'@' | b'_' | b'-' => stem.push(byte as char),
+            b'.' if !stem.is_empty() => stem.push('.'),
+            _ => stem.push_str(&format!("%{byte:02X}")),
+        }
+    }
+    stem
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Database, Row, SqlValue};
+    use tempfile::TempDir;
+
+    #[test]
+    fn file_names_are_sanitized_and_distinct() {
+        let store = AccountStore::new("/data");
+        assert_eq!(
+            store.path_for("Alice@Example.com/phone"),
+            PathBuf::from("/data/alice@example.com.db")
+        );
+        assert_eq!(store.path_for(".."), PathBuf::from("/data/%2E..db"));
+        assert_ne!(
+            store.path_for("a+b@example.com"),
+            store.path_for("a_b@example.com")
+        );
+    }
+
+    #[tokio::test]
+    async fn accounts_get_isolated_databases() {
+        let dir = TempDir::new().unwrap();
+        let store = AccountStore::new(dir.path());
+
+        let alice = store.open("alice@example.com/desktop").await.unwrap();
+        let bob = store.open("bob@example.org").await.unwrap();
+        alice
+            .execute(
+                "INSERT INTO drafts (jid, body, updated_at) VALUES ('carol@example.com', 'hi', 't')",
+                &[],
+            )
+            .await
+            .unwrap();
+
+        assert!(store.path_for("alice@example.com").exists());
+        assert!(store.path_for("bob@example.org").exists());
+        let count = |rows: Vec<Row>| match rows[0].get(0) {
+            Some(SqlValue::Integer(count)) => *count,
+            other => panic!("unexpected count: {other:?}"),
+        };
+        let sql = "SELECT COUNT(*) FROM drafts";
+        assert_eq!(count(alice.query(sql, &[]).await.unwrap()), 1);
+        assert_eq!(count(bob.query(sql, &[]).await.unwrap()), 0);
+
+        let again = store.open("alice@example.com").await.unwrap();
+        assert!(Arc::ptr_eq(&alice, &again));
+    }
+}
@@ -1,10 +1,24 @@
 use std::path::{Path, PathBuf};
 
+use chrono::{DateTime, SecondsFormat, Utc};
+
+#[cfg(feature = "native")]
+mod account_store;
+mod message_store;
+
+#[cfg(feature = "native")]
+pub use account_store::AccountStore;
+pub use message_store::{ConflictPolicy, MessageStore};
+
 #[cfg(feature = "native")]
 use std::{
-    sync::mpsc::{self, Receiver, Sender},
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicIsize, Ordering},
+        mpsc::{self, Receiver, SyncSender, TrySendError},
+    },
     thread,
-    time::Duration,
+    time::{Duration, Instant},
 };
 
 #[cfg(feature = "native")]
@@ -17,7 +31,21 @@ use rusqlite::{
 use tokio::{sync::oneshot, task};
 
 #[cfg(feature = "native")]
-use tracing::info;
+use tracing::{Span, debug, info};
+
+/// Formats a timestamp the way every table stores it: UTC with a `Z` suffix
+/// and fixed millisecond precision, so text order is chronological order.
+pub fn format_timestamp(timestamp: &DateTime<Utc>) -> String {
+    timestamp.to_rfc3339_opts(SecondsFormat::Millis, true)
+}
+
+/// Epoch milliseconds of an RFC 3339 timestamp (e.g. a pagination cursor),
+/// the unit of the indexed `messages.timestamp_ms` column.
+pub fn timestamp_millis(timestamp: &str) -> Option<i64> {
+    DateTime::parse_from_rfc3339(timestamp)
+        .ok()
+        .map(|parsed| parsed.timestamp_millis())
+}
 
 #[derive(Debug, thiserror::Error)]
 pub enum StorageError {
@@ -191,11 +219,24 @@ pub trait Database: Send + Sync + 'static {
         F: FnOnce(&Transaction) -> Result<R, StorageError> + Send;
 }
 
+/// Commands the writer buffers before `execute` has to wait for room.
+#[cfg(feature = "native")]
+pub const DEFAULT_WRITER_QUEUE_DEPTH: usize = 256;
+
 #[cfg(feature = "native")]
 #[derive(Debug)]
 pub struct NativeDatabase {
     path: PathBuf,
-    writer: Sender<WriteCommand>,
+    /// `:memory:` databases exist only on the writer's connection, so reads
+    /// go through the writer too.
+    in_memory: bool,
+    writer: SyncSender<WriteCommand>,
+    queue: Arc<QueueGauge>,
+    slow_query_threshold: Mutex<Option<Duration>>,
+    /// Most rows a query may return before it fails; `None` is unbounded
+    max_rows: Mutex<Option<usize>>,
+    /// Joined on drop so the writer's connection closes with the handle
+    writer_thread: Option<thread::JoinHandle<Result<(), StorageError>>>,
 }
 
 #[cfg(feature = "native")]
@@ -203,8 +244,40 @@ enum WriteCommand {
     Execute {
         sql: String,
         params: Vec<SqlValue>,
+        slow_query: Option<SlowQueryLog>,
         response: oneshot::Sender<Result<u64, StorageError>>,
     },
+    Query {
+        sql: String,
+        params: Vec<SqlValue>,
+        slow_query: Option<SlowQueryLog>,
+        max_rows: Option<usize>,
+        response: oneshot::Sender<Result<Vec<Row>, StorageError>>,
+    },
+    /// Sent on drop; commands queued before it still run.
+    Shutdown,
+}
+
+/// Commands handed to the writer but not yet picked up. Senders count after
+/// the send succeeds and the writer right after receiving, so the gauge reads
+/// at most one above the channel depth.
+#[cfg(feature = "native")]
+#[derive(Debug, Default)]
+struct QueueGauge {
+    queued: AtomicIsize,
+    peak: AtomicIsize,
+}
+
+#[cfg(feature = "native")]
+impl QueueGauge {
+    fn pushed(&self) {
+        let queued = self.queued.fetch_add(1, Ordering::SeqCst) + 1;
+        self.peak.fetch_max(queued, Ordering::SeqCst);
+    }
+
+    fn popped(&self) {
+        self.queued.fetch_sub(1, Ordering::SeqCst);
+    }
 }
 
 #[cfg(feature = "native")]
@@ -291,18 +364,48 @@ fn open_native_connection(path: &Path) -> Result<Connection, StorageError> {
     Ok(connection)
 }
 
+/// Slow-query timing for one statement, captured where it was issued so the
+/// log lands in the caller's span rather than the writer thread's.
+#[cfg(feature = "native")]
+struct SlowQueryLog {
+    threshold: Duration,
+    span: Span,
+}
+
+/// Runs a statement, logging it at debug when it takes at least the
+/// threshold.
+#[cfg(feature = "native")]
+fn log_if_slow<T>(sql: &str, slow_query: Option<&SlowQueryLog>, run: impl FnOnce() -> T) -> T {
+    let Some(slow_query) = slow_query else {
+        return run();
+    };
+
+    let started = Instant::now();
+    let result = run();
+    let elapsed = started.elapsed();
+    if elapsed >= slow_query.threshold {
+        slow_query.span.in_scope(|| {
+            debug!(sql, elapsed_us = elapsed.as_micros() as u64, "slow query");
+        });
+    }
+    result
+}
+
 #[cfg(feature = "native")]
 fn execute_statement(
     connection: &Connection,
     sql: &str,
     params: &[SqlValue],
+    slow_query: Option<&SlowQueryLog>,
 ) -> Result<u64, StorageError> {
     let values = sql_values_to_rusqlite_values(params);
 
-    connection
-        .execute(sql, params_from_iter(values.iter()))
-        .map(|rows_affected| rows_affected as u64)
-        .map_err(|error| StorageError::QueryFailed(error.to_string()))
+    log_if_slow(sql, slow_query, || {
+        connection
+            .execute(sql, params_from_iter(values.iter()))
+            .map(|rows_affected| rows_affected as u64)
+            .map_err(|error| StorageError::QueryFailed(error.to_string()))
+    })
 }
 
 #[cfg(feature = "native")]
@@ -310,6 +413,22 @@ fn query_rows(
     connection: &Connection,
     sql: &str,
     params: &[SqlValue],
+    slow_query: Option<&SlowQueryLog>,
+    max_rows: Option<usize>,
+) -> Result<Vec<Row>, StorageError> {
+    log_if_slow(sql, slow_query, || {
+        read_rows(connection, sql, params, max_rows)
+    })
+}
+
+/// Collects the result set, failing as soon as it grows past `max_rows`
+/// rather than after materializing all of it.
+#[cfg(feature = "native")]
+fn read_rows(
+    connection: &Connection,
+    sql: &str,
+    params: &[SqlValue],
+    max_rows: Option<usize>,
 ) -> Result<Vec<Row>, StorageError> {
     let mut statement = connection
         .prepare(sql)
@@ -325,6 +444,9 @@ fn query_rows(
         .next()
         .map_err(|error| StorageError::QueryFailed(error.to_string()))?
     {
+        if max_rows.is_some_and(|max_rows| output.len() >= max_rows) {
+            return Err(StorageError::QueryFailed("result too large".to_string()));
+        }
         let mut values = Vec::with_capacity(column_count);
         for index in 0..column_count {
             let value = row
@@ -362,6 +484,114 @@ const MIGRATIONS: &[Migration] = &[
         version: 4,
         sql: include_str!("../migrations/004_add_embeds_column.sql"),
     },
+    Migration {
+        version: 5,
+        sql: include_str!("../migrations/005_add_offline_queue_last_error.sql"),
+    },
+    Migration {
+        version: 6,
+        sql: include_str!("../migrations/006_add_muc_rooms_destroyed.sql"),
+    },
+    Migration {
+        version: 7,
+        sql: include_str!("../migrations/007_add_contact_notes.sql"),
+    },
+    Migration {
+        version: 8,
+        sql: include_str!("../migrations/008_add_presence_log.sql"),
+    },
+    Migration {
+        version: 9,
+        sql: include_str!("../migrations/009_add_messages_retracted.sql"),
+    },
+    Migration {
+        version: 10,
+        sql: include_str!("../migrations/010_normalize_timestamps.sql"),
+    },
+    Migration {
+        version: 11,
+        sql: include_str!("../migrations/011_add_messages_timestamp_ms.sql"),
+    },
+    Migration {
+        version: 12,
+        sql: include_str!("../migrations/012_add_messages_room_timeline_index.sql"),
+    },
+    Migration {
+        version: 13,
+        sql: include_str!("../migrations/013_add_conversation_meta.sql"),
+    },
+    Migration {
+        version: 14,
+        sql: include_str!("../migrations/014_add_conversation_meta_marked_unread.sql"),
+    },
+    Migration {
+        version: 15,
+        sql: include_str!("../migrations/015_add_messages_displayed.sql"),
+    },
+    Migration {
+        version: 16,
+        sql: include_str!("../migrations/016_add_messages_source.sql"),
+    },
+    Migration {
+        version: 17,
+        sql: include_str!("../migrations/017_add_last_activity.sql"),
+    },
+    Migration {
+        version: 18,
+        sql: include_str!("../migrations/018_add_messages_bodies.sql"),
+    },
+    Migration {
+        version: 19,
+        sql: include_str!("../migrations/019_add_drafts.sql"),
+    },
+    Migration {
+        version: 20,
+        sql: include_str!("../migrations/020_add_messages_dedup_key.sql"),
+    },
+    Migration {
+        version: 21,
+        sql: include_str!("../migrations/021_add_local_pins.sql"),
+    },
+    Migration {
+        version: 22,
+        sql: include_str!("../migrations/022_add_chat_state_log.sql"),
+    },
+    Migration {
+        version: 23,
+        sql: include_str!("../migrations/023_add_messages_spoiler.sql"),
+    },
+    Migration {
+        version: 24,
+        sql: include_str!("../migrations/024_add_message_deliveries.sql"),
+    },
+    Migration {
+        version: 25,
+        sql: include_str!("../migrations/025_add_messages_stanza_id.sql"),
+    },
+    Migration {
+        version: 26,
+        sql: include_str!("../migrations/026_scope_origin_id_by_sender.sql"),
+    },
+    Migration {
+        version: 27,
+        sql: include_str!("../migrations/027_add_messages_corrected.sql"),
+    },
+    Migration {
+        version: 28,
+        sql: include_str!("../migrations/028_index_conversation_list.sql"),
+    },
+    Migration {
+        version: 29,
+        sql: include_str!("../migrations/029_cascade_message_deletes.sql"),
+    },
+    Migration {
+        version: 30,
+        sql: include_str!("../migrations/030_add_offline_queue_sent_at.sql"),
+    },
+    Migration {
+        version: 31,
+        sql: include_str!("../migrations/031_record_deliveries_by_full_jid.sql"),
+    },
 ];
 
 #[cfg(feature = "native")]
@@ -429,36 +659,82 @@ fn run_migrations(connection: &Connection) -> Result<(), StorageError> {
 }
 
 #[cfg(feature = "native")]
-fn run_writer(path: PathBuf, receiver: Receiver<WriteCommand>) {
-    let mut state = match open_native_connection(&path) {
+fn run_writer(
+    path: PathBuf,
+    receiver: Receiver<WriteCommand>,
+    queue: Arc<QueueGauge>,
+) -> Result<(), StorageError> {
+    let state = match open_native_connection(&path) {
         Ok(connection) => WriterState::Ready(connection),
         Err(error) => WriterState::Failed(error.to_string()),
     };
+    serve_writer(path, state, receiver, queue)
+}
 
+#[cfg(feature = "native")]
+fn writer_connection<'a>(
+    path: &Path,
+    state: &'a WriterState,
+) -> Result<&'a Connection, StorageError> {
+    match state {
+        WriterState::Ready(connection) => Ok(connection),
+        WriterState::Failed(reason) => Err(StorageError::ConnectionFailed {
+            path: path.to_path_buf(),
+            reason: reason.clone(),
+        }),
+    }
+}
+
+#[cfg(feature = "native")]
+/// Runs commands until shutdown, then closes the connection and reports
+/// whether it closed cleanly.
+fn serve_writer(
+    path: PathBuf,
+    state: WriterState,
+    receiver: Receiver<WriteCommand>,
+    queue: Arc<QueueGauge>,
+) -> Result<(), StorageError> {
     while let Ok(command) = receiver.recv() {
+        queue.popped();
         match command {
             WriteCommand::Execute {
                 sql,
                 params,
+                slow_query,
                 response,
             } => {
-                let result = match &mut state {
-                    WriterState::Ready(connection) => execute_statement(connection, &sql, &params),
-                    WriterState::Failed(reason) => Err(StorageError::ConnectionFailed {
-                        path: path.clone(),
-                        reason: reason.clone(),
-                    }),
-                };
-
+                let result = writer_connection(&path, &state).and_then(|connection| {
+                    execute_statement(connection, &sql, &params, slow_query.as_ref())
+                });
                 let _ = response.send(result);
             }
+            WriteCommand::Query {
+                sql,
+                params,
+                slow_query,
+                max_rows,
+                response,
+            } => {
+                let result = writer_connection(&path, &state).and_then(|connection| {
+                    query_rows(connection, &sql, &params, slow_query.as_ref(), max_rows)
+                });
+                let _ = response.send(result);
+            }
+            WriteCommand::Shutdown => break,
         }
     }
+
+    match state {
+        WriterState::Ready(connection) => connection.close().map_err(|(_, error)| {
+            StorageError::QueryFailed(format!("failed to close database: {error}"))
+        }),
+        WriterState::Failed(reason) => Err(StorageError::ConnectionFailed { path, reason }),
+    }
 }
 
 #[cfg(feature = "native")]
 impl NativeDatabase {
-    async fn open(path: &Path) -> Result<Self, StorageError> {
+    async fn open(path: &Path, queue_depth: usize) -> Result<Self, StorageError> {
         let path = path.to_path_buf();
         let setup_path = path.clone();
 
@@ -473,18 +749,197 @@ impl NativeDatabase {
             reason: format!("failed to join native storage setup task: {error}"),
         })??;
 
-        let (writer, receiver) = mpsc::channel();
         let writer_path = path.clone();
+        Self::start_writer(path, false, queue_depth, move |receiver, queue| {
+            run_writer(writer_path, receiver, queue)
+        })
+    }
+
+    async fn open_in_memory() -> Result<Self, StorageError> {
+        let path = PathBuf::from(":memory:");
+        let setup_path = path.clone();
+
+        let connection = task::spawn_blocking(move || {
+            let connection =
+                Connection::open_in_memory().map_err(|error| StorageError::ConnectionFailed {
+                    path: setup_path.clone(),
+                    reason: error.to_string(),
+                })?;
+            connection
+                .pragma_update(None, "foreign_keys", "ON")
+                .map_err(|error| StorageError::ConnectionFailed {
+                    path: setup_path.clone(),
+                    reason: error.to_string(),
+                })?;
+            run_migrations(&connection)?;
+            Ok::<_, StorageError>(connection)
+        })
+        .await
+        .map_err(|error| StorageError::ConnectionFailed {
+            path: path.clone(),
+            reason: format!("failed to join native storage setup task: {error}"),
+        })??;
+
+        let writer_path = path.clone();
+        Self::start_writer(
+            path,
+            true,
+            DEFAULT_WRITER_QUEUE_DEPTH,
+            move |receiver, queue| {
+                serve_writer(writer_path, WriterState::Ready(connection), receiver, queue)
+            },
+        )
+    }
 
-        thread::Builder::new()
+    fn start_writer(
+        path: PathBuf,
+        in_memory: bool,
+        queue_depth: usize,
+        serve: impl FnOnce(Receiver<WriteCommand>, Arc<QueueGauge>) -> Result<(), StorageError>
+        + Send
+        + 'static,
+    ) -> Result<Self, StorageError> {
+        let (writer, receiver) = mpsc::sync_channel(queue_depth);
+        let queue = Arc::new(QueueGauge::default());
+        let writer_queue = Arc::clone(&queue);
+        let writer_thread = thread::Builder::new()
             .name("storage_writer".to_string())
-            .spawn(move || run_writer(writer_path, receiver))
+            .spawn(move || serve(receiver, writer_queue))
             .map_err(|error| StorageError::ConnectionFailed {
                 path: path.clone(),
                 reason: format!("failed to spawn storage_writer task: {error}"),
             })?;
 
-        Ok(Self { path, writer })
+        Ok(Self {
+            path,
+            in_memory,
+            writer,
+            queue,
+            slow_query_threshold: Mutex::new(None),
+            max_rows: Mutex::new(None),
+            writer_thread: Some(writer_thread),
+        })
+    }
+
+    /// Hands a command to the writer, waiting off the runtime for room when
+    /// the queue is full.
+    async fn send_command(&self, command: WriteCommand) -> Result<(), StorageError> {
+        let unavailable =
+            || StorageError::QueryFailed("storage writer task is unavailable".to_string());
+
+        match self.writer.try_send(command) {
+            Ok(()) => {}
+            Err(TrySendError::Disconnected(_)) => return Err(unavailable()),
+            Err(TrySendError::Full(command)) => {
+                let writer = self.writer.clone();
+                task::spawn_blocking(move || writer.send(command).map_err(|_| unavailable()))
+                    .await
+                    .map_err(|error| {
+                        StorageError::QueryFailed(format!(
+                            "failed to join storage writer send task: {error}"
+                        ))
+                    })??;
+            }
+        }
+
+        self.queue.pushed();
+        Ok(())
+    }
+
+    /// Logs, at debug, every statement that takes at least `threshold`;
+    /// `None` turns the timing off.
+    pub fn set_slow_query_threshold(&self, threshold: Option<Duration>) {
+        *self
+            .slow_query_threshold
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner()) = threshold;
+    }
+
+    /// Fails any query returning more than `max_rows` rows with
+    /// `QueryFailed("result too large")`, so an unbounded read cannot
+    /// exhaust memory; `None` lifts the cap.
+    pub fn set_max_rows(&self, max_rows: Option<usize>) {
+        *self
+            .max_rows
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner()) = max_rows;
+    }
+
+    fn max_rows(&self) -> Option<usize> {
+        *self
+            .max_rows
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    fn slow_query_log(&self) -> Option<SlowQueryLog> {
+        let threshold = *self
+            .slow_query_threshold
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        threshold.map(|threshold| SlowQueryLog {
+            threshold,
+            span: Span::current(),
+        })
+    }
+
+    /// Most commands ever waiting in the writer queue at once; bounded by the
+    /// configured depth plus the one the writer is taking.
+    pub fn peak_queued_commands(&self) -> usize {
+        self.queue.peak.load(Ordering::SeqCst).max(0) as usize
+    }
+
+    async fn query_on_writer(
+        &self,
+        sql: &str,
+        params: &[&dyn ToSql],
+    ) -> Result<Vec<Row>, StorageError> {
+        let (response_tx, response_rx) = oneshot::channel();
+        let command = WriteCommand::Query {
+            sql: sql.to_string(),
+            params: collect_params(params),
+            slow_query: self.slow_query_log(),
+            max_rows: self.max_rows(),
+            response: response_tx,
+        };
+
+        self.send_command(command).await?;
+
+        response_rx.await.map_err(|_| {
+            StorageError::QueryFailed(
+                "storage writer task terminated before responding".to_string(),
+            )
+        })?
+    }
+}
+
+#[cfg(feature = "native")]
+impl NativeDatabase {
+    /// Runs every write already issued, closes the connection and returns
+    /// any error from doing so. Dropping the handle does the same but
+    /// discards the result.
+    pub async fn close(mut self) -> Result<(), StorageError> {
+        let _ = self.send_command(WriteCommand::Shutdown).await;
+        let Some(writer_thread) = self.writer_thread.take() else {
+            return Ok(());
+        };
+
+        task::spawn_blocking(move || writer_thread.join())
+            .await
+            .map_err(|error| {
+                StorageError::QueryFailed(format!("failed to join close task: {error}"))
+            })?
+            .map_err(|_| StorageError::QueryFailed("storage writer task panicked".to_string()))?
+    }
+}
+
+#[cfg(feature = "native")]
+impl Drop for NativeDatabase {
+    fn drop(&mut self) {
+        let _ = self.writer.send(WriteCommand::Shutdown);
+        if let Some(writer_thread) = self.writer_thread.take() {
+            let _ = writer_thread.join();
+        }
     }
 }
 
@@ -495,12 +950,11 @@ impl Database for NativeDatabase {
         let command = WriteCommand::Execute {
             sql: sql.to_string(),
             params: collect_params(params),
+            slow_query: self.slow_query_log(),
             response: response_tx,
         };
 
-        self.writer.send(command).map_err(|_| {
-            StorageError::QueryFailed("storage writer task is unavailable".to_string())
-        })?;
+        self.send_command(command).await?;
 
         response_rx.await.map_err(|_| {
             StorageError::QueryFailed(
@@ -514,12 +968,23 @@ impl Database for NativeDatabase {
         sql: &str,
         params: &[&dyn ToSql],
     ) -> Result<Vec<T>, StorageError> {
+        if self.in_memory {
+            return self
+                .query_on_writer(sql, params)
+                .await?
+                .iter()
+                .map(T::from_row)
+                .collect();
+        }
+
         let sql = sql.to_string();
         let params = collect_params(params);
         let path = self.path.clone();
+        let slow_query = self.slow_query_log();
+        let max_rows = self.max_rows();
         let rows = task::spawn_blocking(move || {
             let connection = open_native_connection(&path)?;
-            query_rows(&connection, &sql, &params)
+            query_rows(&connection, &sql, &params, slow_query.as_ref(), max_rows)
         })
         .await
         .map_err(|error| {
@@ -630,14 +1095,165 @@ impl Database for WebDatabase {
     }
 }
 
+/// A failure [`MockDatabase`] can inject, with the message SQLite reports.
+#[cfg(any(test, feature = "test-util"))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MockFailure {
+    Busy,
+    Corrupt,
+    DiskFull,
+}
+
+#[cfg(any(test, feature = "test-util"))]
+impl MockFailure {
+    fn to_error(self) -> StorageError {
+        let reason = match self {
+            MockFailure::Busy => "database is locked",
+            MockFailure::Corrupt => "database disk image is malformed",
+            MockFailure::DiskFull => "database or disk is full",
+        };
+        StorageError::QueryFailed(reason.to_string())
+    }
+}
+
+#[cfg(any(test, feature = "test-util"))]
+#[derive(Debug, Default)]
+struct MockState {
+    /// (SQL substring, failure, statements left to fail)
+    failures: Vec<(String, MockFailure, u32)>,
+    /// (SQL substring, result sets returned to matching queries in order)
+    results: Vec<(String, std::collections::VecDeque<Vec<Row>>)>,
+    executed: Vec<(String, Vec<SqlValue>)>,
+}
+
+/// In-memory [`Database`] for manager unit tests. Statements run no SQL:
+/// `execute` records the statement and reports one affected row, `query`
+/// returns scripted rows, and any statement can be made to fail.
+#[cfg(any(test, feature = "test-util"))]
+#[derive(Debug, Default)]
+pub struct MockDatabase {
+    state: std::sync::Mutex<MockState>,
+}
+
+#[cfg(any(test, feature = "test-util"))]
+impl MockDatabase {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Fails the next `times` statements whose SQL contains `pattern`.
+    pub fn fail_matching(&self, pattern: &str, failure: MockFailure, times: u32) {
+        self.state
+            .lock()
+            .unwrap()
+            .failures
+            .push((pattern.to_string(), failure, times));
+    }
+
+    /// Queues `rows` as the result of the next query whose SQL contains
+    /// `pattern`. Unscripted queries return no rows.
+    pub fn push_rows(&self, pattern: &str, rows: Vec<Row>) {
+        let mut state = self.state.lock().unwrap();
+        match state.results.iter_mut().find(|(p, _)| p == pattern) {
+            Some((_, queue)) => queue.push_back(rows),
+            None => state.results.push((
+                pattern.to_string(),
+                std::collections::VecDeque::from([rows]),
+            )),
+        }
+    }
+
+    /// Every statement passed to `execute` that did not fail, with its
+    /// bound parameters.
+    pub fn executed(&self) -> Vec<(String, Vec<SqlValue>)> {
+        self.state.lock().unwrap().executed.clone()
+    }
+
+    fn injected_failure(state: &mut MockState, sql: &str) -> Option<StorageError> {
+        let (_, failure, remaining) = state
+            .failures
+            .iter_mut()
+            .find(|(pattern, _, remaining)| *remaining > 0 && sql.contains(pattern.as_str()))?;
+        *remaining -= 1;
+        Some(failure.to_error())
+    }
+
+    fn scripted_rows(&self, sql: &str) -> Result<Vec<Row>, StorageError> {
+        let mut state = self.state.lock().unwrap();
+        if let Some(error) = Self::injected_failure(&mut state, sql) {
+            return Err(error);
+        }
+        Ok(state
+            .results
+            .iter_mut()
+            .find(|(pattern, queue)| !queue.is_empty() && sql.contains(pattern.as_str()))
+            .and_then(|(_, queue)| queue.pop_front())
+            .unwrap_or_default())
+    }
+}
+
+#[cfg(any(test, feature = "test-util"))]
+impl Database for MockDatabase {
+    async fn execute(&self, sql: &str, params: &[&dyn ToSql]) -> Result<u64, StorageError> {
+        let mut state = self.state.lock().unwrap();
+        if let Some(error) = Self::injected_failure(&mut state, sql) {
+            return Err(error);
+        }
+        let values = params.iter().map(|param| param.to_sql_value()).collect();
+        state.executed.push((sql.to_string(), values));
+        Ok(1)
+    }
+
+    async fn query<T: FromRow>(
+        &self,
+        sql: &str,
+        _params: &[&dyn ToSql],
+    ) -> Result<Vec<T>, StorageError> {
+        self.scripted_rows(sql)?.iter().map(T::from_row).collect()
+    }
+
+    async fn query_one<T: FromRow>(
+        &self,
+        sql: &str,
+        _params: &[&dyn ToSql],
+    ) -> Result<T, StorageError> {
+        let rows = self.scripted_rows(sql)?;
+        T::from_row(rows.first().ok_or(StorageError::NotFound)?)
+    }
+
+    async fn transaction<F, R>(&self, f: F) -> Result<R, StorageError>
+    where
+        F: FnOnce(&Transaction) -> Result<R, StorageError> + Send,
+    {
+        f(&Transaction::default())
+    }
+}
+
 #[cfg(feature = "native")]
 pub async fn open_database(path: &Path) -> Result<impl Database + use<>, StorageError> {
-    NativeDatabase::open(path).await
+    NativeDatabase::open(path, DEFAULT_WRITER_QUEUE_DEPTH).await
 }
 
 #[cfg(feature = "native")]
 pub async fn open_native_database(path: &Path) -> Result<NativeDatabase, StorageError> {
-    NativeDatabase::open(path).await
+    NativeDatabase::open(path, DEFAULT_WRITER_QUEUE_DEPTH).await
+}
+
+/// Like [`open_native_database`], but `execute` waits once `queue_depth`
+/// commands are already queued for the writer.
+#[cfg(feature = "native")]
+pub async fn open_native_database_with_queue_depth(
+    path: &Path,
+    queue_depth: usize,
+) -> Result<NativeDatabase, StorageError> {
+    NativeDatabase::open(path, queue_depth).await
+}
+
+/// Opens a migrated database that lives only as long as the returned handle,
+/// for tests and ephemeral sessions.
+#[cfg(feature = "native")]
+pub async fn open_in_memory_database() -> Result<NativeDatabase, StorageError> {
+    NativeDatabase::open_in_memory().await
 }
 
 #[cfg(all(not(feature = "native"), feature = "web"))]
@@ -656,7 +1272,7 @@ mod tests {
     async fn open_temp_db() -> (NativeDatabase, TempDir) {
         let dir = TempDir::new().expect("failed to create temp dir");
         let db_path = dir.path().join("test.db");
-        let db = NativeDatabase::open(&db_path)
+        let db = open_native_database(&db_path)
             .await
             .expect("failed to open database");
         (db, dir)
@@ -729,7 +1345,298 @@ mod tests {
             })
             .collect();
 
-        assert_eq!(versions, vec![1, 2, 3]);
+        assert_eq!(
+            versions,
+            vec![
+                1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19, 20, 21, 22, 23,
+                24, 25, 26, 27, 28, 29, 30, 31
+            ]
+        );
+    }
+
+    #[test]
+    fn timestamp_migration_restores_chronological_order() {
+        let connection = Connection::open_in_memory().expect("failed to open in-memory db");
+        for migration in MIGRATIONS.iter().filter(|m| m.version < 10) {
+            connection
+                .execute_batch(migration.sql)
+                .expect("failed to apply earlier migration");
+        }
+
+        // 09:30Z, 10:00Z and 10:15Z, written with mixed offsets and precision
+        for (id, timestamp) in [
+            ("late", "2024-05-01T12:15:00+02:00"),
+            ("early", "2024-05-01T09:30:00.250Z"),
+            ("middle", "2024-05-01T10:00:00+00:00"),
+        ] {
+            connection
+                .execute(
+                    "INSERT INTO messages (id, from_jid, to_jid, body, timestamp, message_type) \
+                     VALUES (?1, 'a@example.com', 'b@example.com', 'x', ?2, 'chat')",
+                    params![id, timestamp],
+                )
+                .expect("failed to insert message");
+        }
+
+        let migration = MIGRATIONS.iter().find(|m| m.version == 10).unwrap();
+        connection
+            .execute_batch(migration.sql)
+            .expect("failed to normalize timestamps");
+
+        let mut statement = connection
+            .prepare("SELECT id, timestamp FROM messages ORDER BY timestamp")
+            .unwrap();
+        let rows: Vec<(String, String)> = statement
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(
+            rows,
+            vec![
+                ("early".to_string(), "2024-05-01T09:30:00.250Z".to_string()),
+                ("middle".to_string(), "2024-05-01T10:00:00.000Z".to_string()),
+                ("late".to_string(), "2024-05-01T10:15:00.000Z".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn timestamp_millis_ignores_offset_notation() {
+        assert_eq!(
+            timestamp_millis("2024-05-01T12:15:00.5+02:00"),
+            timestamp_millis("2024-05-01T10:15:00.500Z")
+        );
+        assert_eq!(timestamp_millis("1970-01-01T00:00:01Z"), Some(1000));
+        assert_eq!(timestamp_millis("not a timestamp"), None);
+    }
+
+    #[test]
+    fn timestamp_ms_migration_backfills_existing_rows() {
+        let connection = Connection::open_in_memory().expect("failed to open in-memory db");
+        for migration in MIGRATIONS.iter().filter(|m| m.version < 11) {
+            connection
+                .execute_batch(migration.sql)
+                .expect("failed to apply earlier migration");
+        }
+        connection
+            .execute(
+                "INSERT INTO messages (id, from_jid, to_jid, body, timestamp, message_type) \
+                 VALUES ('m', 'a@example.com', 'b@example.com', 'x', '2024-05-01T10:15:00.250Z', 'chat')",
+                [],
+            )
+            .expect("failed to insert message");
+
+        let migration = MIGRATIONS.iter().find(|m| m.version == 11).unwrap();
+        connection
+            .execute_batch(migration.sql)
+            .expect("failed to add timestamp_ms");
+
+        let millis: i64 = connection
+            .query_row(
+                "SELECT timestamp_ms FROM messages WHERE id = 'm'",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(Some(millis), timestamp_millis("2024-05-01T10:15:00.250Z"));
+    }
+
+    #[test]
+    fn delivery_migration_qualifies_resources_with_the_recipient() {
+        let connection = Connection::open_in_memory().expect("failed to open in-memory db");
+        for migration in MIGRATIONS.iter().filter(|m| m.version < 31) {
+            connection
+                .execute_batch(migration.sql)
+                .expect("failed to apply earlier migration");
+        }
+        connection
+            .execute_batch(
+                "INSERT INTO messages (id, from_jid, to_jid, body, timestamp, message_type) \
+                 VALUES ('m', 'a@example.com', 'b@example.com', 'x', '2024-05-01T10:15:00.250Z', 'chat'); \
+                 INSERT INTO message_deliveries (message_id, resource, delivered_at) \
+                 VALUES ('m', 'phone', '2024-05-01T10:15:01.000Z');",
+            )
+            .expect("failed to insert delivery");
+
+        let migration = MIGRATIONS.iter().find(|m| m.version == 31).unwrap();
+        connection
+            .execute_batch(migration.sql)
+            .expect("failed to migrate deliveries");
+
+        let delivered_to: String = connection
+            .query_row(
+                "SELECT delivered_to FROM message_deliveries WHERE message_id = 'm'",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(delivered_to, "b@example.com/phone");
+    }
+
+    #[tokio::test]
+    async fn in_memory_database_has_all_tables() {
+        let (file_db, _dir) = open_temp_db().await;
+        let memory_db = open_in_memory_database()
+            .await
+            .expect("failed to open in-memory database");
+
+        let sql = "SELECT name FROM sqlite_master WHERE type IN ('table', 'index') \
+                   AND name NOT LIKE 'sqlite_%' ORDER BY name";
+        let file_schema: Vec<Row> = file_db.query(sql, &[]).await.unwrap();
+        let memory_schema: Vec<Row> = memory_db.query(sql, &[]).await.unwrap();
+        assert!(!memory_schema.is_empty());
+        assert_eq!(memory_schema, file_schema);
+
+        let id = s("m-1");
+        memory_db
+            .execute(
+                "INSERT INTO messages (id, from_jid, to_jid, body, timestamp, message_type) \
+                 VALUES (?1, 'a@example.com', 'b@example.com', 'hi', '2025-01-01T00:00:00.000Z', 'chat')",
+                &[&id],
+            )
+            .await
+            .unwrap();
+        let rows: Vec<Row> = memory_db
+            .query("SELECT id FROM messages", &[])
+            .await
+            .unwrap();
+        assert_eq!(rows, vec![Row::new(vec![SqlValue::Text(id)])]);
+    }
+
+    #[tokio::test]
+    async fn drop_closes_writer_connection_before_returning() {
+        let dir = TempDir::new().expect("failed to create temp dir");
+        let db_path = dir.path().join("test.db");
+
+        let db = open_native_database(&db_path).await.expect("open failed");
+        let key = s("k");
+        db.execute(
+            "INSERT INTO plugin_kv (plugin_id, key, value) VALUES ('p', ?1, x'00')",
+            &[&key],
+        )
+        .await
+        .unwrap();
+        assert!(dir.path().join("test.db-wal").exists());
+        drop(db);
+
+        // The last connection to close checkpoints and removes the WAL.
+        assert!(!dir.path().join("test.db-wal").exists());
+        let reopened = open_native_database(&db_path).await.expect("reopen failed");
+        let rows: Vec<Row> = reopened
+            .query("SELECT key FROM plugin_kv", &[])
+            .await
+            .unwrap();
+        assert_eq!(rows.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn close_flushes_just_issued_write() {
+        let dir = TempDir::new().expect("failed to create temp dir");
+        let db_path = dir.path().join("test.db");
+        let db = open_native_database(&db_path).await.expect("open failed");
+
+        let key = s("pending");
+        {
+            let params: [&dyn ToSql; 1] = [&key];
+            let write = db.execute(
+                "INSERT INTO plugin_kv (plugin_id, key, value) VALUES ('p', ?1, x'00')",
+                &params,
+            );
+            let mut write = std::pin::pin!(write);
+            // Poll once so the write is queued, then stop waiting for it.
+            tokio::select! {
+                biased;
+                _ = &mut write => {}
+                _ = std::future::ready(()) => {}
+            }
+        }
+
+        db.close().await.expect("close failed");
+
+        let reopened = open_native_database(&db_path).await.expect("reopen failed");
+        let rows: Vec<Row> = reopened
+            .query("SELECT key FROM plugin_kv", &[])
+            .await
+            .unwrap();
+        assert_eq!(rows, vec![Row::new(vec![SqlValue::Text(key)])]);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn concurrent_writes_wait_for_bounded_queue() {
+        let dir = TempDir::new().expect("failed to create temp dir");
+        let db_path = dir.path().join("test.db");
+        let db = Arc::new(
+            open_native_database_with_queue_depth(&db_path, 2)
+                .await
+                .expect("open failed"),
+        );
+
+        let writes: Vec<_> = (0..64)
+            .map(|index| {
+                let db = Arc::clone(&db);
+                tokio::spawn(async move {
+                    let key = format!("key-{index}");
+                    db.execute(
+                        "INSERT INTO plugin_kv (plugin_id, key, value) VALUES ('p', ?1, x'00')",
+                        &[&key],
+                    )
+                    .await
+                })
+            })
+            .collect();
+        for write in writes {
+            assert_eq!(write.await.unwrap().unwrap(), 1);
+        }
+
+        let rows: Vec<Row> = db.query("SELECT key FROM plugin_kv", &[]).await.unwrap();
+        assert_eq!(rows.len(), 64);
+        assert!(db.peak_queued_commands() <= 3);
+    }
+
+    #[tokio::test]
+    #[tracing_test::traced_test]
+    async fn slow_query_threshold_logs_statement_and_duration() {
+        let (db, _dir) = open_temp_db().await;
+
+        db.query::<Row>("SELECT 'untimed'", &[]).await.unwrap();
+        assert!(!logs_contain("slow query"));
+
+        db.set_slow_query_threshold(Some(Duration::ZERO));
+        db.query::<Row>("SELECT 'timed'", &[]).await.unwrap();
+
+        assert!(logs_contain("slow query"));
+        assert!(logs_contain("SELECT 'timed'"));
+        assert!(logs_contain("elapsed_us"));
+    }
+
+    #[tokio::test]
+    async fn query_past_max_rows_fails_instead_of_collecting() {
+        let (db, _dir) = open_temp_db().await;
+        for i in 0..5_i64 {
+            let key = format!("key-{i}");
+            db.execute(
+                "INSERT INTO plugin_kv (plugin_id, key, value) VALUES ('p', ?1, 'v')",
+                &[&key],
+            )
+            .await
+            .unwrap();
+        }
+
+        db.set_max_rows(Some(5));
+        let rows: Vec<Row> = db.query("SELECT key FROM plugin_kv", &[]).await.unwrap();
+        assert_eq!(rows.len(), 5);
+
+        db.set_max_rows(Some(4));
+        let result = db.query::<Row>("SELECT key FROM plugin_kv", &[]).await;
+        assert!(
+            matches!(result, Err(StorageError::QueryFailed(ref reason)) if reason == "result too large")
+        );
+        let rows: Vec<Row> = db
+            .query("SELECT key FROM plugin_kv LIMIT 4", &[])
+            .await
+            .unwrap();
+        assert_eq!(rows.len(), 4);
     }
 
     #[tokio::test]
@@ -737,14 +1644,12 @@ mod tests {
         let dir = TempDir::new().expect("failed to create temp dir");
         let db_path = dir.path().join("test.db");
 
-        let _db1 = NativeDatabase::open(&db_path)
+        let _db1 = open_native_database(&db_path)
             .await
             .expect("first open failed");
         drop(_db1);
 
-        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
-
-        let db2 = NativeDatabase::open(&db_path)
+        let db2 = open_native_database(&db_path)
             .await
             .expect("second open failed");
 
@@ -763,7 +1668,10 @@ mod tests {
 
         assert_eq!(
             versions,
-            vec![1, 2, 3],
+            vec![
+                1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19, 20, 21, 22, 23,
+                24, 25, 26, 27, 28, 29, 30, 31
+            ],
             "migrations should not duplicate on re-open"
         );
     }
@@ -791,6 +1699,7 @@ mod tests {
         assert!(index_names.contains(&"idx_messages_from"));
         assert!(index_names.contains(&"idx_messages_to"));
         assert!(index_names.contains(&"idx_messages_timestamp"));
+        assert!(index_names.contains(&"idx_messages_room_timeline"));
         assert!(index_names.contains(&"idx_offline_queue_status"));
     }
 
@@ -1426,4 +2335,54 @@ mod tests {
         assert_eq!(row.get(0), Some(&SqlValue::Text(s("stanza-2"))));
         assert_eq!(row.get(1), Some(&SqlValue::Text(s("2025-01-02T00:00:00Z"))));
     }
+
+    #[tokio::test]
+    async fn mock_database_injects_failures_a_limited_number_of_times() {
+        let db = MockDatabase::new();
+        db.fail_matching("INTO messages", MockFailure::DiskFull, 2);
+
+        let id = s("m-1");
+        for _ in 0..2 {
+            let err = db
+                .execute("INSERT INTO messages (id) VALUES (?1)", &[&id])
+                .await
+                .unwrap_err();
+            assert!(
+                matches!(err, StorageError::QueryFailed(ref reason) if reason.contains("full"))
+            );
+        }
+        db.execute("INSERT INTO roster (jid) VALUES (?1)", &[&id])
+            .await
+            .unwrap();
+        db.execute("INSERT INTO messages (id) VALUES (?1)", &[&id])
+            .await
+            .unwrap();
+
+        let executed = db.executed();
+        assert_eq!(executed.len(), 2);
+        assert_eq!(executed[1].1, vec![SqlValue::Text(s("m-1"))]);
+    }
+
+    #[tokio::test]
+    async fn mock_database_returns_scripted_rows_in_order() {
+        let db = MockDatabase::new();
+        db.push_rows(
+            "FROM roster",
+            vec![Row::new(vec![SqlValue::Text(s("alice"))])],
+        );
+        db.push_rows("FROM roster", vec![]);
+        db.fail_matching("FROM messages", MockFailure::Busy, 1);
+
+        let first: Vec<Row> = db.query("SELECT jid FROM roster", &[]).await.unwrap();
+        assert_eq!(first.len(), 1);
+        let second: Vec<Row> = db.query("SELECT jid FROM roster", &[]).await.unwrap();
+        assert!(second.is_empty());
+        let missing = db.query_one::<Row>("SELECT jid FROM roster", &[]).await;
+        assert!(matches!(missing, Err(StorageError::NotFound)));
+
+        let busy = db.query::<Row>("SELECT id FROM messages", &[]).await;
+        assert!(
+            matches!(busy, Err(StorageError::QueryFailed(ref reason)) if reason.contains("locked"))
+        );
+    }
 }
@@ -0,0 +1,844 @@
+use std::sync::Arc;
+
+use sha2::{Digest, Sha256};
+use waddle_core::event::{ChatMessage, MessageSource};
+
+use crate::{Database, Row, SqlValue, StorageError, format_timestamp};
+
+/// An archive copy and a live copy with the same [`content_key`] are the
+/// same message when sent at most this many milliseconds apart.
+const CONTENT_MATCH_WINDOW_MS: i64 = 2_000;
+
+/// Digest of the type, sender, recipient and body, for matching copies of a
+/// message without an origin-id. `None` when a copy lacks the fields to key
+/// on, such as our own send before the router fills in `from`.
+fn content_key(message: &ChatMessage) -> Option<String> {
+    if message.from.is_empty() || message.to.is_empty() || message.body.is_empty() {
+        return None;
+    }
+
+    let bare = |jid: &str| jid.split('/').next().unwrap_or(jid).to_string();
+    // An occupant is told apart by nick, so groupchat senders keep theirs.
+    let from = if message.message_type.is_groupchat() {
+        message.from.clone()
+    } else {
+        bare(&message.from)
+    };
+
+    let mut digest = Sha256::new();
+    for part in [
+        message.message_type.as_str(),
+        &from,
+        &bare(&message.to),
+        &message.body,
+    ] {
+        digest.update(part.as_bytes());
+        digest.update([0]);
+    }
+    Some(format!("{:x}", digest.finalize()))
+}
+
+/// Who `message`'s origin-id belongs to, since XEP-0359 ids are only unique
+/// per sender: the occupant JID in a room, the bare JID otherwise. `None`
+/// for our own direct send before the router fills in `from`.
+fn origin_sender(message: &ChatMessage) -> Option<String> {
+    let from = message.from.trim();
+    if from.is_empty() {
+        return None;
+    }
+    if message.message_type.is_groupchat() {
+        return Some(from.to_string());
+    }
+    Some(from.split('/').next().unwrap_or(from).to_string())
+}
+
+/// What an arriving copy does to the stored copy of the same message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ConflictPolicy {
+    /// The first copy stored wins; later copies only fill in blanks.
+    #[default]
+    Ignore,
+    /// The arriving copy is authoritative: its body and send time replace
+    /// the stored ones, e.g. the server's timestamp from the archive. Only a
+    /// copy from the stored message's sender counts, a retracted message
+    /// stays a tombstone and a corrected body stays corrected.
+    ReplaceNewer,
+}
+
+/// Columns an authoritative copy overwrites, bound to the replace flag `?15`
+/// and only when it comes from the stored message's sender; our own send
+/// has no sender until a copy names us. Shared by both conflict targets of
+/// the upsert.
+const AUTHORITATIVE_COLUMNS: &str = "\
+    body = CASE \
+        WHEN ?15 = 1 AND (messages.origin_sender IS NULL OR messages.origin_sender = excluded.origin_sender) \
+            AND messages.retracted = 0 AND messages.corrected = 0 THEN excluded.body \
+        ELSE messages.body \
+    END, \
+    timestamp = CASE \
+        WHEN ?15 = 1 AND (messages.origin_sender IS NULL OR messages.origin_sender = excluded.origin_sender) \
+            THEN excluded.timestamp \
+        ELSE messages.timestamp \
+    END, \
+    timestamp_ms = CASE \
+        WHEN ?15 = 1 AND (messages.origin_sender IS NULL OR messages.origin_sender = excluded.origin_sender) \
+            THEN excluded.timestamp_ms \
+        ELSE messages.timestamp_ms \
+    END, \
+    bodies = CASE \
+        WHEN ?15 = 1 AND (messages.origin_sender IS NULL OR messages.origin_sender = excluded.origin_sender) \
+            AND messages.retracted = 0 AND messages.corrected = 0 \
+            THEN COALESCE(excluded.bodies, messages.bodies) \
+        ELSE COALESCE(messages.bodies, excluded.bodies) \
+    END";
+
+/// Writes chat messages to the `messages` table. Every manager that stores
+/// messages goes through here, so the insert exists once.
+pub struct MessageStore<D: Database> {
+    db: Arc<D>,
+}
+
+impl<D: Database> MessageStore<D> {
+    pub fn new(db: Arc<D>) -> Self {
+        Self { db }
+    }
+
+    /// Inserts `message`, or merges it into the stored copy with the same id:
+    /// empty or shorter JIDs, threads and embeds are filled in from the new
+    /// copy, as is a missing room or archive stanza-id, while the body, its
+    /// language variants and the first `source` are kept. Also advances the
+    /// conversation's `last_activity`.
+    ///
+    /// A copy stored under another id is merged into it when the same sender
+    /// gave both the same origin-id, which a unique index enforces, and
+    /// dropped when it has the same [`content_key`] and came the other way
+    /// (archive vs live) within [`CONTENT_MATCH_WINDOW_MS`]. Identical
+    /// messages that both arrived live stay apart, as do different senders'
+    /// messages that happen to share an origin-id.
+    pub async fn persist(
+        &self,
+        message: &ChatMessage,
+        source: MessageSource,
+    ) -> Result<(), StorageError> {
+        self.persist_message_with(message, source, ConflictPolicy::Ignore)
+            .await
+    }
+
+    /// Like [`Self::persist`], with `conflict` deciding whether a copy
+    /// already stored under the same id or origin-id keeps its body and send
+    /// time.
+    pub async fn persist_message_with(
+        &self,
+        message: &ChatMessage,
+        source: MessageSource,
+        conflict: ConflictPolicy,
+    ) -> Result<(), StorageError> {
+        let mut id = message.id.clone();
+        let from = message.from.clone();
+        let to = message.to.clone();
+        let body = message.body.clone();
+        let ts = format_timestamp(&message.timestamp);
+        let ts_ms = message.timestamp.timestamp_millis();
+        let mt = message.message_type.as_str().to_string();
+        let thread = message.thread.clone();
+        let read = 0_i64;
+        let from_archive = source == MessageSource::Mam;
+        let source = source.as_str().to_string();
+
+        let embeds = if message.embeds.is_empty() {
+            None
+        } else {
+            Some(serde_json::to_string(&message.embeds).unwrap_or_default())
+        };
+        let bodies = if message.bodies.is_empty() {
+            None
+        } else {
+            Some(serde_json::to_string(&message.bodies).unwrap_or_default())
+        };
+        let replace = i64::from(conflict == ConflictPolicy::ReplaceNewer);
+        let origin_id = message.origin_id.clone().filter(|id| !id.is_empty());
+        let origin_sender = origin_sender(message);
+        let spoiler = message.spoiler.clone();
+        let stanza_id = message.stanza_id.clone().filter(|id| !id.is_empty());
+        let content_key = content_key(message);
+        if let Some(content_key) = &content_key
+            && let Some(stored_id) = self
+                .copy_from_other_path(&id, content_key, ts_ms, from_archive)
+                .await?
+        {
+            if conflict == ConflictPolicy::ReplaceNewer {
+                self.db
+                    .execute(
+                        "UPDATE messages SET timestamp = ?1, timestamp_ms = ?2 WHERE id = ?3",
+                        &[&ts, &ts_ms, &stored_id],
+                    )
+                    .await?;
+            }
+            if stanza_id.is_some() {
+                self.db
+                    .execute(
+                        "UPDATE messages SET stanza_id = COALESCE(stanza_id, ?1) WHERE id = ?2",
+                        &[&stanza_id, &stored_id],
+                    )
+                    .await?;
+            }
+            return Ok(());
+        }
+        if let Some(origin_id) = &origin_id
+            && let Some(stored_id) = self
+                .copy_with_origin_id(&id, origin_id, origin_sender.as_deref(), message)
+                .await?
+        {
+            id = stored_id;
+        }
+
+        let sql = format!(
+            "INSERT INTO messages (id, from_jid, to_jid, body, timestamp, message_type, thread, read, embeds, timestamp_ms, source, bodies, origin_id, content_key, spoiler, stanza_id, origin_sender) \
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?16, ?17, ?18) \
+             ON CONFLICT(id) DO UPDATE SET {AUTHORITATIVE_COLUMNS}, \
+             from_jid = CASE \
+                WHEN TRIM(COALESCE(excluded.from_jid, '')) = '' THEN messages.from_jid \
+                WHEN TRIM(COALESCE(messages.from_jid, '')) = '' THEN excluded.from_jid \
+                WHEN LENGTH(excluded.from_jid) > LENGTH(messages.from_jid) THEN excluded.from_jid \
+                ELSE messages.from_jid \
+             END, \
+             to_jid = CASE \
+                WHEN TRIM(COALESCE(excluded.to_jid, '')) = '' THEN messages.to_jid \
+                WHEN TRIM(COALESCE(messages.to_jid, '')) = '' THEN excluded.to_jid \
+                WHEN LENGTH(excluded.to_jid) > LENGTH(messages.to_jid) THEN excluded.to_jid \
+                ELSE messages.to_jid \
+             END, \
+             thread = CASE \
+                WHEN TRIM(COALESCE(excluded.thread, '')) = '' THEN messages.thread \
+                WHEN TRIM(COALESCE(messages.thread, '')) = '' THEN excluded.thread \
+                WHEN LENGTH(COALESCE(excluded.thread, '')) > LENGTH(COALESCE(messages.thread, '')) THEN excluded.thread \
+                ELSE messages.thread \
+             END, \
+             embeds = CASE \
+                WHEN excluded.embeds IS NULL OR TRIM(excluded.embeds) = '' OR excluded.embeds = '[]' THEN messages.embeds \
+                WHEN messages.embeds IS NULL OR TRIM(messages.embeds) = '' OR messages.embeds = '[]' THEN excluded.embeds \
+                WHEN LENGTH(excluded.embeds) > LENGTH(COALESCE(messages.embeds, '')) THEN excluded.embeds \
+                ELSE messages.embeds \
+             END, \
+             origin_id = COALESCE(messages.origin_id, excluded.origin_id), \
+             content_key = COALESCE(messages.content_key, excluded.content_key), \
+             spoiler = COALESCE(messages.spoiler, excluded.spoiler), \
+             stanza_id = COALESCE(messages.stanza_id, excluded.stanza_id), \
+             origin_sender = COALESCE(messages.origin_sender, excluded.origin_sender) \
+             ON CONFLICT(origin_sender, origin_id) \
+                WHERE origin_id IS NOT NULL AND origin_sender IS NOT NULL \
+             DO UPDATE SET {AUTHORITATIVE_COLUMNS}, \
+             stanza_id = COALESCE(messages.stanza_id, excluded.stanza_id)"
+        );
+        self.db
+            .execute(
+                &sql,
+                &[
+                    &id,
+                    &from,
+                    &to,
+                    &body,
+                    &ts,
+                    &mt,
+                    &thread,
+                    &read,
+                    &embeds,
+                    &ts_ms,
+                    &source,
+                    &bodies,
+                    &origin_id,
+                    &content_key,
+                    &replace,
+                    &spoiler,
+                    &stanza_id,
+                    &origin_sender,
+                ],
+            )
+            .await?;
+
+        self.record_activity(message, ts_ms).await
+    }
+
+    /// The id of a copy with `origin_id` stored under another id by the same
+    /// sender. A copy whose sender is not known yet, our own direct send,
+    /// matches on its recipient instead; rooms always name the sender.
+    async fn copy_with_origin_id(
+        &self,
+        id: &str,
+        origin_id: &str,
+        origin_sender: Option<&str>,
+        message: &ChatMessage,
+    ) -> Result<Option<String>, StorageError> {
+        let id = id.to_string();
+        let origin_id = origin_id.to_string();
+        let origin_sender = origin_sender.map(str::to_string);
+        let to = message.to.clone();
+        let bare_to = to.split('/').next().unwrap_or(&to).to_string();
+        let own_match = i64::from(!message.message_type.is_groupchat());
+        let rows: Vec<Row> = self
+            .db
+            .query(
+                "SELECT id FROM messages \
+                 WHERE origin_id = ?1 AND id != ?2 \
+                   AND (origin_sender = ?3 \
+                        OR (?6 = 1 AND (?3 IS NULL OR origin_sender IS NULL) \
+                            AND to_jid IN (?4, ?5))) \
+                 LIMIT 1",
+                &[&origin_id, &id, &origin_sender, &to, &bare_to, &own_match],
+            )
+            .await?;
+        Ok(rows.first().and_then(|row| match row.get(0) {
+            Some(SqlValue::Text(id)) => Some(id.clone()),
+            _ => None,
+        }))
+    }
+
+    /// The id of a copy with `content_key` stored under another id, sent
+    /// within the match window of `ts_ms` and received the other way: live
+    /// for an archive copy, from the archive otherwise.
+    async fn copy_from_other_path(
+        &self,
+        id: &str,
+        content_key: &str,
+        ts_ms: i64,
+        from_archive: bool,
+    ) -> Result<Option<String>, StorageError> {
+        let id = id.to_string();
+        let content_key = content_key.to_string();
+        let from_archive = i64::from(from_archive);
+        let window = CONTENT_MATCH_WINDOW_MS;
+        let rows: Vec<Row> = self
+            .db
+            .query(
+                "SELECT id FROM messages \
+                 WHERE content_key = ?1 AND id != ?2 \
+                   AND (COALESCE(source, '') = 'mam') != ?3 \
+                   AND ABS(timestamp_ms - ?4) <= ?5 \
+                 LIMIT 1",
+                &[&content_key, &id, &from_archive, &ts_ms, &window],
+            )
+            .await?;
+        Ok(rows.first().and_then(|row| match row.get(0) {
+            Some(SqlValue::Text(id)) => Some(id.clone()),
+            _ => None,
+        }))
+    }
+
+    /// Moves `last_activity` forward to `ts_ms` for the room a groupchat
+    /// message went to, or for both ends of a direct message. Our own JID
+    /// gets a row too, but no conversation lists us as its peer.
+    async fn record_activity(&self, message: &ChatMessage, ts_ms: i64) -> Result<(), StorageError> {
+        if message.message_type.is_groupchat() {
+            let room = message.to.clone();
+            self.db
+                .execute(
+                    "UPDATE muc_rooms SET last_activity = MAX(COALESCE(last_activity, 0), ?2) \
+                     WHERE room_jid = ?1",
+                    &[&room, &ts_ms],
+                )
+                .await?;
+            return Ok(());
+        }
+
+        for jid in [&message.from, &message.to] {
+            if jid.is_empty() {
+                continue;
+            }
+            self.db
+                .execute(
+                    "INSERT INTO conversation_meta (jid, last_activity) VALUES (?1, ?2) \
+                     ON CONFLICT(jid) DO UPDATE SET last_activity = \
+                        MAX(COALESCE(conversation_meta.last_activity, 0), excluded.last_activity)",
+                    &[jid, &ts_ms],
+                )
+                .await?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(all(test, feature = "native"))]
+mod tests {
+    use super::*;
+    use crate::{Row, SqlValue, open_database};
+    use chrono::Utc;
+    use std::collections::BTreeMap;
+    use tempfile::TempDir;
+    use waddle_core::event::MessageType;
+
+    #[tokio::test]
+    async fn persist_dedups_and_round_trips() {
+        let dir = TempDir::new().unwrap();
+        let db = Arc::new(open_database(&dir.path().join("test.db")).await.unwrap());
+        let store = MessageStore::new(db.clone());
+
+        let mut message = ChatMessage {
+            id: "m-1".to_string(),
+            from: "alice@example.com".to_string(),
+            to: "bob@example.com".to_string(),
+            body: "Hello".to_string(),
+            timestamp: Utc::now(),
+            message_type: MessageType::Chat,
+            thread: None,
+            embeds: vec![],
+            bodies: BTreeMap::new(),
+            origin_id: None,
+            spoiler: None,
+            stanza_id: None,
+        };
+        store.persist(&message, MessageSource::Mam).await.unwrap();
+        message.from = "alice@example.com/phone".to_string();
+        message.thread = Some("t-1".to_string());
+        store.persist(&message, MessageSource::Live).await.unwrap();
+
+        let rows: Vec<Row> = db
+            .query(
+                "SELECT from_jid, to_jid, body, timestamp, message_type, thread, source \
+                 FROM messages",
+                &[],
+            )
+            .await
+            .unwrap();
+        assert_eq!(rows.len(), 1);
+        let text = |i: usize| match rows[0].get(i) {
+            Some(SqlValue::Text(value)) => value.clone(),
+            other => panic!("column {i} is not text: {other:?}"),
+        };
+        assert_eq!(text(0), "alice@example.com/phone");
+        assert_eq!(text(1), "bob@example.com");
+        assert_eq!(text(2), "Hello");
+        assert_eq!(text(3), format_timestamp(&message.timestamp));
+        assert_eq!(text(4), "chat");
+        assert_eq!(text(5), "t-1");
+        assert_eq!(text(6), "mam");
+    }
+
+    fn colliding_copy(body: &str, secs: i64) -> ChatMessage {
+        ChatMessage {
+            id: "m-1".to_string(),
+            from: "bob@example.com".to_string(),
+            to: "alice@example.com".to_string(),
+            body: body.to_string(),
+            timestamp: chrono::DateTime::from_timestamp(1_700_000_000 + secs, 0).unwrap(),
+            message_type: MessageType::Chat,
+            thread: None,
+            embeds: vec![],
+            bodies: BTreeMap::new(),
+            origin_id: None,
+            spoiler: None,
+            stanza_id: None,
+        }
+    }
+
+    async fn stored_body_and_time<D: Database>(db: &D) -> (SqlValue, SqlValue) {
+        let rows: Vec<Row> = db
+            .query("SELECT body, timestamp_ms FROM messages", &[])
+            .await
+            .unwrap();
+        assert_eq!(rows.len(), 1);
+        (
+            rows[0].get(0).cloned().unwrap(),
+            rows[0].get(1).cloned().unwrap(),
+        )
+    }
+
+    #[tokio::test]
+    async fn ignore_policy_keeps_the_first_copy() {
+        let dir = TempDir::new().unwrap();
+        let db = Arc::new(open_database(&dir.path().join("test.db")).await.unwrap());
+        let store = MessageStore::new(db.clone());
+
+        let first = colliding_copy("Helo", 0);
+        store.persist(&first, MessageSource::Live).await.unwrap();
+        store
+            .persist_message_with(
+                &colliding_copy("Hello", 5),
+                MessageSource::Mam,
+                ConflictPolicy::Ignore,
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(
+            stored_body_and_time(db.as_ref()).await,
+            (
+                SqlValue::Text("Helo".to_string()),
+                SqlValue::Integer(first.timestamp.timestamp_millis())
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn replace_newer_policy_takes_the_arriving_copy() {
+        let dir = TempDir::new().unwrap();
+        let db = Arc::new(open_database(&dir.path().join("test.db")).await.unwrap());
+        let store = MessageStore::new(db.clone());
+
+        store
+            .persist(&colliding_copy("Helo", 0), MessageSource::Live)
+            .await
+            .unwrap();
+        let authoritative = colliding_copy("Hello", 5);
+        store
+            .persist_message_with(
+                &authoritative,
+                MessageSource::Mam,
+                ConflictPolicy::ReplaceNewer,
+            )
+            .await
+            .unwrap();
+
+        let stamp = SqlValue::Integer(authoritative.timestamp.timestamp_millis());
+        assert_eq!(
+            stored_body_and_time(db.as_ref()).await,
+            (SqlValue::Text("Hello".to_string()), stamp.clone())
+        );
+
+        // A retracted message is not brought back.
+        db.execute(
+            "UPDATE messages SET body = '', retracted = 1 WHERE id = 'm-1'",
+            &[],
+        )
+        .await
+        .unwrap();
+        store
+            .persist_message_with(
+                &authoritative,
+                MessageSource::Mam,
+                ConflictPolicy::ReplaceNewer,
+            )
+            .await
+            .unwrap();
+        assert_eq!(
+            stored_body_and_time(db.as_ref()).await,
+            (SqlValue::Text(String::new()), stamp)
+        );
+    }
+
+    #[tokio::test]
+    async fn replace_newer_only_trusts_the_same_sender() {
+        let dir = TempDir::new().unwrap();
+        let db = Arc::new(open_database(&dir.path().join("test.db")).await.unwrap());
+        let store = MessageStore::new(db.clone());
+
+        let original = colliding_copy("Hello", 0);
+        store.persist(&original, MessageSource::Live).await.unwrap();
+        let mut forged = colliding_copy("Send me your password", 5);
+        forged.from = "mallory@example.com".to_string();
+        forged.origin_id = Some("m-1".to_string());
+        store
+            .persist_message_with(&forged, MessageSource::Mam, ConflictPolicy::ReplaceNewer)
+            .await
+            .unwrap();
+
+        let stamp = SqlValue::Integer(original.timestamp.timestamp_millis());
+        assert_eq!(
+            stored_body_and_time(db.as_ref()).await,
+            (SqlValue::Text("Hello".to_string()), stamp)
+        );
+    }
+
+    #[tokio::test]
+    async fn replace_newer_keeps_a_corrected_body() {
+        let dir = TempDir::new().unwrap();
+        let db = Arc::new(open_database(&dir.path().join("test.db")).await.unwrap());
+        let store = MessageStore::new(db.clone());
+
+        store
+            .persist(&colliding_copy("Helo", 0), MessageSource::Live)
+            .await
+            .unwrap();
+        db.execute(
+            "UPDATE messages SET body = 'Hello', corrected = 1 WHERE id = 'm-1'",
+            &[],
+        )
+        .await
+        .unwrap();
+        let archived = colliding_copy("Helo", 5);
+        store
+            .persist_message_with(&archived, MessageSource::Mam, ConflictPolicy::ReplaceNewer)
+            .await
+            .unwrap();
+
+        // The server's time still wins; the correction is not undone.
+        assert_eq!(
+            stored_body_and_time(db.as_ref()).await,
+            (
+                SqlValue::Text("Hello".to_string()),
+                SqlValue::Integer(archived.timestamp.timestamp_millis())
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn copies_under_different_ids_collapse_onto_one_row() {
+        let dir = TempDir::new().unwrap();
+        let db = Arc::new(open_database(&dir.path().join("test.db")).await.unwrap());
+        let store = MessageStore::new(db.clone());
+
+        let at = |ms: i64| chrono::DateTime::from_timestamp_millis(1_700_000_000_000 + ms).unwrap();
+        let message =
+            |id: &str, from: &str, body: &str, ms: i64, origin_id: Option<&str>| ChatMessage {
+                id: id.to_string(),
+                from: from.to_string(),
+                to: "alice@example.com".to_string(),
+                body: body.to_string(),
+                timestamp: at(ms),
+                message_type: MessageType::Chat,
+                thread: None,
+                embeds: vec![],
+                bodies: BTreeMap::new(),
+                origin_id: origin_id.map(str::to_string),
+                spoiler: None,
+                stanza_id: None,
+            };
+
+        for (copy, source) in [
+            // Same origin-id: the archive copy goes, however far off its time.
+            (
+                message("live-1", "bob@example.com", "Hi", 0, Some("o-1")),
+                MessageSource::Live,
+            ),
+            (
+                message("mam-1", "bob@example.com/phone", "Hi", 90_000, Some("o-1")),
+                MessageSource::Mam,
+            ),
+            // No origin-id: the archive copy of a live message goes...
+            (
+                message("live-2", "bob@example.com/phone", "Lunch?", 20_000, None),
+                MessageSource::Live,
+            ),
+            (
+                message("mam-2", "bob@example.com", "Lunch?", 21_500, None),
+                MessageSource::Mam,
+            ),
+            // ...but the same text sent again is another message.
+            (
+                message("live-3", "bob@example.com/phone", "Lunch?", 20_800, None),
+                MessageSource::Live,
+            ),
+            (
+                message("mam-3", "bob@example.com", "Lunch?", 45_000, None),
+                MessageSource::Mam,
+            ),
+        ] {
+            store.persist(&copy, source).await.unwrap();
+        }
+
+        let rows: Vec<Row> = db
+            .query("SELECT id FROM messages ORDER BY timestamp_ms", &[])
+            .await
+            .unwrap();
+        let ids: Vec<SqlValue> = rows
+            .iter()
+            .map(|row| row.get(0).cloned().unwrap())
+            .collect();
+        let text = |value: &str| SqlValue::Text(value.to_string());
+        assert_eq!(
+            ids,
+            vec![
+                text("live-1"),
+                text("live-2"),
+                text("live-3"),
+                text("mam-3")
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn origin_ids_only_collide_for_the_same_sender() {
+        let dir = TempDir::new().unwrap();
+        let db = Arc::new(open_database(&dir.path().join("test.db")).await.unwrap());
+        let store = MessageStore::new(db.clone());
+
+        let message = |id: &str, from: &str, to: &str, body: &str| ChatMessage {
+            id: id.to_string(),
+            from: from.to_string(),
+            to: to.to_string(),
+            body: body.to_string(),
+            timestamp: Utc::now(),
+            message_type: MessageType::Chat,
+            thread: None,
+            embeds: vec![],
+            bodies: BTreeMap::new(),
+            origin_id: Some("shared".to_string()),
+            spoiler: None,
+            stanza_id: None,
+        };
+
+        for (copy, source) in [
+            (
+                message(
+                    "bob-1",
+                    "bob@example.com/phone",
+                    "me@example.com",
+                    "From Bob",
+                ),
+                MessageSource::Live,
+            ),
+            (
+                message(
+                    "carol-1",
+                    "carol@example.com/desk",
+                    "me@example.com",
+                    "From Carol",
+                ),
+                MessageSource::Live,
+            ),
+            // Our own send, stored before the router knows our JID...
+            (
+                message("mine-1", "", "bob@example.com", "From me"),
+                MessageSource::Local,
+            ),
+            // ...merges with its archive copy, which names us.
+            (
+                message(
+                    "mam-1",
+                    "me@example.com/laptop",
+                    "bob@example.com",
+                    "From me",
+                ),
+                MessageSource::Mam,
+            ),
+            // Bob's archived copy still lands on Bob's row.
+            (
+                message("mam-2", "bob@example.com", "me@example.com", "From Bob"),
+                MessageSource::Mam,
+            ),
+        ] {
+            store
+                .persist_message_with(&copy, source, ConflictPolicy::ReplaceNewer)
+                .await
+                .unwrap();
+        }
+
+        let rows: Vec<Row> = db
+            .query("SELECT id, from_jid, body FROM messages ORDER BY id", &[])
+            .await
+            .unwrap();
+        let stored: Vec<(SqlValue, SqlValue, SqlValue)> = rows
+            .iter()
+            .map(|row| {
+                (
+                    row.get(0).cloned().unwrap(),
+                    row.get(1).cloned().unwrap(),
+                    row.get(2).cloned().unwrap(),
+                )
+            })
+            .collect();
+        let text = |value: &str| SqlValue::Text(value.to_string());
+        assert_eq!(
+            stored,
+            vec![
+                (
+                    text("bob-1"),
+                    text("bob@example.com/phone"),
+                    text("From Bob")
+                ),
+                (
+                    text("carol-1"),
+                    text("carol@example.com/desk"),
+                    text("From Carol")
+                ),
+                (
+                    text("mine-1"),
+                    text("me@example.com/laptop"),
+                    text("From me")
+                ),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn last_activity_tracks_newest_message() {
+        let dir = TempDir::new().unwrap();
+        let db = Arc::new(open_database(&dir.path().join("test.db")).await.unwrap());
+        let store = MessageStore::new(db.clone());
+        db.execute(
+            "INSERT INTO muc_rooms (room_jid, nick, joined) VALUES ('room@conference.example.com', 'me', 1)",
+            &[],
+        )
+        .await
+        .unwrap();
+
+        let at = |secs: i64| chrono::DateTime::from_timestamp(1_700_000_000 + secs, 0).unwrap();
+        let message = |id: &str, from: &str, to: &str, secs: i64, message_type| ChatMessage {
+            id: id.to_string(),
+            from: from.to_string(),
+            to: to.to_string(),
+            body: "hi".to_string(),
+            timestamp: at(secs),
+            message_type,
+            thread: None,
+            embeds: vec![],
+            bodies: BTreeMap::new(),
+            origin_id: None,
+            spoiler: None,
+            stanza_id: None,
+        };
+        // Arrives out of order, as MAM backfill does.
+        for msg in [
+            message("d-1", "bob@example.com", "", 20, MessageType::Chat),
+            message("d-2", "", "bob@example.com", 10, MessageType::Chat),
+            message(
+                "r-1",
+                "room@conference.example.com/ann",
+                "room@conference.example.com",
+                5,
+                MessageType::Groupchat,
+            ),
+            message(
+                "r-2",
+                "room@conference.example.com/ann",
+                "room@conference.example.com",
+                30,
+                MessageType::Groupchat,
+            ),
+            message(
+                "r-3",
+                "room@conference.example.com/ann",
+                "room@conference.example.com",
+                15,
+                MessageType::Groupchat,
+            ),
+        ] {
+            store.persist(&msg, MessageSource::Live).await.unwrap();
+        }
+
+        let single = |sql: &'static str| {
+            let db = db.clone();
+            async move {
+                let rows: Vec<Row> = db.query(sql, &[]).await.unwrap();
+                rows[0].get(0).cloned()
+            }
+        };
+        let bob_max = single(
+            "SELECT MAX(timestamp_ms) FROM messages \
+             WHERE from_jid = 'bob@example.com' OR to_jid = 'bob@example.com'",
+        )
+        .await;
+        assert_eq!(bob_max, Some(SqlValue::Integer(at(20).timestamp_millis())));
+        assert_eq!(
+            single("SELECT last_activity FROM conversation_meta WHERE jid = 'bob@example.com'")
+                .await,
+            bob_max
+        );
+        assert_eq!(
+            single("SELECT last_activity FROM muc_rooms").await,
+            Some(SqlValue::Integer(at(30).timestamp_millis()))
+        );
+
+        store
+            .persist(
+                &message("d-3", "bob@example.com", "", 40, MessageType::Chat),
+                MessageSource::Live,
+            )
+            .await
+            .unwrap();
+        assert_eq!(
+            single("SELECT last_activity FROM conversation_meta WHERE jid = 'bob@example.com'")
+                .await,
+            Some(SqlValue::Integer(at(40).timestamp_millis()))
+        );
+    }
+}
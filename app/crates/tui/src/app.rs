@@ -150,6 +150,7 @@ fn handle_command(
             EventPayload::PresenceSetRequested {
                 show: show.clone(),
                 status: non_empty_string(tail),
+                priority: 0,
             },
         )?;
 
@@ -184,6 +185,7 @@ fn handle_command(
                 EventPayload::PresenceSetRequested {
                     show: show.clone(),
                     status: non_empty_string(status_tail),
+                    priority: 0,
                 },
             )?;
 
@@ -337,7 +339,7 @@ fn command_help_text(state: &AppState) -> String {
 
 fn handle_bus_event(state: &mut AppState, event: Event) {
     match event.payload {
-        EventPayload::RosterReceived { items } => {
+        EventPayload::RosterReceived { items, .. } => {
             state.roster = items
                 .into_iter()
                 .map(|item| RosterEntry {
@@ -347,7 +349,7 @@ fn handle_bus_event(state: &mut AppState, event: Event) {
                 })
                 .collect();
         }
-        EventPayload::RosterUpdated { item } => {
+        EventPayload::RosterUpdated { item, .. } => {
             if let Some(entry) = state.roster.iter_mut().find(|e| e.item.jid == item.jid) {
                 entry.item = item;
             } else {
@@ -358,7 +360,7 @@ fn handle_bus_event(state: &mut AppState, event: Event) {
                 });
             }
         }
-        EventPayload::RosterRemoved { jid } => {
+        EventPayload::RosterRemoved { jid, .. } => {
             state.roster.retain(|e| e.item.jid != jid);
             if state.sidebar_index >= state.sidebar_items_count() && state.sidebar_index > 0 {
                 state.sidebar_index -= 1;
@@ -410,7 +412,7 @@ fn handle_bus_event(state: &mut AppState, event: Event) {
                 });
             }
         }
-        EventPayload::MucLeft { room } => {
+        EventPayload::MucLeft { room, .. } | EventPayload::MucDestroyed { room, .. } => {
             state.rooms.retain(|r| r.jid != room);
             if state.active_conversation.as_deref() == Some(&room) {
                 state.active_conversation = None;
@@ -531,6 +533,7 @@ mod tests {
             EventPayload::PresenceSetRequested {
                 show: PresenceShow::Away,
                 status: Some(status),
+                ..
             } if status == "in a meeting"
         ));
     }
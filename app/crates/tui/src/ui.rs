@@ -330,15 +330,18 @@ fn draw_conversation(frame: &mut Frame, state: &AppState, area: Rect, palette: &
             let visible_lines: Vec<Line> = lines.into_iter().skip(skip).collect();
 
             let mut typing_lines = visible_lines;
-            if let Some(chat_state) = &conversation.remote_chat_state {
-                if matches!(chat_state, waddle_core::event::ChatState::Composing) {
-                    typing_lines.push(Line::from(Span::styled(
-                        state.i18n.t("chatstate-typing", None),
-                        Style::default()
-                            .fg(palette.muted)
-                            .add_modifier(Modifier::ITALIC),
-                    )));
-                }
+            let chat_state_key = match &conversation.remote_chat_state {
+                Some(waddle_core::event::ChatState::Composing) => Some("chatstate-typing"),
+                Some(waddle_core::event::ChatState::Gone) => Some("chatstate-gone"),
+                _ => None,
+            };
+            if let Some(key) = chat_state_key {
+                typing_lines.push(Line::from(Span::styled(
+                    state.i18n.t(key, None),
+                    Style::default()
+                        .fg(palette.muted)
+                        .add_modifier(Modifier::ITALIC),
+                )));
             }
 
             let paragraph = Paragraph::new(typing_lines)
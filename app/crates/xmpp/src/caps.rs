@@ -0,0 +1,127 @@
+use std::collections::BTreeSet;
+
+use xmpp_parsers::caps::{self, Caps};
+use xmpp_parsers::disco::{DiscoInfoResult, Feature, Identity};
+use xmpp_parsers::hashes::Algo;
+use xmpp_parsers::minidom::Element;
+use xmpp_parsers::ns;
+
+const CAPS_NODE: &str = "https://waddle.social";
+
+/// Features we support regardless of which PEP nodes we follow.
+const FEATURES: &[&str] = &[
+    ns::DISCO_INFO,
+    ns::CAPS,
+    ns::CHATSTATES,
+    ns::MESSAGE_CORRECT,
+    ns::MUC,
+];
+
+/// Our XEP-0115 entity capabilities. Servers deliver a contact's PEP items
+/// only to clients advertising `<node>+notify`, so the PEP nodes someone
+/// subscribed to locally are part of what we advertise.
+#[derive(Debug, Default)]
+pub struct ClientCaps {
+    notify_nodes: BTreeSet<String>,
+}
+
+impl ClientCaps {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Replaces the PEP nodes we ask notifications for. Returns whether the
+    /// advertised capabilities changed.
+    pub fn set_notify_nodes(&mut self, nodes: impl IntoIterator<Item = String>) -> bool {
+        let nodes: BTreeSet<String> = nodes.into_iter().collect();
+        if nodes == self.notify_nodes {
+            return false;
+        }
+        self.notify_nodes = nodes;
+        true
+    }
+
+    /// The disco#info answer for a query on `node`, or `None` when `node`
+    /// is neither absent nor our current caps node.
+    pub fn disco_info(&self, node: Option<&str>) -> Option<DiscoInfoResult> {
+        let mut info = self.info();
+        match node {
+            None => {}
+            Some(node) if node == self.caps_node() => info.node = Some(node.to_string()),
+            Some(_) => return None,
+        }
+        Some(info)
+    }
+
+    /// The `<c/>` element to attach to our available presence.
+    pub fn caps(&self) -> Caps {
+        let hash = caps::hash_caps(&caps::compute_disco(&self.info()), Algo::Sha_1)
+            .expect("sha-1 is supported");
+        Caps::new(CAPS_NODE, hash)
+    }
+
+    fn caps_node(&self) -> String {
+        let caps = Element::from(self.caps());
+        format!(
+            "{}#{}",
+            caps.attr("node").unwrap_or_default(),
+            caps.attr("ver").unwrap_or_default()
+        )
+    }
+
+    fn info(&self) -> DiscoInfoResult {
+        let mut features: Vec<Feature> = FEATURES.iter().map(|var| Feature::new(*var)).collect();
+        features.extend(
+            self.notify_nodes
+                .iter()
+                .map(|node| Feature::new(format!("{node}+notify"))),
+        );
+        DiscoInfoResult {
+            node: None,
+            identities: vec![Identity::new("client", "pc", "en", "Waddle")],
+            features,
+            extensions: vec![],
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NS_MOOD: &str = "http://jabber.org/protocol/mood";
+
+    fn features(info: &DiscoInfoResult) -> Vec<&str> {
+        info.features.iter().map(|f| f.var.as_str()).collect()
+    }
+
+    #[test]
+    fn notify_nodes_are_advertised_as_features() {
+        let mut caps = ClientCaps::new();
+        let before = Element::from(caps.caps()).attr("ver").map(str::to_string);
+
+        assert!(caps.set_notify_nodes([NS_MOOD.to_string()]));
+        assert!(!caps.set_notify_nodes([NS_MOOD.to_string()]));
+
+        let info = caps.disco_info(None).unwrap();
+        assert!(features(&info).contains(&"http://jabber.org/protocol/mood+notify"));
+        let after = Element::from(caps.caps()).attr("ver").map(str::to_string);
+        assert_ne!(before, after);
+    }
+
+    #[test]
+    fn answers_only_for_the_current_caps_node() {
+        let mut caps = ClientCaps::new();
+        caps.set_notify_nodes([NS_MOOD.to_string()]);
+        let node = caps.caps_node();
+
+        let info = caps.disco_info(Some(&node)).unwrap();
+        assert_eq!(info.node.as_deref(), Some(node.as_str()));
+        assert!(features(&info).contains(&"http://jabber.org/protocol/mood+notify"));
+
+        assert!(
+            caps.disco_info(Some("https://waddle.social#stale"))
+                .is_none()
+        );
+    }
+}
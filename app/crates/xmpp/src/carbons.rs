@@ -2,6 +2,8 @@ use std::str::FromStr;
 
 use xmpp_parsers::minidom::Element;
 
+use crate::forwarded::Forwarded;
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
 pub enum CarbonsState {
     #[default]
@@ -123,18 +125,11 @@ impl CarbonsManager {
                 _ => continue,
             };
 
-            let forwarded = child
-                .children()
-                .find(|c| c.name() == "forwarded" && c.ns() == "urn:xmpp:forward:0")?;
-
-            let inner_message = forwarded.children().find(|c| c.name() == "message")?;
-
-            let mut payload = Vec::new();
-            inner_message.write_to(&mut payload).ok()?;
+            let forwarded = child.children().find_map(Forwarded::parse)?;
 
             return Some(UnwrappedCarbon {
                 direction,
-                forwarded_stanza: payload,
+                forwarded,
             });
         }
 
@@ -157,7 +152,7 @@ pub enum CarbonDirection {
 #[derive(Debug, Clone, PartialEq)]
 pub struct UnwrappedCarbon {
     pub direction: CarbonDirection,
-    pub forwarded_stanza: Vec<u8>,
+    pub forwarded: Forwarded,
 }
 
 const CARBONS_ENABLE_IQ_ID: &str = "carbons-enable";
@@ -387,9 +382,16 @@ mod tests {
         let unwrapped = result.unwrap();
         assert_eq!(unwrapped.direction, CarbonDirection::Received);
 
-        let inner = String::from_utf8(unwrapped.forwarded_stanza).unwrap();
-        assert!(inner.contains("bob@example.com"));
-        assert!(inner.contains("Hello"));
+        let inner = &unwrapped.forwarded.message;
+        assert_eq!(
+            inner.from.as_ref().map(|jid| jid.to_string()),
+            Some("bob@example.com".to_string())
+        );
+        assert_eq!(
+            inner.get_best_body(vec![]).map(|(_, body)| body.as_str()),
+            Some("Hello")
+        );
+        assert_eq!(unwrapped.forwarded.delay, None);
     }
 
     #[test]
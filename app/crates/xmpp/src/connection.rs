@@ -13,6 +13,7 @@ use crate::{
     },
     transport::XmppTransport,
 };
+use waddle_core::retry::RetryPolicy;
 
 #[cfg(feature = "native")]
 use waddle_core::event::{Channel, Event, EventBus, EventPayload, EventSource};
@@ -92,6 +93,8 @@ where
         loop {
             match T::connect(&self.config).await {
                 Ok(mut transport) => {
+                    #[cfg(feature = "native")]
+                    let resuming = self.is_resuming();
                     if transport.supports_stream_management() {
                         if let Err(error) = self.bootstrap_stream_management(&mut transport).await {
                             self.stream_manager.on_connect_attempt_failed();
@@ -108,7 +111,10 @@ where
                     self.state = ConnectionState::Connected;
                     self.bootstrap_csi().await;
                     #[cfg(feature = "native")]
-                    self.emit_connection_established();
+                    {
+                        self.emit_connection_established();
+                        self.emit_if_resume_failed(resuming);
+                    }
                     return Ok(());
                 }
                 Err(error) => {
@@ -220,8 +226,12 @@ where
             return Ok(false);
         };
 
-        let actions = self.stream_manager.process_nonza(nonza)?;
-        self.apply_stream_management_actions(actions).await?;
+        #[cfg(feature = "native")]
+        let resuming = self.is_resuming();
+        let actions = self.stream_manager.process_nonza(nonza);
+        #[cfg(feature = "native")]
+        self.emit_if_resume_failed(resuming);
+        self.apply_stream_management_actions(actions?).await?;
         Ok(true)
     }
 
@@ -367,32 +377,66 @@ where
     }
 
     fn reconnect_delay(attempt: u32) -> Duration {
-        let shift = attempt.saturating_sub(1);
-        let seconds = 1_u64.checked_shl(shift).unwrap_or(u64::MAX).clamp(
-            Self::INITIAL_RECONNECT_DELAY_SECONDS,
-            Self::MAX_RECONNECT_DELAY_SECONDS,
-        );
-        Duration::from_secs(seconds)
+        RetryPolicy {
+            retries: u32::MAX,
+            initial_delay: Duration::from_secs(Self::INITIAL_RECONNECT_DELAY_SECONDS),
+            max_delay: Duration::from_secs(Self::MAX_RECONNECT_DELAY_SECONDS),
+        }
+        .delay_for(attempt)
     }
 
+    /// Announces the session under the JID the server bound, which carries
+    /// our resource, falling back to the configured JID.
     #[cfg(feature = "native")]
     fn emit_connection_established(&self) {
+        let jid = self
+            .transport
+            .as_ref()
+            .and_then(|transport| transport.bound_jid())
+            .unwrap_or(&self.config.jid)
+            .to_string();
         self.emit_event(
             "system.connection.established",
-            EventPayload::ConnectionEstablished {
-                jid: self.config.jid.clone(),
-            },
+            EventPayload::ConnectionEstablished { jid },
         );
     }
 
     #[cfg(feature = "native")]
     fn emit_connection_lost(&self, reason: String, will_retry: bool) {
+        let resumable =
+            will_retry && self.stream_manager.state() == StreamManagementState::Resuming;
         self.emit_event(
             "system.connection.lost",
-            EventPayload::ConnectionLost { reason, will_retry },
+            EventPayload::ConnectionLost {
+                reason,
+                will_retry,
+                resumable,
+            },
         );
     }
 
+    #[cfg(feature = "native")]
+    fn is_resuming(&self) -> bool {
+        self.stream_manager.state() == StreamManagementState::Resuming
+    }
+
+    /// Announces `StreamResumeFailed` when a resume that was under way
+    /// (`was_resuming`) ended without the stream being resumed.
+    #[cfg(feature = "native")]
+    fn emit_if_resume_failed(&self, was_resuming: bool) {
+        if was_resuming
+            && !matches!(
+                self.stream_manager.state(),
+                StreamManagementState::Resuming | StreamManagementState::Enabled
+            )
+        {
+            self.emit_event(
+                "system.connection.resume_failed",
+                EventPayload::StreamResumeFailed,
+            );
+        }
+    }
+
     #[cfg(feature = "native")]
     fn emit_connection_reconnecting(&self, attempt: u32) {
         self.emit_event(
@@ -538,6 +582,7 @@ mod native_tests {
         connect_calls: u32,
         close_calls: u32,
         sent_payloads: Vec<String>,
+        bound_jid: Option<String>,
     }
 
     fn transport_state() -> &'static Mutex<TestTransportState> {
@@ -558,6 +603,14 @@ mod native_tests {
         state.connect_calls = 0;
         state.close_calls = 0;
         state.sent_payloads.clear();
+        state.bound_jid = None;
+    }
+
+    fn bind_transport_to(jid: &str) {
+        transport_state()
+            .lock()
+            .expect("failed to lock transport state")
+            .bound_jid = Some(jid.to_string());
     }
 
     fn connect_calls() -> u32 {
@@ -600,7 +653,9 @@ mod native_tests {
         }
     }
 
-    struct TestTransport;
+    struct TestTransport {
+        bound_jid: Option<String>,
+    }
 
     impl XmppTransport for TestTransport {
         async fn connect(_config: &ConnectionConfig) -> Result<Self, ConnectionError> {
@@ -609,7 +664,9 @@ mod native_tests {
                 .expect("failed to lock transport state");
             state.connect_calls += 1;
             match state.connect_outcomes.pop_front().unwrap_or(Ok(())) {
-                Ok(()) => Ok(Self),
+                Ok(()) => Ok(Self {
+                    bound_jid: state.bound_jid.clone(),
+                }),
                 Err(error) => Err(error),
             }
         }
@@ -639,6 +696,35 @@ mod native_tests {
         fn supports_stream_management(&self) -> bool {
             true
         }
+
+        fn bound_jid(&self) -> Option<&str> {
+            self.bound_jid.as_deref()
+        }
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn established_event_carries_the_bound_resource() {
+        let _guard = test_lock().lock().await;
+        configure_transport(vec![Ok(())]);
+        bind_transport_to("alice@example.com/waddle-4f2a");
+
+        let event_bus: Arc<dyn EventBus> = Arc::new(BroadcastEventBus::new(16));
+        let mut established = event_bus
+            .subscribe("system.connection.established")
+            .expect("failed to subscribe established events");
+
+        let mut manager =
+            ConnectionManager::<TestTransport>::with_event_bus(config(0), event_bus.clone());
+        manager.connect().await.expect("connect should succeed");
+
+        let event = time::timeout(Duration::from_millis(100), established.recv())
+            .await
+            .expect("timed out waiting for established event")
+            .expect("failed to receive established event");
+        assert!(matches!(
+            event.payload,
+            EventPayload::ConnectionEstablished { jid } if jid == "alice@example.com/waddle-4f2a"
+        ));
     }
 
     #[tokio::test(flavor = "current_thread")]
@@ -795,6 +881,9 @@ mod native_tests {
         configure_transport(vec![Ok(()), Ok(())]);
 
         let event_bus: Arc<dyn EventBus> = Arc::new(BroadcastEventBus::new(16));
+        let mut lost = event_bus
+            .subscribe("system.connection.lost")
+            .expect("failed to subscribe lost events");
         let mut manager =
             ConnectionManager::<TestTransport>::with_event_bus(config(0), event_bus.clone());
         manager.connect().await.expect("connect should succeed");
@@ -820,6 +909,19 @@ mod native_tests {
             .await
             .expect("recovery should reconnect");
 
+        let lost_event = time::timeout(Duration::from_millis(100), lost.recv())
+            .await
+            .expect("timed out waiting for lost event")
+            .expect("failed to receive lost event");
+        assert!(matches!(
+            lost_event.payload,
+            EventPayload::ConnectionLost {
+                will_retry: true,
+                resumable: true,
+                ..
+            }
+        ));
+
         assert_eq!(connect_calls(), 2);
         let resume = nonzas_sent()
             .into_iter()
@@ -855,6 +957,47 @@ mod native_tests {
         assert_eq!(two_count, 2);
     }
 
+    #[tokio::test(flavor = "current_thread")]
+    async fn refused_resumption_is_announced() {
+        let _guard = test_lock().lock().await;
+        configure_transport(vec![Ok(()), Ok(())]);
+
+        let event_bus: Arc<dyn EventBus> = Arc::new(BroadcastEventBus::new(16));
+        let mut resume_failed = event_bus
+            .subscribe("system.connection.resume_failed")
+            .expect("failed to subscribe resume failed events");
+        let mut manager =
+            ConnectionManager::<TestTransport>::with_event_bus(config(0), event_bus.clone());
+        manager.connect().await.expect("connect should succeed");
+        manager
+            .handle_stream_management_frame(
+                br#"<enabled xmlns='urn:xmpp:sm:3' id='stream-1' resume='true'/>"#,
+            )
+            .await
+            .expect("failed to process stream-management enabled response");
+        manager
+            .recover_after_network_interruption("network lost".to_string())
+            .await
+            .expect("recovery should reconnect");
+        assert!(
+            time::timeout(Duration::from_millis(50), resume_failed.recv())
+                .await
+                .is_err(),
+            "the resume is still pending"
+        );
+
+        let refused = manager
+            .handle_stream_management_frame(br#"<failed xmlns='urn:xmpp:sm:3' h='0'/>"#)
+            .await;
+        assert!(refused.is_err());
+
+        let event = time::timeout(Duration::from_millis(100), resume_failed.recv())
+            .await
+            .expect("timed out waiting for resume failed event")
+            .expect("failed to receive resume failed event");
+        assert!(matches!(event.payload, EventPayload::StreamResumeFailed));
+    }
+
     #[tokio::test(flavor = "current_thread")]
     async fn disconnect_closes_transport_and_emits_lost_without_retry() {
         let _guard = test_lock().lock().await;
@@ -0,0 +1,101 @@
+use chrono::{DateTime, Utc};
+use xmpp_parsers::forwarding;
+use xmpp_parsers::message::Message;
+use xmpp_parsers::minidom::Element;
+
+/// A message wrapped in a XEP-0297 `<forwarded/>`, as carried by carbons
+/// and MAM results.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Forwarded {
+    /// When the message was originally sent, from the wrapper's `<delay/>`
+    pub delay: Option<DateTime<Utc>>,
+    pub message: Message,
+}
+
+impl Forwarded {
+    /// Parses a `<forwarded xmlns='urn:xmpp:forward:0'/>` element.
+    pub fn parse(element: &Element) -> Option<Self> {
+        forwarding::Forwarded::try_from(element.clone())
+            .ok()
+            .map(Self::from)
+    }
+
+    /// The original send time, or now when the forwarder did not record one.
+    pub fn timestamp(&self) -> DateTime<Utc> {
+        self.delay.unwrap_or_else(Utc::now)
+    }
+}
+
+impl From<forwarding::Forwarded> for Forwarded {
+    fn from(forwarded: forwarding::Forwarded) -> Self {
+        Self {
+            delay: forwarded.delay.map(|delay| delay.stamp.0.to_utc()),
+            message: forwarded.message,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn element(xml: &str) -> Element {
+        xml.parse().unwrap()
+    }
+
+    #[test]
+    fn parses_forwarded_with_delay() {
+        let forwarded = Forwarded::parse(&element(
+            "<forwarded xmlns='urn:xmpp:forward:0'>\
+                <delay xmlns='urn:xmpp:delay' stamp='2024-03-01T09:30:00+01:00'/>\
+                <message xmlns='jabber:client' from='bob@example.com/phone' \
+                    to='alice@example.com' type='chat' id='fwd-1'>\
+                    <body>Forward me</body>\
+                </message>\
+            </forwarded>",
+        ))
+        .unwrap();
+
+        let expected = "2024-03-01T08:30:00Z".parse::<DateTime<Utc>>().unwrap();
+        assert_eq!(forwarded.delay, Some(expected));
+        assert_eq!(forwarded.timestamp(), expected);
+        assert_eq!(
+            forwarded.message.from.as_ref().map(|jid| jid.to_string()),
+            Some("bob@example.com/phone".to_string())
+        );
+        assert_eq!(
+            forwarded
+                .message
+                .get_best_body(vec![])
+                .map(|(_, body)| body.as_str()),
+            Some("Forward me")
+        );
+    }
+
+    #[test]
+    fn parses_forwarded_without_delay() {
+        let forwarded = Forwarded::parse(&element(
+            "<forwarded xmlns='urn:xmpp:forward:0'>\
+                <message xmlns='jabber:client' to='bob@example.com' type='chat'>\
+                    <body>No stamp</body>\
+                </message>\
+            </forwarded>",
+        ))
+        .unwrap();
+
+        assert_eq!(forwarded.delay, None);
+    }
+
+    #[test]
+    fn rejects_other_elements() {
+        assert!(
+            Forwarded::parse(&element(
+                "<received xmlns='urn:xmpp:carbons:2'>\
+                    <message xmlns='jabber:client'/>\
+                </received>"
+            ))
+            .is_none()
+        );
+        assert!(Forwarded::parse(&element("<forwarded xmlns='urn:xmpp:forward:0'/>")).is_none());
+    }
+}
@@ -1,8 +1,12 @@
+pub mod caps;
 pub mod carbons;
 pub mod connection;
 pub mod csi;
 pub mod error;
+pub mod forwarded;
 pub mod outbound;
+#[cfg(feature = "native")]
+pub mod pep;
 pub mod pipeline;
 pub mod processors;
 pub mod sasl;
@@ -10,21 +14,25 @@ pub mod stanza;
 pub mod stream_management;
 pub mod transport;
 
+pub use caps::ClientCaps;
 pub use carbons::{CarbonDirection, CarbonsManager, CarbonsState, UnwrappedCarbon};
 pub use connection::{ConnectionConfig, ConnectionManager, ConnectionState};
 pub use csi::{ClientState, CsiManager};
 pub use error::{ConnectionError, PipelineError};
+pub use forwarded::Forwarded;
 pub use outbound::{OutboundRouter, OutboundRouterError};
 #[cfg(feature = "native")]
 pub use outbound::{StanzaReceiver, StanzaSender, stanza_channel};
+#[cfg(feature = "native")]
+pub use pep::{PepError, PepItem, PepManager};
 pub use pipeline::{
     ProcessorContext, ProcessorResult, StanzaDirection, StanzaPipeline, StanzaProcessor,
 };
 #[cfg(debug_assertions)]
 pub use processors::DebugProcessor;
 pub use processors::{
-    ChatStateProcessor, MamProcessor, MessageProcessor, MucProcessor, PresenceProcessor,
-    RosterProcessor,
+    ChatStateProcessor, DiscoProcessor, MamProcessor, MessageProcessor, MucProcessor, PepProcessor,
+    PresenceProcessor, RosterProcessor,
 };
 pub use sasl::SelectedMechanism;
 pub use stanza::{Stanza, parse_stanza, serialize_stanza};
@@ -1,14 +1,19 @@
 use std::sync::Arc;
 
+#[cfg(feature = "native")]
+use std::collections::BTreeMap;
+#[cfg(feature = "native")]
+use std::sync::Mutex;
 #[cfg(feature = "native")]
 use std::sync::atomic::{AtomicBool, Ordering};
 
 #[cfg(feature = "native")]
 use tokio::sync::mpsc;
-use tracing::{debug, error, warn};
+use tracing::{debug, error, field, instrument, warn};
 use uuid::Uuid;
 use xmpp_parsers::chatstates::ChatState as XmppChatState;
 use xmpp_parsers::data_forms::{DataForm, DataFormType, Field};
+use xmpp_parsers::disco::{DiscoInfoQuery, DiscoInfoResult};
 use xmpp_parsers::iq::Iq;
 use xmpp_parsers::jid;
 use xmpp_parsers::mam;
@@ -17,15 +22,18 @@ use xmpp_parsers::minidom::Element;
 use xmpp_parsers::presence::{Presence, Show, Type as PresenceType};
 use xmpp_parsers::roster;
 use xmpp_parsers::rsm;
+use xmpp_parsers::stanza_error::{DefinedCondition, ErrorType, StanzaError};
+use xmpp_parsers::stanza_id::OriginId;
 
 use waddle_core::event::{
     ChatMessage, ChatState as CoreChatState, Event, EventPayload, EventSource,
-    MessageType as CoreMessageType, PresenceShow as CorePresenceShow,
+    MessageType as CoreMessageType, MucAffiliation, PresenceShow as CorePresenceShow,
 };
 
 #[cfg(feature = "native")]
 use waddle_core::event::{Channel, EventBus};
 
+use crate::caps::ClientCaps;
 use crate::pipeline::StanzaPipeline;
 use crate::stanza::Stanza;
 
@@ -42,6 +50,14 @@ pub fn stanza_channel(buffer: usize) -> (StanzaSender, StanzaReceiver) {
 
 #[cfg(feature = "native")]
 const OFFLINE_DRAIN_SOURCE: &str = "offline";
+#[cfg(feature = "native")]
+const WIRE_DRAIN_POLL: std::time::Duration = std::time::Duration::from_millis(10);
+
+const NS_MESSAGE_CORRECT: &str = "urn:xmpp:message-correct:0";
+const NS_MESSAGE_MODERATE: &str = "urn:xmpp:message-moderate:1";
+const NS_MESSAGE_RETRACT: &str = "urn:xmpp:message-retract:1";
+const NS_MUC_ADMIN: &str = "http://jabber.org/protocol/muc#admin";
+const NS_PUBSUB: &str = "http://jabber.org/protocol/pubsub";
 
 pub struct OutboundRouter {
     #[cfg(feature = "native")]
@@ -51,6 +67,11 @@ pub struct OutboundRouter {
     wire_sender: StanzaSender,
     #[cfg(feature = "native")]
     is_online: AtomicBool,
+    #[cfg(feature = "native")]
+    caps: Mutex<ClientCaps>,
+    /// The presence we last broadcast, re-sent when our caps change
+    #[cfg(feature = "native")]
+    last_presence: Mutex<Option<(CorePresenceShow, Option<String>, i8)>>,
 }
 
 impl OutboundRouter {
@@ -65,6 +86,8 @@ impl OutboundRouter {
             pipeline,
             wire_sender,
             is_online: AtomicBool::new(false),
+            caps: Mutex::new(ClientCaps::new()),
+            last_presence: Mutex::new(None),
         }
     }
 
@@ -72,7 +95,7 @@ impl OutboundRouter {
     pub async fn run(&self) -> Result<(), OutboundRouterError> {
         let mut subscription = self
             .event_bus
-            .subscribe("{ui,system}.**")
+            .subscribe("{ui,system,xmpp}.**")
             .map_err(|e| OutboundRouterError::SubscriptionFailed(e.to_string()))?;
 
         loop {
@@ -102,18 +125,30 @@ impl OutboundRouter {
     }
 
     #[cfg(feature = "native")]
+    #[instrument(skip_all, fields(channel = %event.channel, correlation_id = event.correlation_id.map(field::display)))]
     async fn handle_event(&self, event: &Event) -> Result<(), OutboundRouterError> {
         match &event.payload {
             EventPayload::ConnectionEstablished { .. } | EventPayload::ComingOnline => {
                 self.is_online.store(true, Ordering::Relaxed);
                 return Ok(());
             }
-            EventPayload::ConnectionLost { .. }
-            | EventPayload::ConnectionReconnecting { .. }
-            | EventPayload::GoingOffline => {
+            EventPayload::ConnectionLost { .. } | EventPayload::ConnectionReconnecting { .. } => {
                 self.is_online.store(false, Ordering::Relaxed);
                 return Ok(());
             }
+            EventPayload::GoingOffline => {
+                self.is_online.store(false, Ordering::Relaxed);
+                *self.last_presence.lock().unwrap() = None;
+                return Ok(());
+            }
+            EventPayload::PepInterestsChanged { nodes } => {
+                return self.update_notify_nodes(nodes).await;
+            }
+            EventPayload::OutboundFlushRequested => {
+                self.wait_for_wire_drain().await;
+                self.emit_outbound_flushed(event);
+                return Ok(());
+            }
             _ => {}
         }
 
@@ -123,7 +158,6 @@ impl OutboundRouter {
         }
 
         let mut message_sent = None;
-        let mut own_presence_changed = None;
 
         let stanza = match &event.payload {
             EventPayload::MessageSendRequested {
@@ -140,10 +174,19 @@ impl OutboundRouter {
                 message_sent = Some((message_id, to.clone(), body.clone(), message_type.clone()));
                 Some(stanza)
             }
-            EventPayload::PresenceSetRequested { show, status } => {
-                let stanza = build_presence_stanza(show, status.as_deref());
-                own_presence_changed = Some((show.clone(), status.clone()));
-                Some(stanza)
+            EventPayload::MessageCorrectRequested {
+                to,
+                target_id,
+                body,
+            } => Some(build_message_correction_stanza(to, target_id, body)?),
+            EventPayload::PresenceSetRequested {
+                show,
+                status,
+                priority,
+            } => {
+                *self.last_presence.lock().unwrap() =
+                    Some((show.clone(), status.clone(), *priority));
+                Some(self.presence_stanza(show, status.as_deref(), *priority))
             }
             EventPayload::RosterAddRequested { jid, name, groups } => {
                 Some(build_roster_add_stanza(jid, name.as_deref(), groups)?)
@@ -163,12 +206,37 @@ impl OutboundRouter {
                 Some(build_muc_join_stanza(room, nick)?)
             }
             EventPayload::MucLeaveRequested { room } => Some(build_muc_leave_stanza(room)?),
+            EventPayload::MucNickChangeRequested { room, nick } => {
+                Some(build_muc_nick_change_stanza(room, nick)?)
+            }
             EventPayload::MucSendRequested { room, body } => {
-                Some(build_muc_message_stanza(room, body)?)
+                let message_id = event
+                    .correlation_id
+                    .map(|id| id.to_string())
+                    .unwrap_or_else(|| Uuid::new_v4().to_string());
+                Some(build_muc_message_stanza(room, body, &message_id)?)
             }
+            EventPayload::MucModerateRequested { room, id, reason } => {
+                Some(build_muc_moderate_stanza(room, id, reason.as_deref())?)
+            }
+            EventPayload::MucRoomInfoRequested { query_id, room } => {
+                Some(build_disco_info_stanza(query_id, room)?)
+            }
+            EventPayload::MucAffiliationListRequested {
+                query_id,
+                room,
+                affiliation,
+            } => Some(build_muc_affiliation_query_stanza(
+                query_id,
+                room,
+                affiliation,
+            )?),
             EventPayload::ChatStateSendRequested { to, state } => {
                 Some(build_chat_state_stanza(to, state)?)
             }
+            EventPayload::MucChatStateSendRequested { room, state } => {
+                Some(build_muc_chat_state_stanza(room, state)?)
+            }
             EventPayload::MamQueryRequested {
                 query_id,
                 with_jid,
@@ -178,33 +246,89 @@ impl OutboundRouter {
             } => Some(build_mam_query_stanza(
                 query_id, with_jid, after, before, *max,
             )),
+            EventPayload::PepPublishRequested {
+                node,
+                item_id,
+                payload,
+            } => Some(build_pep_publish_stanza(node, item_id.as_deref(), payload)?),
+            EventPayload::DiscoInfoQueried { from, id, node } => {
+                let info = self.caps.lock().unwrap().disco_info(node.as_deref());
+                Some(build_disco_info_reply_stanza(from, id, info)?)
+            }
             _ => None,
         };
 
         if let Some(stanza) = stanza {
-            let bytes = self
-                .pipeline
-                .process_outbound(stanza)
-                .await
-                .map_err(|e| OutboundRouterError::PipelineFailed(e.to_string()))?;
-
-            self.wire_sender
-                .send(bytes)
-                .await
-                .map_err(|_| OutboundRouterError::WireSendFailed)?;
+            self.send_stanza(stanza).await?;
 
             if let Some((message_id, to, body, message_type)) = message_sent {
                 self.emit_message_sent(event, &message_id, &to, &body, &message_type);
             }
-
-            if let Some((show, status)) = own_presence_changed {
-                self.emit_own_presence_changed(&show, status.as_deref());
-            }
         }
 
         Ok(())
     }
 
+    #[cfg(feature = "native")]
+    async fn send_stanza(&self, stanza: Stanza) -> Result<(), OutboundRouterError> {
+        let bytes = self
+            .pipeline
+            .process_outbound(stanza)
+            .await
+            .map_err(|e| OutboundRouterError::PipelineFailed(e.to_string()))?;
+
+        self.wire_sender
+            .send(bytes)
+            .await
+            .map_err(|_| OutboundRouterError::WireSendFailed)
+    }
+
+    /// Our presence, carrying our entity capabilities unless it is an
+    /// unavailable presence.
+    #[cfg(feature = "native")]
+    fn presence_stanza(
+        &self,
+        show: &CorePresenceShow,
+        status: Option<&str>,
+        priority: i8,
+    ) -> Stanza {
+        let mut stanza = build_presence_stanza(show, status, priority);
+        if let Stanza::Presence(presence) = &mut stanza
+            && presence.type_ == PresenceType::None
+        {
+            presence
+                .payloads
+                .push(self.caps.lock().unwrap().caps().into());
+        }
+        stanza
+    }
+
+    /// Advertises `nodes` as `+notify` features. Contacts' servers only
+    /// learn of new caps from a presence, so a changed set re-sends ours.
+    #[cfg(feature = "native")]
+    async fn update_notify_nodes(&self, nodes: &[String]) -> Result<(), OutboundRouterError> {
+        if !self
+            .caps
+            .lock()
+            .unwrap()
+            .set_notify_nodes(nodes.iter().cloned())
+        {
+            return Ok(());
+        }
+        if !self.is_online.load(Ordering::Relaxed) {
+            return Ok(());
+        }
+        let presence = self.last_presence.lock().unwrap().clone();
+        match presence {
+            Some((show, status, priority)) if show != CorePresenceShow::Unavailable => {
+                debug!("PEP interests changed, re-sending presence with new caps");
+                let stanza = self.presence_stanza(&show, status.as_deref(), priority);
+                self.send_stanza(stanza).await
+            }
+            _ => Ok(()),
+        }
+    }
+
     #[cfg(feature = "native")]
     fn emit_message_sent(
         &self,
@@ -233,6 +357,10 @@ impl OutboundRouter {
             message_type: message_type.clone(),
             thread: None,
             embeds: vec![],
+            bodies: BTreeMap::new(),
+            origin_id: Some(message_id.to_string()),
+            spoiler: None,
+            stanza_id: None,
         };
 
         let sent_event = if let Some(corr) = event.correlation_id {
@@ -253,20 +381,28 @@ impl OutboundRouter {
         let _ = self.event_bus.publish(sent_event);
     }
 
+    /// Waits until the wire pump has taken every stanza queued so far.
     #[cfg(feature = "native")]
-    fn emit_own_presence_changed(&self, show: &CorePresenceShow, status: Option<&str>) {
-        let channel = match Channel::new("xmpp.presence.own_changed") {
+    async fn wait_for_wire_drain(&self) {
+        while !self.wire_sender.is_closed()
+            && self.wire_sender.capacity() < self.wire_sender.max_capacity()
+        {
+            tokio::time::sleep(WIRE_DRAIN_POLL).await;
+        }
+    }
+
+    #[cfg(feature = "native")]
+    fn emit_outbound_flushed(&self, event: &Event) {
+        let channel = match Channel::new("xmpp.outbound.flushed") {
             Ok(c) => c,
             Err(_) => return,
         };
-        let _ = self.event_bus.publish(Event::new(
-            channel,
-            EventSource::Xmpp,
-            EventPayload::OwnPresenceChanged {
-                show: show.clone(),
-                status: status.map(String::from),
-            },
-        ));
+        let payload = EventPayload::OutboundFlushed;
+        let flushed = match event.correlation_id {
+            Some(corr) => Event::with_correlation(channel, EventSource::Xmpp, payload, corr),
+            None => Event::new(channel, EventSource::Xmpp, payload),
+        };
+        let _ = self.event_bus.publish(flushed);
     }
 }
 
@@ -288,19 +424,42 @@ fn build_message_stanza(
         CoreMessageType::Error => XmppMessageType::Error,
     };
 
+    let message_id = message_id
+        .map(ToOwned::to_owned)
+        .unwrap_or_else(|| Uuid::new_v4().to_string());
     let mut msg = Message::new_with_type(xmpp_type, Some(to_jid));
-    msg.id = Some(xmpp_parsers::message::Id(
-        message_id
-            .map(ToOwned::to_owned)
-            .unwrap_or_else(|| Uuid::new_v4().to_string()),
-    ));
+    msg.id = Some(xmpp_parsers::message::Id(message_id.clone()));
     msg.bodies.insert(Lang::new(), body.to_string());
+    // XEP-0359: carbon and archive copies keep the origin-id, so they can be
+    // matched to the copy we stored when sending.
+    msg.payloads.push(OriginId { id: message_id }.into());
 
     Ok(Stanza::Message(Box::new(msg)))
 }
 
-fn build_presence_stanza(show: &CorePresenceShow, status: Option<&str>) -> Stanza {
-    let mut presence = Presence::new(PresenceType::None);
+/// XEP-0308: a chat message whose body replaces that of `target_id`.
+fn build_message_correction_stanza(
+    to: &str,
+    target_id: &str,
+    body: &str,
+) -> Result<Stanza, OutboundRouterError> {
+    let mut stanza = build_message_stanza(to, body, &CoreMessageType::Chat, None)?;
+    if let Stanza::Message(msg) = &mut stanza {
+        msg.payloads.push(
+            Element::builder("replace", NS_MESSAGE_CORRECT)
+                .attr(
+                    "id".try_into()
+                        .expect("static id attribute should be valid NCName"),
+                    target_id,
+                )
+                .build(),
+        );
+    }
+    Ok(stanza)
+}
+
+fn build_presence_stanza(show: &CorePresenceShow, status: Option<&str>, priority: i8) -> Stanza {
+    let mut presence = Presence::new(PresenceType::None).with_priority(priority);
 
     match show {
         CorePresenceShow::Unavailable => {
@@ -456,18 +615,184 @@ fn build_muc_leave_stanza(room: &str) -> Result<Stanza, OutboundRouterError> {
     Ok(Stanza::Presence(Box::new(presence)))
 }
 
-fn build_muc_message_stanza(room: &str, body: &str) -> Result<Stanza, OutboundRouterError> {
+/// A plain presence to our new occupant JID; unlike a join it carries no
+/// MUC payload, so the room treats it as a nick change.
+fn build_muc_nick_change_stanza(room: &str, nick: &str) -> Result<Stanza, OutboundRouterError> {
+    let occupant_jid: jid::Jid = format!("{room}/{nick}")
+        .parse()
+        .map_err(|_| OutboundRouterError::InvalidJid(format!("{room}/{nick}")))?;
+
+    let mut presence = Presence::new(PresenceType::None);
+    presence.to = Some(occupant_jid);
+
+    Ok(Stanza::Presence(Box::new(presence)))
+}
+
+fn build_muc_message_stanza(
+    room: &str,
+    body: &str,
+    message_id: &str,
+) -> Result<Stanza, OutboundRouterError> {
     let room_jid: jid::Jid = room
         .parse()
         .map_err(|_| OutboundRouterError::InvalidJid(room.to_string()))?;
 
     let mut msg = Message::new_with_type(XmppMessageType::Groupchat, Some(room_jid));
-    msg.id = Some(xmpp_parsers::message::Id(Uuid::new_v4().to_string()));
+    msg.id = Some(xmpp_parsers::message::Id(message_id.to_string()));
     msg.bodies.insert(Lang::new(), body.to_string());
+    // XEP-0359: the room may rewrite the stanza id, the origin-id lets us
+    // match the reflection against our own send.
+    msg.payloads.push(
+        OriginId {
+            id: message_id.to_string(),
+        }
+        .into(),
+    );
 
     Ok(Stanza::Message(Box::new(msg)))
 }
 
+/// XEP-0425: ask the room to retract another occupant's message on our behalf.
+fn build_muc_moderate_stanza(
+    room: &str,
+    message_id: &str,
+    reason: Option<&str>,
+) -> Result<Stanza, OutboundRouterError> {
+    let room_jid: jid::Jid = room
+        .parse()
+        .map_err(|_| OutboundRouterError::InvalidJid(room.to_string()))?;
+
+    let mut moderate = Element::builder("moderate", NS_MESSAGE_MODERATE)
+        .attr(
+            "id".try_into()
+                .expect("static id attribute should be valid NCName"),
+            message_id,
+        )
+        .append(Element::builder("retract", NS_MESSAGE_RETRACT).build());
+    if let Some(reason) = reason {
+        moderate = moderate.append(
+            Element::builder("reason", NS_MESSAGE_MODERATE)
+                .append(reason)
+                .build(),
+        );
+    }
+
+    let iq = Iq::Set {
+        from: None,
+        to: Some(room_jid),
+        id: Uuid::new_v4().to_string(),
+        payload: moderate.build(),
+    };
+    Ok(Stanza::Iq(Box::new(iq)))
+}
+
+fn build_disco_info_stanza(query_id: &str, to: &str) -> Result<Stanza, OutboundRouterError> {
+    let to_jid: jid::Jid = to
+        .parse()
+        .map_err(|_| OutboundRouterError::InvalidJid(to.to_string()))?;
+
+    let iq = Iq::from_get(query_id.to_string(), DiscoInfoQuery { node: None }).with_to(to_jid);
+    Ok(Stanza::Iq(Box::new(iq)))
+}
+
+/// Answers the disco#info query `id` from `to`: `info`, or
+/// `<item-not-found/>` when the queried node is not ours.
+fn build_disco_info_reply_stanza(
+    to: &str,
+    id: &str,
+    info: Option<DiscoInfoResult>,
+) -> Result<Stanza, OutboundRouterError> {
+    let to_jid: jid::Jid = to
+        .parse()
+        .map_err(|_| OutboundRouterError::InvalidJid(to.to_string()))?;
+
+    let iq = match info {
+        Some(info) => Iq::from_result(id.to_string(), Some(info)),
+        None => Iq::from_error(
+            id.to_string(),
+            StanzaError::new(
+                ErrorType::Cancel,
+                DefinedCondition::ItemNotFound,
+                "en",
+                "unknown node",
+            ),
+        ),
+    };
+    Ok(Stanza::Iq(Box::new(iq.with_to(to_jid))))
+}
+
+fn build_muc_affiliation_query_stanza(
+    query_id: &str,
+    room: &str,
+    affiliation: &MucAffiliation,
+) -> Result<Stanza, OutboundRouterError> {
+    let room_jid: jid::Jid = room
+        .parse()
+        .map_err(|_| OutboundRouterError::InvalidJid(room.to_string()))?;
+
+    let query = Element::builder("query", NS_MUC_ADMIN)
+        .append(
+            Element::builder("item", NS_MUC_ADMIN)
+                .attr(
+                    "affiliation"
+                        .try_into()
+                        .expect("static affiliation attribute should be valid NCName"),
+                    affiliation.as_str(),
+                )
+                .build(),
+        )
+        .build();
+
+    let iq = Iq::Get {
+        from: None,
+        to: Some(room_jid),
+        id: query_id.to_string(),
+        payload: query,
+    };
+    Ok(Stanza::Iq(Box::new(iq)))
+}
+
+/// XEP-0163: an IQ without a `to` publishes on our own account's PEP service.
+fn build_pep_publish_stanza(
+    node: &str,
+    item_id: Option<&str>,
+    payload: &str,
+) -> Result<Stanza, OutboundRouterError> {
+    let payload: Element = payload
+        .parse()
+        .map_err(|e| OutboundRouterError::InvalidPayload(format!("{node}: {e}")))?;
+
+    let mut item = Element::builder("item", NS_PUBSUB);
+    if let Some(id) = item_id {
+        item = item.attr(
+            "id".try_into()
+                .expect("static id attribute should be valid NCName"),
+            id,
+        );
+    }
+    let pubsub = Element::builder("pubsub", NS_PUBSUB)
+        .append(
+            Element::builder("publish", NS_PUBSUB)
+                .attr(
+                    "node"
+                        .try_into()
+                        .expect("static node attribute should be valid NCName"),
+                    node,
+                )
+                .append(item.append(payload).build())
+                .build(),
+        )
+        .build();
+
+    let iq = Iq::Set {
+        from: None,
+        to: None,
+        id: Uuid::new_v4().to_string(),
+        payload: pubsub,
+    };
+    Ok(Stanza::Iq(Box::new(iq)))
+}
+
 fn build_mam_query_stanza(
     query_id: &str,
     with_jid: &Option<String>,
@@ -523,6 +848,17 @@ fn build_chat_state_stanza(to: &str, state: &CoreChatState) -> Result<Stanza, Ou
     Ok(Stanza::Message(Box::new(msg)))
 }
 
+fn build_muc_chat_state_stanza(
+    room: &str,
+    state: &CoreChatState,
+) -> Result<Stanza, OutboundRouterError> {
+    let mut stanza = build_chat_state_stanza(room, state)?;
+    if let Stanza::Message(msg) = &mut stanza {
+        msg.type_ = XmppMessageType::Groupchat;
+    }
+    Ok(stanza)
+}
+
 #[derive(Debug, thiserror::Error)]
 pub enum OutboundRouterError {
     #[error("failed to subscribe to events: {0}")]
@@ -531,6 +867,9 @@ pub enum OutboundRouterError {
     #[error("invalid JID: {0}")]
     InvalidJid(String),
 
+    #[error("invalid payload: {0}")]
+    InvalidPayload(String),
+
     #[error("outbound pipeline failed: {0}")]
     PipelineFailed(String),
 
@@ -583,7 +922,7 @@ mod tests {
 
     #[test]
     fn builds_available_presence() {
-        let stanza = build_presence_stanza(&CorePresenceShow::Available, None);
+        let stanza = build_presence_stanza(&CorePresenceShow::Available, None, 0);
         let Stanza::Presence(p) = &stanza else {
             panic!("expected presence stanza");
         };
@@ -593,7 +932,7 @@ mod tests {
 
     #[test]
     fn builds_away_presence_with_status() {
-        let stanza = build_presence_stanza(&CorePresenceShow::Away, Some("brb"));
+        let stanza = build_presence_stanza(&CorePresenceShow::Away, Some("brb"), 0);
         let Stanza::Presence(p) = &stanza else {
             panic!("expected presence stanza");
         };
@@ -603,7 +942,7 @@ mod tests {
 
     #[test]
     fn builds_unavailable_presence() {
-        let stanza = build_presence_stanza(&CorePresenceShow::Unavailable, None);
+        let stanza = build_presence_stanza(&CorePresenceShow::Unavailable, None, 0);
         let Stanza::Presence(p) = &stanza else {
             panic!("expected presence stanza");
         };
@@ -612,7 +951,7 @@ mod tests {
 
     #[test]
     fn builds_dnd_presence() {
-        let stanza = build_presence_stanza(&CorePresenceShow::Dnd, Some("busy"));
+        let stanza = build_presence_stanza(&CorePresenceShow::Dnd, Some("busy"), 0);
         let Stanza::Presence(p) = &stanza else {
             panic!("expected presence stanza");
         };
@@ -620,6 +959,19 @@ mod tests {
         assert_eq!(p.statuses.get("").map(String::as_str), Some("busy"));
     }
 
+    #[test]
+    fn presence_carries_priority() {
+        let stanza = build_presence_stanza(&CorePresenceShow::Available, None, -1);
+        let bytes = stanza.to_bytes().expect("stanza should serialize");
+        let Stanza::Presence(reparsed) = Stanza::parse(&bytes).expect("should reparse") else {
+            panic!("expected presence stanza");
+        };
+        assert_eq!(
+            reparsed.priority,
+            Presence::available().with_priority(-1).priority
+        );
+    }
+
     #[test]
     fn builds_roster_add_stanza_test() {
         let stanza =
@@ -743,7 +1095,8 @@ mod tests {
     #[test]
     fn builds_muc_message_stanza_test() {
         let stanza =
-            build_muc_message_stanza("room@conference.example.com", "Hello room!").unwrap();
+            build_muc_message_stanza("room@conference.example.com", "Hello room!", "muc-1")
+                .unwrap();
         let Stanza::Message(msg) = &stanza else {
             panic!("expected message stanza");
         };
@@ -755,6 +1108,183 @@ mod tests {
         assert_eq!(msg.bodies.get("").map(String::as_str), Some("Hello room!"));
     }
 
+    #[test]
+    fn message_stanza_carries_origin_id() {
+        let stanza = build_message_stanza(
+            "bob@example.com",
+            "Hello!",
+            &CoreMessageType::Chat,
+            Some("msg-1"),
+        )
+        .unwrap();
+        let Stanza::Message(msg) = &stanza else {
+            panic!("expected message stanza");
+        };
+        assert_eq!(msg.id.as_ref().map(|id| id.0.as_str()), Some("msg-1"));
+        let origin_id = msg
+            .payloads
+            .iter()
+            .find_map(|el| OriginId::try_from(el.clone()).ok())
+            .expect("origin-id payload");
+        assert_eq!(origin_id.id, "msg-1");
+    }
+
+    #[test]
+    fn muc_message_stanza_carries_origin_id() {
+        let stanza =
+            build_muc_message_stanza("room@conference.example.com", "Hello room!", "muc-1")
+                .unwrap();
+        let Stanza::Message(msg) = &stanza else {
+            panic!("expected message stanza");
+        };
+        assert_eq!(msg.id.as_ref().map(|id| id.0.as_str()), Some("muc-1"));
+        let origin_id = msg
+            .payloads
+            .iter()
+            .find_map(|el| OriginId::try_from(el.clone()).ok())
+            .expect("origin-id payload");
+        assert_eq!(origin_id.id, "muc-1");
+    }
+
+    #[test]
+    fn builds_muc_moderate_stanza_test() {
+        let stanza =
+            build_muc_moderate_stanza("room@conference.example.com", "stanza-id-1", Some("Spam"))
+                .unwrap();
+        let Stanza::Iq(iq) = &stanza else {
+            panic!("expected iq stanza");
+        };
+        let Iq::Set { to, payload, .. } = iq.as_ref() else {
+            panic!("expected iq set");
+        };
+        assert_eq!(
+            to.as_ref().map(|j| j.to_string()),
+            Some("room@conference.example.com".to_string())
+        );
+        assert!(payload.is("moderate", NS_MESSAGE_MODERATE));
+        assert_eq!(payload.attr("id"), Some("stanza-id-1"));
+        assert!(payload.has_child("retract", NS_MESSAGE_RETRACT));
+        assert_eq!(
+            payload
+                .get_child("reason", NS_MESSAGE_MODERATE)
+                .map(|r| r.text()),
+            Some("Spam".to_string())
+        );
+    }
+
+    #[test]
+    fn muc_moderate_stanza_omits_missing_reason() {
+        let stanza =
+            build_muc_moderate_stanza("room@conference.example.com", "stanza-id-1", None).unwrap();
+        let Stanza::Iq(iq) = &stanza else {
+            panic!("expected iq stanza");
+        };
+        let Iq::Set { payload, .. } = iq.as_ref() else {
+            panic!("expected iq set");
+        };
+        assert!(!payload.has_child("reason", NS_MESSAGE_MODERATE));
+    }
+
+    #[test]
+    fn builds_message_correction_stanza() {
+        let stanza =
+            build_message_correction_stanza("bob@example.com", "msg-1", "Hello, Bob!").unwrap();
+        let Stanza::Message(msg) = &stanza else {
+            panic!("expected message stanza");
+        };
+        assert_eq!(msg.type_, XmppMessageType::Chat);
+        assert_eq!(
+            msg.get_best_body(vec![]).map(|(_, body)| body.as_str()),
+            Some("Hello, Bob!")
+        );
+        let replace = msg
+            .payloads
+            .iter()
+            .find(|el| el.is("replace", NS_MESSAGE_CORRECT))
+            .expect("expected <replace/>");
+        assert_eq!(replace.attr("id"), Some("msg-1"));
+        assert_ne!(msg.id.as_ref().map(|id| id.0.as_str()), Some("msg-1"));
+    }
+
+    #[test]
+    fn builds_pep_publish_stanza_test() {
+        let stanza = build_pep_publish_stanza(
+            "http://jabber.org/protocol/tune",
+            Some("current"),
+            "<tune xmlns='http://jabber.org/protocol/tune'/>",
+        )
+        .unwrap();
+        let Stanza::Iq(iq) = &stanza else {
+            panic!("expected iq stanza");
+        };
+        let Iq::Set { to, payload, .. } = iq.as_ref() else {
+            panic!("expected iq set");
+        };
+        assert!(to.is_none());
+        assert!(payload.is("pubsub", NS_PUBSUB));
+        let publish = payload.get_child("publish", NS_PUBSUB).unwrap();
+        assert_eq!(
+            publish.attr("node"),
+            Some("http://jabber.org/protocol/tune")
+        );
+        let item = publish.get_child("item", NS_PUBSUB).unwrap();
+        assert_eq!(item.attr("id"), Some("current"));
+        assert!(item.has_child("tune", "http://jabber.org/protocol/tune"));
+
+        assert!(matches!(
+            build_pep_publish_stanza("urn:example", None, "<unclosed"),
+            Err(OutboundRouterError::InvalidPayload(_))
+        ));
+    }
+
+    #[test]
+    fn builds_disco_info_stanza_test() {
+        let stanza = build_disco_info_stanza("q-1", "room@conference.example.com").unwrap();
+        let Stanza::Iq(iq) = &stanza else {
+            panic!("expected iq stanza");
+        };
+        let Iq::Get {
+            to, id, payload, ..
+        } = iq.as_ref()
+        else {
+            panic!("expected iq get");
+        };
+        assert_eq!(id, "q-1");
+        assert_eq!(
+            to.as_ref().map(|j| j.to_string()),
+            Some("room@conference.example.com".to_string())
+        );
+        assert!(payload.is("query", "http://jabber.org/protocol/disco#info"));
+        assert_eq!(payload.attr("node"), None);
+    }
+
+    #[test]
+    fn builds_muc_affiliation_query_stanza_test() {
+        let stanza = build_muc_affiliation_query_stanza(
+            "q-1",
+            "room@conference.example.com",
+            &MucAffiliation::Outcast,
+        )
+        .unwrap();
+        let Stanza::Iq(iq) = &stanza else {
+            panic!("expected iq stanza");
+        };
+        let Iq::Get {
+            to, id, payload, ..
+        } = iq.as_ref()
+        else {
+            panic!("expected iq get");
+        };
+        assert_eq!(id, "q-1");
+        assert_eq!(
+            to.as_ref().map(|j| j.to_string()),
+            Some("room@conference.example.com".to_string())
+        );
+        assert!(payload.is("query", NS_MUC_ADMIN));
+        let item = payload.get_child("item", NS_MUC_ADMIN).expect("item");
+        assert_eq!(item.attr("affiliation"), Some("outcast"));
+    }
+
     #[test]
     fn builds_mam_query_stanza_with_query_id_and_jid_filter() {
         let stanza = build_mam_query_stanza(
@@ -812,6 +1342,27 @@ mod tests {
         assert!(matches!(state, Some(XmppChatState::Composing)));
     }
 
+    #[test]
+    fn builds_muc_chat_state_as_groupchat() {
+        let stanza =
+            build_muc_chat_state_stanza("room@conference.example.com", &CoreChatState::Composing)
+                .unwrap();
+        let Stanza::Message(msg) = &stanza else {
+            panic!("expected message stanza");
+        };
+        assert_eq!(msg.type_, XmppMessageType::Groupchat);
+        assert_eq!(
+            msg.to.as_ref().map(|j| j.to_string()),
+            Some("room@conference.example.com".to_string())
+        );
+        assert!(msg.bodies.is_empty());
+        let state = msg
+            .payloads
+            .iter()
+            .find_map(|el| XmppChatState::try_from(el.clone()).ok());
+        assert!(matches!(state, Some(XmppChatState::Composing)));
+    }
+
     #[test]
     fn builds_chat_state_active() {
         let stanza = build_chat_state_stanza("bob@example.com", &CoreChatState::Active).unwrap();
@@ -842,9 +1393,9 @@ mod tests {
     fn all_stanzas_serialize_to_valid_xml() {
         let stanzas = vec![
             build_message_stanza("bob@example.com", "test", &CoreMessageType::Chat, None).unwrap(),
-            build_presence_stanza(&CorePresenceShow::Available, None),
-            build_presence_stanza(&CorePresenceShow::Away, Some("brb")),
-            build_presence_stanza(&CorePresenceShow::Unavailable, None),
+            build_presence_stanza(&CorePresenceShow::Available, None, 0),
+            build_presence_stanza(&CorePresenceShow::Away, Some("brb"), 0),
+            build_presence_stanza(&CorePresenceShow::Unavailable, None, 0),
             build_roster_add_stanza("alice@example.com", Some("Alice"), &[]).unwrap(),
             build_roster_remove_stanza("alice@example.com").unwrap(),
             build_subscription_response_stanza("carol@example.com", true).unwrap(),
@@ -853,7 +1404,9 @@ mod tests {
             build_subscription_send_stanza("carol@example.com", false).unwrap(),
             build_muc_join_stanza("room@conference.example.com", "nick").unwrap(),
             build_muc_leave_stanza("room@conference.example.com").unwrap(),
-            build_muc_message_stanza("room@conference.example.com", "hi").unwrap(),
+            build_muc_message_stanza("room@conference.example.com", "hi", "muc-1").unwrap(),
+            build_muc_moderate_stanza("room@conference.example.com", "muc-1", Some("spam"))
+                .unwrap(),
             build_chat_state_stanza("bob@example.com", &CoreChatState::Composing).unwrap(),
         ];
 
@@ -970,6 +1523,7 @@ mod integration_tests {
             EventPayload::PresenceSetRequested {
                 show: CorePresenceShow::Away,
                 status: Some("brb".to_string()),
+                priority: 0,
             },
         );
 
@@ -1307,38 +1861,164 @@ mod integration_tests {
     }
 
     #[tokio::test]
-    async fn closed_wire_channel_does_not_emit_own_presence_changed_event() {
-        let event_bus: Arc<dyn EventBus> = Arc::new(BroadcastEventBus::new(64));
+    async fn presence_set_leaves_own_presence_changed_to_the_presence_manager() {
+        let (router, mut rx, event_bus) = make_router();
         let mut presence_sub = event_bus
-            .subscribe("xmpp.presence.own_changed")
+            .subscribe("{system,xmpp}.presence.own_changed")
             .expect("subscribe should succeed");
-        let pipeline = Arc::new(StanzaPipeline::new());
-        let (tx, rx) = stanza_channel(1);
-        drop(rx);
 
-        let router = OutboundRouter::new(event_bus.clone(), pipeline, tx);
-        router
-            .handle_event(&connection_established_event())
-            .await
-            .expect("connection established event should succeed");
+        let _handle = tokio::spawn(async move { router.run().await });
+        yield_to_router().await;
+        publish_connection_established(&event_bus).await;
 
-        let event = Event::new(
-            Channel::new("ui.presence.set").expect("valid channel"),
-            EventSource::Ui(UiTarget::Tui),
+        publish_ui_event(
+            &event_bus,
+            "ui.presence.set",
             EventPayload::PresenceSetRequested {
                 show: CorePresenceShow::Away,
                 status: Some("brb".to_string()),
+                priority: 0,
             },
         );
-
-        let result = router.handle_event(&event).await;
-        assert!(matches!(result, Err(OutboundRouterError::WireSendFailed)));
+        timeout(Duration::from_millis(200), rx.recv())
+            .await
+            .expect("timed out waiting for wire bytes")
+            .expect("channel should not be closed");
 
         let received = timeout(Duration::from_millis(100), presence_sub.recv()).await;
         assert!(
             received.is_err(),
-            "presence.own_changed should not be emitted when wire send fails"
+            "own presence changes are announced by the presence manager only"
+        );
+
+        _handle.abort();
+    }
+
+    async fn next_stanza(rx: &mut StanzaReceiver) -> Stanza {
+        let bytes = timeout(Duration::from_millis(200), rx.recv())
+            .await
+            .expect("timed out waiting for wire bytes")
+            .expect("channel should not be closed");
+        Stanza::parse(&bytes).expect("wire bytes should parse as stanza")
+    }
+
+    fn presence_caps(stanza: &Stanza) -> Element {
+        let Stanza::Presence(presence) = stanza else {
+            panic!("expected presence stanza, got {stanza:?}");
+        };
+        presence
+            .payloads
+            .iter()
+            .find(|el| el.is("c", "http://jabber.org/protocol/caps"))
+            .cloned()
+            .expect("presence should carry entity caps")
+    }
+
+    #[tokio::test]
+    async fn changed_pep_interests_resend_presence_with_new_caps() {
+        let (router, mut rx, event_bus) = make_router();
+
+        let _handle = tokio::spawn(async move { router.run().await });
+        yield_to_router().await;
+        publish_connection_established(&event_bus).await;
+
+        publish_ui_event(
+            &event_bus,
+            "ui.presence.set",
+            EventPayload::PresenceSetRequested {
+                show: CorePresenceShow::Away,
+                status: Some("brb".to_string()),
+                priority: 0,
+            },
         );
+        let before = presence_caps(&next_stanza(&mut rx).await);
+
+        publish_ui_event(
+            &event_bus,
+            "ui.pep.interests",
+            EventPayload::PepInterestsChanged {
+                nodes: vec!["http://jabber.org/protocol/mood".to_string()],
+            },
+        );
+        let resent = next_stanza(&mut rx).await;
+        let Stanza::Presence(presence) = &resent else {
+            panic!("expected presence stanza");
+        };
+        assert_eq!(presence.show, Some(Show::Away));
+        assert_eq!(presence.statuses.get("").map(String::as_str), Some("brb"));
+        let after = presence_caps(&resent);
+        assert_ne!(before.attr("ver"), after.attr("ver"));
+
+        publish_ui_event(
+            &event_bus,
+            "ui.pep.interests",
+            EventPayload::PepInterestsChanged {
+                nodes: vec!["http://jabber.org/protocol/mood".to_string()],
+            },
+        );
+        assert!(
+            timeout(Duration::from_millis(100), rx.recv())
+                .await
+                .is_err(),
+            "unchanged interests should not re-send presence"
+        );
+
+        _handle.abort();
+    }
+
+    #[tokio::test]
+    async fn disco_info_queries_are_answered_with_notify_features() {
+        let (router, mut rx, event_bus) = make_router();
+
+        let _handle = tokio::spawn(async move { router.run().await });
+        yield_to_router().await;
+        publish_connection_established(&event_bus).await;
+
+        publish_ui_event(
+            &event_bus,
+            "ui.pep.interests",
+            EventPayload::PepInterestsChanged {
+                nodes: vec!["http://jabber.org/protocol/mood".to_string()],
+            },
+        );
+        yield_to_router().await;
+        event_bus
+            .publish(Event::new(
+                Channel::new("xmpp.disco.info.queried").expect("valid channel"),
+                EventSource::Xmpp,
+                EventPayload::DiscoInfoQueried {
+                    from: "bob@example.com/phone".to_string(),
+                    id: "disco1".to_string(),
+                    node: None,
+                },
+            ))
+            .expect("publish should succeed");
+
+        let Stanza::Iq(iq) = next_stanza(&mut rx).await else {
+            panic!("expected iq stanza");
+        };
+        let Iq::Result {
+            id,
+            to,
+            payload: Some(payload),
+            ..
+        } = *iq
+        else {
+            panic!("expected iq result, got {iq:?}");
+        };
+        assert_eq!(id, "disco1");
+        assert_eq!(
+            to.map(|j| j.to_string()).as_deref(),
+            Some("bob@example.com/phone")
+        );
+        let info = DiscoInfoResult::try_from(payload).expect("disco#info result");
+        assert!(
+            info.features
+                .iter()
+                .any(|f| f.var == "http://jabber.org/protocol/mood+notify")
+        );
+
+        _handle.abort();
     }
 
     #[tokio::test]
@@ -1363,6 +2043,7 @@ mod integration_tests {
                 EventPayload::PresenceSetRequested {
                     show: CorePresenceShow::Dnd,
                     status: None,
+                    priority: 0,
                 },
             ),
             (
@@ -1454,4 +2135,56 @@ mod integration_tests {
 
         _handle.abort();
     }
+
+    #[tokio::test]
+    async fn flush_is_answered_once_the_wire_has_taken_earlier_commands() {
+        let (router, mut rx, event_bus) = make_router();
+        let mut flushed = event_bus.subscribe("xmpp.outbound.flushed").unwrap();
+        router
+            .handle_event(&connection_established_event())
+            .await
+            .unwrap();
+
+        let _handle = tokio::spawn(async move { router.run().await });
+        yield_to_router().await;
+
+        publish_ui_event(
+            &event_bus,
+            "ui.roster.add",
+            EventPayload::RosterAddRequested {
+                jid: "bob@example.com".to_string(),
+                name: None,
+                groups: vec![],
+            },
+        );
+        let correlation_id = Uuid::new_v4();
+        event_bus
+            .publish(Event::with_correlation(
+                Channel::new("ui.outbound.flush").unwrap(),
+                EventSource::System("test".into()),
+                EventPayload::OutboundFlushRequested,
+                correlation_id,
+            ))
+            .unwrap();
+
+        assert!(
+            timeout(Duration::from_millis(50), flushed.recv())
+                .await
+                .is_err(),
+            "flush answered while a stanza was still waiting for the wire"
+        );
+        timeout(Duration::from_millis(200), rx.recv())
+            .await
+            .expect("roster add should reach the wire")
+            .unwrap();
+
+        let event = timeout(Duration::from_millis(200), flushed.recv())
+            .await
+            .expect("flush should be answered")
+            .unwrap();
+        assert!(matches!(event.payload, EventPayload::OutboundFlushed));
+        assert_eq!(event.correlation_id, Some(correlation_id));
+
+        _handle.abort();
+    }
 }
@@ -0,0 +1,284 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use tokio::sync::mpsc;
+use tracing::{debug, error, warn};
+use xmpp_parsers::minidom::Element;
+
+use waddle_core::event::{Channel, Event, EventBus, EventPayload, EventSource};
+
+#[derive(Debug, thiserror::Error)]
+pub enum PepError {
+    #[error("event bus error: {0}")]
+    EventBus(String),
+}
+
+/// An item a contact published on one of their PEP nodes.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PepItem {
+    pub from: String,
+    pub node: String,
+    pub item_id: Option<String>,
+    pub payload: Element,
+}
+
+/// Shared XEP-0163 substrate: publishes items on our own PEP nodes and
+/// hands inbound items to whoever subscribed to their node, so features
+/// built on PEP (avatars, bookmarks, mood) need no pubsub code of their own.
+/// The subscribed nodes are announced as `PepInterestsChanged`, which the
+/// outbound router advertises as `<node>+notify` in our entity caps so
+/// contacts' servers actually send us their items.
+pub struct PepManager {
+    event_bus: Arc<dyn EventBus>,
+    /// Node -> receivers of its items
+    routes: Mutex<HashMap<String, Vec<mpsc::UnboundedSender<PepItem>>>>,
+}
+
+impl PepManager {
+    pub fn new(event_bus: Arc<dyn EventBus>) -> Self {
+        Self {
+            event_bus,
+            routes: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Publishes `payload` on our own `node`. Without `item_id` the server
+    /// assigns one; singleton nodes conventionally use `current`.
+    pub fn publish(
+        &self,
+        node: &str,
+        item_id: Option<&str>,
+        payload: &Element,
+    ) -> Result<(), PepError> {
+        self.event_bus
+            .publish(Event::new(
+                Channel::new("ui.pep.publish").unwrap(),
+                EventSource::System("pep".into()),
+                EventPayload::PepPublishRequested {
+                    node: node.to_string(),
+                    item_id: item_id.map(str::to_string),
+                    payload: String::from(payload),
+                },
+            ))
+            .map_err(|e| PepError::EventBus(e.to_string()))
+    }
+
+    /// Items published on `node` from now on. The route goes away once the
+    /// receiver is dropped.
+    pub fn subscribe(&self, node: &str) -> mpsc::UnboundedReceiver<PepItem> {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let mut routes = self.routes.lock().unwrap();
+        let is_new = !routes.contains_key(node);
+        routes.entry(node.to_string()).or_default().push(sender);
+        if is_new {
+            self.announce_interests(&mut routes);
+        }
+        receiver
+    }
+
+    /// Drops routes whose receivers are gone and publishes the nodes left.
+    fn announce_interests(
+        &self,
+        routes: &mut HashMap<String, Vec<mpsc::UnboundedSender<PepItem>>>,
+    ) {
+        routes.retain(|_, senders| {
+            senders.retain(|sender| !sender.is_closed());
+            !senders.is_empty()
+        });
+        let mut nodes: Vec<String> = routes.keys().cloned().collect();
+        nodes.sort();
+        let _ = self.event_bus.publish(Event::new(
+            Channel::new("ui.pep.interests").unwrap(),
+            EventSource::System("pep".into()),
+            EventPayload::PepInterestsChanged { nodes },
+        ));
+    }
+
+    pub async fn handle_event(&self, event: &Event) {
+        if let EventPayload::ConnectionEstablished { .. } = &event.payload {
+            self.announce_interests(&mut self.routes.lock().unwrap());
+            return;
+        }
+        let EventPayload::PepItemReceived {
+            from,
+            node,
+            item_id,
+            payload,
+        } = &event.payload
+        else {
+            return;
+        };
+
+        let mut routes = self.routes.lock().unwrap();
+        let Some(senders) = routes.get_mut(node) else {
+            debug!(from = %from, node = %node, "no subscriber for PEP node");
+            return;
+        };
+        let payload: Element = match payload.parse() {
+            Ok(payload) => payload,
+            Err(e) => {
+                warn!(from = %from, node = %node, error = %e, "dropping malformed PEP item");
+                return;
+            }
+        };
+        let item = PepItem {
+            from: from.clone(),
+            node: node.clone(),
+            item_id: item_id.clone(),
+            payload,
+        };
+        senders.retain(|sender| sender.send(item.clone()).is_ok());
+        if senders.is_empty() {
+            routes.remove(node);
+            self.announce_interests(&mut routes);
+        }
+    }
+
+    pub async fn run(self: Arc<Self>) -> Result<(), PepError> {
+        let mut sub = self
+            .event_bus
+            .subscribe("{system,xmpp}.**")
+            .map_err(|e| PepError::EventBus(e.to_string()))?;
+
+        loop {
+            match sub.recv().await {
+                Ok(event) => {
+                    self.handle_event(&event).await;
+                }
+                Err(waddle_core::error::EventBusError::ChannelClosed) => {
+                    debug!("event bus closed, PEP manager stopping");
+                    return Ok(());
+                }
+                Err(waddle_core::error::EventBusError::Lagged(count)) => {
+                    warn!(count, "PEP manager lagged, some events dropped");
+                }
+                Err(e) => {
+                    error!(error = %e, "PEP manager subscription error");
+                    return Err(PepError::EventBus(e.to_string()));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use waddle_core::event::BroadcastEventBus;
+
+    const NS_TUNE: &str = "http://jabber.org/protocol/tune";
+
+    fn item_event(node: &str, payload: &str) -> Event {
+        Event::new(
+            Channel::new("xmpp.pep.item.received").unwrap(),
+            EventSource::Xmpp,
+            EventPayload::PepItemReceived {
+                from: "alice@example.com".into(),
+                node: node.into(),
+                item_id: Some("current".into()),
+                payload: payload.into(),
+            },
+        )
+    }
+
+    #[tokio::test]
+    async fn publish_requests_item_on_own_node() {
+        let event_bus: Arc<dyn EventBus> = Arc::new(BroadcastEventBus::default());
+        let manager = PepManager::new(event_bus.clone());
+        let mut sub = event_bus.subscribe("ui.pep.publish").unwrap();
+
+        let tune = Element::builder("tune", NS_TUNE).build();
+        manager.publish(NS_TUNE, Some("current"), &tune).unwrap();
+
+        let event = sub.recv().await.unwrap();
+        let EventPayload::PepPublishRequested {
+            node,
+            item_id,
+            payload,
+        } = event.payload
+        else {
+            panic!("expected PepPublishRequested, got {:?}", event.payload);
+        };
+        assert_eq!(node, NS_TUNE);
+        assert_eq!(item_id.as_deref(), Some("current"));
+        assert_eq!(payload.parse::<Element>().unwrap(), tune);
+    }
+
+    #[tokio::test]
+    async fn inbound_items_reach_only_their_node_subscribers() {
+        let event_bus: Arc<dyn EventBus> = Arc::new(BroadcastEventBus::default());
+        let manager = PepManager::new(event_bus);
+        let mut tunes = manager.subscribe(NS_TUNE);
+        let mut moods = manager.subscribe("http://jabber.org/protocol/mood");
+
+        manager
+            .handle_event(&item_event(
+                NS_TUNE,
+                "<tune xmlns='http://jabber.org/protocol/tune'/>",
+            ))
+            .await;
+
+        let item = tunes.try_recv().unwrap();
+        assert_eq!(item.from, "alice@example.com");
+        assert_eq!(item.node, NS_TUNE);
+        assert_eq!(item.item_id.as_deref(), Some("current"));
+        assert!(item.payload.is("tune", NS_TUNE));
+        assert!(moods.try_recv().is_err());
+
+        drop(tunes);
+        manager
+            .handle_event(&item_event(
+                NS_TUNE,
+                "<tune xmlns='http://jabber.org/protocol/tune'/>",
+            ))
+            .await;
+        assert!(!manager.routes.lock().unwrap().contains_key(NS_TUNE));
+    }
+
+    #[tokio::test]
+    async fn subscribed_nodes_are_announced_as_interests() {
+        let event_bus: Arc<dyn EventBus> = Arc::new(BroadcastEventBus::default());
+        let manager = PepManager::new(event_bus.clone());
+        let mut sub = event_bus.subscribe("ui.pep.interests").unwrap();
+
+        let tunes = manager.subscribe(NS_TUNE);
+        let _more_tunes = manager.subscribe(NS_TUNE);
+        let mood = manager.subscribe("http://jabber.org/protocol/mood");
+
+        let mut announced = Vec::new();
+        for _ in 0..2 {
+            let event = sub.recv().await.unwrap();
+            let EventPayload::PepInterestsChanged { nodes } = event.payload else {
+                panic!("expected PepInterestsChanged, got {:?}", event.payload);
+            };
+            announced.push(nodes);
+        }
+        assert_eq!(
+            announced,
+            vec![
+                vec![NS_TUNE.to_string()],
+                vec![
+                    "http://jabber.org/protocol/mood".to_string(),
+                    NS_TUNE.to_string()
+                ],
+            ]
+        );
+
+        drop(tunes);
+        drop(mood);
+        manager
+            .handle_event(&Event::new(
+                Channel::new("system.connection.established").unwrap(),
+                EventSource::Xmpp,
+                EventPayload::ConnectionEstablished {
+                    jid: "bob@example.com".into(),
+                },
+            ))
+            .await;
+        let event = sub.recv().await.unwrap();
+        let EventPayload::PepInterestsChanged { nodes } = event.payload else {
+            panic!("expected PepInterestsChanged, got {:?}", event.payload);
+        };
+        assert_eq!(nodes, vec![NS_TUNE.to_string()]);
+    }
+}
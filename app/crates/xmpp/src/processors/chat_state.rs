@@ -24,6 +24,16 @@ impl ChatStateProcessor {
     }
 }
 
+pub(super) fn convert_chat_state(state: XmppChatState) -> CoreChatState {
+    match state {
+        XmppChatState::Active => CoreChatState::Active,
+        XmppChatState::Composing => CoreChatState::Composing,
+        XmppChatState::Paused => CoreChatState::Paused,
+        XmppChatState::Inactive => CoreChatState::Inactive,
+        XmppChatState::Gone => CoreChatState::Gone,
+    }
+}
+
 impl StanzaProcessor for ChatStateProcessor {
     fn name(&self) -> &str {
         "chat_state"
@@ -49,13 +59,7 @@ impl StanzaProcessor for ChatStateProcessor {
 
         let from = msg.from.as_ref().map(|j| j.to_string()).unwrap_or_default();
 
-        let core_state = match state {
-            XmppChatState::Active => CoreChatState::Active,
-            XmppChatState::Composing => CoreChatState::Composing,
-            XmppChatState::Paused => CoreChatState::Paused,
-            XmppChatState::Inactive => CoreChatState::Inactive,
-            XmppChatState::Gone => CoreChatState::Gone,
-        };
+        let core_state = convert_chat_state(state);
 
         debug!(from = %from, state = ?core_state, "chat state received");
         #[cfg(feature = "native")]
@@ -0,0 +1,113 @@
+use std::sync::Arc;
+
+use tracing::debug;
+use xmpp_parsers::disco::DiscoInfoQuery;
+use xmpp_parsers::iq::Iq;
+
+use waddle_core::event::{Channel, Event, EventPayload, EventSource};
+
+#[cfg(feature = "native")]
+use waddle_core::event::EventBus;
+
+use crate::pipeline::{ProcessorContext, ProcessorResult, StanzaProcessor};
+use crate::stanza::Stanza;
+
+/// Turns XEP-0030 disco#info queries addressed to us into
+/// `DiscoInfoQueried` events, so the outbound router can answer them with
+/// our entity capabilities.
+pub struct DiscoProcessor {
+    #[cfg(feature = "native")]
+    event_bus: Arc<dyn EventBus>,
+}
+
+impl DiscoProcessor {
+    #[cfg(feature = "native")]
+    pub fn new(event_bus: Arc<dyn EventBus>) -> Self {
+        Self { event_bus }
+    }
+}
+
+impl StanzaProcessor for DiscoProcessor {
+    fn name(&self) -> &str {
+        "disco"
+    }
+
+    fn process_inbound(&self, stanza: &mut Stanza, _ctx: &ProcessorContext) -> ProcessorResult {
+        let Stanza::Iq(iq) = stanza else {
+            return ProcessorResult::Continue;
+        };
+        let Iq::Get {
+            from: Some(from),
+            id,
+            payload,
+            ..
+        } = iq.as_ref()
+        else {
+            return ProcessorResult::Continue;
+        };
+        let Ok(query) = DiscoInfoQuery::try_from(payload.clone()) else {
+            return ProcessorResult::Continue;
+        };
+
+        debug!(from = %from, node = ?query.node, "disco#info query received");
+        #[cfg(feature = "native")]
+        {
+            let _ = self.event_bus.publish(Event::new(
+                Channel::new("xmpp.disco.info.queried").unwrap(),
+                EventSource::Xmpp,
+                EventPayload::DiscoInfoQueried {
+                    from: from.to_string(),
+                    id: id.clone(),
+                    node: query.node,
+                },
+            ));
+        }
+
+        ProcessorResult::Continue
+    }
+
+    fn process_outbound(&self, _stanza: &mut Stanza, _ctx: &ProcessorContext) -> ProcessorResult {
+        ProcessorResult::Continue
+    }
+
+    fn priority(&self) -> i32 {
+        10
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(feature = "native")]
+    #[tokio::test]
+    async fn disco_info_query_is_announced() {
+        use crate::pipeline::StanzaDirection;
+        use waddle_core::event::BroadcastEventBus;
+
+        let xml = b"<iq xmlns='jabber:client' type='get' id='disco1' \
+            from='bob@example.com/phone' to='alice@example.com/desk'>\
+            <query xmlns='http://jabber.org/protocol/disco#info' \
+                node='https://waddle.social#abc='/>\
+        </iq>";
+        let bus: Arc<dyn EventBus> = Arc::new(BroadcastEventBus::default());
+        let mut sub = bus.subscribe("xmpp.**").unwrap();
+        let processor = DiscoProcessor::new(bus.clone());
+
+        let mut stanza = Stanza::parse(xml).unwrap();
+        processor.process_inbound(
+            &mut stanza,
+            &ProcessorContext {
+                direction: StanzaDirection::Inbound,
+            },
+        );
+
+        let event = sub.recv().await.unwrap();
+        let EventPayload::DiscoInfoQueried { from, id, node } = event.payload else {
+            panic!("expected DiscoInfoQueried, got {:?}", event.payload);
+        };
+        assert_eq!(from, "bob@example.com/phone");
+        assert_eq!(id, "disco1");
+        assert_eq!(node.as_deref(), Some("https://waddle.social#abc="));
+    }
+}
@@ -1,6 +1,5 @@
 use std::sync::Arc;
 
-use chrono::Utc;
 use tracing::debug;
 use xmpp_parsers::iq::Iq;
 use xmpp_parsers::mam;
@@ -9,11 +8,12 @@ use waddle_core::event::{
     Channel, ChatMessage, Event, EventPayload, EventSource, MessageType as CoreMessageType,
 };
 
-use super::message::parse_embeds_from_payloads;
+use super::message::{body_variants, origin_id, parse_embeds_from_payloads, replace_id, spoiler};
 
 #[cfg(feature = "native")]
 use waddle_core::event::EventBus;
 
+use crate::forwarded::Forwarded;
 use crate::pipeline::{ProcessorContext, ProcessorResult, StanzaProcessor};
 use crate::stanza::Stanza;
 
@@ -46,19 +46,39 @@ impl StanzaProcessor for MamProcessor {
                     return ProcessorResult::Continue;
                 };
 
-                let forwarded_msg = &result.forwarded.message;
-                let timestamp = result
-                    .forwarded
-                    .delay
-                    .as_ref()
-                    .map(|d| d.stamp.0.to_utc())
-                    .unwrap_or_else(Utc::now);
+                let forwarded = Forwarded::from(result.forwarded);
+                let forwarded_msg = &forwarded.message;
+                let timestamp = forwarded.timestamp();
 
                 let body = forwarded_msg
                     .get_best_body(vec![])
                     .map(|(_, b)| b.clone())
                     .unwrap_or_default();
 
+                // A replayed XEP-0308 correction edits the message it replaces
+                // rather than being history of its own.
+                if let Some(target_id) = replace_id(forwarded_msg) {
+                    let from = forwarded_msg
+                        .from
+                        .as_ref()
+                        .map(|j| j.to_bare().to_string())
+                        .unwrap_or_default();
+                    debug!(from = %from, target_id = %target_id, "archived message correction");
+                    #[cfg(feature = "native")]
+                    {
+                        let _ = self.event_bus.publish(Event::new(
+                            Channel::new("xmpp.message.corrected").unwrap(),
+                            EventSource::Xmpp,
+                            EventPayload::MessageCorrectionReceived {
+                                from,
+                                target_id,
+                                body,
+                            },
+                        ));
+                    }
+                    return ProcessorResult::Continue;
+                }
+
                 let embeds = parse_embeds_from_payloads(&forwarded_msg.payloads);
 
                 let chat_message = ChatMessage {
@@ -84,6 +104,10 @@ impl StanzaProcessor for MamProcessor {
                     },
                     thread: forwarded_msg.thread.as_ref().map(|t| t.id.clone()),
                     embeds,
+                    bodies: body_variants(forwarded_msg),
+                    origin_id: origin_id(forwarded_msg),
+                    spoiler: spoiler(forwarded_msg),
+                    stanza_id: Some(result.id.clone()),
                 };
 
                 let query_id = result
@@ -181,4 +205,56 @@ mod tests {
         assert_eq!(result.id, "archive-id-1");
         assert_eq!(result.queryid.as_ref().map(|q| q.0.as_str()), Some("q1"));
     }
+
+    #[cfg(feature = "native")]
+    #[tokio::test]
+    async fn archived_correction_is_applied_not_stored() {
+        use crate::pipeline::StanzaDirection;
+        use waddle_core::event::BroadcastEventBus;
+
+        let xml = b"<message xmlns='jabber:client' to='alice@example.com'>\
+            <result xmlns='urn:xmpp:mam:2' queryid='q1' id='archive-id-2'>\
+                <forwarded xmlns='urn:xmpp:forward:0'>\
+                    <delay xmlns='urn:xmpp:delay' stamp='2023-06-15T12:01:00Z'/>\
+                    <message xmlns='jabber:client' type='chat' \
+                        from='bob@example.com/phone' to='alice@example.com' id='fix-1'>\
+                        <body>Hello from the past!</body>\
+                        <replace xmlns='urn:xmpp:message-correct:0' id='orig-1'/>\
+                    </message>\
+                </forwarded>\
+            </result>\
+        </message>";
+        let bus: Arc<dyn EventBus> = Arc::new(BroadcastEventBus::default());
+        let mut sub = bus.subscribe("xmpp.**").unwrap();
+        let processor = MamProcessor::new(bus.clone());
+
+        let mut stanza = Stanza::parse(xml).unwrap();
+        processor.process_inbound(
+            &mut stanza,
+            &ProcessorContext {
+                direction: StanzaDirection::Inbound,
+            },
+        );
+
+        let event = sub.recv().await.unwrap();
+        let EventPayload::MessageCorrectionReceived {
+            from,
+            target_id,
+            body,
+        } = event.payload
+        else {
+            panic!(
+                "expected MessageCorrectionReceived, got {:?}",
+                event.payload
+            );
+        };
+        assert_eq!(from, "bob@example.com");
+        assert_eq!(target_id, "orig-1");
+        assert_eq!(body, "Hello from the past!");
+        assert!(
+            tokio::time::timeout(std::time::Duration::from_millis(50), sub.recv())
+                .await
+                .is_err()
+        );
+    }
 }
@@ -1,9 +1,12 @@
+use std::collections::BTreeMap;
 use std::sync::Arc;
 
-use chrono::Utc;
+use chrono::{DateTime, Utc};
 use tracing::debug;
-use xmpp_parsers::message::MessageType;
+use xmpp_parsers::delay::Delay;
+use xmpp_parsers::message::{Message, MessageType};
 use xmpp_parsers::receipts;
+use xmpp_parsers::stanza_id::OriginId;
 
 use waddle_core::event::{
     Channel, ChatMessage, Event, EventPayload, EventSource, MessageEmbed,
@@ -51,12 +54,17 @@ impl StanzaProcessor for MessageProcessor {
                     .as_ref()
                     .map(|j| j.to_bare().to_string())
                     .unwrap_or_default();
+                let resource = msg
+                    .from
+                    .as_ref()
+                    .and_then(|j| j.resource().map(|r| r.to_string()));
                 let _ = self.event_bus.publish(Event::new(
                     Channel::new("xmpp.message.delivered").unwrap(),
                     EventSource::Xmpp,
                     EventPayload::MessageDelivered {
                         id: received.id,
                         to,
+                        resource,
                     },
                 ));
             }
@@ -68,6 +76,28 @@ impl StanzaProcessor for MessageProcessor {
             None => return ProcessorResult::Continue,
         };
 
+        if let Some(target_id) = replace_id(msg) {
+            let from = msg
+                .from
+                .as_ref()
+                .map(|j| j.to_bare().to_string())
+                .unwrap_or_default();
+            debug!(from = %from, target_id = %target_id, "message correction received");
+            #[cfg(feature = "native")]
+            {
+                let _ = self.event_bus.publish(Event::new(
+                    Channel::new("xmpp.message.corrected").unwrap(),
+                    EventSource::Xmpp,
+                    EventPayload::MessageCorrectionReceived {
+                        from,
+                        target_id,
+                        body,
+                    },
+                ));
+            }
+            return ProcessorResult::Continue;
+        }
+
         // Parse plugin embeds from stanza payloads
         let embeds = parse_embeds_from_payloads(&msg.payloads);
 
@@ -84,7 +114,7 @@ impl StanzaProcessor for MessageProcessor {
                 .map(|j| j.to_bare().to_string())
                 .unwrap_or_default(),
             body,
-            timestamp: Utc::now(),
+            timestamp: message_timestamp(msg),
             message_type: match msg.type_ {
                 MessageType::Chat => CoreMessageType::Chat,
                 MessageType::Normal => CoreMessageType::Normal,
@@ -94,6 +124,10 @@ impl StanzaProcessor for MessageProcessor {
             },
             thread: msg.thread.as_ref().map(|t| t.id.clone()),
             embeds,
+            bodies: body_variants(msg),
+            origin_id: origin_id(msg),
+            spoiler: spoiler(msg),
+            stanza_id: None,
         };
 
         debug!(
@@ -125,6 +159,57 @@ impl StanzaProcessor for MessageProcessor {
     }
 }
 
+/// When the message was originally sent: the XEP-0203 `<delay/>` stamp for
+/// offline storage and MUC history, otherwise now.
+pub(crate) fn message_timestamp(msg: &Message) -> DateTime<Utc> {
+    msg.payloads
+        .iter()
+        .find_map(|el| Delay::try_from(el.clone()).ok())
+        .map(|delay| delay.stamp.0.to_utc())
+        .unwrap_or_else(Utc::now)
+}
+
+/// The XEP-0359 `<origin-id/>` the sending client stamped on the message.
+pub(crate) fn origin_id(msg: &Message) -> Option<String> {
+    msg.payloads
+        .iter()
+        .find_map(|el| OriginId::try_from(el.clone()).ok())
+        .map(|origin| origin.id)
+}
+
+const NS_SPOILER: &str = "urn:xmpp:spoiler:0";
+
+/// The XEP-0382 spoiler hint, `Some("")` for a spoiler without a hint.
+pub(crate) fn spoiler(msg: &Message) -> Option<String> {
+    msg.payloads
+        .iter()
+        .find(|el| el.is("spoiler", NS_SPOILER))
+        .map(|el| el.text().trim().to_string())
+}
+
+const NS_MESSAGE_CORRECT: &str = "urn:xmpp:message-correct:0";
+
+/// The id of the message a XEP-0308 correction replaces.
+pub(crate) fn replace_id(msg: &Message) -> Option<String> {
+    msg.payloads
+        .iter()
+        .find(|el| el.is("replace", NS_MESSAGE_CORRECT))
+        .and_then(|el| el.attr("id"))
+        .map(str::to_string)
+}
+
+/// Every `<body/>` by its `xml:lang` when there is more than one, so the
+/// stored message can offer the other languages; empty otherwise.
+pub(crate) fn body_variants(msg: &Message) -> BTreeMap<String, String> {
+    if msg.bodies.len() < 2 {
+        return BTreeMap::new();
+    }
+    msg.bodies
+        .iter()
+        .map(|(lang, body)| (lang.0.clone(), body.clone()))
+        .collect()
+}
+
 /// Known embed namespace for GitHub metadata.
 const NS_WADDLE_GITHUB: &str = "urn:waddle:github:0";
 
@@ -262,7 +347,7 @@ pub(crate) fn parse_embeds_from_payloads(payloads: &[xmpp_parsers::minidom::Elem
     embeds
 }
 
-fn try_extract_receipt(msg: &xmpp_parsers::message::Message) -> Option<receipts::Received> {
+fn try_extract_receipt(msg: &Message) -> Option<receipts::Received> {
     for payload in &msg.payloads {
         if let Ok(received) = receipts::Received::try_from(payload.clone()) {
             return Some(received);
@@ -316,6 +401,105 @@ mod tests {
         assert_eq!(msg.type_, MessageType::Groupchat);
     }
 
+    #[test]
+    fn collects_body_variants_by_lang() {
+        let xml: &[u8] = b"<message xmlns='jabber:client' type='chat' \
+            from='alice@example.com' to='bob@example.com' id='msg-l1'>\
+            <body>Hello</body>\
+            <body xml:lang='de'>Hallo</body>\
+            <body xml:lang='fr'>Bonjour</body>\
+        </message>";
+        let Stanza::Message(msg) = Stanza::parse(xml).unwrap() else {
+            panic!("expected message");
+        };
+
+        let bodies = body_variants(&msg);
+        assert_eq!(bodies.len(), 3);
+        assert_eq!(bodies[""], "Hello");
+        assert_eq!(bodies["de"], "Hallo");
+        assert_eq!(bodies["fr"], "Bonjour");
+    }
+
+    #[test]
+    fn single_body_has_no_variants() {
+        let Stanza::Message(msg) = Stanza::parse(CHAT_MESSAGE_XML).unwrap() else {
+            panic!("expected message");
+        };
+        assert!(body_variants(&msg).is_empty());
+    }
+
+    #[test]
+    fn parses_spoiler_hint() {
+        let xml: &[u8] = b"<message xmlns='jabber:client' type='chat' \
+            from='alice@example.com' to='bob@example.com' id='msg-s1'>\
+            <body>The butler did it</body>\
+            <spoiler xmlns='urn:xmpp:spoiler:0'>Murder mystery</spoiler>\
+        </message>";
+        let Stanza::Message(msg) = Stanza::parse(xml).unwrap() else {
+            panic!("expected message");
+        };
+        assert_eq!(spoiler(&msg).as_deref(), Some("Murder mystery"));
+
+        let bare: &[u8] = b"<message xmlns='jabber:client' type='chat' \
+            from='alice@example.com' to='bob@example.com' id='msg-s2'>\
+            <body>The butler did it</body>\
+            <spoiler xmlns='urn:xmpp:spoiler:0'/>\
+        </message>";
+        let Stanza::Message(msg) = Stanza::parse(bare).unwrap() else {
+            panic!("expected message");
+        };
+        assert_eq!(spoiler(&msg).as_deref(), Some(""));
+
+        let Stanza::Message(msg) = Stanza::parse(CHAT_MESSAGE_XML).unwrap() else {
+            panic!("expected message");
+        };
+        assert!(spoiler(&msg).is_none());
+    }
+
+    #[test]
+    fn parses_correction_target() {
+        let xml: &[u8] = b"<message xmlns='jabber:client' type='chat' \
+            from='alice@example.com/phone' to='bob@example.com' id='msg-c2'>\
+            <body>Hello, Bob!</body>\
+            <replace xmlns='urn:xmpp:message-correct:0' id='msg-c1'/>\
+        </message>";
+        let Stanza::Message(msg) = Stanza::parse(xml).unwrap() else {
+            panic!("expected message");
+        };
+        assert_eq!(replace_id(&msg).as_deref(), Some("msg-c1"));
+
+        let Stanza::Message(msg) = Stanza::parse(CHAT_MESSAGE_XML).unwrap() else {
+            panic!("expected message");
+        };
+        assert!(replace_id(&msg).is_none());
+    }
+
+    #[test]
+    fn delayed_message_uses_delay_stamp() {
+        let xml: &[u8] = b"<message xmlns='jabber:client' type='chat' \
+            from='alice@example.com/phone' to='bob@example.com' id='msg-d1'>\
+            <body>Sent while you were away</body>\
+            <delay xmlns='urn:xmpp:delay' from='example.com' stamp='2023-06-15T12:00:00+02:00'/>\
+        </message>";
+        let stanza = Stanza::parse(xml).unwrap();
+        let Stanza::Message(msg) = &stanza else {
+            panic!("expected message");
+        };
+        let expected = "2023-06-15T10:00:00Z".parse::<DateTime<Utc>>().unwrap();
+        assert_eq!(message_timestamp(msg), expected);
+    }
+
+    #[test]
+    fn undelayed_message_is_stamped_now() {
+        let stanza = Stanza::parse(CHAT_MESSAGE_XML).unwrap();
+        let Stanza::Message(msg) = &stanza else {
+            panic!("expected message");
+        };
+        let before = Utc::now();
+        let timestamp = message_timestamp(msg);
+        assert!(timestamp >= before && timestamp <= Utc::now());
+    }
+
     // ── Embed parsing tests ────────────────────────────────────────
 
     #[test]
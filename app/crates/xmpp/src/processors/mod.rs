@@ -1,15 +1,19 @@
 mod chat_state;
 mod debug;
+mod disco;
 mod mam;
 mod message;
 mod muc;
+mod pep;
 mod presence;
 mod roster;
 
 pub use chat_state::ChatStateProcessor;
 pub use debug::DebugProcessor;
+pub use disco::DiscoProcessor;
 pub use mam::MamProcessor;
 pub use message::MessageProcessor;
 pub use muc::MucProcessor;
+pub use pep::PepProcessor;
 pub use presence::PresenceProcessor;
 pub use roster::RosterProcessor;
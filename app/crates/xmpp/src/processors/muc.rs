@@ -1,18 +1,29 @@
-use std::sync::Arc;
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
 
-use chrono::Utc;
 use tracing::debug;
-use xmpp_parsers::message::MessageType;
+use xmpp_parsers::chatstates::ChatState as XmppChatState;
+use xmpp_parsers::disco::DiscoInfoResult;
+use xmpp_parsers::iq::Iq;
+use xmpp_parsers::jid::Jid;
+use xmpp_parsers::message::{Message, MessageType};
+use xmpp_parsers::minidom::Element;
 use xmpp_parsers::muc::user::{MucUser, Status};
+use xmpp_parsers::ns;
 use xmpp_parsers::presence::Type as PresenceType;
+use xmpp_parsers::stanza_id::StanzaId;
 
 use waddle_core::event::{
     Channel, ChatMessage, Event, EventPayload, EventSource, MessageType as CoreMessageType,
-    MucAffiliation as CoreAffiliation, MucOccupant as CoreOccupant, MucRole as CoreRole,
+    MucAffiliation as CoreAffiliation, MucJoinFailure, MucOccupant as CoreOccupant, MucRemoval,
+    MucRole as CoreRole, MucRoomInfo,
 };
 
-// Re-use the embed parser from the message processor
-use super::message::parse_embeds_from_payloads;
+use super::chat_state::convert_chat_state;
+// Re-use the embed parser and timestamp handling from the message processor
+use super::message::{
+    body_variants, message_timestamp, origin_id, parse_embeds_from_payloads, spoiler,
+};
 
 #[cfg(feature = "native")]
 use waddle_core::event::EventBus;
@@ -20,15 +31,37 @@ use waddle_core::event::EventBus;
 use crate::pipeline::{ProcessorContext, ProcessorResult, StanzaProcessor};
 use crate::stanza::Stanza;
 
+const NS_MESSAGE_MODERATE: &str = "urn:xmpp:message-moderate:1";
+const NS_MESSAGE_RETRACT: &str = "urn:xmpp:message-retract:1";
+const NS_MUC_ADMIN: &str = "http://jabber.org/protocol/muc#admin";
+
 pub struct MucProcessor {
     #[cfg(feature = "native")]
     event_bus: Arc<dyn EventBus>,
+    /// Bare JIDs of the rooms our own presence says we are in
+    joined_rooms: Mutex<HashSet<String>>,
 }
 
 impl MucProcessor {
     #[cfg(feature = "native")]
     pub fn new(event_bus: Arc<dyn EventBus>) -> Self {
-        Self { event_bus }
+        Self {
+            event_bus,
+            joined_rooms: Mutex::new(HashSet::new()),
+        }
+    }
+
+    /// Whether `from` is a room we are in, as itself rather than one of
+    /// its occupants.
+    fn is_joined_room(&self, from: Option<&Jid>) -> bool {
+        from.is_some_and(|from| {
+            from.resource().is_none()
+                && self
+                    .joined_rooms
+                    .lock()
+                    .unwrap()
+                    .contains(&from.to_bare().to_string())
+        })
     }
 }
 
@@ -44,6 +77,23 @@ impl StanzaProcessor for MucProcessor {
                     return ProcessorResult::Continue;
                 }
 
+                if let Some(moderated) = parse_moderation(msg) {
+                    if !self.is_joined_room(msg.from.as_ref()) {
+                        debug!(from = ?msg.from, "dropping moderation notice not sent by a joined room");
+                        return ProcessorResult::Continue;
+                    }
+                    debug!(payload = ?moderated, "MUC message moderated");
+                    #[cfg(feature = "native")]
+                    {
+                        let _ = self.event_bus.publish(Event::new(
+                            Channel::new("xmpp.muc.message.moderated").unwrap(),
+                            EventSource::Xmpp,
+                            moderated,
+                        ));
+                    }
+                    return ProcessorResult::Continue;
+                }
+
                 if let Some((_, subject)) = msg.get_best_subject(vec![]) {
                     let room = msg
                         .from
@@ -67,7 +117,20 @@ impl StanzaProcessor for MucProcessor {
 
                 let body = match msg.get_best_body(vec![]) {
                     Some((_, body)) => body.clone(),
-                    None => return ProcessorResult::Continue,
+                    None => {
+                        if let Some(chat_state) = parse_occupant_chat_state(msg) {
+                            debug!(payload = ?chat_state, "MUC chat state received");
+                            #[cfg(feature = "native")]
+                            {
+                                let _ = self.event_bus.publish(Event::new(
+                                    Channel::new("xmpp.muc.chatstate.received").unwrap(),
+                                    EventSource::Xmpp,
+                                    chat_state,
+                                ));
+                            }
+                        }
+                        return ProcessorResult::Continue;
+                    }
                 };
 
                 let room = msg
@@ -79,14 +142,18 @@ impl StanzaProcessor for MucProcessor {
                 let embeds = parse_embeds_from_payloads(&msg.payloads);
 
                 let chat_message = ChatMessage {
-                    id: msg.id.as_ref().map(|id| id.0.clone()).unwrap_or_default(),
+                    id: groupchat_message_id(msg),
                     from: msg.from.as_ref().map(|j| j.to_string()).unwrap_or_default(),
                     to: msg.to.as_ref().map(|j| j.to_string()).unwrap_or_default(),
                     body,
-                    timestamp: Utc::now(),
+                    timestamp: message_timestamp(msg),
                     message_type: CoreMessageType::Groupchat,
                     thread: msg.thread.as_ref().map(|t| t.id.clone()),
                     embeds,
+                    bodies: body_variants(msg),
+                    origin_id: origin_id(msg),
+                    spoiler: spoiler(msg),
+                    stanza_id: room_stanza_id(msg, &room),
                 };
 
                 debug!(room = %room, "MUC message received");
@@ -103,6 +170,53 @@ impl StanzaProcessor for MucProcessor {
                 }
             }
             Stanza::Presence(presence) => {
+                if presence.type_ == PresenceType::Error
+                    && let Some(from) = presence.from.as_ref()
+                    && let Some(nick) = from.resource()
+                    && let Some(failure) = parse_join_failure(&presence.payloads)
+                {
+                    let room = from.to_bare().to_string();
+                    let nick = nick.to_string();
+                    debug!(room = %room, nick = %nick, failure = ?failure, "MUC join failed");
+                    #[cfg(feature = "native")]
+                    {
+                        let _ = self.event_bus.publish(Event::new(
+                            Channel::new("xmpp.muc.join.failed").unwrap(),
+                            EventSource::Xmpp,
+                            EventPayload::MucJoinFailed {
+                                room,
+                                nick,
+                                failure,
+                            },
+                        ));
+                    }
+                    return ProcessorResult::Continue;
+                }
+
+                if presence.type_ == PresenceType::Unavailable
+                    && let Some((reason, alternate)) = parse_destroy(&presence.payloads)
+                {
+                    let room = presence
+                        .from
+                        .as_ref()
+                        .map(|j| j.to_bare().to_string())
+                        .unwrap_or_default();
+                    debug!(room = %room, alternate = ?alternate, "MUC room destroyed");
+                    #[cfg(feature = "native")]
+                    {
+                        let _ = self.event_bus.publish(Event::new(
+                            Channel::new("xmpp.muc.destroyed").unwrap(),
+                            EventSource::Xmpp,
+                            EventPayload::MucDestroyed {
+                                room,
+                                reason,
+                                alternate,
+                            },
+                        ));
+                    }
+                    return ProcessorResult::Continue;
+                }
+
                 let muc_user = presence
                     .payloads
                     .iter()
@@ -126,15 +240,37 @@ impl StanzaProcessor for MucProcessor {
 
                 let is_self = muc_user.status.contains(&Status::SelfPresence);
 
+                if presence.type_ == PresenceType::Unavailable
+                    && let Some(new_nick) = parse_new_nick(&muc_user)
+                {
+                    debug!(room = %room, nick = %nick, new_nick = %new_nick, "MUC nick changed");
+                    #[cfg(feature = "native")]
+                    {
+                        let _ = self.event_bus.publish(Event::new(
+                            Channel::new("xmpp.muc.nick.changed").unwrap(),
+                            EventSource::Xmpp,
+                            EventPayload::MucNickChanged {
+                                room,
+                                old_nick: nick,
+                                new_nick,
+                                own: is_self,
+                            },
+                        ));
+                    }
+                    return ProcessorResult::Continue;
+                }
+
                 if presence.type_ == PresenceType::Unavailable {
                     if is_self {
-                        debug!(room = %room, "left MUC room");
+                        self.joined_rooms.lock().unwrap().remove(&room);
+                        let removal = parse_removal(&muc_user, &presence.payloads);
+                        debug!(room = %room, removal = ?removal, "left MUC room");
                         #[cfg(feature = "native")]
                         {
                             let _ = self.event_bus.publish(Event::new(
                                 Channel::new("xmpp.muc.left").unwrap(),
                                 EventSource::Xmpp,
-                                EventPayload::MucLeft { room },
+                                EventPayload::MucLeft { room, removal },
                             ));
                         }
                     } else {
@@ -148,6 +284,7 @@ impl StanzaProcessor for MucProcessor {
                     }
                 } else {
                     if is_self {
+                        self.joined_rooms.lock().unwrap().insert(room.clone());
                         debug!(room = %room, nick = %nick, "joined MUC room");
                         #[cfg(feature = "native")]
                         {
@@ -170,7 +307,71 @@ impl StanzaProcessor for MucProcessor {
                     );
                 }
             }
-            _ => {}
+            Stanza::Iq(iq) => match iq.as_ref() {
+                Iq::Result {
+                    id,
+                    from: Some(room),
+                    payload: Some(payload),
+                    ..
+                } => {
+                    if let Some(items) = parse_affiliation_list(payload) {
+                        debug!(room = %room, count = items.len(), "MUC affiliation list received");
+                        #[cfg(feature = "native")]
+                        {
+                            let _ = self.event_bus.publish(Event::new(
+                                Channel::new("xmpp.muc.affiliations.received").unwrap(),
+                                EventSource::Xmpp,
+                                EventPayload::MucAffiliationListReceived {
+                                    query_id: id.clone(),
+                                    room: room.to_string(),
+                                    items,
+                                },
+                            ));
+                        }
+                    } else if let Ok(disco) = DiscoInfoResult::try_from(payload.clone()) {
+                        let info = room_info_from_disco(&disco);
+                        debug!(room = %room, info = ?info, "MUC room info received");
+                        #[cfg(feature = "native")]
+                        {
+                            let _ = self.event_bus.publish(Event::new(
+                                Channel::new("xmpp.muc.info.received").unwrap(),
+                                EventSource::Xmpp,
+                                EventPayload::MucRoomInfoReceived {
+                                    query_id: id.clone(),
+                                    room: room.to_string(),
+                                    info,
+                                },
+                            ));
+                        }
+                    }
+                }
+                // Rooms are addressed by bare JID, so only those errors can
+                // answer a room query.
+                Iq::Error {
+                    id,
+                    from: Some(room),
+                    error,
+                    ..
+                } if room.resource().is_none() => {
+                    let condition = Element::from(error.defined_condition.clone())
+                        .name()
+                        .to_string();
+                    debug!(room = %room, condition = %condition, "MUC query failed");
+                    #[cfg(feature = "native")]
+                    {
+                        let _ = self.event_bus.publish(Event::new(
+                            Channel::new("xmpp.muc.query.failed").unwrap(),
+                            EventSource::Xmpp,
+                            EventPayload::MucQueryFailed {
+                                query_id: id.clone(),
+                                room: room.to_string(),
+                                condition,
+                            },
+                        ));
+                    }
+                }
+                _ => {}
+            },
         }
 
         ProcessorResult::Continue
@@ -185,6 +386,179 @@ impl StanzaProcessor for MucProcessor {
     }
 }
 
+/// Rooms may rewrite the stanza id when reflecting a message, so prefer the
+/// sender's XEP-0359 origin-id to keep our own reflections matchable.
+fn groupchat_message_id(msg: &Message) -> String {
+    origin_id(msg)
+        .or_else(|| msg.id.as_ref().map(|id| id.0.clone()))
+        .unwrap_or_default()
+}
+
+/// The XEP-0359 `<stanza-id/>` the room stamped on `msg`. Ids claimed by
+/// anyone else are ignored, since the sender could forge them.
+fn room_stanza_id(msg: &Message, room: &str) -> Option<String> {
+    msg.payloads
+        .iter()
+        .filter_map(|el| StanzaId::try_from(el.clone()).ok())
+        .find(|stanza_id| stanza_id.by.to_string() == room)
+        .map(|stanza_id| stanza_id.id)
+}
+
+/// Extracts a standalone XEP-0085 chat state sent by a room occupant.
+fn parse_occupant_chat_state(msg: &Message) -> Option<EventPayload> {
+    let from = msg.from.as_ref()?;
+    let nick = from.resource()?.to_string();
+    let state = msg
+        .payloads
+        .iter()
+        .find_map(|el| XmppChatState::try_from(el.clone()).ok())?;
+
+    Some(EventPayload::MucChatStateReceived {
+        room: from.to_bare().to_string(),
+        nick,
+        state: convert_chat_state(state),
+    })
+}
+
+/// Recognises a XEP-0425 moderation notice: a `<retract/>` from the room
+/// carrying a `<moderated/>` marker. Such messages may also carry a fallback
+/// body, so this must be checked before treating the stanza as chat.
+fn parse_moderation(msg: &Message) -> Option<EventPayload> {
+    let retract = msg
+        .payloads
+        .iter()
+        .find(|el| el.is("retract", NS_MESSAGE_RETRACT))?;
+    let moderated = retract.get_child("moderated", NS_MESSAGE_MODERATE)?;
+
+    let room = msg
+        .from
+        .as_ref()
+        .map(|j| j.to_bare().to_string())
+        .unwrap_or_default();
+    let reason = retract
+        .get_child("reason", NS_MESSAGE_RETRACT)
+        .map(|r| r.text())
+        .filter(|r| !r.is_empty());
+
+    Some(EventPayload::MessageModerated {
+        room,
+        id: retract.attr("id")?.to_string(),
+        by: moderated.attr("by").map(str::to_string),
+        reason,
+    })
+}
+
+/// Extracts the `<destroy/>` notice (reason, alternate room JID) from an
+/// unavailable presence. Read from the raw payload because xmpp-parsers'
+/// `MucUser` does not model `<destroy/>`.
+fn parse_destroy(payloads: &[Element]) -> Option<(Option<String>, Option<String>)> {
+    let destroy = payloads
+        .iter()
+        .find(|el| el.is("x", ns::MUC_USER))?
+        .get_child("destroy", ns::MUC_USER)?;
+
+    let reason = destroy
+        .get_child("reason", ns::MUC_USER)
+        .map(|r| r.text())
+        .filter(|r| !r.is_empty());
+    let alternate = destroy.attr("jid").map(str::to_string);
+
+    Some((reason, alternate))
+}
+
+/// Maps the error condition of a room's reply to our join presence. Read from
+/// the raw `<error/>` so unmodelled conditions keep their name.
+fn parse_join_failure(payloads: &[Element]) -> Option<MucJoinFailure> {
+    let condition = payloads
+        .iter()
+        .find(|el| el.is("error", ns::DEFAULT_NS))?
+        .children()
+        .find(|child| child.ns() == ns::XMPP_STANZAS && child.name() != "text")?;
+
+    Some(match condition.name() {
+        "conflict" => MucJoinFailure::NickConflict,
+        "registration-required" => MucJoinFailure::NotMember,
+        "item-not-found" => MucJoinFailure::RoomLocked,
+        "forbidden" => MucJoinFailure::Banned,
+        other => MucJoinFailure::Other(other.to_string()),
+    })
+}
+
+/// Summarises a room's disco#info features (XEP-0045 section 6.4).
+fn room_info_from_disco(disco: &DiscoInfoResult) -> MucRoomInfo {
+    let features: Vec<String> = disco.features.iter().map(|f| f.var.clone()).collect();
+    let has = |var: &str| features.iter().any(|f| f == var);
+    MucRoomInfo {
+        exists: true,
+        members_only: has("muc_membersonly"),
+        password_protected: has("muc_passwordprotected"),
+        features,
+    }
+}
+
+/// Reads the entries of a XEP-0045 admin query result: each item's JID and
+/// optional reason. Items without a JID are skipped.
+fn parse_affiliation_list(payload: &Element) -> Option<Vec<(String, Option<String>)>> {
+    if !payload.is("query", NS_MUC_ADMIN) {
+        return None;
+    }
+
+    Some(
+        payload
+            .children()
+            .filter(|child| child.is("item", NS_MUC_ADMIN))
+            .filter_map(|item| {
+                let jid = item.attr("jid")?.to_string();
+                let reason = item
+                    .get_child("reason", NS_MUC_ADMIN)
+                    .map(|reason| reason.text());
+                Some((jid, reason))
+            })
+            .collect(),
+    )
+}
+
+/// The new nick announced by an unavailable presence with status 303.
+fn parse_new_nick(muc_user: &MucUser) -> Option<String> {
+    if !muc_user.status.contains(&Status::NewNick) {
+        return None;
+    }
+    muc_user.items.first()?.nick.clone()
+}
+
+/// Extracts why the room removed us from our own unavailable presence.
+/// Returns `None` for a voluntary leave.
+fn parse_removal(muc_user: &MucUser, payloads: &[Element]) -> Option<MucRemoval> {
+    let code = muc_user.status.iter().find_map(|status| match status {
+        Status::Banned => Some(301),
+        Status::Kicked => Some(307),
+        Status::RemovalFromRoom => Some(321),
+        Status::ConfigMembersOnly => Some(322),
+        Status::ServiceShutdown => Some(332),
+        Status::ServiceErrorKick => Some(333),
+        _ => None,
+    })?;
+
+    let item = muc_user.items.first();
+    let reason = item.and_then(|item| item.reason.as_ref().map(|r| r.0.clone()));
+
+    // The actor's attributes are not exposed by xmpp-parsers, so read them
+    // from the raw <x/> payload.
+    let actor = payloads
+        .iter()
+        .find(|el| el.is("x", ns::MUC_USER))
+        .and_then(|x| x.get_child("item", ns::MUC_USER))
+        .and_then(|item| item.get_child("actor", ns::MUC_USER))
+        .and_then(|actor| actor.attr("nick").or_else(|| actor.attr("jid")))
+        .map(str::to_string);
+
+    Some(MucRemoval {
+        code,
+        reason,
+        actor,
+    })
+}
+
 fn emit_occupant_changed(
     room: &str,
     nick: &str,
@@ -247,6 +621,348 @@ mod tests {
         </x>\
     </presence>";
 
+    const MUC_KICK_XML: &[u8] = b"<presence xmlns='jabber:client' type='unavailable' \
+        from='room@conference.example.com/bob'>\
+        <x xmlns='http://jabber.org/protocol/muc#user'>\
+            <item affiliation='none' role='none'>\
+                <actor nick='alice'/>\
+                <reason>Spamming</reason>\
+            </item>\
+            <status code='110'/>\
+            <status code='307'/>\
+        </x>\
+    </presence>";
+
+    const MUC_SELF_LEAVE_XML: &[u8] = b"<presence xmlns='jabber:client' type='unavailable' \
+        from='room@conference.example.com/bob'>\
+        <x xmlns='http://jabber.org/protocol/muc#user'>\
+            <item affiliation='member' role='none'/>\
+            <status code='110'/>\
+        </x>\
+    </presence>";
+
+    const MUC_DESTROY_XML: &[u8] = b"<presence xmlns='jabber:client' type='unavailable' \
+        from='room@conference.example.com/bob'>\
+        <x xmlns='http://jabber.org/protocol/muc#user'>\
+            <item affiliation='none' role='none'/>\
+            <destroy jid='new@conference.example.com'>\
+                <reason>Moved</reason>\
+            </destroy>\
+        </x>\
+    </presence>";
+
+    const MUC_NICK_CONFLICT_XML: &[u8] = b"<presence xmlns='jabber:client' type='error' \
+        from='room@conference.example.com/bob'>\
+        <x xmlns='http://jabber.org/protocol/muc'/>\
+        <error type='cancel' by='room@conference.example.com'>\
+            <conflict xmlns='urn:ietf:params:xml:ns:xmpp-stanzas'/>\
+        </error>\
+    </presence>";
+
+    fn parse_presence_join_failure(xml: &[u8]) -> Option<MucJoinFailure> {
+        let stanza = Stanza::parse(xml).unwrap();
+        let Stanza::Presence(presence) = &stanza else {
+            panic!("expected presence");
+        };
+        parse_join_failure(&presence.payloads)
+    }
+
+    #[test]
+    fn join_error_conditions_map_to_failures() {
+        assert_eq!(
+            parse_presence_join_failure(MUC_NICK_CONFLICT_XML),
+            Some(MucJoinFailure::NickConflict)
+        );
+
+        let members_only: &[u8] = b"<presence xmlns='jabber:client' type='error' \
+            from='room@conference.example.com/bob'>\
+            <error type='auth'>\
+                <registration-required xmlns='urn:ietf:params:xml:ns:xmpp-stanzas'/>\
+                <text xmlns='urn:ietf:params:xml:ns:xmpp-stanzas'>Members only</text>\
+            </error>\
+        </presence>";
+        assert_eq!(
+            parse_presence_join_failure(members_only),
+            Some(MucJoinFailure::NotMember)
+        );
+
+        let full: &[u8] = b"<presence xmlns='jabber:client' type='error' \
+            from='room@conference.example.com/bob'>\
+            <error type='wait'>\
+                <service-unavailable xmlns='urn:ietf:params:xml:ns:xmpp-stanzas'/>\
+            </error>\
+        </presence>";
+        assert_eq!(
+            parse_presence_join_failure(full),
+            Some(MucJoinFailure::Other("service-unavailable".to_string()))
+        );
+
+        assert!(parse_presence_join_failure(MUC_PRESENCE_XML).is_none());
+    }
+
+    #[cfg(feature = "native")]
+    #[tokio::test]
+    async fn nick_conflict_presence_publishes_join_failed() {
+        use crate::pipeline::StanzaDirection;
+        use waddle_core::event::BroadcastEventBus;
+
+        let bus: Arc<dyn EventBus> = Arc::new(BroadcastEventBus::default());
+        let mut sub = bus.subscribe("xmpp.muc.join.failed").unwrap();
+        let processor = MucProcessor::new(bus.clone());
+
+        let mut stanza = Stanza::parse(MUC_NICK_CONFLICT_XML).unwrap();
+        processor.process_inbound(
+            &mut stanza,
+            &ProcessorContext {
+                direction: StanzaDirection::Inbound,
+            },
+        );
+
+        let event = sub.recv().await.unwrap();
+        let EventPayload::MucJoinFailed {
+            room,
+            nick,
+            failure,
+        } = event.payload
+        else {
+            panic!("expected MucJoinFailed");
+        };
+        assert_eq!(room, "room@conference.example.com");
+        assert_eq!(nick, "bob");
+        assert_eq!(failure, MucJoinFailure::NickConflict);
+    }
+
+    #[cfg(feature = "native")]
+    #[tokio::test]
+    async fn affiliation_list_result_publishes_items() {
+        use crate::pipeline::StanzaDirection;
+        use waddle_core::event::BroadcastEventBus;
+
+        let bus: Arc<dyn EventBus> = Arc::new(BroadcastEventBus::default());
+        let mut sub = bus.subscribe("xmpp.muc.affiliations.received").unwrap();
+        let processor = MucProcessor::new(bus.clone());
+
+        let xml = b"<iq xmlns='jabber:client' type='result' id='q-1' \
+            from='room@conference.example.com' to='alice@example.com/desktop'>\
+            <query xmlns='http://jabber.org/protocol/muc#admin'>\
+                <item affiliation='outcast' jid='troll@example.com'>\
+                    <reason>Spamming</reason>\
+                </item>\
+                <item affiliation='outcast' jid='bot@example.net'/>\
+            </query>\
+        </iq>";
+        let mut stanza = Stanza::parse(xml).unwrap();
+        processor.process_inbound(
+            &mut stanza,
+            &ProcessorContext {
+                direction: StanzaDirection::Inbound,
+            },
+        );
+
+        let event = sub.recv().await.unwrap();
+        let EventPayload::MucAffiliationListReceived {
+            query_id,
+            room,
+            items,
+        } = event.payload
+        else {
+            panic!("expected MucAffiliationListReceived");
+        };
+        assert_eq!(query_id, "q-1");
+        assert_eq!(room, "room@conference.example.com");
+        assert_eq!(
+            items,
+            vec![
+                (
+                    "troll@example.com".to_string(),
+                    Some("Spamming".to_string())
+                ),
+                ("bot@example.net".to_string(), None),
+            ]
+        );
+    }
+
+    #[cfg(feature = "native")]
+    async fn process_with_bus(xml: &[u8], channel: &str) -> Event {
+        use crate::pipeline::StanzaDirection;
+        use waddle_core::event::BroadcastEventBus;
+
+        let bus: Arc<dyn EventBus> = Arc::new(BroadcastEventBus::default());
+        let mut sub = bus.subscribe(channel).unwrap();
+        let processor = MucProcessor::new(bus.clone());
+
+        let mut stanza = Stanza::parse(xml).unwrap();
+        processor.process_inbound(
+            &mut stanza,
+            &ProcessorContext {
+                direction: StanzaDirection::Inbound,
+            },
+        );
+        sub.recv().await.unwrap()
+    }
+
+    #[cfg(feature = "native")]
+    #[tokio::test]
+    async fn disco_info_result_publishes_room_info() {
+        let xml = b"<iq xmlns='jabber:client' type='result' id='q-2' \
+            from='room@conference.example.com' to='alice@example.com/desktop'>\
+            <query xmlns='http://jabber.org/protocol/disco#info'>\
+                <identity category='conference' type='text' name='Room'/>\
+                <feature var='http://jabber.org/protocol/muc'/>\
+                <feature var='muc_membersonly'/>\
+            </query>\
+        </iq>";
+        let event = process_with_bus(xml, "xmpp.muc.info.received").await;
+
+        let EventPayload::MucRoomInfoReceived {
+            query_id,
+            room,
+            info,
+        } = event.payload
+        else {
+            panic!("expected MucRoomInfoReceived");
+        };
+        assert_eq!(query_id, "q-2");
+        assert_eq!(room, "room@conference.example.com");
+        assert!(info.exists);
+        assert!(info.members_only);
+        assert!(!info.password_protected);
+        assert_eq!(
+            info.features,
+            vec!["http://jabber.org/protocol/muc", "muc_membersonly"]
+        );
+    }
+
+    #[cfg(feature = "native")]
+    #[tokio::test]
+    async fn room_iq_error_publishes_query_failed() {
+        let xml = b"<iq xmlns='jabber:client' type='error' id='q-3' \
+            from='missing@conference.example.com' to='alice@example.com/desktop'>\
+            <error type='cancel'>\
+                <item-not-found xmlns='urn:ietf:params:xml:ns:xmpp-stanzas'/>\
+            </error>\
+        </iq>";
+        let event = process_with_bus(xml, "xmpp.muc.query.failed").await;
+
+        let EventPayload::MucQueryFailed {
+            query_id,
+            room,
+            condition,
+        } = event.payload
+        else {
+            panic!("expected MucQueryFailed");
+        };
+        assert_eq!(query_id, "q-3");
+        assert_eq!(room, "missing@conference.example.com");
+        assert_eq!(condition, "item-not-found");
+    }
+
+    #[cfg(feature = "native")]
+    #[tokio::test]
+    async fn own_nick_change_publishes_nick_changed_not_left() {
+        let xml = b"<presence xmlns='jabber:client' type='unavailable' \
+            from='room@conference.example.com/bob'>\
+            <x xmlns='http://jabber.org/protocol/muc#user'>\
+                <item affiliation='member' role='participant' nick='robert'/>\
+                <status code='303'/>\
+                <status code='110'/>\
+            </x>\
+        </presence>";
+        let event = process_with_bus(xml, "xmpp.muc.**").await;
+
+        let EventPayload::MucNickChanged {
+            room,
+            old_nick,
+            new_nick,
+            own,
+        } = event.payload
+        else {
+            panic!("expected MucNickChanged, got {:?}", event.payload);
+        };
+        assert_eq!(room, "room@conference.example.com");
+        assert_eq!(old_nick, "bob");
+        assert_eq!(new_nick, "robert");
+        assert!(own);
+    }
+
+    #[test]
+    fn destroy_presence_yields_reason_and_alternate() {
+        let stanza = Stanza::parse(MUC_DESTROY_XML).unwrap();
+        let Stanza::Presence(presence) = &stanza else {
+            panic!("expected presence");
+        };
+        let (reason, alternate) =
+            parse_destroy(&presence.payloads).expect("destroy should be detected");
+        assert_eq!(reason.as_deref(), Some("Moved"));
+        assert_eq!(alternate.as_deref(), Some("new@conference.example.com"));
+    }
+
+    #[test]
+    fn kick_presence_is_not_a_destroy() {
+        let stanza = Stanza::parse(MUC_KICK_XML).unwrap();
+        let Stanza::Presence(presence) = &stanza else {
+            panic!("expected presence");
+        };
+        assert!(parse_destroy(&presence.payloads).is_none());
+    }
+
+    fn parse_presence_removal(xml: &[u8]) -> Option<MucRemoval> {
+        let stanza = Stanza::parse(xml).unwrap();
+        let Stanza::Presence(presence) = &stanza else {
+            panic!("expected presence");
+        };
+        let muc_user = presence
+            .payloads
+            .iter()
+            .find_map(|el| MucUser::try_from(el.clone()).ok())
+            .expect("muc#user payload");
+        parse_removal(&muc_user, &presence.payloads)
+    }
+
+    #[test]
+    fn kick_self_presence_yields_removal() {
+        let removal = parse_presence_removal(MUC_KICK_XML).expect("kick should be a removal");
+        assert_eq!(removal.code, 307);
+        assert_eq!(removal.reason.as_deref(), Some("Spamming"));
+        assert_eq!(removal.actor.as_deref(), Some("alice"));
+    }
+
+    #[test]
+    fn voluntary_leave_is_not_a_removal() {
+        assert!(parse_presence_removal(MUC_SELF_LEAVE_XML).is_none());
+    }
+
+    #[cfg(feature = "native")]
+    #[tokio::test]
+    async fn muc_history_message_keeps_delay_stamp() {
+        use crate::pipeline::StanzaDirection;
+        use waddle_core::event::BroadcastEventBus;
+
+        let xml: &[u8] = b"<message xmlns='jabber:client' type='groupchat' \
+            from='room@conference.example.com/alice' to='bob@example.com' id='muc-h1'>\
+            <body>From before you joined</body>\
+            <delay xmlns='urn:xmpp:delay' from='room@conference.example.com' \
+                   stamp='2023-06-15T12:00:00Z'/>\
+        </message>";
+        let bus: Arc<dyn EventBus> = Arc::new(BroadcastEventBus::default());
+        let mut sub = bus.subscribe("xmpp.muc.message.received").unwrap();
+        let processor = MucProcessor::new(bus.clone());
+
+        let mut stanza = Stanza::parse(xml).unwrap();
+        processor.process_inbound(
+            &mut stanza,
+            &ProcessorContext {
+                direction: StanzaDirection::Inbound,
+            },
+        );
+
+        let event = sub.recv().await.unwrap();
+        let EventPayload::MucMessageReceived { message, .. } = event.payload else {
+            panic!("expected MucMessageReceived");
+        };
+        assert_eq!(message.timestamp.to_rfc3339(), "2023-06-15T12:00:00+00:00");
+    }
+
     #[test]
     fn parses_muc_message() {
         let stanza = Stanza::parse(MUC_MESSAGE_XML).unwrap();
@@ -256,6 +972,162 @@ mod tests {
         assert_eq!(msg.type_, MessageType::Groupchat);
     }
 
+    #[test]
+    fn groupchat_message_id_prefers_origin_id() {
+        let xml = b"<message xmlns='jabber:client' type='groupchat' \
+            from='room@conference.example.com/alice' to='alice@example.com' id='rewritten'>\
+            <body>Hello everyone!</body>\
+            <origin-id xmlns='urn:xmpp:sid:0' id='origin-1'/>\
+        </message>";
+        let stanza = Stanza::parse(xml).unwrap();
+        let Stanza::Message(msg) = &stanza else {
+            panic!("expected message");
+        };
+        assert_eq!(groupchat_message_id(msg), "origin-1");
+
+        let stanza = Stanza::parse(MUC_MESSAGE_XML).unwrap();
+        let Stanza::Message(msg) = &stanza else {
+            panic!("expected message");
+        };
+        assert_eq!(groupchat_message_id(msg), "muc-1");
+    }
+
+    #[test]
+    fn room_stanza_id_ignores_ids_claimed_by_others() {
+        let xml = b"<message xmlns='jabber:client' type='groupchat' \
+            from='room@conference.example.com/alice' to='bob@example.com'>\
+            <body>Hi</body>\
+            <stanza-id xmlns='urn:xmpp:sid:0' by='alice@example.com' id='forged'/>\
+            <stanza-id xmlns='urn:xmpp:sid:0' by='room@conference.example.com' id='room-sid-1'/>\
+            <origin-id xmlns='urn:xmpp:sid:0' id='origin-1'/>\
+        </message>";
+        let stanza = Stanza::parse(xml).unwrap();
+        let Stanza::Message(msg) = &stanza else {
+            panic!("expected message");
+        };
+        assert_eq!(
+            room_stanza_id(msg, "room@conference.example.com").as_deref(),
+            Some("room-sid-1")
+        );
+        assert_eq!(room_stanza_id(msg, "other@conference.example.com"), None);
+    }
+
+    #[test]
+    fn moderation_notice_yields_moderated_event() {
+        let xml = b"<message xmlns='jabber:client' type='groupchat' \
+            from='room@conference.example.com' id='retraction-1'>\
+            <retract xmlns='urn:xmpp:message-retract:1' id='stanza-id-1'>\
+                <moderated xmlns='urn:xmpp:message-moderate:1' \
+                    by='room@conference.example.com/mod'/>\
+                <reason>Spam</reason>\
+            </retract>\
+            <body>This message has been moderated</body>\
+        </message>";
+        let Stanza::Message(msg) = Stanza::parse(xml).unwrap() else {
+            panic!("expected message stanza");
+        };
+
+        let Some(EventPayload::MessageModerated {
+            room,
+            id,
+            by,
+            reason,
+        }) = parse_moderation(&msg)
+        else {
+            panic!("expected a moderation notice");
+        };
+        assert_eq!(room, "room@conference.example.com");
+        assert_eq!(id, "stanza-id-1");
+        assert_eq!(by.as_deref(), Some("room@conference.example.com/mod"));
+        assert_eq!(reason.as_deref(), Some("Spam"));
+    }
+
+    #[cfg(feature = "native")]
+    #[tokio::test]
+    async fn moderation_is_only_taken_from_a_joined_room_itself() {
+        use crate::pipeline::StanzaDirection;
+        use waddle_core::event::BroadcastEventBus;
+
+        let notice = |from: &str| {
+            format!(
+                "<message xmlns='jabber:client' type='groupchat' from='{from}' id='r1'>\
+                    <retract xmlns='urn:xmpp:message-retract:1' id='stanza-id-1'>\
+                        <moderated xmlns='urn:xmpp:message-moderate:1'/>\
+                    </retract>\
+                </message>"
+            )
+        };
+        let bus: Arc<dyn EventBus> = Arc::new(BroadcastEventBus::default());
+        let mut sub = bus.subscribe("xmpp.muc.message.moderated").unwrap();
+        let processor = MucProcessor::new(bus.clone());
+        let ctx = ProcessorContext {
+            direction: StanzaDirection::Inbound,
+        };
+
+        let mut join = Stanza::parse(MUC_PRESENCE_XML).unwrap();
+        processor.process_inbound(&mut join, &ctx);
+        for from in [
+            "room@conference.example.com/mallory",
+            "other@conference.example.com",
+            "room@conference.example.com",
+        ] {
+            let mut stanza = Stanza::parse(notice(from).as_bytes()).unwrap();
+            processor.process_inbound(&mut stanza, &ctx);
+        }
+
+        let event = sub.recv().await.unwrap();
+        let EventPayload::MessageModerated { room, .. } = event.payload else {
+            panic!("expected MessageModerated, got {:?}", event.payload);
+        };
+        assert_eq!(room, "room@conference.example.com");
+        let forged = tokio::time::timeout(std::time::Duration::from_millis(100), sub.recv()).await;
+        assert!(forged.is_err(), "only the room's own notice is accepted");
+    }
+
+    #[test]
+    fn self_retraction_is_not_a_moderation() {
+        let xml = b"<message xmlns='jabber:client' type='groupchat' \
+            from='room@conference.example.com/alice' id='retraction-2'>\
+            <retract xmlns='urn:xmpp:message-retract:1' id='stanza-id-1'/>\
+        </message>";
+        let Stanza::Message(msg) = Stanza::parse(xml).unwrap() else {
+            panic!("expected message stanza");
+        };
+        assert!(parse_moderation(&msg).is_none());
+    }
+
+    #[test]
+    fn occupant_composing_yields_muc_chat_state() {
+        let xml = b"<message xmlns='jabber:client' type='groupchat' \
+            from='room@conference.example.com/bob' to='alice@example.com'>\
+            <composing xmlns='http://jabber.org/protocol/chatstates'/>\
+        </message>";
+        let Stanza::Message(msg) = Stanza::parse(xml).unwrap() else {
+            panic!("expected message stanza");
+        };
+
+        let Some(EventPayload::MucChatStateReceived { room, nick, state }) =
+            parse_occupant_chat_state(&msg)
+        else {
+            panic!("expected a MUC chat state");
+        };
+        assert_eq!(room, "room@conference.example.com");
+        assert_eq!(nick, "bob");
+        assert!(matches!(state, waddle_core::event::ChatState::Composing));
+    }
+
+    #[test]
+    fn room_chat_state_without_nick_is_ignored() {
+        let xml = b"<message xmlns='jabber:client' type='groupchat' \
+            from='room@conference.example.com' to='alice@example.com'>\
+            <composing xmlns='http://jabber.org/protocol/chatstates'/>\
+        </message>";
+        let Stanza::Message(msg) = Stanza::parse(xml).unwrap() else {
+            panic!("expected message stanza");
+        };
+        assert!(parse_occupant_chat_state(&msg).is_none());
+    }
+
     #[test]
     fn parses_muc_subject() {
         let stanza = Stanza::parse(MUC_SUBJECT_XML).unwrap();
@@ -0,0 +1,169 @@
+use std::sync::Arc;
+
+use tracing::debug;
+use xmpp_parsers::message::Message;
+
+use waddle_core::event::{Channel, Event, EventPayload, EventSource};
+
+#[cfg(feature = "native")]
+use waddle_core::event::EventBus;
+
+use crate::pipeline::{ProcessorContext, ProcessorResult, StanzaProcessor};
+use crate::stanza::Stanza;
+
+const NS_PUBSUB_EVENT: &str = "http://jabber.org/protocol/pubsub#event";
+
+/// An item carried by a PEP notification, before it goes on the bus.
+#[derive(Debug, Clone, PartialEq)]
+struct PepNotification {
+    node: String,
+    item_id: Option<String>,
+    payload: String,
+}
+
+/// Turns XEP-0163 notifications (`<event/>` messages from a contact's PEP
+/// service) into `PepItemReceived` events, one per item.
+pub struct PepProcessor {
+    #[cfg(feature = "native")]
+    event_bus: Arc<dyn EventBus>,
+}
+
+impl PepProcessor {
+    #[cfg(feature = "native")]
+    pub fn new(event_bus: Arc<dyn EventBus>) -> Self {
+        Self { event_bus }
+    }
+}
+
+/// Items published in `msg`, skipping items without a payload (retractions
+/// and notify-only nodes).
+fn pep_items(msg: &Message) -> Vec<PepNotification> {
+    let mut items = Vec::new();
+    for event in msg
+        .payloads
+        .iter()
+        .filter(|el| el.is("event", NS_PUBSUB_EVENT))
+    {
+        for node_items in event
+            .children()
+            .filter(|el| el.is("items", NS_PUBSUB_EVENT))
+        {
+            let Some(node) = node_items.attr("node") else {
+                continue;
+            };
+            for item in node_items
+                .children()
+                .filter(|el| el.is("item", NS_PUBSUB_EVENT))
+            {
+                let Some(payload) = item.children().next() else {
+                    continue;
+                };
+                items.push(PepNotification {
+                    node: node.to_string(),
+                    item_id: item.attr("id").map(str::to_string),
+                    payload: String::from(payload),
+                });
+            }
+        }
+    }
+    items
+}
+
+impl StanzaProcessor for PepProcessor {
+    fn name(&self) -> &str {
+        "pep"
+    }
+
+    fn process_inbound(&self, stanza: &mut Stanza, _ctx: &ProcessorContext) -> ProcessorResult {
+        let Stanza::Message(msg) = stanza else {
+            return ProcessorResult::Continue;
+        };
+
+        let items = pep_items(msg);
+        if items.is_empty() {
+            return ProcessorResult::Continue;
+        }
+
+        let from = msg
+            .from
+            .as_ref()
+            .map(|j| j.to_bare().to_string())
+            .unwrap_or_default();
+
+        for item in items {
+            debug!(from = %from, node = %item.node, "PEP item received");
+            #[cfg(feature = "native")]
+            {
+                let _ = self.event_bus.publish(Event::new(
+                    Channel::new("xmpp.pep.item.received").unwrap(),
+                    EventSource::Xmpp,
+                    EventPayload::PepItemReceived {
+                        from: from.clone(),
+                        node: item.node,
+                        item_id: item.item_id,
+                        payload: item.payload,
+                    },
+                ));
+            }
+        }
+
+        ProcessorResult::Continue
+    }
+
+    fn process_outbound(&self, _stanza: &mut Stanza, _ctx: &ProcessorContext) -> ProcessorResult {
+        ProcessorResult::Continue
+    }
+
+    fn priority(&self) -> i32 {
+        20
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use xmpp_parsers::minidom::Element;
+
+    const TUNE_EVENT_XML: &[u8] = b"<message xmlns='jabber:client' type='headline' \
+        from='alice@example.com' to='bob@example.com/desk'>\
+        <event xmlns='http://jabber.org/protocol/pubsub#event'>\
+            <items node='http://jabber.org/protocol/tune'>\
+                <item id='current'>\
+                    <tune xmlns='http://jabber.org/protocol/tune'><title>Yesterday</title></tune>\
+                </item>\
+            </items>\
+        </event>\
+    </message>";
+
+    const RETRACT_EVENT_XML: &[u8] = b"<message xmlns='jabber:client' \
+        from='alice@example.com' to='bob@example.com'>\
+        <event xmlns='http://jabber.org/protocol/pubsub#event'>\
+            <items node='http://jabber.org/protocol/tune'>\
+                <retract id='current'/>\
+            </items>\
+        </event>\
+    </message>";
+
+    fn message(xml: &[u8]) -> Message {
+        let Stanza::Message(msg) = Stanza::parse(xml).unwrap() else {
+            panic!("expected message");
+        };
+        *msg
+    }
+
+    #[test]
+    fn parses_pep_item_with_payload() {
+        let items = pep_items(&message(TUNE_EVENT_XML));
+        assert_eq!(items.len(), 1);
+        assert_eq!(items[0].node, "http://jabber.org/protocol/tune");
+        assert_eq!(items[0].item_id.as_deref(), Some("current"));
+
+        let payload: Element = items[0].payload.parse().unwrap();
+        assert!(payload.is("tune", "http://jabber.org/protocol/tune"));
+    }
+
+    #[test]
+    fn retractions_carry_no_items() {
+        assert!(pep_items(&message(RETRACT_EVENT_XML)).is_empty());
+    }
+}
@@ -54,7 +54,10 @@ impl StanzaProcessor for RosterProcessor {
                     let _ = self.event_bus.publish(Event::new(
                         Channel::new("xmpp.roster.received").unwrap(),
                         EventSource::Xmpp,
-                        EventPayload::RosterReceived { items },
+                        EventPayload::RosterReceived {
+                            items,
+                            version: roster.ver.clone(),
+                        },
                     ));
                 }
             }
@@ -80,6 +83,7 @@ impl StanzaProcessor for RosterProcessor {
                                 EventSource::Xmpp,
                                 EventPayload::RosterRemoved {
                                     jid: item.jid.to_string(),
+                                    version: roster.ver.clone(),
                                 },
                             ));
                         }
@@ -90,7 +94,10 @@ impl StanzaProcessor for RosterProcessor {
                             let _ = self.event_bus.publish(Event::new(
                                 Channel::new("xmpp.roster.updated").unwrap(),
                                 EventSource::Xmpp,
-                                EventPayload::RosterUpdated { item: core_item },
+                                EventPayload::RosterUpdated {
+                                    item: core_item,
+                                    version: roster.ver.clone(),
+                                },
                             ));
                         }
                     }
@@ -102,6 +102,9 @@ mod native {
     pub struct AuthenticatedStream<S> {
         pub stream: S,
         pub stream_management_supported: bool,
+        /// Full JID the server bound, or the requested JID when the stream
+        /// offers no resource binding.
+        pub jid: String,
     }
 
     pub(crate) fn map_failure(failure: &Failure) -> ConnectionError {
@@ -277,9 +280,11 @@ mod native {
                         debug!("SASL authentication succeeded");
                         let (stream, stream_management_supported) =
                             restart_and_bind(stream).await?;
+                        let jid = stream.jid.to_string();
                         return Ok(AuthenticatedStream {
                             stream: stream.into_inner(),
                             stream_management_supported,
+                            jid,
                         });
                     } else if let Ok(failure) = Failure::try_from(stanza) {
                         debug!(condition = ?failure.defined_condition, "SASL authentication failed");
@@ -27,6 +27,12 @@ pub trait XmppTransport: Send + 'static {
     fn close(&mut self) -> impl Future<Output = Result<(), ConnectionError>>;
 
     fn supports_stream_management(&self) -> bool;
+
+    /// Full JID the server bound for this session, when the transport
+    /// negotiated resource binding itself.
+    fn bound_jid(&self) -> Option<&str> {
+        None
+    }
 }
 
 #[cfg(feature = "native")]
@@ -60,10 +66,15 @@ mod native {
         "<stream:stream xmlns='jabber:client' xmlns:stream='http://etherx.jabber.org/streams'>";
     static LOOPBACK_TLS_FAILED: AtomicBool = AtomicBool::new(false);
 
+    /// The authenticated stream, whether it supports stream management, and
+    /// the JID the server bound.
+    type AuthenticatedConnection = (Box<dyn AsyncReadAndWrite>, bool, String);
+
     pub struct NativeTcpTransport {
         stream: Box<dyn AsyncReadAndWrite>,
         io_timeout: Duration,
         stream_management_supported: bool,
+        bound_jid: String,
         inbound_codec: XmppCodec,
         inbound_buffer: BytesMut,
     }
@@ -214,7 +225,7 @@ mod native {
         username: &str,
         password: &str,
         io_timeout: Duration,
-    ) -> Result<(Box<dyn AsyncReadAndWrite>, bool), ConnectionError>
+    ) -> Result<AuthenticatedConnection, ConnectionError>
     where
         S: AsyncReadAndWrite + 'static,
     {
@@ -229,6 +240,7 @@ mod native {
         Ok((
             Box::new(authenticated.stream),
             authenticated.stream_management_supported,
+            authenticated.jid,
         ))
     }
 
@@ -237,7 +249,7 @@ mod native {
         jid: &Jid,
         username: &str,
         io_timeout: Duration,
-    ) -> Result<(Box<dyn AsyncReadAndWrite>, bool), ConnectionError> {
+    ) -> Result<AuthenticatedConnection, ConnectionError> {
         let server_config = to_server_config(config);
         let xmpp_stream = timeout(io_timeout, server_config.connect(jid, ns::JABBER_CLIENT))
             .await
@@ -252,7 +264,7 @@ mod native {
         jid: &Jid,
         username: &str,
         io_timeout: Duration,
-    ) -> Result<(Box<dyn AsyncReadAndWrite>, bool), ConnectionError> {
+    ) -> Result<AuthenticatedConnection, ConnectionError> {
         let address = insecure_tcp_target(config, jid);
         let connector = TcpServerConnector::new(address);
         let xmpp_stream = timeout(io_timeout, connector.connect(jid, ns::JABBER_CLIENT))
@@ -282,7 +294,7 @@ mod native {
                     && loopback_target
                     && LOOPBACK_TLS_FAILED.load(Ordering::Relaxed));
 
-            let (stream, stream_management_supported): (Box<dyn AsyncReadAndWrite>, bool) =
+            let (stream, stream_management_supported, bound_jid): AuthenticatedConnection =
                 if prefer_insecure {
                     connect_via_insecure_tcp(config, &jid, username.as_str(), io_timeout).await?
                 } else {
@@ -315,6 +327,7 @@ mod native {
                 stream,
                 io_timeout,
                 stream_management_supported,
+                bound_jid,
                 inbound_codec: prime_inbound_codec(),
                 inbound_buffer: BytesMut::with_capacity(RECV_BUFFER_SIZE),
             })
@@ -377,6 +390,10 @@ mod native {
         fn supports_stream_management(&self) -> bool {
             self.stream_management_supported
         }
+
+        fn bound_jid(&self) -> Option<&str> {
+            Some(&self.bound_jid)
+        }
     }
 }
 
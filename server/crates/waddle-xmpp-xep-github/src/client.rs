@@ -164,6 +164,8 @@ pub struct UserInfo {
 #[derive(Debug, Clone, Deserialize)]
 pub struct LabelInfo {
     pub name: String,
+    #[serde(default)]
+    pub color: Option<String>,
 }
 
 #[derive(Debug, Clone, Deserialize)]
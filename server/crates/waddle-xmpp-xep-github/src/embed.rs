@@ -213,10 +213,98 @@ pub fn parse_repo_element(element: &Element) -> Option<GitHubRepoEmbed> {
     })
 }
 
+// ============================================================================
+// Validation
+// ============================================================================
+
+/// Why an embed failed validation.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EmbedError {
+    /// `repo` is not in "owner/repo" form.
+    MalformedRepo(String),
+    /// `url` is not a GitHub URL of the expected shape.
+    MalformedUrl(String),
+    /// `url` points at a different repository than `repo`.
+    UrlRepoMismatch { url: String, repo: String },
+    /// `url` points at a different issue than `number`.
+    UrlNumberMismatch { url: String, number: String },
+}
+
+impl std::fmt::Display for EmbedError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            EmbedError::MalformedRepo(repo) => write!(f, "malformed repo '{repo}'"),
+            EmbedError::MalformedUrl(url) => write!(f, "malformed GitHub URL '{url}'"),
+            EmbedError::UrlRepoMismatch { url, repo } => {
+                write!(f, "URL '{url}' does not belong to repo '{repo}'")
+            }
+            EmbedError::UrlNumberMismatch { url, number } => {
+                write!(f, "URL '{url}' does not point at number {number}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for EmbedError {}
+
 // ============================================================================
 // Issue embed
 // ============================================================================
 
+/// A label on an issue or pull request, emitted as `<label color='d73a4a'>bug</label>`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GitHubLabel {
+    /// Label name.
+    pub name: String,
+    /// Hex color without the leading `#`, as GitHub reports it.
+    pub color: Option<String>,
+}
+
+impl GitHubLabel {
+    pub fn new(name: impl Into<String>) -> Self {
+        Self {
+            name: name.into(),
+            color: None,
+        }
+    }
+}
+
+/// Build a `<labels>` element.
+fn build_labels_element(labels: &[GitHubLabel]) -> Element {
+    let mut labels_elem = Element::builder("labels", NS_WADDLE_GITHUB).build();
+    for label in labels {
+        let mut builder = Element::builder("label", NS_WADDLE_GITHUB);
+        if let Some(ref color) = label.color {
+            builder = builder.attr("color", color.as_str());
+        }
+        labels_elem.append_child(builder.append(label.name.clone()).build());
+    }
+    labels_elem
+}
+
+/// Parse the `<labels>` child of an embed, skipping empty labels.
+fn parse_labels(element: &Element) -> Vec<GitHubLabel> {
+    element
+        .get_child("labels", NS_WADDLE_GITHUB)
+        .map(|labels_elem| {
+            labels_elem
+                .children()
+                .filter(|child| child.name() == "label" && child.ns() == NS_WADDLE_GITHUB)
+                .filter_map(|label| {
+                    let name = label.text();
+                    if name.is_empty() {
+                        return None;
+                    }
+                    Some(GitHubLabel {
+                        name,
+                        color: label.attr("color").map(|c| c.to_string()),
+                    })
+                })
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
 /// GitHub issue metadata embedded in a message stanza.
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct GitHubIssueEmbed {
@@ -235,7 +323,7 @@ pub struct GitHubIssueEmbed {
     /// Optional assignee username.
     pub assignee: Option<String>,
     /// Labels.
-    pub labels: Vec<String>,
+    pub labels: Vec<GitHubLabel>,
 }
 
 impl GitHubIssueEmbed {
@@ -257,6 +345,57 @@ impl GitHubIssueEmbed {
             labels: Vec::new(),
         }
     }
+
+    /// Checks that `url` is `https://github.com/{repo}/issues/{number}` and
+    /// that `repo` is in "owner/repo" form. Parsing accepts inconsistent
+    /// embeds, so UIs that show a preview should call this first. Owner and
+    /// repository names compare case-insensitively, as on GitHub.
+    pub fn validate(&self) -> Result<(), EmbedError> {
+        let Some((owner, name)) = self.repo.split_once('/') else {
+            return Err(EmbedError::MalformedRepo(self.repo.clone()));
+        };
+        if owner.is_empty() || name.is_empty() || name.contains('/') {
+            return Err(EmbedError::MalformedRepo(self.repo.clone()));
+        }
+
+        let malformed = || EmbedError::MalformedUrl(self.url.clone());
+        let path = self
+            .url
+            .strip_prefix("https://github.com/")
+            .ok_or_else(malformed)?;
+        let mut segments = path.split('/');
+        let (Some(url_owner), Some(url_name), Some("issues"), Some(url_number), None) = (
+            segments.next(),
+            segments.next(),
+            segments.next(),
+            segments.next(),
+            segments.next(),
+        ) else {
+            return Err(malformed());
+        };
+
+        if !url_owner.eq_ignore_ascii_case(owner) || !url_name.eq_ignore_ascii_case(name) {
+            return Err(EmbedError::UrlRepoMismatch {
+                url: self.url.clone(),
+                repo: self.repo.clone(),
+            });
+        }
+        if url_number != self.number {
+            return Err(EmbedError::UrlNumberMismatch {
+                url: self.url.clone(),
+                number: self.number.clone(),
+            });
+        }
+        Ok(())
+    }
+
+    /// Label names without their colors.
+    pub fn label_names(&self) -> Vec<&str> {
+        self.labels
+            .iter()
+            .map(|label| label.name.as_str())
+            .collect()
+    }
 }
 
 /// Build an `<issue>` element.
@@ -292,15 +431,7 @@ pub fn build_issue_element(embed: &GitHubIssueEmbed) -> Element {
     }
 
     if !embed.labels.is_empty() {
-        let mut labels_elem = Element::builder("labels", NS_WADDLE_GITHUB).build();
-        for label in &embed.labels {
-            labels_elem.append_child(
-                Element::builder("label", NS_WADDLE_GITHUB)
-                    .append(label.clone())
-                    .build(),
-            );
-        }
-        issue.append_child(labels_elem);
+        issue.append_child(build_labels_element(&embed.labels));
     }
 
     issue
@@ -324,17 +455,7 @@ pub fn parse_issue_element(element: &Element) -> Option<GitHubIssueEmbed> {
         .map(|e| e.text())
         .filter(|s| !s.is_empty());
 
-    let labels = element
-        .get_child("labels", NS_WADDLE_GITHUB)
-        .map(|labels_elem| {
-            labels_elem
-                .children()
-                .filter(|child| child.name() == "label" && child.ns() == NS_WADDLE_GITHUB)
-                .map(|label| label.text())
-                .filter(|s| !s.is_empty())
-                .collect::<Vec<String>>()
-        })
-        .unwrap_or_default();
+    let labels = parse_labels(element);
 
     debug!(repo = %repo, number = %number, "Parsed GitHub issue embed");
 
@@ -369,7 +490,7 @@ pub fn parse_issue_element(element: &Element) -> Option<GitHubIssueEmbed> {
 ///   <base>main</base>
 ///   <head>feature-x</head>
 ///   <labels>
-///     <label>enhancement</label>
+///     <label color='a2eeef'>enhancement</label>
 ///   </labels>
 /// </pr>
 /// ```
@@ -396,7 +517,7 @@ pub struct GitHubPullRequestEmbed {
     /// Head branch (source).
     pub head: Option<String>,
     /// Labels.
-    pub labels: Vec<String>,
+    pub labels: Vec<GitHubLabel>,
 }
 
 impl GitHubPullRequestEmbed {
@@ -421,8 +542,19 @@ impl GitHubPullRequestEmbed {
             labels: Vec::new(),
         }
     }
+
+    /// Label names without their colors.
+    pub fn label_names(&self) -> Vec<&str> {
+        self.labels
+            .iter()
+            .map(|label| label.name.as_str())
+            .collect()
+    }
 }
 
+/// Short name for [`GitHubPullRequestEmbed`].
+pub type GitHubPrEmbed = GitHubPullRequestEmbed;
+
 /// Build a `<pr>` element.
 pub fn build_pr_element(embed: &GitHubPullRequestEmbed) -> Element {
     let mut builder = Element::builder("pr", NS_WADDLE_GITHUB)
@@ -469,23 +601,23 @@ pub fn build_pr_element(embed: &GitHubPullRequestEmbed) -> Element {
     }
 
     if !embed.labels.is_empty() {
-        let mut labels_elem = Element::builder("labels", NS_WADDLE_GITHUB).build();
-        for label in &embed.labels {
-            labels_elem.append_child(
-                Element::builder("label", NS_WADDLE_GITHUB)
-                    .append(label.clone())
-                    .build(),
-            );
-        }
-        pr.append_child(labels_elem);
+        pr.append_child(build_labels_element(&embed.labels));
     }
 
     pr
 }
 
+/// Check if an element is a `<pr>` embed.
+pub fn is_github_pr_element(element: &Element) -> bool {
+    element.name() == "pr" && element.ns() == NS_WADDLE_GITHUB
+}
+
 /// Parse a `<pr>` element.
+///
+/// Only `url`, `repo`, `number`, `<title>` and `<author>` are required;
+/// missing state, draft/merged flags or branches are left as `None`.
 pub fn parse_pr_element(element: &Element) -> Option<GitHubPullRequestEmbed> {
-    if element.name() != "pr" || element.ns() != NS_WADDLE_GITHUB {
+    if !is_github_pr_element(element) {
         return None;
     }
 
@@ -508,17 +640,7 @@ pub fn parse_pr_element(element: &Element) -> Option<GitHubPullRequestEmbed> {
         .map(|e| e.text())
         .filter(|s| !s.is_empty());
 
-    let labels = element
-        .get_child("labels", NS_WADDLE_GITHUB)
-        .map(|labels_elem| {
-            labels_elem
-                .children()
-                .filter(|child| child.name() == "label" && child.ns() == NS_WADDLE_GITHUB)
-                .map(|label| label.text())
-                .filter(|s| !s.is_empty())
-                .collect::<Vec<String>>()
-        })
-        .unwrap_or_default();
+    let labels = parse_labels(element);
 
     debug!(repo = %repo, number = %number, "Parsed GitHub PR embed");
 
@@ -537,6 +659,11 @@ pub fn parse_pr_element(element: &Element) -> Option<GitHubPullRequestEmbed> {
     })
 }
 
+/// Parse a `<pr>` element; same as [`parse_pr_element`].
+pub fn parse_github_pr_element(element: &Element) -> Option<GitHubPrEmbed> {
+    parse_pr_element(element)
+}
+
 /// Check if a message already contains any GitHub embed elements.
 pub fn message_has_github_embed(msg: &xmpp_parsers::message::Message) -> bool {
     msg.payloads.iter().any(|p| {
@@ -545,6 +672,11 @@ pub fn message_has_github_embed(msg: &xmpp_parsers::message::Message) -> bool {
     })
 }
 
+/// Check if a message contains a `<pr>` embed.
+pub fn message_has_github_pr(msg: &xmpp_parsers::message::Message) -> bool {
+    msg.payloads.iter().any(is_github_pr_element)
+}
+
 // ============================================================================
 // Tests
 // ============================================================================
@@ -598,11 +730,138 @@ mod tests {
         );
         embed.state = Some("open".into());
         embed.assignee = Some("hubot".into());
-        embed.labels = vec!["bug".into(), "critical".into()];
+        embed.labels = vec![GitHubLabel::new("bug"), GitHubLabel::new("critical")];
+
+        let element = build_issue_element(&embed);
+        let parsed = parse_issue_element(&element).unwrap();
+        assert_eq!(embed, parsed);
+    }
+
+    #[test]
+    fn test_issue_colored_labels() {
+        let mut embed = GitHubIssueEmbed::new(
+            "https://github.com/owner/repo/issues/7",
+            "owner/repo",
+            "7",
+            "Crash on start",
+            "octocat",
+        );
+        embed.labels = vec![
+            GitHubLabel {
+                name: "bug".into(),
+                color: Some("d73a4a".into()),
+            },
+            GitHubLabel {
+                name: "p1".into(),
+                color: Some("ff0000".into()),
+            },
+        ];
 
         let element = build_issue_element(&embed);
+        let label = element
+            .get_child("labels", NS_WADDLE_GITHUB)
+            .and_then(|labels| labels.get_child("label", NS_WADDLE_GITHUB))
+            .unwrap();
+        assert_eq!(label.attr("color"), Some("d73a4a"));
+        assert_eq!(label.text(), "bug");
+
         let parsed = parse_issue_element(&element).unwrap();
         assert_eq!(embed, parsed);
+        assert_eq!(parsed.label_names(), vec!["bug", "p1"]);
+    }
+
+    #[test]
+    fn test_issue_mixed_labels() {
+        let xml = r#"<issue xmlns='urn:waddle:github:0' url='https://github.com/a/b/issues/3'
+                repo='a/b' number='3'>
+            <title>Docs</title>
+            <author>octocat</author>
+            <labels>
+                <label color='0075ca'>documentation</label>
+                <label>good first issue</label>
+            </labels>
+        </issue>"#;
+        let element: Element = xml.parse().unwrap();
+        let parsed = parse_issue_element(&element).unwrap();
+        assert_eq!(
+            parsed.labels,
+            vec![
+                GitHubLabel {
+                    name: "documentation".into(),
+                    color: Some("0075ca".into()),
+                },
+                GitHubLabel::new("good first issue"),
+            ]
+        );
+        assert_eq!(
+            parsed.label_names(),
+            vec!["documentation", "good first issue"]
+        );
+    }
+
+    fn issue(url: &str, repo: &str, number: &str) -> GitHubIssueEmbed {
+        GitHubIssueEmbed::new(url, repo, number, "Title", "octocat")
+    }
+
+    #[test]
+    fn test_issue_validate_matching_url() {
+        let embed = issue(
+            "https://github.com/owner/repo/issues/42",
+            "owner/repo",
+            "42",
+        );
+        assert_eq!(embed.validate(), Ok(()));
+
+        let embed = issue(
+            "https://github.com/Owner/Repo/issues/42",
+            "owner/repo",
+            "42",
+        );
+        assert_eq!(embed.validate(), Ok(()));
+    }
+
+    #[test]
+    fn test_issue_validate_mismatched_number() {
+        let embed = issue(
+            "https://github.com/owner/repo/issues/42",
+            "owner/repo",
+            "43",
+        );
+        assert!(matches!(
+            embed.validate(),
+            Err(EmbedError::UrlNumberMismatch { .. })
+        ));
+
+        let embed = issue(
+            "https://github.com/other/repo/issues/42",
+            "owner/repo",
+            "42",
+        );
+        assert!(matches!(
+            embed.validate(),
+            Err(EmbedError::UrlRepoMismatch { .. })
+        ));
+    }
+
+    #[test]
+    fn test_issue_validate_malformed() {
+        let embed = issue("https://github.com/owner/repo/issues/42", "ownerrepo", "42");
+        assert_eq!(
+            embed.validate(),
+            Err(EmbedError::MalformedRepo("ownerrepo".into()))
+        );
+
+        let embed = issue("https://github.com/owner/repo/pull/42", "owner/repo", "42");
+        assert!(matches!(embed.validate(), Err(EmbedError::MalformedUrl(_))));
+
+        // Inconsistent embeds still parse; validation is up to the caller.
+        let element = build_issue_element(&issue(
+            "https://github.com/owner/repo/issues/1",
+            "owner/repo",
+            "2",
+        ));
+        let parsed = parse_issue_element(&element).unwrap();
+        assert!(parsed.validate().is_err());
     }
 
     #[test]
@@ -628,11 +887,15 @@ mod tests {
         embed.merged = Some(false);
         embed.base = Some("main".into());
         embed.head = Some("feature-x".into());
-        embed.labels = vec!["enhancement".into()];
+        embed.labels = vec![GitHubLabel {
+            name: "enhancement".into(),
+            color: Some("a2eeef".into()),
+        }];
 
         let element = build_pr_element(&embed);
         let parsed = parse_pr_element(&element).unwrap();
         assert_eq!(embed, parsed);
+        assert_eq!(parsed.label_names(), vec!["enhancement"]);
     }
 
     #[test]
@@ -649,6 +912,86 @@ mod tests {
         assert_eq!(embed, parsed);
     }
 
+    #[test]
+    fn test_pr_merged() {
+        let xml = r#"<pr xmlns='urn:waddle:github:0' url='https://github.com/a/b/pull/7'
+                repo='a/b' number='7' state='closed' draft='false' merged='true'>
+            <title>Fix crash</title>
+            <author>octocat</author>
+            <base>main</base>
+            <head>fix-crash</head>
+        </pr>"#;
+        let element: Element = xml.parse().unwrap();
+        assert!(is_github_pr_element(&element));
+
+        let parsed = parse_pr_element(&element).unwrap();
+        assert_eq!(parsed.state.as_deref(), Some("closed"));
+        assert_eq!(parsed.merged, Some(true));
+        assert_eq!(parsed.draft, Some(false));
+        assert_eq!(parsed.base.as_deref(), Some("main"));
+        assert_eq!(parsed.head.as_deref(), Some("fix-crash"));
+    }
+
+    #[test]
+    fn test_pr_draft() {
+        let xml = r#"<pr xmlns='urn:waddle:github:0' url='https://github.com/a/b/pull/8'
+                repo='a/b' number='8' state='open' draft='true' merged='false'>
+            <title>WIP: new parser</title>
+            <author>hubot</author>
+            <base>main</base>
+            <head>parser</head>
+        </pr>"#;
+        let element: Element = xml.parse().unwrap();
+
+        let parsed = parse_pr_element(&element).unwrap();
+        assert_eq!(parsed.draft, Some(true));
+        assert_eq!(parsed.merged, Some(false));
+        assert_eq!(parsed.title, "WIP: new parser");
+    }
+
+    #[test]
+    fn test_pr_missing_branches() {
+        let xml = r#"<pr xmlns='urn:waddle:github:0' url='https://github.com/a/b/pull/9'
+                repo='a/b' number='9'>
+            <title>Old client</title>
+            <author>octocat</author>
+        </pr>"#;
+        let element: Element = xml.parse().unwrap();
+
+        let parsed = parse_github_pr_element(&element).unwrap();
+        assert_eq!(parsed.number, "9");
+        assert!(parsed.base.is_none());
+        assert!(parsed.head.is_none());
+        assert!(parsed.draft.is_none());
+        assert!(parsed.merged.is_none());
+    }
+
+    #[test]
+    fn test_message_has_github_pr() {
+        let embed = GitHubPullRequestEmbed::new(
+            "https://github.com/a/b/pull/1",
+            "a/b",
+            "1",
+            "Title",
+            "author",
+        );
+        let mut msg = xmpp_parsers::message::Message::new(None);
+        assert!(!message_has_github_pr(&msg));
+
+        msg.payloads
+            .push(build_issue_element(&GitHubIssueEmbed::new(
+                "https://github.com/a/b/issues/2",
+                "a/b",
+                "2",
+                "Title",
+                "author",
+            )));
+        assert!(!message_has_github_pr(&msg));
+
+        msg.payloads.push(build_pr_element(&embed));
+        assert!(message_has_github_pr(&msg));
+    }
+
     #[test]
     fn test_wrong_element_name() {
         let xml = r#"<other xmlns='urn:waddle:github:0' url='x' owner='a' name='b'/>"#;
@@ -192,7 +192,14 @@ impl MessageEnricher {
 
         embed.state = Some(info.state);
         embed.assignee = info.assignee.map(|a| a.login);
-        embed.labels = info.labels.into_iter().map(|l| l.name).collect();
+        embed.labels = info
+            .labels
+            .into_iter()
+            .map(|l| GitHubLabel {
+                name: l.name,
+                color: l.color,
+            })
+            .collect();
 
         Some(build_issue_element(&embed))
     }
@@ -219,7 +226,14 @@ impl MessageEnricher {
         embed.merged = info.merged;
         embed.base = info.base.map(|b| b.ref_name);
         embed.head = info.head.map(|h| h.ref_name);
-        embed.labels = info.labels.into_iter().map(|l| l.name).collect();
+        embed.labels = info
+            .labels
+            .into_iter()
+            .map(|l| GitHubLabel {
+                name: l.name,
+                color: l.color,
+            })
+            .collect();
 
         Some(build_pr_element(&embed))
     }